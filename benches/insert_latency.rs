@@ -43,7 +43,7 @@ use test::Bencher;
 use std::mem;
 use std::sync::{Once, ONCE_INIT};
 
-use kvs::common::Pointer;
+use kvs::common::{Pointer,u64_key};
 use kvs::lsm::{LSM,PutPolicy};
 use kvs::segment::ObjDesc;
 use kvs::sched::pin_cpu;
@@ -66,7 +66,7 @@ type KeyType = u64;
 // as each invocation of Bencher::iter.
 lazy_static! {
     pub static ref KVS: LSM =
-        LSM::new(LSM_CAPACITY<<30);
+        LSM::new(LSM_CAPACITY<<30).unwrap();
 }
 
 static START: Once = ONCE_INIT;
@@ -88,7 +88,7 @@ fn insert_n(b: &mut Bencher, p: PutPolicy,
     let mut rng = rand::thread_rng();
     let key = rng.gen::<u64>();
     let val: Vec<u8> = Vec::with_capacity(len);
-    let obj = ObjDesc::new(key, Pointer(val.as_ptr()), len);
+    let obj = ObjDesc::new(u64_key(&key), Pointer(val.as_ptr()), len);
     b.iter( || { kvs.put_where(&obj,p) });
 }
 
@@ -31,8 +31,7 @@ extern crate kvs;
 
 use clap::{Arg, App, SubCommand};
 use log::LogLevel;
-use kvs::common::{Pointer,ErrorCode,rdrand};
-use kvs::epoch;
+use kvs::common::{Pointer,ErrorCode,rdrand,u64_key};
 use kvs::logger;
 use kvs::memory;
 use kvs::lsm::{PutPolicy,LSM};
@@ -61,7 +60,7 @@ fn run() { }
 fn run() {
     logger::enable();
 
-    let mut kvs = LSM::new( 1usize<<33 );
+    let mut kvs = LSM::new( 1usize<<33 ).unwrap();
     kvs.enable_compaction(NodeId(0));
     let capacity = kvs.capacity();
 
@@ -74,9 +73,10 @@ fn run() {
     let fill80 = ((capacity as f64) * 0.8 / 2f64) as usize;
     info!("filling socket 0 80%: {}", fill80);
     loop {
-        let obj = ObjDesc::new(counter as u64, v, size as u32);
+        let key = counter as u64;
+        let obj = ObjDesc::new(u64_key(&key), v, size as u32);
         if let Err(e) = kvs.put_where(&obj, PutPolicy::Specific(0)) {
-            if let ErrorCode::OutOfMemory = e {
+            if let ErrorCode::OutOfMemory{..} = e {
                 info!("log filled, no more inserting");
                 break;
             }
@@ -103,7 +103,8 @@ fn run() {
 
     let mut counter = 0;
     for x in &k {
-        if let Err(e) = kvs.del_object(*x as u64) {
+        let key = *x as u64;
+        if let Err(e) = kvs.del_object(u64_key(&key)) {
             println!("x {}", x);
             panic!("error: {:?}", e);
         }
@@ -37,7 +37,7 @@ extern crate kvs;
 use clap::{Arg, App, SubCommand};
 use log::LogLevel;
 use kvs::clock;
-use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq};
+use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq,u64_key};
 use kvs::meta;
 use kvs::logger;
 use kvs::memory;
@@ -60,20 +60,20 @@ fn main() {
 
     let capacity = 1usize << 31;
     let ht_nitems = 1usize << 20;
-    let mut kvs: LSM = LSM::new2(capacity, ht_nitems);
+    let mut kvs: LSM = LSM::new2(capacity, ht_nitems).unwrap();
 
     let key: u64 = 1;
     let v: Vec<u8> = vec![1u8,2,3,4,5];
     let p = Pointer(v.as_ptr() as *const u8);
-    let obj = ObjDesc::new(key, p, v.len()*8);
+    let obj = ObjDesc::new(u64_key(&key), p, v.len());
     assert!(kvs.put_object(&obj).is_ok());
 
     let mut b = [0u8; 5];
-    assert!(kvs.get_object(key, &mut b).is_ok());
+    assert_eq!(kvs.get_object(u64_key(&key), &mut b).ok(), Some(v.len()));
 
     println!("v: {:?}", v);
     println!("b: {:?}", b);
     assert_eq!(v, b);
 
-    assert!(kvs.del_object(key).is_ok());
+    assert!(kvs.del_object(u64_key(&key)).is_ok());
 }
@@ -46,7 +46,7 @@ use kvs::distributions::*;
 
 use clap::{Arg, App, SubCommand};
 use kvs::clock;
-use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq};
+use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq,u64_key};
 use kvs::logger;
 use kvs::lsm::{self,LSM};
 use kvs::memory;
@@ -78,13 +78,13 @@ pub const MAX_KEYSIZE: usize = 1usize << 10;
 
 #[inline(always)]
 fn put_object(kvs: &mut LSM, key: u64, value: Pointer<u8>, len: usize, sock: usize) {
-    let obj = ObjDesc::new(key, value, len);
+    let obj = ObjDesc::new(u64_key(&key), value, len);
     let nibnode = lsm::PutPolicy::Specific(sock);
     loop {
         let err = kvs.put_where(&obj, nibnode);
         if unlikely!(err.is_err()) {
             match err {
-                Err(ErrorCode::OutOfMemory) => continue,
+                Err(ErrorCode::OutOfMemory{..}) => continue,
                 _ => {
                     println!("Error: {:?}", err.unwrap());
                     unsafe { intrinsics::abort(); }
@@ -100,7 +100,7 @@ fn put_object(kvs: &mut LSM, key: u64, value: Pointer<u8>, len: usize, sock: usi
 fn get_object(kvs: &mut LSM, key: u64) {
     let mut buf: [u8;MAX_KEYSIZE] =
         unsafe { mem::uninitialized() };
-    let _ = kvs.get_object(key, &mut buf);
+    let _ = kvs.get_object(u64_key(&key), &mut buf);
 }
 
 macro_rules! _put {
@@ -146,7 +146,8 @@ fn put_many(kvs: &mut LSM, nitems: usize, itemsz: usize, sock: usize, kbase: u64
 
 fn del_many(kvs: &mut LSM, nitems: usize, kbase: u64) {
     for key in 1..nitems {
-        let _ = kvs.del_object(key as u64 +kbase);
+        let key = key as u64 + kbase;
+        let _ = kvs.del_object(u64_key(&key));
     }
 }
 
@@ -157,7 +158,7 @@ fn do_local() {
     let nitems = activesz / itemsz;
     println!("totalsz {} activesz {} itemsz {} nitems {}",
              totalsz, activesz, itemsz, nitems);
-    let mut kvs = LSM::new2(totalsz, nitems*2);
+    let mut kvs = LSM::new2(totalsz, nitems*2).unwrap();
     for node in 0..numa::NODE_MAP.sockets() { kvs.enable_compaction(NodeId(node)); }
 
     // different keys for each socket
@@ -200,7 +201,7 @@ fn do_all() {
     let nitems = activesz / itemsz;
     println!("totalsz {} activesz {} itemsz {} nitems {}",
              totalsz, activesz, itemsz, nitems);
-    let mut kvs = LSM::new2(totalsz, nitems*2);
+    let mut kvs = LSM::new2(totalsz, nitems*2).unwrap();
     for node in 0..numa::NODE_MAP.sockets() { kvs.enable_compaction(NodeId(node)); }
 
     println!("warmup");
@@ -88,7 +88,7 @@ fn main() {
 
     let cap: usize = arg_as_num::<usize>(&matches, "capacity");
 
-    let mut kvs = LSM::new(cap);
+    let mut kvs = LSM::new(cap).unwrap();
     for n in 0..numa::NODE_MAP.sockets() {
         kvs.enable_compaction(NodeId(n));
     }
@@ -57,7 +57,7 @@ extern crate kvs;
 
 use clap::{Arg, App, SubCommand};
 use log::LogLevel;
-use kvs::common::{Pointer,ErrorCode,rdrand};
+use kvs::common::{Pointer,ErrorCode,rdrand,u64_key};
 use kvs::epoch;
 use kvs::logger;
 use kvs::memory;
@@ -128,7 +128,6 @@ fn pairs(kvs: &LSM, npairs: usize, objsize: usize) {
                 unsafe { value.set_len(objsize); }
                 let vptr = Pointer(value.as_ptr() as *const u8);
                 let vlen = value.len() * 8;
-                let mut obj = ObjDesc::new(0u64, vptr, vlen);
 
                 b.wait();
 
@@ -138,20 +137,20 @@ fn pairs(kvs: &LSM, npairs: usize, objsize: usize) {
                     while key <= (start+ per as u64) {
 
                         // wait for other thread to catch up
-                        if kvs.exists(key) {
+                        if kvs.exists(u64_key(&key)) {
                             let now_ = Instant::now();
                             loop {
                                 if now_.elapsed().as_secs() > 2 {
                                     assert!(false,
                                             "sender waited too long");
                                 }
-                                if !kvs.exists(key) {
+                                if !kvs.exists(u64_key(&key)) {
                                     break;
                                 }
                             }
                         }
 
-                        obj.key = key;
+                        let obj = ObjDesc::new(u64_key(&key), vptr, vlen);
                         assert!(kvs.put_where(&obj,
                                    PutPolicy::Specific(sock)).is_ok());
                         key += 1;
@@ -207,7 +206,7 @@ fn pairs(kvs: &LSM, npairs: usize, objsize: usize) {
                             if key == 0u64 {
                                 break;
                             }
-                            assert!(kvs.del_object(key).is_ok());
+                            assert!(kvs.del_object(u64_key(&key)).is_ok());
                             many += 1;
                             fails = 0usize;
                         },
@@ -286,7 +285,7 @@ fn main() {
     cap *= 1usize<<30;
 
     logger::enable();
-    let kvs = LSM::new(cap);
+    let kvs = LSM::new(cap).unwrap();
 
     // turn on compaction for all sockets
     for sock in 0..numa::NODE_MAP.sockets() {
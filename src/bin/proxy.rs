@@ -40,7 +40,7 @@ extern {
 
 fn main() {
     let kvs =
-        Box::new(LSM::new(1usize<<35));
+        Box::new(LSM::new(1usize<<35).unwrap());
     unsafe {
         let p = Box::into_raw(kvs);
         KVS = Pointer(p);
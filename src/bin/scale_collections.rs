@@ -18,7 +18,6 @@
 
 
 #![feature(test)]
-#![feature(asm)]
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(unused_mut)]
@@ -32,11 +31,11 @@ extern crate clap;
 
 extern crate kvs;
 
+use std::arch::asm;
 use std::collections::*;
 use clap::{Arg, App, SubCommand};
 use log::LogLevel;
 use kvs::common::ErrorCode;
-use kvs::epoch;
 use kvs::logger;
 use kvs::memory;
 use kvs::lsm::{PutPolicy,LSM};
@@ -55,7 +54,7 @@ use std::time::Instant;
 #[allow(unused_mut)]
 unsafe fn rdrand() -> u32 {
     let mut r: u32;
-    asm!("rdrand $0" : "=r" (r));
+    asm!("rdrand {0:e}", out(reg) r, options(nomem, nostack));
     r
 }
 
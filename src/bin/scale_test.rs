@@ -33,8 +33,7 @@ extern crate kvs;
 
 use clap::{Arg, App, SubCommand};
 use log::LogLevel;
-use kvs::common::{Pointer,ErrorCode,rdrand};
-use kvs::epoch;
+use kvs::common::{Pointer,ErrorCode,rdrand,u64_key};
 use kvs::logger;
 use kvs::memory;
 use kvs::lsm::{PutPolicy,LSM};
@@ -92,13 +91,13 @@ fn run(config: &Config) {
             if fill > LSM::default_capacity() {
                 panic!("nobjects too many for capacity");
             }
-            LSM::default()
+            LSM::default().unwrap()
         },
         _ => {
             if fill > config.capacity {
                 panic!("nobjects too many for capacity");
             }
-            LSM::new(config.capacity)
+            LSM::new(config.capacity).unwrap()
         },
     };
 
@@ -151,7 +150,7 @@ fn run(config: &Config) {
                 info!("range [{},{}) on socket {}",
                     start_key, end_key, sock);
                 for key in start_key..end_key {
-                    let obj = ObjDesc::new(key, v, size as u32);
+                    let obj = ObjDesc::new(u64_key(&key), v, size as u32);
                     if let Err(code) = arc.put_where(&obj,
                                         PutPolicy::Specific(sock)) {
                         panic!("{:?}", code)
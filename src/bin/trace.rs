@@ -41,7 +41,7 @@ use clap::{Arg, App, SubCommand};
 use log::LogLevel;
 
 use kvs::clock;
-use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq};
+use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq,u64_key};
 use kvs::meta;
 use kvs::logger;
 use kvs::memory;
@@ -538,7 +538,7 @@ fn main() {
 #[cfg(not(feature = "extern_ycsb"))]
 fn kvs_init(config: &Config) {
     let kvs =
-        Box::new(LSM::new2(config.total, config.records*2));
+        Box::new(LSM::new2(config.total, config.records*2).unwrap());
         //Box::new(LSM::new2(config.total, 1usize<<30));
     info!("Enabling compaction");
     for node in 0..numa::NODE_MAP.sockets() {
@@ -554,13 +554,13 @@ fn kvs_init(config: &Config) {
 #[cfg(not(feature = "extern_ycsb"))]
 fn put_object(key: u64, value: Pointer<u8>, len: usize, sock: usize) {
     let kvs: &LSM = unsafe { &*KVS.0 };
-    let obj = ObjDesc::new(key, value, len);
+    let obj = ObjDesc::new(u64_key(&key), value, len);
     let nibnode = lsm::PutPolicy::Specific(sock);
     loop {
         let err = kvs.put_where(&obj, nibnode);
         if err.is_err() {
             match err {
-                Err(ErrorCode::OutOfMemory) => continue,
+                Err(ErrorCode::OutOfMemory{..}) => continue,
                 _ => {
                     println!("Error: {:?}", err.unwrap());
                     unsafe { intrinsics::abort(); }
@@ -586,7 +586,7 @@ fn get_object(key: u64) {
         let mut buf: &mut [u8] = unsafe {
             slice::from_raw_parts_mut::<u8>(*p, MAX_KEYSIZE)
         };
-        let _ = kvs.get_object(key, buf);
+        let _ = kvs.get_object(u64_key(&key), buf);
     });
 }
 
@@ -594,7 +594,7 @@ fn get_object(key: u64) {
 #[cfg(not(feature = "extern_ycsb"))]
 fn del_object(key: u64) {
     let kvs: &LSM = unsafe { &*KVS.0 };
-    let _ = kvs.del_object(key);
+    let _ = kvs.del_object(u64_key(&key));
 }
 
 #[link(name = "micaext")]
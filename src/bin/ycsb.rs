@@ -45,7 +45,7 @@ use kvs::distributions::*;
 use clap::{Arg, App, SubCommand};
 use log::LogLevel;
 use kvs::clock;
-use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq};
+use kvs::common::{self,Pointer,ErrorCode,rdrand,rdrandq,u64_key};
 use kvs::meta;
 use kvs::logger;
 use kvs::memory;
@@ -83,7 +83,7 @@ static mut KVS: Pointer<LSM> = Pointer(0 as *const LSM);
 #[cfg(not(feature = "extern_ycsb"))]
 fn kvs_init(config: &Config) {
     let mut kvs =
-        Box::new(LSM::new2(config.total, config.records*2));
+        Box::new(LSM::new2(config.total, config.records*2).unwrap());
         //Box::new(LSM::new2(config.total, 1usize<<30));
     if config.comp {
         info!("Enabling compaction");
@@ -103,13 +103,13 @@ fn kvs_init(config: &Config) {
 #[cfg(not(feature = "extern_ycsb"))]
 fn put_object(key: u64, value: Pointer<u8>, len: usize, sock: usize) {
     let kvs: &LSM = unsafe { &*KVS.0 };
-    let obj = ObjDesc::new(key, value, len);
+    let obj = ObjDesc::new(u64_key(&key), value, len);
     let nibnode = lsm::PutPolicy::Specific(sock);
     loop {
         let err = kvs.put_where(&obj, nibnode);
         if err.is_err() {
             match err {
-                Err(ErrorCode::OutOfMemory) => continue,
+                Err(ErrorCode::OutOfMemory{..}) => continue,
                 _ => {
                     println!("Error: {:?}", err.unwrap());
                     unsafe { intrinsics::abort(); }
@@ -127,8 +127,8 @@ fn get_object(key: u64) {
     let kvs: &LSM = unsafe { &*KVS.0 };
     let mut buf: [u8;MAX_KEYSIZE] =
         unsafe { mem::uninitialized() };
-    let _ = kvs.get_object(key, &mut buf);
-    //if let Err(e) = kvs.get_object(key, &mut buf) {
+    let _ = kvs.get_object(u64_key(&key), &mut buf);
+    //if let Err(e) = kvs.get_object(u64_key(&key), &mut buf) {
         //warn!("{:?} {:x}", e, key);
         //unsafe { intrinsics::abort(); }
     //}
@@ -399,9 +399,9 @@ impl WorkloadGenerator {
 
         info!("Starting experiment");
         let mut counter = 0u64;
-        let start = unsafe { clock::rdtsc() }; // for throttling
+        let start = clock::now(); // for throttling
 
-        let mut tic = unsafe { clock::rdtsc() }; // report performance
+        let mut tic = clock::now(); // report performance
         let mut per_loop = 0u64; // ops performed per report
 
         let duration = self.config.dur;
@@ -626,7 +626,7 @@ impl WorkloadGenerator {
                                 // throttling, if enabled
                                 if cpo > 0 {
                                     let next = start + ops as u64 * cpo;
-                                    while unsafe { clock::rdtsc() } < next {;}
+                                    while clock::now() < next {;}
                                 }
 
                             } // while some time
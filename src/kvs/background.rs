@@ -0,0 +1,250 @@
+/*
+ * Nibble - Concurrent Log-Structured Memory for Many-Core Key-Value Stores
+ *
+ * (c) 2017 Hewlett Packard Enterprise Development LP.
+ *
+ * This program is free software: you can redistribute it and/or modify it under the terms of the
+ * GNU Lesser General Public License as published by the Free Software Foundation, either version 3
+ * of the License, or (at your option) any later version. This program is distributed in the hope that
+ * it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE.  See the GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along with this program.
+ * If not, see <http://www.gnu.org/licenses/>. As an exception, the copyright holders of this Library
+ * grant you permission to (i) compile an Application with the Library, and (ii) distribute the Application
+ * containing code generated by the Library and added to the Application during this compilation process
+ * under terms of your choice, provided you also meet the terms and conditions of the Application license.
+ */
+
+//! Pieces shared by the per-socket background threads.
+//!
+//! The scrubber and sweeper each repeat a pass over their socket's
+//! log at an interval, on a low-priority thread pinned to the socket.
+//! Background wraps that thread: the client supplies the state and
+//! its Pass, and may also run a pass on its own thread.
+//!
+//! remove_ifeq drops an index entry found to be dead (relocated,
+//! evicted or expired) and logs its removal; compaction and the
+//! sweeper both use it.
+
+use common::*;
+use index::*;
+use wal::WalRef;
+use meta::NsId;
+use sched;
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+/// How often a sleeping thread checks whether it must exit.
+const STOP_POLL_MS: u64 = 10;
+
+//==----------------------------------------------------==//
+//      Periodic threads
+//==----------------------------------------------------==//
+
+/// One pass of a periodic background task.
+pub trait Pass: Send + Sync + 'static {
+    fn run(&self);
+}
+
+pub struct Background<S> {
+    /// Names the thread (with the socket) and its log messages.
+    name: &'static str,
+    socket: usize,
+    /// Pause between the end of one pass and the start of the next;
+    /// zero runs passes back to back.
+    interval_ms: u64,
+    state: Arc<S>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<S> Background<S> {
+
+    pub fn new(name: &'static str, socket: usize, interval_ms: u64,
+               state: S) -> Self {
+        Background {
+            name: name,
+            socket: socket,
+            interval_ms: interval_ms,
+            state: Arc::new(state),
+            stop: Arc::new(AtomicBool::new(false)),
+            handle: None,
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &*self.state
+    }
+
+    /// The state, for changes before the thread is first spawned.
+    /// None once it has been.
+    pub fn state_mut(&mut self) -> Option<&mut S> {
+        Arc::get_mut(&mut self.state)
+    }
+
+    /// Stop the thread and wait for it to exit. A pass in progress
+    /// is finished first.
+    pub fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Err(e) = handle.join() {
+                warn!("{} thread panicked: {:?}", self.name, e);
+            }
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.handle.is_some()
+    }
+}
+
+impl<S: Pass> Background<S> {
+
+    /// Start the thread, if not already running.
+    pub fn spawn(&mut self) {
+        if self.handle.is_some() {
+            return;
+        }
+        info!("Spawning {} thread on socket {}", self.name, self.socket);
+        self.stop.store(false, Ordering::SeqCst);
+        let state = self.state.clone();
+        let stop = self.stop.clone();
+        let (name, socket) = (self.name, self.socket);
+        let interval_ms = self.interval_ms;
+        let handle = match thread::Builder::new()
+            .name(format!("{}::{}", name, socket))
+            .spawn( move || periodic(name, socket, interval_ms,
+                                     state, stop) ) {
+                Ok(handle) => handle,
+                Err(e) => panic!("spawning thread: {:?}",e),
+            };
+        self.handle = Some(handle);
+    }
+}
+
+impl<S> Drop for Background<S> {
+
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn periodic<S: Pass>(name: &'static str, socket: usize,
+                     interval_ms: u64, state: Arc<S>,
+                     stop: Arc<AtomicBool>) {
+    unsafe {
+        sched::pin_socket(socket);
+        // lowest priority; we only take spare cycles
+        if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+            warn!("{}: cannot lower priority", name);
+        }
+    }
+    'passes: loop {
+        state.run();
+        // checked after every pass, however short the interval
+        let mut slept = 0u64;
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                break 'passes;
+            }
+            if slept >= interval_ms {
+                break;
+            }
+            let ms = cmp::min(STOP_POLL_MS, interval_ms - slept);
+            thread::sleep(Duration::from_millis(ms));
+            slept += ms;
+        }
+    }
+    debug!("{} exiting", name);
+}
+
+//==----------------------------------------------------==//
+//      Removal of dead objects
+//==----------------------------------------------------==//
+
+/// Remove the key if it still refers to the entry at ientry, logging
+/// the removal. Returns whether it was removed; the caller accounts
+/// for the entry's bytes.
+pub fn remove_ifeq(index: &IndexRef, wal: Option<&WalRef>,
+                   key: &KeyType, ns: NsId, ientry: IndexEntry) -> bool {
+    let mut seq = 0u64;
+    let removed = index.remove_map_if(key, &|e| e == ientry,
+        &mut |e| {
+            if e != Some(ientry) {
+                return false;
+            }
+            if let Some(wal) = wal {
+                seq = wal.next_seq();
+            }
+            true
+        });
+    if !removed {
+        return false;
+    }
+    if let Some(wal) = wal {
+        let socket = extract(ientry).0 as usize;
+        if let Err(code) = wal.log_del(socket, seq, ns, key) {
+            warn!("logging removal of {:?}: {}", key, code);
+        }
+    }
+    true
+}
+
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct Counter(AtomicUsize);
+
+    impl Pass for Counter {
+        fn run(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn spawn_and_stop() {
+        let mut bg = Background::new("count", 0, 1,
+                                     Counter(AtomicUsize::new(0)));
+        assert!(bg.state_mut().is_some());
+        bg.spawn();
+        assert!(bg.is_running());
+        assert!(bg.state_mut().is_none());
+        while bg.state().0.load(Ordering::Relaxed) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        bg.stop();
+        assert!(!bg.is_running());
+        let n = bg.state().0.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(5 * STOP_POLL_MS));
+        assert_eq!(bg.state().0.load(Ordering::Relaxed), n);
+        // may be restarted
+        bg.spawn();
+        assert!(bg.is_running());
+    }
+
+    /// with no interval, passes run back to back and still stop
+    #[test]
+    fn zero_interval() {
+        let mut bg = Background::new("count", 0, 0,
+                                     Counter(AtomicUsize::new(0)));
+        bg.spawn();
+        while bg.state().0.load(Ordering::Relaxed) < 100 {
+            thread::yield_now();
+        }
+        bg.stop();
+        assert!(!bg.is_running());
+    }
+}
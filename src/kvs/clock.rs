@@ -19,23 +19,30 @@
 
 use std::time::Instant;
 use std::intrinsics;
+#[cfg(feature="tsc")]
+use std::arch::x86_64;
+
+use libc;
+use numa;
 
 //==----------------------------------------------------==//
 //      Cycles information / state
 //==----------------------------------------------------==//
 
+// Timestamps ("cycles" below) come from the TSC if built with the
+// 'tsc' feature, else they are nanoseconds from CLOCK_MONOTONIC. The
+// former is faster but requires an invariant TSC synchronized across
+// sockets; the latter is portable.
+
 static mut CYCLES_PER_SECOND: u64 = 0u64;
 
 /// Read CPU's time-stamp counter.
 /// TODO make safe
+#[cfg(all(target_arch="x86_64", feature="tsc"))]
 #[inline(always)]
 #[allow(unused_mut)]
 pub unsafe fn rdtsc() -> u64 {
-    let mut low: u32;
-    let mut high: u32;
-    asm!("rdtsc" : "={eax}" (low),
-                    "={edx}" (high));
-    ((high as u64) << 32) | (low as u64)
+    x86_64::_rdtsc()
 }
 
 /// Read the CPU ID using RDTSCP. Returns (socket,core) where core is
@@ -45,12 +52,13 @@ pub unsafe fn rdtsc() -> u64 {
 /// identifiers; first 12 bits hold the CPU, and bit 12+ hold the NUMA
 /// node. The state of this MSR depends on the implementation of the
 /// OS kernel (it must initialize this value).
+#[cfg(all(target_arch="x86_64", feature="tsc"))]
 #[inline(always)]
 #[allow(unused_mut)]
 pub fn rdtscp_id() -> (u32,u32) {
-    let mut ecx: u32;
+    let mut ecx: u32 = 0;
     unsafe {
-        asm!("rdtscp" : "={ecx}" (ecx));
+        x86_64::__rdtscp(&mut ecx);
     }
     (ecx >> 12, ecx & ((1<<12)-1))
 }
@@ -58,28 +66,64 @@ pub fn rdtscp_id() -> (u32,u32) {
 // TODO when RDPID is available, use that instead of RDTSCP
 
 /// Return all data reported by rdtscp as (tsc,sock,core)
+#[cfg(all(target_arch="x86_64", feature="tsc"))]
 #[inline(always)]
 #[allow(unused_mut)]
 pub unsafe fn rdtscp_all() -> (u64,u32,u32) {
-    let mut low: u32;
-    let mut high: u32;
-    let mut ecx: u32;
-    asm!("rdtscp" : "={eax}" (low),
-                    "={edx}" (high),
-                    "={ecx}" (ecx));
-    (
-        ((high as u64) << 32) | (low as u64),
-        ecx >> 12,
-        ecx & ((1<<12)-1)
-    )
+    let mut ecx: u32 = 0;
+    let tsc = x86_64::__rdtscp(&mut ecx);
+    (tsc, ecx >> 12, ecx & ((1<<12)-1))
 }
 
 /// Same as calling rdtsc but we internalize the unsafe block
+#[cfg(feature="tsc")]
 #[inline(always)]
 pub fn now() -> u64 {
     unsafe { rdtsc() }
 }
 
+/// Nanoseconds from the monotonic clock; never zero.
+#[cfg(not(feature="tsc"))]
+#[inline(always)]
+pub fn now() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    (ts.tv_sec as u64) * NANO_PER_SEC + (ts.tv_nsec as u64)
+}
+
+/// Returns (socket,core) of the calling thread, as rdtscp_id. The
+/// socket comes from our own map of the topology, which differs from
+/// the hardware's when NIBNODES describes a synthetic one.
+#[cfg(feature="tsc")]
+#[inline(always)]
+pub fn cpu_id() -> (u32,u32) {
+    let (_,core) = rdtscp_id();
+    (numa::NODE_MAP.sock_of(core as usize).0 as u32, core)
+}
+
+/// Returns (socket,core) of the calling thread, as rdtscp_id. glibc
+/// answers sched_getcpu from the vDSO, so this does not enter the
+/// kernel; the socket comes from our own map of the topology.
+#[cfg(not(feature="tsc"))]
+#[inline(always)]
+pub fn cpu_id() -> (u32,u32) {
+    let cpu = match unsafe { libc::sched_getcpu() } {
+        n if n < 0 => 0usize,
+        n => n as usize,
+    };
+    (numa::NODE_MAP.sock_of(cpu).0 as u32, cpu as u32)
+}
+
+#[cfg(not(feature="tsc"))]
+fn init() {
+    unsafe {
+        CYCLES_PER_SECOND = NANO_PER_SEC;
+    }
+}
+
+#[cfg(feature="tsc")]
 fn init() {
     let now = Instant::now();
     let start = unsafe { rdtsc() };
@@ -104,7 +148,7 @@ fn init() {
 
 macro_rules! do_init {
     () => {
-        if unlikely!(CYCLES_PER_SECOND == 0u64) {
+        if unlikely!(unsafe { CYCLES_PER_SECOND } == 0u64) {
             init();
         }
     }
@@ -20,18 +20,30 @@
 use libc;
 use num;
 use rand::{self,Rng};
+use std::mem;
 use std::ptr;
 use std::slice;
+use std::fmt;
+use std::io;
+use std::error::Error;
+#[cfg(not(feature="rdrand"))]
+use std::cell::Cell;
 
 use std::intrinsics;
+use std::arch::asm;
+use std::arch::x86_64::__cpuid_count;
+use std::sync::atomic::{AtomicU64,Ordering};
 
 //==----------------------------------------------------==//
 //      General types
 //==----------------------------------------------------==//
 
-/// Keys are fixed 8-byte unsigned values.
-/// TODO use this everywhere we have size_of::<u64>() hard-coded
-pub type KeyType = u64;
+/// Keys are arbitrary byte strings. The index only holds a 64-bit
+/// hash of a key; the key itself is stored in the log with its value.
+pub type KeyType = [u8];
+
+/// Hashed form of a key, as held by the index.
+pub type KeyHash = u64;
 
 /// Size of a cache line in bytes.
 pub const CACHE_LINE: usize = 64;
@@ -55,7 +67,7 @@ impl<T> uslice<T> {
     }
     pub fn ptr(&self) -> *const T { self.1 .0 }
     pub fn len(&self) -> usize { self.0 }
-    pub unsafe fn slice(&self) -> &[T] {
+    pub unsafe fn slice<'a>(&self) -> &'a [T] {
         slice::from_raw_parts(self.1 .0, self.0)
     }
 }
@@ -68,12 +80,18 @@ unsafe impl<T> Sync for uslice<T> {}
 
 #[inline(always)] pub
 fn prefetchw(cacheline: *const u8) {
-    unsafe { asm!("prefetchw $0" : : "m" (cacheline) : ) }
+    unsafe {
+        asm!("prefetchw [{0}]", in(reg) cacheline,
+             options(nostack, readonly, preserves_flags));
+    }
 }
 
 #[inline(always)] pub
 fn prefetch(cacheline: *const u8) {
-    unsafe { asm!("prefetch $0" : : "m" (cacheline) : ) }
+    unsafe {
+        asm!("prefetcht0 [{0}]", in(reg) cacheline,
+             options(nostack, readonly, preserves_flags));
+    }
 }
 
 #[inline(always)] pub
@@ -82,14 +100,17 @@ fn prefetch2(addr: *const u8, off: usize) {
 }
 
 #[inline] pub unsafe
-fn atomic_add<T: num::Integer>(loc: *mut T, amt: T) -> T {
-    intrinsics::atomic_xadd(loc, amt)
+fn atomic_add(loc: *mut u64, amt: u64) -> u64 {
+    AtomicU64::from_ptr(loc).fetch_add(amt, Ordering::SeqCst)
 }
 
 #[inline] pub unsafe
-fn atomic_cas<T: num::Integer>(loc: *mut T, old: T, val: T) -> (T,bool) {
-    intrinsics::atomic_cxchg(loc, old, val)
-    // intrinsics::atomic_cxchg_failrelaxed
+fn atomic_cas(loc: *mut u64, old: u64, val: u64) -> (u64,bool) {
+    let o = Ordering::SeqCst;
+    match AtomicU64::from_ptr(loc).compare_exchange(old, val, o, o) {
+        Ok(v) => (v, true),
+        Err(v) => (v, false),
+    }
 }
 
 #[inline] pub unsafe
@@ -116,18 +137,63 @@ pub const FNV_PRIME_64: u64 = 0x100000001b3_u64;
 
 #[inline] pub
 fn fnv1a(value: u64) -> u64 {
+    fnv1a_bytes(u64_key(&value))
+}
+
+#[inline] pub
+fn fnv1a_bytes(bytes: &[u8]) -> u64 {
     let mut hash: u64 = FNV_OFFSET_BASIS_64;
-    let p = &value as *const u64 as *const u8;
-    let bytes: &[u8] =
-        unsafe {
-            slice::from_raw_parts(p, 8)
-        };
     for b in bytes {
         hash = (hash ^ (*b as u64)).wrapping_mul(FNV_PRIME_64);
     }
     hash
 }
 
+/// Hash the bytes of a key into the value the index stores.
+#[inline(always)] pub
+fn hash_key(key: &KeyType) -> KeyHash {
+    fnv1a_bytes(key)
+}
+
+/// View an integer as a key. Useful for applications (and our
+/// benchmarks) that still name objects with 8-byte identifiers.
+#[inline(always)] pub
+fn u64_key(key: &u64) -> &KeyType {
+    unsafe {
+        slice::from_raw_parts(key as *const u64 as *const u8,
+                              mem::size_of::<u64>())
+    }
+}
+
+/// Reflected polynomial for CRC-32C (Castagnoli).
+const CRC32C_POLY: u32 = 0x82f63b78;
+
+lazy_static! {
+    static ref CRC32C_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for i in 0..256 {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if (crc & 1) == 1 { (crc >> 1) ^ CRC32C_POLY }
+                      else { crc >> 1 };
+            }
+            table[i] = crc;
+        }
+        table
+    };
+}
+
+/// Compute CRC-32C over bytes. Pass the result of a prior call as
+/// crc to continue a checksum over multiple buffers (start with 0).
+pub fn crc32c(crc: u32, bytes: &[u8]) -> u32 {
+    let table: &[u32; 256] = &*CRC32C_TABLE;
+    let mut crc = !crc;
+    for b in bytes {
+        crc = table[((crc ^ (*b as u32)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
 /// Knuth or FY shuffle
 pub
 fn shuffle<T: num::Integer>(vec: &mut Vec<T>) {
@@ -154,13 +220,9 @@ pub unsafe fn rdrand() -> u32 {
     let mut r: u32;
     let mut eflags: u8;
     loop {
-        asm!("rdrand $0; lahf"
-             : "=r" (r), "={ah}"(eflags)
-             :
-             :
-             : "volatile"
-            );
-        if intrinsics::likely(1 == (eflags & 1)) {
+        asm!("rdrand {0:e}", "setc {1}", out(reg) r, out(reg_byte) eflags,
+             options(nomem, nostack));
+        if likely!(1 == (eflags & 1)) {
             break;
         }
         warn!("rdrand CF=0");
@@ -176,13 +238,9 @@ pub unsafe fn rdrandq() -> u64 {
     let mut r: u64;
     let mut eflags: u8;
     loop {
-        asm!("rdrand $0; lahf"
-             : "=r" (r), "={ah}"(eflags)
-             :
-             :
-             : "volatile"
-            );
-        if intrinsics::likely(1 == (eflags & 1)) {
+        asm!("rdrand {0}", "setc {1}", out(reg) r, out(reg_byte) eflags,
+             options(nomem, nostack));
+        if likely!(1 == (eflags & 1)) {
             break;
         }
         warn!("rdrandq CF=0");
@@ -190,20 +248,46 @@ pub unsafe fn rdrandq() -> u64 {
     r
 }
 
-/// Slower and potentially less-scalable method for
-/// random number generation using urandom.
+/// Per-thread xorshift64* state, seeded once from the OS.
 #[cfg(not(feature="rdrand"))]
-pub unsafe fn rdrand() -> u32 {
+thread_local!(static XORSHIFT: Cell<u64> = Cell::new(xorshift_seed()));
+
+#[cfg(not(feature="rdrand"))]
+fn xorshift_seed() -> u64 {
     let mut rng = rand::thread_rng();
-    rng.gen::<u32>()
+    loop {
+        let s = rng.gen::<u64>();
+        if s != 0 { return s; }
+    }
+}
+
+/// Fast, portable (but not cryptographic) random numbers for when
+/// the rdrand instruction is not used.
+#[cfg(not(feature="rdrand"))]
+#[inline(always)]
+fn xorshift() -> u64 {
+    XORSHIFT.with( |state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545f4914f6cdd1d_u64)
+    })
+}
+
+/// 32-bit random numbers from a per-thread PRNG.
+#[cfg(not(feature="rdrand"))]
+#[inline(always)]
+pub unsafe fn rdrand() -> u32 {
+    (xorshift() >> 32) as u32
 }
 
-/// Slower and potentially less-scalable method for
-/// random no. generation.
+/// 64-bit random numbers from a per-thread PRNG.
 #[cfg(not(feature="rdrand"))]
+#[inline(always)]
 pub unsafe fn rdrandq() -> u64 {
-    let mut rng = rand::thread_rng();
-    rng.gen::<u64>()
+    xorshift()
 }
 
 //==----------------------------------------------------==//
@@ -225,17 +309,8 @@ impl CPUIDRegs {
 
 #[allow(unused_mut)]
 pub unsafe fn cpuid(id: u32, subid: u32) -> CPUIDRegs {
-    let mut regs = CPUIDRegs::new();
-    asm!("cpuid"
-         : "={eax}" (regs.eax),
-           "={ebx}" (regs.ebx),
-           "={ecx}" (regs.ecx),
-           "={edx}" (regs.edx)
-         : "{eax}" (id), "{ecx}" (subid)
-         :
-         : "volatile"
-         );
-    regs
+    let r = __cpuid_count(id, subid);
+    CPUIDRegs { eax: r.eax, ebx: r.ebx, ecx: r.ecx, edx: r.edx }
 }
 
 /// Determine whether the CPU has the 'rdrand' instruction.
@@ -253,7 +328,7 @@ pub fn kvs_rdrand_compile_flags() -> bool {
 /// an implementation without the instruction.
 #[cfg(not(feature="rdrand"))]
 pub fn kvs_rdrand_compile_flags() -> bool {
-    info!("rand: using urandom");
+    info!("rand: using xorshift");
     true
 }
 
@@ -273,35 +348,110 @@ pub unsafe fn set_errno(val: i32) {
     ptr::write(loc, val);
 }
 
-#[derive(Debug)]
+/// Errors returned by the store. Variants carry what is known about
+/// the failure; Display renders it for users.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub enum ErrorCode {
 
     SegmentFull,
     SegmentClosed,
 
-    OutOfMemory,
+    /// No log memory left on the socket, or on any socket if None.
+    OutOfMemory { socket: Option<usize> },
     TableFull,
 
     KeyNotExist,
-    InvalidSocket,
+    /// Conditional PUT found the key already present.
+    KeyExists,
+    /// Conditional operation expected a version the object did not
+    /// have.
+    VersionMismatch { expected: u64, found: u64 },
+    InvalidSocket { socket: usize, nsockets: usize },
 
     EmptyObject,
     ObjectGrew,
 
-    ObjectTooBig,
+    /// Object (key, value and header) of size bytes exceeds max.
+    ObjectTooBig { size: usize, max: usize },
+    /// Value of needed bytes does not fit the caller's buffer of len.
+    BufferTooSmall { needed: usize, len: usize },
+    NotOrdered,
+    IoError { kind: io::ErrorKind },
+    BadSnapshot,
+    Corrupted,
+    InvalidConfig,
+    NoRdrand,
+    /// Object would take namespace ns past its quota of bytes.
+    QuotaExceeded { ns: u32, quota: usize },
+    NamespaceDropped,
+    /// Value of len bytes cannot be used as an 8-byte counter.
+    NotCounter { len: usize },
 }
 
-pub fn err2str(code: ErrorCode) -> &'static str {
-    match code {
+/// Short description of the kind of error, without its context.
+pub fn err2str(code: &ErrorCode) -> &'static str {
+    match *code {
         ErrorCode::SegmentFull   => { "Segment is full" },
         ErrorCode::SegmentClosed => { "Segment is closed" },
-        ErrorCode::OutOfMemory   => { "Out of memory" },
+        ErrorCode::OutOfMemory{..} => { "Out of memory" },
         ErrorCode::TableFull     => { "Table is full" },
         ErrorCode::KeyNotExist   => { "Key does not exist" },
-        ErrorCode::InvalidSocket => { "Invalid socket ID" },
-        ErrorCode::EmptyObject   => { "Object is empty" },
+        ErrorCode::KeyExists     => { "Key already exists" },
+        ErrorCode::VersionMismatch{..} => { "Object version mismatch" },
+        ErrorCode::InvalidSocket{..} => { "Invalid socket ID" },
+        ErrorCode::EmptyObject   => { "Object key is empty" },
         ErrorCode::ObjectGrew    => { "Object grew beyond prior size" },
-        ErrorCode::ObjectTooBig  => { "Object too big" },
+        ErrorCode::ObjectTooBig{..} => { "Object too big" },
+        ErrorCode::BufferTooSmall{..} => { "Buffer too small for object" },
+        ErrorCode::NotOrdered    => { "Index does not support ordered scans" },
+        ErrorCode::IoError{..}   => { "I/O error" },
+        ErrorCode::BadSnapshot   => { "Snapshot is malformed or corrupt" },
+        ErrorCode::Corrupted     => { "Object failed its checksum" },
+        ErrorCode::InvalidConfig => { "Invalid configuration" },
+        ErrorCode::NoRdrand      => { "CPU lacks rdrand; build without the rdrand feature" },
+        ErrorCode::QuotaExceeded{..} => { "Namespace quota exceeded" },
+        ErrorCode::NamespaceDropped => { "Namespace was dropped" },
+        ErrorCode::NotCounter{..} => { "Value is not a counter" },
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = err2str(self);
+        match *self {
+            ErrorCode::OutOfMemory { socket: Some(s) } =>
+                write!(f, "{} on socket {}", msg, s),
+            ErrorCode::OutOfMemory { socket: None } =>
+                write!(f, "{} on all sockets", msg),
+            ErrorCode::InvalidSocket { socket, nsockets } =>
+                write!(f, "{} {} (have {})", msg, socket, nsockets),
+            ErrorCode::ObjectTooBig { size, max } =>
+                write!(f, "{}: {} bytes, max {}", msg, size, max),
+            ErrorCode::BufferTooSmall { needed, len } =>
+                write!(f, "{}: need {} bytes, have {}", msg, needed, len),
+            ErrorCode::VersionMismatch { expected, found } =>
+                write!(f, "{}: expected {}, found {}", msg, expected, found),
+            ErrorCode::QuotaExceeded { ns, quota } =>
+                write!(f, "{}: namespace {} limited to {} bytes",
+                       msg, ns, quota),
+            ErrorCode::NotCounter { len } =>
+                write!(f, "{}: value is {} bytes", msg, len),
+            ErrorCode::IoError { kind } =>
+                write!(f, "{}: {:?}", msg, kind),
+            _ => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for ErrorCode {
+    fn description(&self) -> &str {
+        err2str(self)
+    }
+}
+
+impl From<io::Error> for ErrorCode {
+    fn from(e: io::Error) -> Self {
+        ErrorCode::IoError { kind: e.kind() }
     }
 }
 
@@ -74,6 +74,9 @@ use common::*;
 use segment::*;
 use index::*;
 use thelog::*;
+use wal::WalRef;
+use background;
+use config::LSMConfig;
 use clock;
 use meta;
 use sched;
@@ -82,7 +85,7 @@ use numa;
 use std::collections::VecDeque;
 use std::mem;
 use std::sync::Arc;
-use std::sync::atomic::{self,AtomicBool};
+use std::sync::atomic::{self,AtomicBool,AtomicUsize,Ordering};
 use std::thread;
 use std::time::{Duration,Instant};
 use std::intrinsics;
@@ -96,17 +99,26 @@ use parking_lot as pl;
 //      Configuration
 //==----------------------------------------------------==//
 
-/// Ratio of available memory to total capacity, below which
+/// Default ratio of available memory to total capacity, below which
 /// compaction threads will aggressively try to compress memory.
 pub const RATIO: f64 = 0.5_f64;
 
-/// Number of worker threads per instance.
+/// Default number of worker threads per instance.
 pub const WTHREADS: usize = 8_usize;
 
 /// How long reclamation should take in the blocking method before
 /// throwing up.
 pub const WAIT_TO_RECLAIM: usize = 10_usize;
 
+/// States of the control word shared between a Compactor and its
+/// worker threads.
+const CTL_RUN: usize = 0;
+const CTL_PAUSE: usize = 1;
+const CTL_STOP: usize = 2;
+
+/// How often a paused worker checks whether it may continue.
+const PAUSE_POLL_MS: u64 = 10;
+
 //==----------------------------------------------------==//
 //      Compactor types, macros
 //==----------------------------------------------------==//
@@ -134,9 +146,22 @@ pub struct Compactor {
     index: IndexRef,
     seginfo: meta::SegmentInfoTableRef,
     /// The set of worker threads doing compaction.
-    workers: SegQueue<(Arc<pl::RwLock<Worker>>,Handle)>,
+    workers: Vec<(Arc<pl::RwLock<Worker>>,Handle)>,
+    /// Tells worker threads to run, pause or exit (CTL_*).
+    control: Arc<AtomicUsize>,
     /// Global reclamation queue
     reclaim: ReclaimQueueRef,
+    /// Write-ahead log, if the LSM is durable
+    wal: Option<WalRef>,
+    /// Free memory ratio below which workers compact
+    ratio: f64,
+    /// Free memory ratio below which workers evict, if at all
+    evict_ratio: Option<f64>,
+    /// Objects evicted by all workers
+    evicted: Arc<AtomicUsize>,
+    /// Held by workers for reading while they move or evict objects
+    moving: Arc<pl::RwLock<()>>,
+    nworkers: usize,
 }
 
 // TODO metrics for when compaction should begin
@@ -155,16 +180,40 @@ impl Compactor {
 
     pub fn new(manager: &SegmentManagerRef,
                index: &IndexRef) -> Self {
+        Self::with_config(manager, index, &LSMConfig::default())
+    }
+
+    pub fn with_config(manager: &SegmentManagerRef, index: &IndexRef,
+                       config: &LSMConfig) -> Self {
         let seginfo = manager.seginfo();
+        // one worker per closed-segment queue; the manager was sized
+        // from the same config
+        let nworkers = manager.nqueues();
         Compactor {
             manager: manager.clone(),
             index: index.clone(),
             seginfo: seginfo,
-            workers: SegQueue::new(),
+            workers: Vec::with_capacity(nworkers),
+            control: Arc::new(AtomicUsize::new(CTL_RUN)),
             reclaim: Arc::new(SegQueue::new()),
+            wal: None,
+            ratio: config.get_compaction_ratio(),
+            evict_ratio: config.get_eviction_ratio(),
+            evicted: Arc::new(AtomicUsize::new(0)),
+            moving: Arc::new(pl::RwLock::new(())),
+            nworkers: nworkers,
         }
     }
 
+    /// Have workers relocate objects in coordination with, and
+    /// periodically checkpoint, the write-ahead log. Must be called
+    /// before spawn.
+    pub fn set_wal(&mut self, wal: WalRef) {
+        assert!(self.workers.is_empty(),
+            "set_wal after compaction threads were spawned");
+        self.wal = Some(wal);
+    }
+
     // When we clean, we allocate new segment from segment manager and
     // move objects from one to the other. When segment is cleaned, we
     // add to a 'to be free' list that will use epochs for
@@ -172,16 +221,24 @@ impl Compactor {
     // release unused blocks back to the block allocator, and the
     // segment then added back to the log.
 
+    /// Start the worker threads. If they are already running, this
+    /// only resumes them.
     pub fn spawn(&mut self) {
-        info!("Spawning {} compaction threads", WTHREADS);
-        info!("Compaction delay ratio {}", RATIO);
-        for i in 0..WTHREADS {
+        if !self.workers.is_empty() {
+            self.resume();
+            return;
+        }
+        info!("Spawning {} compaction threads", self.nworkers);
+        info!("Compaction delay ratio {}", self.ratio);
+        self.control.store(CTL_RUN, Ordering::SeqCst);
+        for i in 0..self.nworkers {
             let w = Worker::new(i, self);
             let state = Arc::new(pl::RwLock::new(w));
             let give = state.clone();
+            let ctl = self.control.clone();
             let name = format!("compaction::worker");
             let handle = match thread::Builder::new()
-                .name(name).spawn( move || worker(give) ) {
+                .name(name).spawn( move || worker(give, ctl) ) {
                     Ok(handle) => handle,
                     Err(e) => panic!("spawning thread: {:?}",e),
                 };
@@ -189,15 +246,57 @@ impl Compactor {
         }
     }
 
+    /// Ask workers to stop compacting once their current round
+    /// finishes. They remain alive until resumed or stopped.
+    pub fn pause(&self) {
+        let _ = self.control.compare_and_swap(CTL_RUN, CTL_PAUSE,
+                                              Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.control.compare_and_swap(CTL_PAUSE, CTL_RUN,
+                                              Ordering::SeqCst);
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.workers.is_empty() &&
+            self.control.load(Ordering::Relaxed) == CTL_RUN
+    }
+
+    /// Number of objects evicted so far.
+    pub fn evicted(&self) -> usize {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Workers hold this for reading while they move or evict
+    /// objects. Whoever holds it for writing sees every object stay
+    /// where it is; workers wait, and resume once it is released.
+    pub fn moving(&self) -> Arc<pl::RwLock<()>> {
+        self.moving.clone()
+    }
+
+    /// Terminate all worker threads and wait for them to exit. Any
+    /// segments awaiting reclamation are left in the queue; spawn
+    /// may be called again afterwards.
+    pub fn stop(&mut self) {
+        if self.workers.is_empty() {
+            return;
+        }
+        info!("Stopping {} compaction threads", self.workers.len());
+        self.control.store(CTL_STOP, Ordering::SeqCst);
+        for (_,handle) in self.workers.drain(..) {
+            if let Err(e) = handle.join() {
+                warn!("compaction thread panicked: {:?}", e);
+            }
+        }
+    }
+
 }
 
 impl Drop for Compactor {
 
     fn drop(&mut self) {
-        //if self.tid.is_some() {
-            //let order = atomic::Ordering::Relaxed;
-            //self.tstop.store(true, order);
-        //}
+        self.stop();
     }
 }
 
@@ -210,7 +309,7 @@ fn __compact(state: &Arc<pl::RwLock<Worker>>) {
     let mut s = state.write();
     let new = s.check_new();
     debug!("{} new candidates", new);
-    let run: bool = {
+    let ratio: f64 = {
         // FIXME the ratio should include the data not yet returned to
         // the block allocator -- data waiting for reclamation
         let remaining = s.manager.freesz() as f64;
@@ -218,10 +317,18 @@ fn __compact(state: &Arc<pl::RwLock<Worker>>) {
         let ratio = remaining/total;
         debug!("node-{:?} rem. {} total {} ratio {:.2} run: {:?}",
                s.manager.socket().unwrap(),
-               remaining, total, ratio, ratio<RATIO);
-        ratio < RATIO
+               remaining, total, ratio, ratio<s.ratio);
+        ratio
     };
-    if run {
+    if s.evict_ratio.map_or(false, |r| ratio < r) {
+        for _ in 0..4 {
+            s.do_evict();
+        }
+        if let Some(ref wal) = s.wal {
+            wal.maybe_checkpoint();
+        }
+    }
+    else if ratio < s.ratio {
         // do a few times before re-checking the BlockAllocator
         for _ in 0..4 {
             debug!("node-{} compaction initiated",
@@ -232,6 +339,10 @@ fn __compact(state: &Arc<pl::RwLock<Worker>>) {
                    s.manager.socket().unwrap(),
                    clock::to_msec(clock::now()-now));
         }
+        // clean the on-disk log, too
+        if let Some(ref wal) = s.wal {
+            wal.maybe_checkpoint();
+        }
     } else {
         //let l = s.candidates.lock().unwrap();
         //s.__dump_candidates(&l);
@@ -240,7 +351,7 @@ fn __compact(state: &Arc<pl::RwLock<Worker>>) {
     }
 }
 
-fn worker(state: Arc<pl::RwLock<Worker>>) {
+fn worker(state: Arc<pl::RwLock<Worker>>, control: Arc<AtomicUsize>) {
     debug!("thread awake");
     {
         let s = state.read();
@@ -252,8 +363,14 @@ fn worker(state: Arc<pl::RwLock<Worker>>) {
         }
     }
     loop {
-        __compact(&state);
+        match control.load(Ordering::Relaxed) {
+            CTL_RUN => __compact(&state),
+            CTL_PAUSE => thread::sleep(
+                Duration::from_millis(PAUSE_POLL_MS)),
+            _ => break,
+        }
     }
+    debug!("thread exiting");
 }
 
 //==----------------------------------------------------==//
@@ -272,6 +389,8 @@ struct SegCache {
     live_size: usize,
     /// metric used for determinine which to compact
     metric: f64,
+    /// holds a single large object; never compacted
+    large: bool,
 }
 type Candidate = (SegCache, SegmentRef);
 
@@ -287,12 +406,19 @@ struct Worker {
     manager: SegmentManagerRef,
     /// cache of SegmentManager.size
     mgrsize: usize,
+    /// See Compactor::ratio
+    ratio: f64,
+    evict_ratio: Option<f64>,
+    evicted: Arc<AtomicUsize>,
     index: IndexRef,
     seginfo: meta::SegmentInfoTableRef,
     /// Reference to the global list of to-be-reclaimed segments
     /// Compaction threads push to this, Reclaim threads move SegRefs
     /// from this to their private set to manipulate
     reclaim_glob: ReclaimQueueRef,
+    wal: Option<WalRef>,
+    /// See Compactor::moving
+    moving: Arc<pl::RwLock<()>>,
 }
 
 impl Worker {
@@ -312,9 +438,14 @@ impl Worker {
             candidates: pl::Mutex::new(Vec::with_capacity(ncand)),
             manager: compactor.manager.clone(),
             mgrsize: size,
+            ratio: compactor.ratio,
+            evict_ratio: compactor.evict_ratio,
+            evicted: compactor.evicted.clone(),
             index: compactor.index.clone(),
             seginfo: compactor.seginfo.clone(),
             reclaim_glob: compactor.reclaim.clone(),
+            wal: compactor.wal.clone(),
+            moving: compactor.moving.clone(),
         }
     }
 
@@ -340,6 +471,7 @@ impl Worker {
                 alive: self.seginfo.get_epoch(seg.slot()),
                 live_size: self.seginfo.get_live(seg.slot()),
                 metric: 0f64,
+                large: seg.is_large(),
             }
         };
         let mut candidate = (cache, seg.clone());
@@ -422,7 +554,7 @@ impl Worker {
         -> Option<(Vec<Candidate>,usize)> {
 
         // look for enough live space to fill up to this amount
-        let max_size = 3 * SEGMENT_SIZE;
+        let max_size = 3 * self.manager.segment_size();
 
         let mut candidates = self.candidates.lock();
 
@@ -511,6 +643,11 @@ impl Worker {
                 //self.reclaim_glob.push( (meta::next(), seg) );
                 empties.push_back( (meta::next(),cand) );
             }
+            // a large object moves only by being rewritten, so its
+            // segment waits until the object is dead
+            else if cand.0 .large {
+                nc.push(cand);
+            }
             // skip if it has no free space
             else if too_full {
                 debug!("node-{:?} slot {} not enough free space: {}",
@@ -540,7 +677,7 @@ impl Worker {
 
         // first try to release the empties
         if empties.len() > 0 {
-            let start = unsafe { clock::rdtsc() };
+            let start = clock::now();
             loop {
                 let tuple = match empties.pop_front() {
                     None => break,
@@ -552,7 +689,7 @@ impl Worker {
                 let segref = tuple.1 .1;
                 self.manager.free(segref);
             }
-            let end = unsafe { clock::rdtsc() };
+            let end = clock::now();
             let tim = clock::to_nano(end-start);
             debug!("node-{:?} consumed {} nsec to release empties",
                    self.manager.socket().unwrap(), tim);
@@ -594,6 +731,8 @@ impl Worker {
         assert_eq!(self.seginfo.get_live(new.slot()), 0usize);
 
         let mut bytes_appended = 0usize;
+        // reused across entries to avoid allocating per object
+        let mut key: Vec<u8> = Vec::with_capacity(64);
         for candidate in dirty {
             let segref = &candidate.1;
             let dirt = segref.read();
@@ -605,46 +744,77 @@ impl Worker {
                 "Segment {} being compacted is open!", dirt.slot());
 
             let mut n = 0usize;
+            let now = clock::now();
+            let namespaces = self.manager.namespaces();
             for entry in dirt.into_iter() {
-                let key: u64 = unsafe { entry.get_key() };
+                unsafe { entry.get_key(&mut key); }
 
                 let old = entry.get_loc() as u64;
                 let ientry_old = merge(socket as u16, old as u64);
 
-                let va = new.headref() as usize;
-                let ientry_new = merge(socket as u16, va as u64);
-
-                // extend segment to fit entry. keep allocation
-                // out of critical path while holding the bucket lock
-                if !new.can_hold_amt(entry.len) {
-                    let amt = entry.len - new.remaining();
-                    let blks = (amt - 1) / BLOCK_SIZE + 1;
-                    loop {
-                        let op = self.manager.alloc_blocks(blks);
-                        if let Some(mut blocks) = op {
-                            new.extend(&mut blocks);
-                            debug!("seg {} extended by {}",
-                                  new.slot(), blks);
-                            break;
-                        }
+                // expired objects, and those of dropped namespaces,
+                // are dead; drop them from the index instead of
+                // moving them
+                if expired_at(entry.expires, now) ||
+                    namespaces.is_dropped(entry.ns) {
+                    if background::remove_ifeq(&self.index,
+                            self.wal.as_ref(), &key, entry.ns,
+                            ientry_old) {
+                        self.seginfo.decr_live(dirt.slot(), entry.len);
+                        namespaces.credit(entry.ns, entry.len);
                     }
+                    n += 1;
+                    continue;
+                }
+
+                // skip objects already dead; matching on the old
+                // location also guarantees we do not relocate on
+                // behalf of another key with the same hash
+                let live = self.index.get(&key, &|e| e == ientry_old);
+                if live != Some(ientry_old) {
+                    n += 1;
+                    continue;
                 }
 
-                // append while holding lock; no allocation should occur
-                if let Some(lock) = self.index
-                    .update_lock_ifeq(key,ientry_new,ientry_old) {
+                // a corrupt object is still moved; reads of it will
+                // report the error rather than lose the key
+                if !entry.verify() {
+                    warn!("socket {} key {:?} failed its checksum",
+                          socket, key);
+                }
+                // copy before taking the key's lock, which is held
+                // only to swap the entry. If the object was replaced
+                // or removed meanwhile, the swap fails and the copy
+                // is left dead in the new segment; if it was edited
+                // in place, the copy may be stale, so copy it again.
+                loop {
+                    let seq = seq_begin(old as usize);
+                    self.extend_for(&mut new, entry.len);
+                    let va = new.headref() as usize;
+                    let ientry_new = merge(socket as u16, va as u64);
                     let newva = new.append_entry(&entry);
-                    //self.seginfo.incr_live(new.slot(), entry.len);
-                    bytes_appended += entry.len;
                     debug_assert!(newva.is_some());
                     debug_assert_eq!(newva.unwrap(), va);
+                    // the new segment is credited while the key is
+                    // locked, before a put replacing it can debit it
+                    let mut edited = false;
+                    if self.index.update_ifeq_if(&key, ientry_new,
+                            ientry_old, &mut || {
+                        edited = seq_retry(old as usize, seq);
+                        if !edited {
+                            self.seginfo.incr_live(new.slot(), entry.len);
+                        }
+                        !edited
+                    }) {
+                        bytes_appended += entry.len;
+                    }
+                    if !edited {
+                        break;
+                    }
                 }
 
                 n += 1;
             }
-            // TODO use this one line instead of adding each time
-            self.seginfo.incr_live(new.slot(), bytes_appended);
-
             meta::quiesce();
             // make sure nobjects is consistent with the iterator
             assert_eq!(n, dirt.nobjects());
@@ -658,6 +828,71 @@ impl Worker {
         status
     }
 
+    /// Add blocks to the segment until it can hold len more bytes.
+    fn extend_for(&self, new: &mut Segment, len: usize) {
+        if new.can_hold_amt(len) {
+            return;
+        }
+        let amt = len - new.remaining();
+        let blks = (amt - 1) / block_size() + 1;
+        loop {
+            let op = self.manager.alloc_blocks(blks);
+            if let Some(mut blocks) = op {
+                new.extend(&mut blocks);
+                debug!("seg {} extended by {}", new.slot(), blks);
+                break;
+            }
+        }
+    }
+
+    /// Called instead of do_compact once free memory falls below the
+    /// eviction ratio. Rather than move the live objects of the
+    /// oldest candidate segment, remove them from the index, and
+    /// release the segment whole.
+    pub fn do_evict(&mut self) {
+        let victim: Candidate = {
+            let mut candidates = self.candidates.lock();
+            let oldest = candidates.iter().enumerate()
+                .min_by_key(|&(_,c)| c.0 .alive)
+                .map(|(i,_)| i);
+            match oldest {
+                None => { debug!("no candidates"); return; },
+                Some(i) => candidates.swap_remove(i),
+            }
+        };
+        let n = {
+            let _moving = self.moving.read();
+            self.evict(&victim.1)
+        };
+        self.evicted.fetch_add(n, Ordering::Relaxed);
+        debug!("node-{:?} slot {} evicted {} objects",
+               self.manager.socket().unwrap(), victim.0 .slot, n);
+
+        let ep = meta::next();
+        self.reclaim_glob.push( (ep, victim.1) );
+        self.do_reclaim_blocking();
+    }
+
+    /// Remove from the index each object in the segment that it still
+    /// refers to. Returns the number removed.
+    fn evict(&self, segref: &SegmentRef) -> usize {
+        let socket = self.manager.socket().unwrap().0;
+        let seg = segref.read();
+        let mut key: Vec<u8> = Vec::with_capacity(64);
+        let mut n = 0usize;
+        for entry in seg.into_iter() {
+            unsafe { entry.get_key(&mut key); }
+            let ientry = merge(socket as u16, entry.get_loc() as u64);
+            if background::remove_ifeq(&self.index, self.wal.as_ref(),
+                                       &key, entry.ns, ientry) {
+                self.manager.namespaces().credit(entry.ns, entry.len);
+                n += 1;
+            }
+        }
+        self.seginfo.set_live(seg.slot(), 0usize);
+        n
+    }
+
     /// Iterate through the segment to ensure the epoch table reports
     /// a live size that matches what the we corroborate with the
     /// index.
@@ -676,7 +911,7 @@ impl Worker {
         info!("verify: slot {} measured {} epoch live {}",
                slot, size, live);
         assert!(size <= live);
-        assert!(live <= SEGMENT_SIZE);
+        assert!(live <= self.manager.segment_size());
     }
 
     /// Don't use this except when debugging epoch table
@@ -708,7 +943,7 @@ impl Worker {
         if livebytes > 0  {
             // allocate new segment
             let newseg: SegmentRef;
-            let nblks = (livebytes+(BLOCK_SIZE-1))/BLOCK_SIZE;
+            let nblks = (livebytes+(block_size()-1))/block_size();
             debug!("allocating new segment #blks {}",nblks);
             let mut retries = 0;
             let start = Instant::now();
@@ -749,7 +984,15 @@ impl Worker {
                       dur.subsec_nanos() / 1000u32);
             }
 
+            // neither a WAL checkpoint nor a snapshot may run while
+            // objects move
+            let moving = self.moving.clone();
+            let held = moving.read();
+            let wal = self.wal.clone();
+            let relocating = wal.as_ref().map(|w| w.relocating());
             let ret = self.compact(&candidates, &newseg);
+            drop(relocating);
+            drop(held);
             meta::next();
             if ret.is_err() { panic!("compact failed"); }
 
@@ -869,6 +1112,60 @@ impl Worker {
 //      Unit tests
 //==----------------------------------------------------==//
 
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use numa::NodeId;
+
+    fn compactor() -> Compactor {
+        let config = LSMConfig::small();
+        let len = config.get_capacity() / numa::NODE_MAP.sockets();
+        let manager = SegmentManager::with_config(len, NodeId(0), &config)
+            .unwrap();
+        let index: IndexRef = Arc::new(OrderedIndex::new());
+        Compactor::with_config(&Arc::new(manager), &index, &config)
+    }
+
+    /// workers may be paused and resumed any number of times, and
+    /// stopped and spawned again
+    #[test]
+    fn pause_resume_stop() {
+        let mut comp = compactor();
+        assert!(!comp.is_running());
+        // no workers to pause or resume
+        comp.pause();
+        comp.resume();
+        assert!(!comp.is_running());
+
+        comp.spawn();
+        assert!(comp.is_running());
+        let n = comp.workers.len();
+        assert!(n > 0);
+        comp.pause();
+        comp.pause();
+        assert!(!comp.is_running());
+        comp.resume();
+        assert!(comp.is_running());
+        // spawning paused workers only resumes them
+        comp.pause();
+        comp.spawn();
+        assert!(comp.is_running());
+        assert_eq!(comp.workers.len(), n);
+
+        comp.stop();
+        assert!(!comp.is_running());
+        assert!(comp.workers.is_empty());
+        comp.stop();
+        comp.resume();
+        assert!(!comp.is_running());
+
+        comp.spawn();
+        assert!(comp.is_running());
+        assert_eq!(comp.workers.len(), n);
+        // and dropping stops them
+    }
+}
+
 //#[cfg(test)]
 #[cfg(IGNORE)]
 mod tests {
@@ -946,7 +1243,7 @@ mod tests {
         let value_sizes: Vec<u32> = vec!(433, 884, 511); // arbitrary
         let total: u32 = key_sizes.iter().fold(0, ops::Add::add)
             + value_sizes.iter().fold(0, ops::Add::add);
-        let nbatches = (SEGMENT_SIZE - BLOCK_SIZE) / (total as usize);
+        let nbatches = (SEGMENT_SIZE - block_size()) / (total as usize);
 
         // create key-value pairs
         let mut keys: Vec<String> = Vec::new();
@@ -989,7 +1286,7 @@ mod tests {
         let new_capacity = ((value_sizes[0] + key_sizes[0]) as usize
                             + mem::size_of::<EntryHeader>())*nbatches
                             + mem::size_of::<SegmentHeader>();
-        let nblks = (new_capacity / BLOCK_SIZE) + 1;
+        let nblks = (new_capacity / block_size()) + 1;
         let seg_clean_ref;
         match segmgr.lock() {
             Ok(mut mgr) => {
@@ -1067,7 +1364,7 @@ mod tests {
             loop {
                 match log.append(&obj) {
                     Err(code) => match code {
-                        ErrorCode::OutOfMemory => 
+                        ErrorCode::OutOfMemory{..} => 
                             panic!("ran out of memory?"),
                         _ => panic!("log append {:?}", code),
                     },
@@ -1133,7 +1430,7 @@ mod tests {
         // let mut compactor = comp_ref!(&mref, &index);
 
         let logsize = 1usize<<34;
-        let mut kvs = LSM::new2(logsize, 1<<22);
+        let mut kvs = LSM::new2(logsize, 1<<22).unwrap();
         kvs.enable_compaction(NodeId(0));
         //kvs.enable_compaction(NodeId(1));
 
@@ -1180,7 +1477,7 @@ mod tests {
                         }
                     }
                     match kvs.put_object(&obj) {
-                        Err(ErrorCode::OutOfMemory) => continue,
+                        Err(ErrorCode::OutOfMemory{..}) => continue,
                         Err(e) => panic!("Error: {:?}", e),
                         Ok(_) => break,
                     }
@@ -0,0 +1,322 @@
+/*
+ * Nibble - Concurrent Log-Structured Memory for Many-Core Key-Value Stores
+ *
+ * (c) 2017 Hewlett Packard Enterprise Development LP.
+ *
+ * This program is free software: you can redistribute it and/or modify it under the terms of the
+ * GNU Lesser General Public License as published by the Free Software Foundation, either version 3
+ * of the License, or (at your option) any later version. This program is distributed in the hope that
+ * it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE.  See the GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along with this program.
+ * If not, see <http://www.gnu.org/licenses/>. As an exception, the copyright holders of this Library
+ * grant you permission to (i) compile an Application with the Library, and (ii) distribute the Application
+ * containing code generated by the Library and added to the Application during this compilation process
+ * under terms of your choice, provided you also meet the terms and conditions of the Application license.
+ */
+
+//! Runtime configuration of an LSM instance.
+//!
+//! LSMConfig::default() reproduces the values the constants in
+//! segment, compaction and thelog used to fix at compile time. Build
+//! one with the setters and hand it to LSM::with_config, which
+//! validates it first:
+//!
+//! ```ignore
+//! let config = LSMConfig::default()
+//!     .capacity(8usize << 30)
+//!     .segment_size(1usize << 24)
+//!     .compaction_workers(4);
+//! let kvs = LSM::with_config(config)?;
+//! ```
+//!
+//! The block size is special: it is shared by every instance in the
+//! process (see segment::set_block_shift).
+
+use common::*;
+use segment;
+use compaction;
+use index::IndexKind;
+use numa::{self,NodeId};
+
+/// Minimum segments each log head must be able to hold.
+const MIN_SEG_PER_HEAD: usize = 4;
+
+/// Fewer blocks per segment than this leaves log heads no room to
+/// vary their segment sizes (see LogHead::replace).
+const MIN_BLOCKS_PER_SEG: usize = 4;
+
+#[derive(Clone,Debug)]
+pub struct LSMConfig {
+    /// Total bytes of log memory across all sockets. None means the
+    /// minimum the rest of the configuration allows.
+    capacity: Option<usize>,
+    segment_size: usize,
+    block_size: usize,
+    /// Segments' worth of blocks held back for compaction, per socket.
+    reserve_segs: usize,
+    /// Fraction of free memory below which compaction runs.
+    compaction_ratio: f64,
+    /// Fraction of free memory below which compaction evicts objects
+    /// instead of moving them; None never evicts.
+    eviction_ratio: Option<f64>,
+    compaction_workers: usize,
+    /// None means one per core of the socket.
+    log_heads: Option<usize>,
+    index: IndexKind,
+    /// Expected number of objects; sizes the hash index.
+    index_items: usize,
+    hugepages: bool,
+    /// Whether put_object uses PutPolicy::Overwrite.
+    overwrite: bool,
+}
+
+impl Default for LSMConfig {
+    fn default() -> Self {
+        LSMConfig {
+            capacity: None,
+            segment_size: segment::SEGMENT_SIZE,
+            block_size: 1usize << segment::DEFAULT_BLOCK_SHIFT,
+            reserve_segs: segment::RESERVE_SEGS,
+            compaction_ratio: compaction::RATIO,
+            eviction_ratio: None,
+            compaction_workers: compaction::WTHREADS,
+            log_heads: None,
+            index: IndexKind::Hash,
+            index_items: 1usize << 25,
+            hugepages: true,
+            overwrite: false,
+        }
+    }
+}
+
+impl LSMConfig {
+
+    //
+    // Setters
+    //
+
+    pub fn capacity(mut self, bytes: usize) -> Self {
+        self.capacity = Some(bytes);
+        self
+    }
+
+    /// Must be a power of two and a multiple of the block size.
+    pub fn segment_size(mut self, bytes: usize) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Must be a power of two, at least a page, and the same for all
+    /// instances in the process.
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    pub fn reserve_segments(mut self, nsegs: usize) -> Self {
+        self.reserve_segs = nsegs;
+        self
+    }
+
+    /// Compaction begins once free memory on a socket falls below
+    /// this fraction of its capacity.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Run as a cache: once free memory on a socket falls below this
+    /// fraction of its capacity, compaction evicts the objects in the
+    /// oldest segments instead of moving them. Must be below the
+    /// compaction ratio.
+    pub fn eviction_ratio(mut self, ratio: f64) -> Self {
+        self.eviction_ratio = Some(ratio);
+        self
+    }
+
+    /// Compaction threads per socket.
+    pub fn compaction_workers(mut self, n: usize) -> Self {
+        self.compaction_workers = n;
+        self
+    }
+
+    /// Log heads per socket.
+    pub fn log_heads(mut self, n: usize) -> Self {
+        self.log_heads = Some(n);
+        self
+    }
+
+    pub fn index(mut self, kind: IndexKind) -> Self {
+        self.index = kind;
+        self
+    }
+
+    /// Ignored by indexes other than the hash index.
+    pub fn index_items(mut self, n: usize) -> Self {
+        self.index_items = n;
+        self
+    }
+
+    /// Back the log and hash index with 2 MiB pages.
+    pub fn hugepages(mut self, on: bool) -> Self {
+        self.hugepages = on;
+        self
+    }
+
+    /// Have put_object write a value over the one it replaces when
+    /// their lengths are equal, and otherwise place the new copy on
+    /// the socket holding the old one, rather than the nearest (see
+    /// PutPolicy::Overwrite).
+    pub fn overwrite(mut self, on: bool) -> Self {
+        self.overwrite = on;
+        self
+    }
+
+    //
+    // Getters
+    //
+
+    /// Capacity requested, else the minimum allowed.
+    pub fn get_capacity(&self) -> usize {
+        self.capacity.unwrap_or_else(|| self.min_capacity())
+    }
+
+    pub fn get_segment_size(&self) -> usize { self.segment_size }
+    pub fn get_block_size(&self) -> usize { self.block_size }
+    pub fn get_reserve_segments(&self) -> usize { self.reserve_segs }
+    pub fn get_compaction_ratio(&self) -> f64 { self.compaction_ratio }
+    pub fn get_eviction_ratio(&self) -> Option<f64> {
+        self.eviction_ratio
+    }
+    pub fn get_compaction_workers(&self) -> usize {
+        self.compaction_workers
+    }
+    pub fn get_index(&self) -> IndexKind { self.index }
+    pub fn get_index_items(&self) -> usize { self.index_items }
+    pub fn get_hugepages(&self) -> bool { self.hugepages }
+    pub fn get_overwrite(&self) -> bool { self.overwrite }
+
+    pub fn get_log_heads(&self) -> usize {
+        self.log_heads.unwrap_or_else( ||
+            numa::NODE_MAP.cpus_in(NodeId(0)) )
+    }
+
+    pub fn block_shift(&self) -> usize {
+        self.block_size.trailing_zeros() as usize
+    }
+
+    pub fn blocks_per_seg(&self) -> usize {
+        self.segment_size / self.block_size
+    }
+
+    pub fn reserve_blocks(&self) -> usize {
+        self.reserve_segs * self.blocks_per_seg()
+    }
+
+    /// Smallest capacity across all sockets this configuration can
+    /// run with: a few segments per log head plus the compaction
+    /// reserve, on every socket.
+    pub fn min_capacity(&self) -> usize {
+        let nsockets = numa::NODE_MAP.sockets();
+        let nsegs = self.get_log_heads() * MIN_SEG_PER_HEAD
+            + self.reserve_segs;
+        nsegs * self.segment_size * nsockets
+    }
+
+    /// A few MiB per socket on regular pages, with one log head and
+    /// one compaction thread, so that unit tests can create many
+    /// instances side by side. Keeps the default block size, which is
+    /// shared by the whole process.
+    #[cfg(test)]
+    pub fn small() -> Self {
+        let nsockets = numa::NODE_MAP.sockets();
+        LSMConfig::default()
+            .capacity(nsockets * (1usize << 25))
+            .segment_size(1usize << 20)
+            .reserve_segments(2)
+            .compaction_workers(1)
+            .log_heads(1)
+            .index_items(1usize << 16)
+            .hugepages(false)
+    }
+
+    /// Check the settings are usable together. Does not fix the
+    /// process-wide block size; LSM::with_config does that.
+    pub fn validate(&self) -> Result<(),ErrorCode> {
+        let bsz = self.block_size;
+        let ssz = self.segment_size;
+        let ok =
+            bsz.is_power_of_two() && bsz >= numa::PAGE_SIZE &&
+            ssz.is_power_of_two() &&
+            ssz / bsz >= MIN_BLOCKS_PER_SEG &&
+            self.compaction_ratio > 0f64 &&
+            self.compaction_ratio < 1f64 &&
+            self.eviction_ratio.map_or(true, |r|
+                r > 0f64 && r < self.compaction_ratio) &&
+            self.compaction_workers > 0 &&
+            self.get_log_heads() > 0 &&
+            (self.index_items > 0 || self.index != IndexKind::Hash);
+        if !ok {
+            warn!("invalid configuration: {:?}", self);
+            return Err(ErrorCode::InvalidConfig);
+        }
+        if !segment::block_shift_ok(self.block_shift()) {
+            warn!("block size {} differs from {} already in use",
+                  bsz, segment::block_size());
+            return Err(ErrorCode::InvalidConfig);
+        }
+        if self.get_capacity() < self.min_capacity() {
+            warn!("capacity {} below minimum {} for configuration",
+                  self.get_capacity(), self.min_capacity());
+            return Err(ErrorCode::InvalidConfig);
+        }
+        Ok(())
+    }
+}
+
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        let config = LSMConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.blocks_per_seg(),
+                   segment::SEGMENT_SIZE >> segment::DEFAULT_BLOCK_SHIFT);
+    }
+
+    #[test]
+    fn small_is_valid() {
+        let config = LSMConfig::small();
+        assert!(config.validate().is_ok());
+        assert!(config.get_capacity() > config.min_capacity());
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let bad = vec![
+            LSMConfig::default().segment_size(3usize << 20),
+            LSMConfig::default().block_size(1024),
+            LSMConfig::default().block_size(segment::SEGMENT_SIZE),
+            LSMConfig::default().compaction_ratio(1.5f64),
+            LSMConfig::default().eviction_ratio(compaction::RATIO),
+            LSMConfig::default().compaction_workers(0),
+            LSMConfig::default().log_heads(0),
+            LSMConfig::default().index_items(0),
+            LSMConfig::default().capacity(1usize << 20),
+        ];
+        for config in bad {
+            match config.validate() {
+                Err(ErrorCode::InvalidConfig) => {},
+                r => panic!("{:?} accepted: {:?}", config, r),
+            }
+        }
+    }
+}
@@ -28,12 +28,13 @@ use std::hash;
 use std::mem;
 use std::sync::atomic::{AtomicBool, Ordering, AtomicUsize};
 use std::intrinsics;
+use std::arch::x86_64::_mm_sfence;
 
 use num::Integer;
 
 use memory::*;
 use common::{Pointer, fnv1a, is_even, is_odd, atomic_add, atomic_cas};
-use common::{prefetch,prefetchw};
+use common::{prefetch,prefetchw,ErrorCode};
 use logger::*;
 use numa::{self,NodeId};
 use meta;
@@ -60,20 +61,23 @@ const TABLE_VLEN: usize = 1usize << 36;
 const VERSION_MASK: u64 = 0x1;
 const ENTRIES_PER_BUCKET: usize = 15;
 
-/// We reserve this value to indicate the bucket slot is empty.
-const INVALID_KEY: u64 = 0u64;
+/// Occupancy bits of a bucket with every slot in use.
+const FULL_MASK: u64 = (1u64 << ENTRIES_PER_BUCKET) - 1;
 
+/// Slot i holds a key only if bit i of 'used' is set, so every u64
+/// is a valid key. A zeroed bucket is empty.
 struct Bucket {
     _align: [align64;0],
     version: u64,
+    used:   u64,
     key:    [u64; ENTRIES_PER_BUCKET],
     value:  [u64; ENTRIES_PER_BUCKET],
 }
 
 impl fmt::Debug for Bucket {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Bucket {{ ver {} key {:x} {:x} {:x}  }}",
-               self.version,
+        write!(f, "Bucket {{ ver {} used {:x} key {:x} {:x} {:x}  }}",
+               self.version, self.used,
                self.key[0], self.key[1], self.key[2])
     }
 }
@@ -114,6 +118,25 @@ impl Bucket {
         unsafe { ptr::read_volatile(v) }
     }
 
+    #[inline(always)]
+    pub fn read_used(&self) -> u64 {
+        let u = &self.used as *const u64;
+        unsafe { ptr::read_volatile(u) }
+    }
+
+    #[inline(always)]
+    pub fn is_used(&self, idx: usize) -> bool {
+        debug_assert!(idx < ENTRIES_PER_BUCKET);
+        (self.read_used() & (1u64 << idx)) != 0
+    }
+
+    /// Caller must hold the bucket lock.
+    #[inline(always)]
+    fn write_used(&self, used: u64) {
+        let u = &self.used as *const u64 as *mut u64;
+        unsafe { ptr::write_volatile(u, used); }
+    }
+
     #[inline(always)]
     pub fn read_key(&self, idx: usize) -> u64 {
         debug_assert!(idx < ENTRIES_PER_BUCKET);
@@ -132,15 +155,16 @@ impl Bucket {
         }
     }
 
+    /// Store key into slot idx and mark it used.
     #[inline(always)]
     pub fn set_key(&self, idx: usize, key: u64) {
         debug_assert!(idx < ENTRIES_PER_BUCKET);
-        debug_assert!(key != INVALID_KEY);
         unsafe {
             let k = self.key.get_unchecked(idx)
                         as *const u64 as *mut u64;
             ptr::write_volatile(k, key);
         }
+        self.write_used(self.read_used() | (1u64 << idx));
     }
 
     #[inline(always)]
@@ -167,10 +191,10 @@ impl Bucket {
             let v = self.value.get_unchecked(idx)
                         as *const u64 as *mut u64;
             // XXX will this ever be optimized out?
-            //ptr::replace(v, INVALID_KEY)
             *old = ptr::read_volatile(v);
-            ptr::write_volatile(k, INVALID_KEY);
+            ptr::write_volatile(k, 0u64);
         }
+        self.write_used(self.read_used() & !(1u64 << idx));
     }
 
     //#[cfg(IGNORE)]
@@ -295,25 +319,29 @@ impl Bucket {
     /// Locate an empty slot in the bucket. Return index.
     #[inline(always)]
     pub fn find_empty(&self) -> Option<usize> {
-        for i in 0..ENTRIES_PER_BUCKET {
-            if self.read_key(i) == INVALID_KEY {
-                return Some(i);
-            }
+        let free = !self.read_used() & FULL_MASK;
+        if free == 0 {
+            None
+        } else {
+            Some(free.trailing_zeros() as usize)
         }
-        None
     }
 
+    /// Keys are hashes, so two distinct keys may share a slot key;
+    /// m is given the value of each slot whose key matches and decides
+    /// whether it is truly ours.
     #[inline(always)]
-    pub fn find_key(&self, key: u64) -> find_ops {
+    pub fn find_key<M>(&self, key: u64, m: &M) -> find_ops
+        where M: Fn(u64) -> bool {
         let mut idx: Option<usize> = None;
         let mut inv: Option<usize> = None;
+        let used = self.read_used();
         for i in 0..ENTRIES_PER_BUCKET {
-            let k = self.read_key(i);
-            if key == k {
+            if (used & (1u64 << i)) == 0 {
+                inv = Some(i);
+            } else if key == self.read_key(i) && m(self.read_value(i)) {
                 idx = Some(i);
                 break;
-            } else if INVALID_KEY == k {
-                inv = Some(i);
             }
         }
         (idx,inv)
@@ -337,6 +365,47 @@ impl BucketGuard {
         }
     }
 
+    #[inline(always)]
+    fn bucket(&self) -> &Bucket {
+        unsafe { &*self.bucket.0 }
+    }
+
+    /// Slot holding key, if any; m decides as for find_key.
+    pub fn find<M>(&self, key: u64, m: &M) -> Option<usize>
+        where M: Fn(u64) -> bool {
+        self.bucket().find_key(key, m).0
+    }
+
+    pub fn value(&self, idx: usize) -> u64 {
+        self.bucket().read_value(idx)
+    }
+
+    pub fn free_slots(&self) -> usize {
+        (!self.bucket().read_used() & FULL_MASK).count_ones() as usize
+    }
+
+    pub fn set_value(&self, idx: usize, value: u64) {
+        self.bucket().set_value(idx, value);
+    }
+
+    /// Store key and value in a free slot. Returns false if none is.
+    pub fn insert(&self, key: u64, value: u64) -> bool {
+        let bucket = self.bucket();
+        match bucket.find_empty() {
+            None => false,
+            Some(i) => {
+                bucket.set_key(i, key);
+                bucket.set_value(i, value);
+                true
+            },
+        }
+    }
+
+    pub fn delete(&self, idx: usize) {
+        let mut old: u64 = 0;
+        self.bucket().del_key(idx, &mut old);
+    }
+
 //    /// Same as hashtable::put() except the bucket is already locked
 //    pub fn update(&self, key: u64, new: u64) -> (bool,Option<u64>) {
 //        let mut opts: find_ops =
@@ -392,13 +461,21 @@ pub struct HashTable {
 
 impl HashTable {
 
-    pub fn new(entries: usize, sock: usize) -> Self {
+    pub fn new(entries: usize, sock: usize) -> Result<Self,ErrorCode> {
+        Self::with_pages(entries, sock, true)
+    }
+
+    /// As new, but choosing whether the table is backed by huge
+    /// pages. Fails with OutOfMemory if its memory cannot be mapped.
+    pub fn with_pages(entries: usize, sock: usize, huge: bool)
+        -> Result<Self,ErrorCode> {
         let nbuckets =
             (entries / ENTRIES_PER_BUCKET).next_power_of_two();
         let len = nbuckets * mem::size_of::<Bucket>();
-        let align: usize = numa::PAGE_SIZE_HUGE;
+        let align: usize =
+            if huge { numa::PAGE_SIZE_HUGE } else { numa::PAGE_SIZE };
         let mmap = MemMap::numa(TABLE_VLEN,
-                                NodeId(sock), align, false);
+                                NodeId(sock), align, false, huge)?;
         let p = Pointer(mmap.addr() as *const Bucket);
         debug!("new, sock {} nbucket {} current len {}",
               sock, nbuckets, len);
@@ -408,13 +485,14 @@ impl HashTable {
         };
         for b in sl {
             b.version = 0;
+            b.used = 0;
             for i in 0..ENTRIES_PER_BUCKET {
-                b.key[i] = INVALID_KEY;
+                b.key[i] = 0;
                 b.value[i] = 0;
             }
         }
         // TODO use threads to populate the arrays.
-        HashTable {
+        Ok(HashTable {
             buckets: p,
             bucket_mmap: mmap,
 
@@ -425,10 +503,15 @@ impl HashTable {
             nbuckets: nbuckets,
             len: len,
             resized: AtomicUsize::new(0)
-        }
+        })
+    }
+
+    /// How the table's memory was obtained.
+    pub fn mem_mode(&self) -> MemMode {
+        self.bucket_mmap.mode()
     }
 
-    pub fn default(sock: usize) -> Self {
+    pub fn default(sock: usize) -> Result<Self,ErrorCode> {
         Self::new( 1usize << 20, sock )
     }
 
@@ -467,16 +550,27 @@ impl HashTable {
         self.allow_resize = false;
     }
 
+    /// Bring the bucket of key into cache ahead of a lookup. Only a
+    /// hint; the bucket may move if the table is resized meanwhile.
     #[inline(always)]
-    pub fn prefetchw(&self, hash: u64) {
-        let bidx = self.index(hash);
-        let buckets: &[Bucket] = self.as_slice();
-        let bucket: &Bucket = &buckets[bidx];
-        let addr: *const u8 = unsafe {
-            //&bucket.version as *const _ as *const u8
-            bucket.key.get_unchecked(2) as *const _ as *const u8
-        };
-        prefetchw(addr);
+    pub fn prefetch(&self, key: u64) {
+        let bucket = self.bucket_of(key);
+        prefetch(bucket as *const Bucket as *const u8);
+        prefetch(&bucket.value as *const _ as *const u8);
+    }
+
+    /// As prefetch, ahead of an update.
+    #[inline(always)]
+    pub fn prefetchw(&self, key: u64) {
+        let bucket = self.bucket_of(key);
+        prefetchw(bucket as *const Bucket as *const u8);
+        prefetchw(&bucket.value as *const _ as *const u8);
+    }
+
+    #[inline(always)]
+    fn bucket_of(&self, key: u64) -> &Bucket {
+        let bidx = self.index(Self::make_hash(key));
+        &self.as_slice()[bidx]
     }
 
     #[inline(always)]
@@ -499,6 +593,13 @@ impl HashTable {
     /// (false,Some(x)) -> never returned
     #[inline(always)]
     pub fn put(&self, key: u64, value: u64) -> (bool,Option<u64>) {
+        self.put_by(key, value, |_| true)
+    }
+
+    /// Same as put, but use m to disambiguate keys (see find_key)
+    #[inline(always)]
+    pub fn put_by<M>(&self, key: u64, value: u64, m: M)
+        -> (bool,Option<u64>) where M: Fn(u64) -> bool {
         let hash = Self::make_hash(key);
 
         let mut bidx: usize;
@@ -526,14 +627,16 @@ impl HashTable {
 
             bver = bucket.read_version();
 
-            opts = bucket.find_key(key);
+            opts = bucket.find_key(key, &m);
             let (e,inv) = opts;
             if unlikely!(e.is_none() && inv.is_none()) {
                 if !self.allow_resize {
                     return (false, None);
                 }
-                if !self.resize() {
-                    self.wait_resizing();
+                match self.resize() {
+                    Err(_) => return (false, None),
+                    Ok(false) => self.wait_resizing(),
+                    Ok(true) => {},
                 }
                 continue 'retry;
             }
@@ -550,7 +653,7 @@ impl HashTable {
 
             // table has not changed, check if bucket has
             if bucket.read_version() != bver {
-                opts = bucket.find_key(key);
+                opts = bucket.find_key(key, &m);
             }
 
             let (e,inv) = opts;
@@ -572,8 +675,10 @@ impl HashTable {
                 return (false, None);
             }
             drop(guard);
-            if !self.resize() {
-                self.wait_resizing();
+            match self.resize() {
+                Err(_) => return (false, None),
+                Ok(false) => self.wait_resizing(),
+                Ok(true) => {},
             }
             // now loop around and retry
         }
@@ -582,6 +687,13 @@ impl HashTable {
 
     #[inline(always)]
     pub fn get(&self, key: u64, value: &mut u64) -> bool {
+        self.get_by(key, value, |_| true)
+    }
+
+    /// Same as get, but use m to disambiguate keys (see find_key)
+    #[inline(always)]
+    pub fn get_by<M>(&self, key: u64, value: &mut u64, m: M) -> bool
+        where M: Fn(u64) -> bool {
         let hash = Self::make_hash(key);
 
         let mut bidx: usize;
@@ -609,8 +721,11 @@ impl HashTable {
             }
 
             let bver = bucket.wait_version();
+            let used = bucket.read_used();
             for i in 0..ENTRIES_PER_BUCKET {
-                if bucket.read_key(i) == key {
+                if (used & (1u64 << i)) != 0 &&
+                        bucket.read_key(i) == key &&
+                        m(bucket.read_value(i)) {
                     *value = bucket.read_value(i);
                     let must_retry = (bucket.read_version() != bver)
                         || unlikely!(self.version() != tver);
@@ -636,6 +751,13 @@ impl HashTable {
 
     #[inline(always)]
     pub fn del(&self, key: u64, old: &mut u64) -> bool {
+        self.del_by(key, old, |_| true)
+    }
+
+    /// Same as del, but use m to disambiguate keys (see find_key)
+    #[inline(always)]
+    pub fn del_by<M>(&self, key: u64, old: &mut u64, m: M) -> bool
+        where M: Fn(u64) -> bool {
         let hash = Self::make_hash(key);
 
         let mut bidx: usize;
@@ -682,7 +804,7 @@ impl HashTable {
             // }
 
             let guard = bucket.wait_lock();
-            opts = bucket.find_key(key);
+            opts = bucket.find_key(key, &m);
 
             if unlikely!(tver != self.version()) {
                 continue 'retry;
@@ -706,8 +828,17 @@ impl HashTable {
 
     /// copy-pasta from del()
     #[inline(always)]
-    pub fn del_map<F>(&self, key: u64, f: F) -> bool
-        where F: Fn(Option<u64>) {
+    pub fn del_map<M,F>(&self, key: u64, m: M, mut f: F) -> bool
+        where M: Fn(u64) -> bool, F: FnMut(Option<u64>) {
+        self.del_map_if(key, m, |e| { f(e); true })
+    }
+
+    /// As del_map, but the key is removed only if f, given its value
+    /// while the bucket is locked, returns true. Returns whether the
+    /// key was removed.
+    #[inline(always)]
+    pub fn del_map_if<M,F>(&self, key: u64, m: M, mut f: F) -> bool
+        where M: Fn(u64) -> bool, F: FnMut(Option<u64>) -> bool {
         let hash = Self::make_hash(key);
 
         let mut bidx: usize;
@@ -734,7 +865,7 @@ impl HashTable {
             }
 
             let guard = bucket.wait_lock();
-            opts = bucket.find_key(key);
+            opts = bucket.find_key(key, &m);
 
             if unlikely!(tver != self.version()) {
                 continue 'retry;
@@ -747,9 +878,12 @@ impl HashTable {
                 return false;
             }
 
+            let i = e.unwrap();
+            if !f(Some(bucket.read_value(i))) {
+                return false;
+            }
             let mut value: u64 = 0;
-            bucket.del_key(e.unwrap(), &mut value);
-            f(Some(value));
+            bucket.del_key(i, &mut value);
             return true;
         }
         assert!(false, "Unreachable path");
@@ -765,8 +899,19 @@ impl HashTable {
     /// FIXME can we just make this part of put() ? much of the code
     /// is the same
     #[inline(always)]
-    pub fn update_map<F>(&self, key: u64, new: u64, f: F) -> bool
-        where F: Fn(Option<u64>) {
+    pub fn update_map<M,F>(&self, key: u64, new: u64, m: M, mut f: F)
+        -> bool where M: Fn(u64) -> bool, F: FnMut(Option<u64>) {
+        self.update_map_if(key, new, m, |e| { f(e); true }).is_ok()
+    }
+
+    /// As update_map, but the update or insertion is made only if f,
+    /// given the prior value (if any) while the bucket is locked,
+    /// returns true. Returns whether it was made, or TableFull if
+    /// there was no room to insert.
+    #[inline(always)]
+    pub fn update_map_if<M,F>(&self, key: u64, new: u64, m: M, mut f: F)
+        -> Result<bool,ErrorCode>
+        where M: Fn(u64) -> bool, F: FnMut(Option<u64>) -> bool {
 
         let hash = Self::make_hash(key);
 
@@ -795,14 +940,16 @@ impl HashTable {
 
             bver = bucket.read_version();
 
-            opts = bucket.find_key(key);
+            opts = bucket.find_key(key, &m);
             let (e,inv) = opts;
             if unlikely!(e.is_none() && inv.is_none()) {
                 if !self.allow_resize {
-                    return false;
+                    return Err(ErrorCode::TableFull);
                 }
-                if !self.resize() {
-                    self.wait_resizing();
+                match self.resize() {
+                    Err(e) => return Err(e),
+                    Ok(false) => self.wait_resizing(),
+                    Ok(true) => {},
                 }
                 continue 'retry;
             }
@@ -815,31 +962,38 @@ impl HashTable {
 
             // table has not changed, check if bucket has
             if bucket.read_version() != bver {
-                opts = bucket.find_key(key);
+                opts = bucket.find_key(key, &m);
             }
 
             let (e,inv) = opts;
 
             // if exists, overwrite
             if let Some(i) = e {
-                f(Some(bucket.set_value(i, new)));
-                return true;
+                if !f(Some(bucket.read_value(i))) {
+                    return Ok(false);
+                }
+                bucket.set_value(i, new);
+                return Ok(true);
             }
             // else if there is an empty slot, use that
             else if let Some(i) = inv {
+                if !f(None) {
+                    return Ok(false);
+                }
                 bucket.set_key(i, key);
                 bucket.set_value(i, new);
-                f(None);
-                return true;
+                return Ok(true);
             }
 
             // hm..  again no space. we must resize
             if !self.allow_resize {
-                return false;
+                return Err(ErrorCode::TableFull);
             }
             drop(guard);
-            if !self.resize() {
-                self.wait_resizing();
+            match self.resize() {
+                Err(e) => return Err(e),
+                Ok(false) => self.wait_resizing(),
+                Ok(true) => {},
             }
             // now loop around and retry
         }
@@ -848,8 +1002,8 @@ impl HashTable {
 
     /// If the key exists, lock the bucket and execute the lambda.
     #[inline(always)]
-    pub fn lock_map_ifex<F>(&self, key: u64, f: F) -> bool
-        where F: Fn(u64) {
+    pub fn lock_map_ifex<M,F>(&self, key: u64, m: M, mut f: F) -> bool
+        where M: Fn(u64) -> bool, F: FnMut(u64) {
 
         let hash = Self::make_hash(key);
 
@@ -878,7 +1032,7 @@ impl HashTable {
 
             bver = bucket.read_version();
 
-            opts = bucket.find_key(key);
+            opts = bucket.find_key(key, &m);
             let (e,inv) = opts;
             // if key does not exist, we're done
             if e.is_none() {
@@ -893,7 +1047,7 @@ impl HashTable {
 
             // table has not changed, check if bucket has
             if bucket.read_version() != bver {
-                opts = bucket.find_key(key);
+                opts = bucket.find_key(key, &m);
             }
 
             let (e,inv) = opts;
@@ -909,10 +1063,12 @@ impl HashTable {
 
     /// Grab the lock on the bucket holding the key only if the
     /// existing value matches one specified. Before returning,
-    /// replace existing value with new.
+    /// replace existing value with new, if f (invoked under the
+    /// lock) allows it.
     #[inline(always)]
-    pub fn update_lock_ifeq(&self, key: u64, new: u64, old: u64)
-        -> Option<BucketGuard> {
+    pub fn update_lock_ifeq<F>(&self, key: u64, new: u64, old: u64,
+                               f: F) -> Option<BucketGuard>
+        where F: FnOnce() -> bool {
 
         let hash = Self::make_hash(key);
 
@@ -951,7 +1107,9 @@ impl HashTable {
                 continue 'retry;
             }
 
-            opts = bucket.find_key(key);
+            // value must match, too, else it may be another key
+            // with the same hash
+            opts = bucket.find_key(key, &|v| v == old);
 
             // if bucket.read_version() != v {
             //     opts = bucket.find_key(key);
@@ -963,7 +1121,7 @@ impl HashTable {
             }
 
             let i = e.unwrap();
-            if old == bucket.read_value(i) {
+            if old == bucket.read_value(i) && f() {
                 bucket.set_value(i, new);
                 return Some(guard);
             } else {
@@ -974,6 +1132,43 @@ impl HashTable {
         assert!(false, "Unreachable path");
     }
 
+    /// The table version and the index of the bucket holding key.
+    /// Callers locking several buckets do so in order of index; see
+    /// lock_index.
+    pub fn bucket_index(&self, key: u64) -> (u64,usize) {
+        (self.version(), self.index(Self::make_hash(key)))
+    }
+
+    /// Lock the bucket at bidx, as returned by bucket_index under
+    /// table version tver. Returns None if the table has since been
+    /// resized, so that bidx may no longer hold the key. Buckets must
+    /// be locked in increasing order of index (as resize does), else
+    /// we may deadlock.
+    pub fn lock_index(&self, tver: u64, bidx: usize)
+        -> Option<BucketGuard> {
+        if self.version() != tver {
+            return None;
+        }
+        let guard = self.as_slice()[bidx].wait_lock();
+        if self.version() != tver {
+            return None;
+        }
+        Some(guard)
+    }
+
+    /// Make room after finding a bucket full, or wait for another
+    /// thread doing so. Returns TableFull if the table cannot grow.
+    pub fn grow(&self) -> Result<(),ErrorCode> {
+        if !self.allow_resize {
+            return Err(ErrorCode::TableFull);
+        }
+        match self.resize() {
+            Err(e) => Err(e),
+            Ok(false) => { self.wait_resizing(); Ok(()) },
+            Ok(true) => Ok(()),
+        }
+    }
+
     fn lock_all(&self) -> Vec<BucketGuard> {
         let mut v: Vec<BucketGuard> =
             Vec::with_capacity(self.nbuckets);
@@ -996,14 +1191,15 @@ impl HashTable {
             Ordering::SeqCst)
     }
 
-    /// Return false if we raced to lock for resizing but failed.
-    pub fn resize(&self) -> bool {
+    /// Return Ok(false) if we raced to lock for resizing but failed,
+    /// and TableFull if the table cannot grow within its mmap area.
+    pub fn resize(&self) -> Result<bool,ErrorCode> {
         // Threads which lose the race to resize will simply keep
         // trying to insert to the bucket, again trying to lock.
         // Eventually the bucket will be locked by us. After we resize
         // and unlock the bucket, the failed threads will resume.
         if !self.lock_for_resize() {
-            return false;
+            return Ok(false);
         }
 
         let prior = self.resized.fetch_add(1, Ordering::Relaxed);
@@ -1017,8 +1213,12 @@ impl HashTable {
 
         let nbuckets = self.nbuckets * factor;
         let len = self.len * factor;
-        assert!(len <= self.bucket_mmap.len(),
-            "Resizing table cannot grow beyond mmap area");
+        if len > self.bucket_mmap.len() {
+            warn!("Table {:p} cannot grow beyond mmap area",
+                  self as *const Self as *const u8);
+            assert_eq!(self.unlock_for_resize(), true);
+            return Err(ErrorCode::TableFull);
+        }
         debug_assert!(nbuckets.is_power_of_two());
 
         debug!("table 0x{:x} resizing, factor {} nb {} len {}",
@@ -1034,9 +1234,8 @@ impl HashTable {
         let start = clock::now();
         unsafe {
             // this should fault pages on the socket set by mbind
-            // TODO we assume zero is used to indicate INVALID_KEY and
-            // that version zero is a valid starting version
-            // (all true)
+            // zeroed buckets have no slots in use, and version zero
+            // is a valid starting version
             self.bucket_mmap.clear_region(self.len, self.len);
         }
         let end = clock::now();
@@ -1055,15 +1254,46 @@ impl HashTable {
         let end = clock::now();
         debug!("locking buckets {} µs", clock::to_usec(end-start));
 
+        // count what each new bucket will receive before moving
+        // anything, so we can back out if one would overflow
         let start = clock::now();
+        let mut fill: Vec<u8> = vec![0u8; nbuckets - self.nbuckets];
+        let mut overflow = false;
+        for bidx in 0..self.nbuckets {
+            let old: &Bucket = &buckets[bidx];
+            for kidx in 0..ENTRIES_PER_BUCKET {
+                if !old.is_used(kidx) {
+                    continue;
+                }
+                let hash = Self::make_hash(old.read_key(kidx));
+                let bbidx = (hash % (nbuckets as u64)) as usize;
+                if bidx != bbidx {
+                    let n = &mut fill[bbidx - self.nbuckets];
+                    *n += 1;
+                    overflow |= *n as usize > ENTRIES_PER_BUCKET;
+                }
+            }
+        }
+        if overflow {
+            warn!("Table {:p} cannot resize: a new bucket would overflow",
+                  self as *const Self as *const u8);
+            drop(all);
+            assert_eq!(self.unlock_for_resize(), true);
+            return Err(ErrorCode::TableFull);
+        }
+
+        // new buckets were cleared, so each fills from its first slot
+        for n in &mut fill {
+            *n = 0;
+        }
         for bidx in 0..self.nbuckets {
             let old: &Bucket = &buckets[bidx];
             // prefetch next bucket? TODO
             for kidx in 0..ENTRIES_PER_BUCKET {
-                let key = old.read_key(kidx);
-                if INVALID_KEY == key {
+                if !old.is_used(kidx) {
                     continue;
                 }
+                let key = old.read_key(kidx);
                 let hash = Self::make_hash(key);
                 let bbidx = (hash % (nbuckets as u64)) as usize;
                 if bidx != bbidx {
@@ -1071,12 +1301,9 @@ impl HashTable {
                     let new: &Bucket = &buckets[bbidx];
                     let mut value: u64 = 0;
                     old.del_key(kidx, &mut value);
-                    let opt = new.find_empty();
-                    // To really fix this, we'd restart resizing
-                    // with even more buckets
-                    assert!(opt.is_some(),
-                        "While resizing table, new bucket is full!");
-                    let kkidx = opt.unwrap();
+                    let n = &mut fill[bbidx - self.nbuckets];
+                    let kkidx = *n as usize;
+                    *n += 1;
                     new.set_key(kkidx, key);
                     new.set_value(kkidx, value);
                 }
@@ -1091,7 +1318,7 @@ impl HashTable {
         // ensure bucket data and version are visible
         // before upper buckets become visible
         unsafe {
-            asm!("sfence" : : : "memory");
+            _mm_sfence();
         }
 
         unsafe {
@@ -1100,7 +1327,7 @@ impl HashTable {
             me.nbuckets = nbuckets;
             me.len = len;
             // ensure these are visible before unlocking lower buckets
-            asm!("sfence" : : : "memory");
+            _mm_sfence();
         }
 
         // at this point, the upper buckets are now accessible,
@@ -1125,7 +1352,7 @@ impl HashTable {
         //     println!("NEW {:?}", bucket);
         // }
 
-        true
+        Ok(true)
     }
 
     #[inline(always)]
@@ -1146,12 +1373,41 @@ impl HashTable {
         her.finish()
     }
 
+    /// Invoke f on each key and value held. Buckets are not locked;
+    /// each one is read consistently, but may change once f is
+    /// called. Pins the epoch as it goes (see Bucket::wait_version).
+    pub fn for_each<F>(&self, mut f: F) where F: FnMut(u64,u64) {
+        let mut pairs = [(0u64,0u64); ENTRIES_PER_BUCKET];
+        self.wait_resizing();
+        for bucket in self.as_slice() {
+            let mut n;
+            loop {
+                n = 0;
+                let bver = bucket.wait_version();
+                let used = bucket.read_used();
+                for i in 0..ENTRIES_PER_BUCKET {
+                    if (used & (1u64 << i)) != 0 {
+                        pairs[n] = (bucket.read_key(i),
+                                    bucket.read_value(i));
+                        n += 1;
+                    }
+                }
+                if bucket.read_version() == bver {
+                    break;
+                }
+            }
+            for &(key,value) in &pairs[..n] {
+                f(key, value);
+            }
+        }
+    }
+
     fn stats(&self) {
         let buckets: &[Bucket] = self.as_slice();
         for t in buckets.iter().zip(0..) {
             let mut n = 0;
             for i in 0..ENTRIES_PER_BUCKET {
-                if t.0 .key[i] != INVALID_KEY {
+                if t.0 .is_used(i) {
                     n += 1;
                 }
             }
@@ -1163,7 +1419,8 @@ impl HashTable {
 impl Drop for HashTable {
 
     fn drop(&mut self) {
-        // TODO release mmap data
+        // bucket_mmap is unmapped when it is dropped
+        debug!("dropping table, {} buckets", self.nbuckets);
     }
 }
 
@@ -1171,7 +1428,6 @@ mod tests {
     use super::*;
     use super::Bucket;
     use super::ENTRIES_PER_BUCKET;
-    use super::INVALID_KEY;
 
     use std::thread::{self,JoinHandle};
     use std::collections::HashSet;
@@ -1191,9 +1447,9 @@ mod tests {
         logger::enable();
         println!("");
         let tblsize = 1<<20;
-        let mut ht = HashTable::new(tblsize, 0);
+        let mut ht = HashTable::new(tblsize, 0).unwrap();
         ht.forbid_resize();
-        let nb = tblsize / ENTRIES_PER_BUCKET;
+        let nb = (tblsize / ENTRIES_PER_BUCKET).next_power_of_two();
         assert_eq!(ht.nbuckets, nb);
     }
 
@@ -1203,17 +1459,11 @@ mod tests {
         logger::enable();
         println!("");
 
-        let mut ht = HashTable::new(1<<20, 0);
+        let mut ht = HashTable::new(1<<20, 0).unwrap();
         ht.forbid_resize();
         let mut value: u64 = 0;
         for i in 0..8192 {
-            let mut key;
-            loop {
-                key = unsafe { rdrandq() };
-                if INVALID_KEY != key {
-                    break;
-                }
-            }
+            let key = unsafe { rdrandq() };
             assert_eq!(ht.get(key, &mut value), false);
         }
     }
@@ -1231,7 +1481,7 @@ mod tests {
         let mut rng = rand::thread_rng();
 
         'outer: for _ in 0..ntables {
-            let mut ht = HashTable::new(tblsz,0);
+            let mut ht = HashTable::new(tblsz,0).unwrap();
             ht.forbid_resize();
             let mut inserted = 0;
             key = rng.gen::<u64>();
@@ -1261,7 +1511,7 @@ mod tests {
         logger::enable();
         println!("");
 
-        let mut ht = HashTable::new(1<<20,0);
+        let mut ht = HashTable::new(1<<20,0).unwrap();
         ht.forbid_resize();
         let mut value: u64 = 0;
         let mut keys: HashSet<u64> = HashSet::with_capacity(8192);
@@ -1269,9 +1519,6 @@ mod tests {
         let mut rng = rand::thread_rng();
         while keys.len() < 8192 {
             let key = rng.gen::<u64>();
-            if INVALID_KEY == key {
-                continue;
-            }
             keys.insert(key);
         }
 
@@ -1295,12 +1542,33 @@ mod tests {
         }
     }
 
+    /// zero and u64::MAX are keys like any other
+    #[test]
+    fn extreme_keys() {
+        logger::enable();
+
+        let mut ht = HashTable::new(1<<20,0).unwrap();
+        ht.forbid_resize();
+        let mut value: u64 = 0;
+        for &key in &[0u64, u64::max_value()] {
+            assert_eq!(ht.get(key, &mut value), false);
+            assert_eq!(ht.put(key, key ^ 1), (true, None));
+            assert_eq!(ht.get(key, &mut value), true);
+            assert_eq!(value, key ^ 1);
+        }
+        for &key in &[0u64, u64::max_value()] {
+            assert_eq!(ht.del(key, &mut value), true);
+            assert_eq!(value, key ^ 1);
+            assert_eq!(ht.get(key, &mut value), false);
+        }
+    }
+
     #[test]
     fn put_del() {
         logger::enable();
         println!("");
 
-        let mut ht = HashTable::new(1<<20,0);
+        let mut ht = HashTable::new(1<<20,0).unwrap();
         ht.forbid_resize();
         let mut keys: Vec<u64> = Vec::new();
 
@@ -1339,7 +1607,7 @@ mod tests {
         logger::enable();
         println!("");
 
-        let mut ht = HashTable::new(tblsz, 0);
+        let mut ht = HashTable::new(tblsz, 0).unwrap();
         ht.forbid_resize();
 
         let mut inserted: usize = 0;
@@ -1408,7 +1676,7 @@ mod tests {
         logger::enable();
         println!("");
 
-        let mut ht = HashTable::new(tblsz, 0);
+        let mut ht = HashTable::new(tblsz, 0).unwrap();
         ht.forbid_resize();
         let total = tblsz;// >> 1; // no. keys to use
         let keys_per = total / nthreads;
@@ -1535,8 +1803,8 @@ mod tests {
     fn lock_all() {
         logger::enable();
         let entries: usize = 1usize<<20;
-        let mut ht = HashTable::new(entries, 0);
-        ht.lock_all();
+        let mut ht = HashTable::new(entries, 0).unwrap();
+        let guards = ht.lock_all();
         let buckets: &[Bucket] = ht.as_slice();
         for b in buckets {
             assert_eq!(b.read_version().is_odd(), true);
@@ -1548,15 +1816,16 @@ mod tests {
         logger::enable();
 
         let entries: usize = 1usize<<12;
-        let mut ht = HashTable::new(entries, 0);
-        let len = ht.len;
-        let nbuckets = ht.nbuckets;
+        let mut ht = HashTable::new(entries, 0).unwrap();
 
         for k in 0..(1u64<<12) {
             ht.put(k+1, k+1); // 0 not a valid key..
         }
+        // the puts may have grown the table already
+        let len = ht.len;
+        let nbuckets = ht.nbuckets;
 
-        ht.resize();
+        assert_eq!(ht.resize(), Ok(true));
         assert_eq!(ht.len, len*2);
         assert_eq!(ht.nbuckets, nbuckets*2);
 
@@ -1568,7 +1837,7 @@ mod tests {
 
         debug!("checking values");
         let mut value: u64 = 0;
-        for k in 0..(1u64<<15) {
+        for k in 0..(1u64<<12) {
             assert!(ht.get(k+1, &mut value),
                 "key {} not found", k+1);
             assert!(value == k+1,
@@ -1583,7 +1852,7 @@ mod tests {
 
         let nthreads = 12;
         info!("creating hash table");
-        let ht = HashTable::new(1<<20, 0);
+        let ht = HashTable::new(1<<20, 0).unwrap();
         let tids = AtomicUsize::new(1);
 
         let mut guards = vec![];
@@ -1666,7 +1935,7 @@ mod tests {
         logger::enable();
 
         let entries: usize = 1usize<<12;
-        let mut ht = HashTable::new(entries, 0);
+        let mut ht = HashTable::new(entries, 0).unwrap();
         ht.forbid_resize();
         let len = ht.len;
         let nbuckets = ht.nbuckets;
@@ -19,13 +19,18 @@
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize,Ordering};
+use std::cmp::{self,Reverse};
+use std::collections::{BTreeMap,BinaryHeap,VecDeque};
+use std::collections::Bound;
 use crossbeam;
 use parking_lot as pl;
 
 use hashtable::*;
-use common::{self,Pointer};
+use common::{self,Pointer,KeyType,KeyHash,ErrorCode};
+use memory::{MemMode,align64};
 use numa::{self,NodeId};
 use sched;
+use meta;
 
 //==----------------------------------------------------==//
 //      Index
@@ -50,17 +55,158 @@ pub fn merge(socket: u16, va: u64) -> IndexEntry {
     ((socket as u64) << 48) | (va & ((1u64<<48)-1))
 }
 
-/// Index structure that allows us to retreive objects from the log.
-/// It is just a simple wrapper over whatever data structure we wish
-/// to eventually use.
-pub struct Index {
+/// Decides whether an entry found for a key truly belongs to it.
+/// Indexes which store only a hash of each key use this to resolve
+/// collisions; indexes holding the full key may ignore it.
+pub type Matcher<'a> = &'a Fn(IndexEntry) -> bool;
+
+/// Which index implementation an LSM instance uses.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum IndexKind {
+    /// Hash tables; fastest point operations, no ordering.
+    Hash,
+    /// Keys kept in lexicographic byte order; supports scans.
+    Ordered,
+}
+
+/// Maps keys to the location of their objects in the log. Every
+/// method taking a closure runs it while the key is locked, so that
+/// callers (e.g. the compactor) observe no concurrent update.
+pub trait Index: Send + Sync {
+
+    /// Return value of object if it exists, else None.
+    fn get(&self, key: &KeyType, m: Matcher) -> Option<IndexEntry>;
+
+    /// Update location of object in the index. Returns None if object
+    /// was newly inserted, or the virtual address of the prior
+    /// object.
+    fn update(&self, key: &KeyType, value: IndexEntry, m: Matcher)
+        -> (bool,Option<IndexEntry>);
+
+    /// Remove an entry. If it existed, return value, else return
+    /// None.
+    fn remove(&self, key: &KeyType, m: Matcher) -> Option<IndexEntry>;
+
+    /// Attempt to delete the key. Invoke f while the key is locked,
+    /// passing None if the key does not exist, else Some() with the
+    /// entry.
+    fn remove_map(&self, key: &KeyType, m: Matcher,
+                  f: &mut FnMut(Option<IndexEntry>)) -> bool;
+
+    /// If the key maps to old, replace it with new. Entries are
+    /// compared by value (old), thus no matcher is needed to resolve
+    /// colliding keys. Whatever new refers to must be in place
+    /// beforehand, so that the key is locked only for the swap.
+    /// Returns whether the update was made.
+    fn update_ifeq(&self, key: &KeyType, new: IndexEntry,
+                   old: IndexEntry) -> bool;
+
+    /// As update_ifeq, but the update is made only if f, invoked
+    /// while the key is locked, returns true.
+    fn update_ifeq_if(&self, key: &KeyType, new: IndexEntry,
+                      old: IndexEntry, f: &mut FnMut() -> bool) -> bool;
+
+    /// Insert or update the key, invoking f with the prior entry (if
+    /// any) while the key is locked.
+    fn update_map(&self, key: &KeyType, new: IndexEntry, m: Matcher,
+                  f: &mut FnMut(Option<IndexEntry>)) -> bool;
+
+    /// As update_map, but the entry is inserted or updated only if f
+    /// returns true. Returns whether it was, or TableFull if there
+    /// was no room to insert.
+    fn update_map_if(&self, key: &KeyType, new: IndexEntry, m: Matcher,
+                     f: &mut FnMut(Option<IndexEntry>) -> bool)
+        -> Result<bool,ErrorCode>;
+
+    /// As remove_map, but the entry is removed only if f returns
+    /// true. Returns whether it was removed.
+    fn remove_map_if(&self, key: &KeyType, m: Matcher,
+                     f: &mut FnMut(Option<IndexEntry>) -> bool) -> bool;
+
+    /// Invoke f with the entry while the key is locked, only if the
+    /// key exists. Returns whether it existed.
+    fn lock_map_ifex(&self, key: &KeyType, m: Matcher,
+                     f: &mut FnMut(IndexEntry)) -> bool;
+
+    /// Set each of the distinct keys to the matching entry in news,
+    /// or remove it if None, such that readers observe either all of
+    /// the changes or none: every key is locked, in a fixed order,
+    /// before any is changed. f is given the prior entry of each key
+    /// while they are locked, and the changes are made only if it
+    /// returns true. m(i,e) resolves collisions for keys[i]. Returns
+    /// whether the changes were made, or TableFull if there was no
+    /// room to insert.
+    fn update_batch(&self, keys: &[&KeyType], news: &[Option<IndexEntry>],
+                    m: &Fn(usize, IndexEntry) -> bool,
+                    f: &mut FnMut(&[Option<IndexEntry>]) -> bool)
+        -> Result<bool,ErrorCode>;
+
+    /// Append to out, in key order, up to max entries whose keys lie
+    /// within [start,end) (unbounded if end is None). Each entry is
+    /// read atomically. Returns the number of entries appended.
+    #[allow(unused_variables)]
+    fn scan(&self, start: &KeyType, end: Option<&KeyType>, max: usize,
+            out: &mut Vec<(Vec<u8>,IndexEntry)>)
+        -> Result<usize,ErrorCode> {
+        Err(ErrorCode::NotOrdered)
+    }
+
+    /// Invoke f on every entry, without holding locks on keys, so
+    /// entries may change once seen. f also receives a test of
+    /// whether a key (e.g. read back from the log) is one the entry
+    /// could be stored under. The epoch is pinned as we go, always
+    /// before entries handed to f are read; callers must quiesce
+    /// afterwards. f must not call back into the index.
+    fn for_each(&self, f: &mut FnMut(IndexEntry, &Fn(&KeyType) -> bool));
+
+    /// Hint that key is about to be looked up, or updated if write,
+    /// so its place in the index may be brought into cache.
+    #[allow(unused_variables)]
+    fn prefetch(&self, key: &KeyType, write: bool) {}
+
+    /// Number of keys held.
+    fn len(&self) -> usize;
+
+    fn kind(&self) -> IndexKind;
+
+    /// How the memory behind each of the index's mappings was
+    /// obtained. Indexes using the ordinary heap return none.
+    fn memory(&self) -> Vec<MemMode> {
+        vec![]
+    }
+}
+
+//==----------------------------------------------------==//
+//      Hash index
+//==----------------------------------------------------==//
+
+/// Count of the keys held by one table, alone on its cache line so
+/// updates to different tables do not contend on it.
+struct LiveCount {
+    _align: [align64;0],
+    n: AtomicUsize,
+}
+
+/// Index over a set of hash tables partitioned across the sockets.
+/// The tables hold only a hash of each key.
+pub struct HashIndex {
     nnodes: usize,
     tables: Vec<Pointer<HashTable>>,
+    /// keys held by each table, adjusted once the bucket has been
+    /// changed, so a count may briefly lag its table
+    live: Vec<LiveCount>,
 }
 
-impl Index {
+impl HashIndex {
+
+    pub fn new(n: usize, per: usize) -> Result<Self,ErrorCode> {
+        Self::with_pages(n, per, true)
+    }
 
-    pub fn new(n: usize, per: usize) -> Self {
+    /// As new, but choosing whether the tables are backed by huge
+    /// pages. Fails with OutOfMemory if a table cannot be mapped.
+    pub fn with_pages(n: usize, per: usize, huge: bool)
+        -> Result<Self,ErrorCode> {
         let mut tables: Vec<Pointer<HashTable>>;
         tables = Vec::with_capacity(n);
         let nsockets = numa::NODE_MAP.sockets();
@@ -71,6 +217,7 @@ impl Index {
 
         // wrap it up for sharing
         let sharedq = pl::Mutex::new(tables);
+        let failed: pl::Mutex<Option<ErrorCode>> = pl::Mutex::new(None);
 
         let tables_per = n / nsockets;
         let mut guards = vec![];
@@ -84,7 +231,10 @@ impl Index {
                     let cpu = numa::NODE_MAP.cpus_of(NodeId(sock)).lowest();
                     unsafe { sched::pin_cpu(cpu); }
                     for _ in 0..tables_per {
-                        let t = Box::new(HashTable::new(per,sock));
+                        let t = match HashTable::with_pages(per,sock,huge) {
+                            Err(e) => { *failed.lock() = Some(e); break; },
+                            Ok(t) => Box::new(t),
+                        };
                         let p = Pointer(Box::into_raw(t));
                         sharedq.lock().push(p);
                     }
@@ -96,140 +246,587 @@ impl Index {
             guard.join();
         }
 
-        let tables = sharedq.into_inner();
-
-        Index {
+        let index = HashIndex {
             nnodes: numa::NODE_MAP.sockets(),
-            tables: tables,
+            tables: sharedq.into_inner(),
+            live: (0..n).map(|_| LiveCount {
+                _align: [], n: AtomicUsize::new(0),
+            }).collect(),
+        };
+        // dropping the index releases the tables we did make
+        match failed.into_inner() {
+            Some(e) => Err(e),
+            None => Ok(index),
         }
     }
 
-    /// Return value of object if it exists, else None.
+    //
+    // Priate methods
+    //
+
+    /// Locate the table responsible for the hash.
     #[inline(always)]
-    pub fn get(&self, key: u64) -> Option<IndexEntry> {
-        debug_assert!(key > 0);
-        let tidx = self.table_idx(key);
+    fn table(&self, hash: KeyHash) -> &HashTable {
+        let tidx = self.table_idx(hash);
         debug_assert!(tidx < self.tables.len());
         let ref p = self.tables[tidx];
         debug_assert!(!p.0 .is_null());
-        let mut ret: Option<IndexEntry> = None;
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            let mut v: u64 = 0;
-            if ht.get(key, &mut v) {
-                ret = Some(v);
-            }
-        };
-        ret
+        unsafe { &* p.0 }
     }
 
-    /// Update location of object in the index. Returns None if object
-    /// was newly inserted, or the virtual address of the prior
-    /// object.
+    /// See comment for HashTable::index()
+    #[inline(always)]
+    fn table_idx(&self, hash: KeyHash) -> usize {
+        let hash = common::fnv1a(hash);
+        ((hash >> 29) & (self.tables.len() as u64 - 1)) as usize
+    }
+
+    /// Account for a key inserted into the table holding hash.
+    #[inline(always)]
+    fn inserted(&self, hash: KeyHash) {
+        self.live[self.table_idx(hash)].n
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account for a key removed from the table holding hash.
     #[inline(always)]
-    pub fn update(&self, key: u64, value: IndexEntry)
+    fn removed(&self, hash: KeyHash) {
+        self.live[self.table_idx(hash)].n
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Index for HashIndex {
+
+    #[inline(always)]
+    fn get(&self, key: &KeyType, m: Matcher) -> Option<IndexEntry> {
+        let hash = common::hash_key(key);
+        let mut v: u64 = 0;
+        if self.table(hash).get_by(hash, &mut v, m) {
+            Some(v)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn update(&self, key: &KeyType, value: IndexEntry, m: Matcher)
         -> (bool,Option<IndexEntry>) {
+        let hash = common::hash_key(key);
+        //ht.prefetchw(hash); // FIXME where to put?
+        let ret = self.table(hash).put_by(hash, value, m);
+        if let (true,None) = ret {
+            self.inserted(hash);
+        }
+        ret
+    }
 
-        debug_assert!(key > 0);
-        let tidx = self.table_idx(key);
-        debug_assert!(tidx < self.tables.len());
-        let ref p = self.tables[tidx];
-        debug_assert!(!p.0 .is_null());
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            //ht.prefetchw(hash); // FIXME where to put?
-            ht.put(key, value)
+    #[inline(always)]
+    fn remove(&self, key: &KeyType, m: Matcher) -> Option<IndexEntry> {
+        let hash = common::hash_key(key);
+        let mut old: u64 = 0;
+        if self.table(hash).del_by(hash, &mut old, m) {
+            self.removed(hash);
+            Some(old)
+        } else {
+            None
         }
     }
 
-    /// Remove an entry. If it existed, return value, else return
-    /// None.
     #[inline(always)]
-    pub fn remove(&self, key: u64) -> Option<IndexEntry> {
-        debug_assert!(key > 0);
-        let tidx = self.table_idx(key);
-        debug_assert!(tidx < self.tables.len());
-        let ref p = self.tables[tidx];
-        debug_assert!(!p.0 .is_null());
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            let mut old: u64 = 0;
-            if ht.del(key, &mut old) {
-                Some(old)
-            } else {
-                None
-            }
+    fn remove_map(&self, key: &KeyType, m: Matcher,
+                  f: &mut FnMut(Option<IndexEntry>)) -> bool {
+        let hash = common::hash_key(key);
+        let ok = self.table(hash).del_map(hash, m, |e| f(e));
+        if ok {
+            self.removed(hash);
         }
+        ok
+    }
+
+    fn update_ifeq(&self, key: &KeyType, new: IndexEntry,
+                   old: IndexEntry) -> bool {
+        let hash = common::hash_key(key);
+        // bucket is unlocked as the guard drops
+        self.table(hash).update_lock_ifeq(hash, new, old, || true)
+            .is_some()
+    }
+
+    fn update_ifeq_if(&self, key: &KeyType, new: IndexEntry,
+                      old: IndexEntry, f: &mut FnMut() -> bool) -> bool {
+        let hash = common::hash_key(key);
+        self.table(hash).update_lock_ifeq(hash, new, old, || f())
+            .is_some()
     }
 
-    /// Attempt to delete the key. Invoke f while bucket lock is held, passing
-    /// None if the key does not exist, else Some() with the entry.
     #[inline(always)]
-    pub fn remove_map<F>(&self, key: u64, f: F) -> bool
-        where F: Fn(Option<u64>) {
+    fn update_map(&self, key: &KeyType, new: IndexEntry, m: Matcher,
+                  f: &mut FnMut(Option<IndexEntry>)) -> bool {
+        let hash = common::hash_key(key);
+        // f is last given what the update replaced
+        let mut insert = false;
+        let ok = self.table(hash).update_map(hash, new, m, |e| {
+            insert = e.is_none();
+            f(e)
+        });
+        if ok && insert {
+            self.inserted(hash);
+        }
+        ok
+    }
 
-        let tidx = self.table_idx(key);
-        let ref p = self.tables[tidx];
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            ht.del_map(key, f)
+    fn update_map_if(&self, key: &KeyType, new: IndexEntry, m: Matcher,
+                     f: &mut FnMut(Option<IndexEntry>) -> bool)
+        -> Result<bool,ErrorCode> {
+        let hash = common::hash_key(key);
+        let mut insert = false;
+        let ok = self.table(hash).update_map_if(hash, new, m, |e| {
+            insert = e.is_none();
+            f(e)
+        })?;
+        if ok && insert {
+            self.inserted(hash);
         }
+        Ok(ok)
     }
 
-    pub fn update_lock_ifeq(&self, key: u64, new: u64, old: u64)
-        -> Option<BucketGuard> {
+    fn remove_map_if(&self, key: &KeyType, m: Matcher,
+                     f: &mut FnMut(Option<IndexEntry>) -> bool) -> bool {
+        let hash = common::hash_key(key);
+        let ok = self.table(hash).del_map_if(hash, m, |e| f(e));
+        if ok {
+            self.removed(hash);
+        }
+        ok
+    }
 
-        debug_assert!(key > 0);
-        let tidx = self.table_idx(key);
-        debug_assert!(tidx < self.tables.len());
-        let ref p = self.tables[tidx];
-        debug_assert!(!p.0 .is_null());
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            ht.update_lock_ifeq(key, new, old)
+    #[inline(always)]
+    fn lock_map_ifex(&self, key: &KeyType, m: Matcher,
+                     f: &mut FnMut(IndexEntry)) -> bool {
+        let hash = common::hash_key(key);
+        self.table(hash).lock_map_ifex(hash, m, |e| f(e))
+    }
+
+    fn update_batch(&self, keys: &[&KeyType], news: &[Option<IndexEntry>],
+                    m: &Fn(usize, IndexEntry) -> bool,
+                    f: &mut FnMut(&[Option<IndexEntry>]) -> bool)
+        -> Result<bool,ErrorCode> {
+        debug_assert_eq!(keys.len(), news.len());
+        let hashes: Vec<KeyHash> = keys.iter()
+            .map(|k| common::hash_key(k)).collect();
+        let mut olds: Vec<Option<IndexEntry>> = vec![None; keys.len()];
+        let mut slots: Vec<Option<usize>> = vec![None; keys.len()];
+        'retry: loop {
+            // (table, bucket, table version, key) in locking order
+            let mut order: Vec<(usize,usize,u64,usize)> = hashes.iter()
+                .enumerate().map(|(i,&hash)| {
+                    let (tver,bidx) = self.table(hash).bucket_index(hash);
+                    (self.table_idx(hash), bidx, tver, i)
+                }).collect();
+            order.sort();
+
+            // lock each bucket once, and find the keys within
+            let mut guards: Vec<BucketGuard> = Vec::new();
+            let mut held: Vec<usize> = Vec::with_capacity(order.len());
+            let mut last: Option<(usize,usize)> = None;
+            let mut inserts = 0usize;
+            // hash of a key whose bucket has no room for it
+            let mut full: Option<KeyHash> = None;
+            for &(tidx, bidx, tver, i) in &order {
+                if last != Some((tidx,bidx)) {
+                    match self.table(hashes[i]).lock_index(tver, bidx) {
+                        // resized; bucket indexes are stale
+                        None => continue 'retry,
+                        Some(guard) => guards.push(guard),
+                    }
+                    last = Some((tidx,bidx));
+                    inserts = 0;
+                }
+                held.push(guards.len() - 1);
+                let guard = &guards[guards.len() - 1];
+                slots[i] = guard.find(hashes[i], &|e| m(i, e));
+                olds[i] = slots[i].map(|s| guard.value(s));
+                if slots[i].is_none() && news[i].is_some() {
+                    inserts += 1;
+                    if inserts > guard.free_slots() {
+                        full = Some(hashes[i]);
+                        break;
+                    }
+                }
+            }
+            if let Some(hash) = full {
+                drop(guards);
+                self.table(hash).grow()?;
+                continue 'retry;
+            }
+
+            if !f(&olds) {
+                return Ok(false);
+            }
+            for (&(_, _, _, i), &g) in order.iter().zip(&held) {
+                let guard = &guards[g];
+                match (slots[i], news[i]) {
+                    (Some(s), Some(new)) => guard.set_value(s, new),
+                    (Some(s), None) => {
+                        guard.delete(s);
+                        self.removed(hashes[i]);
+                    },
+                    (None, Some(new)) => {
+                        let ok = guard.insert(hashes[i], new);
+                        debug_assert!(ok);
+                        self.inserted(hashes[i]);
+                    },
+                    (None, None) => {},
+                }
+            }
+            // buckets are unlocked as the guards drop
+            return Ok(true);
         }
     }
 
-    /// Lock the bucket which would hold the key and execute the given
-    /// lambda while holding the lock
     #[inline(always)]
-    pub fn update_map<F>(&self, key: u64, new: u64, f: F) -> bool
-        where F: Fn(Option<u64>) {
+    fn prefetch(&self, key: &KeyType, write: bool) {
+        let hash = common::hash_key(key);
+        let table = self.table(hash);
+        if write {
+            table.prefetchw(hash);
+        } else {
+            table.prefetch(hash);
+        }
+    }
 
-        let tidx = self.table_idx(key);
-        let ref p = self.tables[tidx];
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            ht.update_map(key, new, f)
+    fn for_each(&self, f: &mut FnMut(IndexEntry, &Fn(&KeyType) -> bool)) {
+        for p in &self.tables {
+            let table = unsafe { &* p.0 };
+            table.for_each(|hash, entry| {
+                f(entry, &|key| common::hash_key(key) == hash)
+            });
+        }
+    }
+
+    fn len(&self) -> usize {
+        // a removal may be counted before the insertion it follows,
+        // leaving a count transiently below zero
+        let n: isize = self.live.iter()
+            .map(|l| l.n.load(Ordering::Relaxed) as isize).sum();
+        cmp::max(n, 0) as usize
+    }
+
+    fn kind(&self) -> IndexKind { IndexKind::Hash }
+
+    fn memory(&self) -> Vec<MemMode> {
+        self.tables.iter()
+            .map(|p| unsafe { &* p.0 }.mem_mode())
+            .collect()
+    }
+}
+
+impl Drop for HashIndex {
+
+    fn drop(&mut self) {
+        // tables were leaked from Boxes in new()
+        for p in self.tables.drain(..) {
+            unsafe { drop(Box::from_raw(p.0 as *mut HashTable)); }
         }
     }
+}
+
+//==----------------------------------------------------==//
+//      Ordered index
+//==----------------------------------------------------==//
+
+/// Number of partitions in the ordered index; a power of two.
+const ORDERED_PARTS: usize = 256;
+
+/// Entries copied out of a partition at a time by for_each.
+const FOR_EACH_BATCH: usize = 1024;
+
+type OrderedPart = pl::RwLock<BTreeMap<Vec<u8>,IndexEntry>>;
+
+/// Index keeping full keys in lexicographic byte order. Keys are
+/// spread over the partitions by their hash, and each partition is an
+/// ordered tree behind a reader-writer lock, so operations contend
+/// only on keys sharing a partition however alike the keys are (e.g.
+/// all under one prefix). A scan merges the ranges of all partitions,
+/// locking one partition at a time, so it is not a snapshot.
+pub struct OrderedIndex {
+    parts: Vec<OrderedPart>,
+}
+
+impl OrderedIndex {
+
+    pub fn new() -> Self {
+        let mut parts: Vec<OrderedPart>;
+        parts = Vec::with_capacity(ORDERED_PARTS);
+        for _ in 0..ORDERED_PARTS {
+            parts.push(pl::RwLock::new(BTreeMap::new()));
+        }
+        OrderedIndex { parts: parts }
+    }
 
     #[inline(always)]
-    pub fn lock_map_ifex<F>(&self, key: u64, f: F) -> bool
-        where F: Fn(u64) {
+    fn part_idx(key: &KeyType) -> usize {
+        // as HashIndex::table_idx
+        let hash = common::fnv1a(common::hash_key(key));
+        ((hash >> 29) as usize) & (ORDERED_PARTS - 1)
+    }
 
-        let tidx = self.table_idx(key);
-        let ref p = self.tables[tidx];
-        unsafe {
-            let ht: &HashTable = &* p.0;
-            ht.lock_map_ifex(key, f)
+    #[inline(always)]
+    fn part(&self, key: &KeyType) -> &OrderedPart {
+        &self.parts[Self::part_idx(key)]
+    }
+}
+
+/// Position of a scan within one partition of the ordered index.
+/// Keys are copied out in chunks, each twice the last, so a short
+/// scan copies little from each partition and a long one locks each
+/// partition only a few times.
+struct ScanCursor {
+    buf: VecDeque<(Vec<u8>,IndexEntry)>,
+    /// last key copied out, if any
+    last: Option<Vec<u8>>,
+    chunk: usize,
+    /// whether the partition has no more keys in range
+    done: bool,
+}
+
+impl ScanCursor {
+
+    fn new() -> Self {
+        ScanCursor {
+            buf: VecDeque::new(),
+            last: None,
+            chunk: 1,
+            done: false,
         }
     }
 
-    pub fn len(&self) -> usize {
-        unimplemented!();
+    /// Next key in part from start up to hi, copying out no more than
+    /// max (the most the scan may still want) if the buffer is empty.
+    fn next(&mut self, part: &OrderedPart, start: &KeyType,
+            hi: Bound<&[u8]>, max: usize)
+        -> Option<(Vec<u8>,IndexEntry)> {
+        if self.buf.is_empty() && !self.done && max > 0 {
+            let want = cmp::min(self.chunk, max);
+            {
+                let part = part.read();
+                let lo: Bound<&[u8]> = match self.last {
+                    None => Bound::Included(start),
+                    Some(ref k) => Bound::Excluded(&k[..]),
+                };
+                let range = part.range::<[u8],_>((lo,hi));
+                for (k,v) in range.take(want) {
+                    self.buf.push_back( (k.clone(), *v) );
+                }
+            }
+            self.done = self.buf.len() < want;
+            self.last = self.buf.back().map(|&(ref k,_)| k.clone());
+            self.chunk = cmp::min(self.chunk * 2, FOR_EACH_BATCH);
+        }
+        self.buf.pop_front()
     }
+}
 
-    //
-    // Priate methods
-    //
+impl Index for OrderedIndex {
 
-    /// See comment for HashTable::index()
     #[inline(always)]
-    fn table_idx(&self, key: u64) -> usize {
-        let hash = common::fnv1a(key);
-        ((hash >> 29) & (self.tables.len() as u64 - 1)) as usize
+    #[allow(unused_variables)]
+    fn get(&self, key: &KeyType, m: Matcher) -> Option<IndexEntry> {
+        self.part(key).read().get(key).map(|e| *e)
+    }
+
+    #[allow(unused_variables)]
+    fn update(&self, key: &KeyType, value: IndexEntry, m: Matcher)
+        -> (bool,Option<IndexEntry>) {
+        (true, self.part(key).write().insert(key.to_vec(), value))
+    }
+
+    #[allow(unused_variables)]
+    fn remove(&self, key: &KeyType, m: Matcher) -> Option<IndexEntry> {
+        self.part(key).write().remove(key)
+    }
+
+    #[allow(unused_variables)]
+    fn remove_map(&self, key: &KeyType, m: Matcher,
+                  f: &mut FnMut(Option<IndexEntry>)) -> bool {
+        let mut part = self.part(key).write();
+        let old = part.remove(key);
+        f(old);
+        old.is_some()
+    }
+
+    fn update_ifeq(&self, key: &KeyType, new: IndexEntry,
+                   old: IndexEntry) -> bool {
+        let mut part = self.part(key).write();
+        match part.get_mut(key) {
+            Some(e) if *e == old => { *e = new; true },
+            _ => false,
+        }
+    }
+
+    fn update_ifeq_if(&self, key: &KeyType, new: IndexEntry,
+                      old: IndexEntry, f: &mut FnMut() -> bool) -> bool {
+        let mut part = self.part(key).write();
+        match part.get_mut(key) {
+            Some(e) if *e == old && f() => { *e = new; true },
+            _ => false,
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn update_map(&self, key: &KeyType, new: IndexEntry, m: Matcher,
+                  f: &mut FnMut(Option<IndexEntry>)) -> bool {
+        let mut part = self.part(key).write();
+        let old = part.insert(key.to_vec(), new);
+        f(old);
+        true
+    }
+
+    #[allow(unused_variables)]
+    fn update_map_if(&self, key: &KeyType, new: IndexEntry, m: Matcher,
+                     f: &mut FnMut(Option<IndexEntry>) -> bool)
+        -> Result<bool,ErrorCode> {
+        let mut part = self.part(key).write();
+        let old = part.get(key).map(|e| *e);
+        if !f(old) {
+            return Ok(false);
+        }
+        part.insert(key.to_vec(), new);
+        Ok(true)
+    }
+
+    #[allow(unused_variables)]
+    fn remove_map_if(&self, key: &KeyType, m: Matcher,
+                     f: &mut FnMut(Option<IndexEntry>) -> bool) -> bool {
+        let mut part = self.part(key).write();
+        let old = part.get(key).map(|e| *e);
+        if !f(old) || old.is_none() {
+            return false;
+        }
+        part.remove(key);
+        true
+    }
+
+    #[allow(unused_variables)]
+    fn lock_map_ifex(&self, key: &KeyType, m: Matcher,
+                     f: &mut FnMut(IndexEntry)) -> bool {
+        let part = self.part(key).write();
+        match part.get(key) {
+            Some(e) => { f(*e); true },
+            None => false,
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn update_batch(&self, keys: &[&KeyType], news: &[Option<IndexEntry>],
+                    m: &Fn(usize, IndexEntry) -> bool,
+                    f: &mut FnMut(&[Option<IndexEntry>]) -> bool)
+        -> Result<bool,ErrorCode> {
+        debug_assert_eq!(keys.len(), news.len());
+        // lock each partition involved once, in order
+        let mut idxs: Vec<usize> = keys.iter()
+            .map(|k| Self::part_idx(k)).collect();
+        idxs.sort();
+        idxs.dedup();
+        let mut parts: Vec<_> = idxs.iter()
+            .map(|&i| self.parts[i].write()).collect();
+        let part_of = |key: &KeyType| {
+            idxs.binary_search(&Self::part_idx(key)).unwrap()
+        };
+
+        let olds: Vec<Option<IndexEntry>> = keys.iter()
+            .map(|k| parts[part_of(k)].get(*k).map(|e| *e)).collect();
+        if !f(&olds) {
+            return Ok(false);
+        }
+        for (k,new) in keys.iter().zip(news) {
+            let part = &mut parts[part_of(k)];
+            match *new {
+                Some(e) => { part.insert(k.to_vec(), e); },
+                None => { part.remove(*k); },
+            }
+        }
+        Ok(true)
     }
+
+    fn scan(&self, start: &KeyType, end: Option<&KeyType>, max: usize,
+            out: &mut Vec<(Vec<u8>,IndexEntry)>)
+        -> Result<usize,ErrorCode> {
+
+        if let Some(e) = end {
+            if e <= start {
+                return Ok(0);
+            }
+        }
+        let hi: Bound<&[u8]> = match end {
+            None => Bound::Unbounded,
+            Some(e) => Bound::Excluded(e),
+        };
+        // merge the partitions' ranges, smallest key first, through a
+        // cursor on each; a cursor copies out its next keys with only
+        // its own partition read-locked
+        let mut cursors: Vec<ScanCursor> = (0..ORDERED_PARTS)
+            .map(|_| ScanCursor::new()).collect();
+        let mut heap: BinaryHeap<Reverse<(Vec<u8>,usize,IndexEntry)>>;
+        heap = BinaryHeap::with_capacity(ORDERED_PARTS);
+        for (i,c) in cursors.iter_mut().enumerate() {
+            if let Some((k,v)) = c.next(&self.parts[i], start, hi, max) {
+                heap.push(Reverse((k, i, v)));
+            }
+        }
+        let mut n = 0usize;
+        while n < max {
+            let (k,i,v) = match heap.pop() {
+                None => break,
+                Some(Reverse(next)) => next,
+            };
+            out.push( (k, v) );
+            n += 1;
+            let next = cursors[i].next(&self.parts[i], start, hi, max - n);
+            if let Some((k,v)) = next {
+                heap.push(Reverse((k, i, v)));
+            }
+        }
+        Ok(n)
+    }
+
+    fn for_each(&self, f: &mut FnMut(IndexEntry, &Fn(&KeyType) -> bool)) {
+        // copy out a batch at a time, so f runs without the partition
+        // locked
+        let mut batch: Vec<(Vec<u8>,IndexEntry)> =
+            Vec::with_capacity(FOR_EACH_BATCH);
+        for part in &self.parts {
+            let mut last: Option<Vec<u8>> = None;
+            loop {
+                batch.clear();
+                meta::pin();
+                {
+                    let part = part.read();
+                    let lo: Bound<&[u8]> = match last {
+                        None => Bound::Unbounded,
+                        Some(ref k) => Bound::Excluded(&k[..]),
+                    };
+                    let range = part.range::<[u8],_>((lo,Bound::Unbounded));
+                    for (k,v) in range.take(FOR_EACH_BATCH) {
+                        batch.push( (k.clone(), *v) );
+                    }
+                }
+                for &(ref k, v) in &batch {
+                    f(v, &|key| key == &k[..]);
+                }
+                if batch.len() < FOR_EACH_BATCH {
+                    break;
+                }
+                last = batch.pop().map(|(k,_)| k);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.parts.iter().map(|p| p.read().len()).sum()
+    }
+
+    fn kind(&self) -> IndexKind { IndexKind::Ordered }
 }
 
 //==----------------------------------------------------==//
@@ -239,6 +836,7 @@ impl Index {
 mod tests {
     use super::*;
     use super::super::logger;
+    use common::u64_key;
     use rand::{self,Rng};
     use std::sync::atomic::{AtomicUsize,Ordering};
     use crossbeam;
@@ -250,7 +848,7 @@ mod tests {
         let keys_per = nkeys/nthreads;
         let cap = nkeys*10;
 
-        let index = Index::new(ntables, cap);
+        let index = HashIndex::new(ntables, cap).unwrap();
 
         let mut guards = vec![];
         let tids = AtomicUsize::new(0);
@@ -275,33 +873,36 @@ mod tests {
                     let niter = 1024/ntables;
                     for _ in 0..niter {
 
+                        // keys are unique; no need to resolve collisions
+                        let any: Matcher = &|_| true;
+
                         for k in start..end {
-                            assert_eq!(index.get(k), None);
+                            assert_eq!(index.get(u64_key(&k), any), None);
                         }
 
                         for k in start..end {
-                            let (ok,opt) = index.update(k, value);
+                            let (ok,opt) = index.update(u64_key(&k), value, any);
                             assert_eq!(ok, true);
                             assert_eq!(opt, None);
-                            assert_eq!(index.get(k), Some(value));
+                            assert_eq!(index.get(u64_key(&k), any), Some(value));
                         }
 
                         for k in start..end {
-                            let (ok,opt) = index.update(k, 0xffff);
+                            let (ok,opt) = index.update(u64_key(&k), 0xffff, any);
                             assert_eq!(ok, true);
                             assert_eq!(opt, Some(value));
                         }
 
                         for k in start..end {
-                            let (ok,opt) = index.update(k, value);
+                            let (ok,opt) = index.update(u64_key(&k), value, any);
                             assert_eq!(ok, true);
                             assert_eq!(opt, Some(0xffff));
                         }
 
                         for k in start..end {
-                            assert_eq!(index.remove(k), Some(value));
-                            assert_eq!(index.get(k), None);
-                            assert_eq!(index.remove(k), None);
+                            assert_eq!(index.remove(u64_key(&k), any), Some(value));
+                            assert_eq!(index.get(u64_key(&k), any), None);
+                            assert_eq!(index.remove(u64_key(&k), any), None);
                         }
                     }
 
@@ -335,4 +936,195 @@ mod tests {
     fn multiple_many() {
         base(64,12);
     }
+
+    #[test]
+    fn ordered_scan() {
+        let index = OrderedIndex::new();
+        let any: Matcher = &|_| true;
+        let keys: Vec<&[u8]> = vec![&b"b"[..], &b"a"[..], &b"ab"[..],
+                                    &b"\xff\x01"[..], &b"abc"[..]];
+        for (k,v) in keys.iter().zip(0u64..) {
+            assert_eq!(index.update(k, v, any), (true,None));
+        }
+        assert_eq!(index.get(b"ab", any), Some(2));
+
+        let mut out = vec![];
+        assert_eq!(index.scan(b"", None, 100, &mut out).ok(), Some(5));
+        let found: Vec<&[u8]> = out.iter().map(|t| &t.0[..]).collect();
+        assert_eq!(found, vec![&b"a"[..], &b"ab"[..], &b"abc"[..],
+                               &b"b"[..], &b"\xff\x01"[..]]);
+
+        out.clear();
+        assert_eq!(index.scan(b"ab", Some(&b"b"[..]), 100, &mut out).ok(), Some(2));
+        out.clear();
+        assert_eq!(index.scan(b"a", None, 2, &mut out).ok(), Some(2));
+        assert_eq!(out[1].0, b"ab".to_vec());
+
+        assert_eq!(index.remove(b"ab", any), Some(2));
+        assert_eq!(index.get(b"ab", any), None);
+
+        // an empty or reversed range
+        out.clear();
+        assert_eq!(index.scan(b"b", Some(&b"a"[..]), 100, &mut out).ok(),
+                   Some(0));
+        assert_eq!(index.scan(b"b", Some(&b"b"[..]), 100, &mut out).ok(),
+                   Some(0));
+    }
+
+    /// keys under one prefix are spread over the partitions, and a
+    /// scan still yields them in order
+    #[test]
+    fn ordered_partitions() {
+        let index = OrderedIndex::new();
+        let any: Matcher = &|_| true;
+        let mut keys: Vec<Vec<u8>> = (0..4096u64)
+            .map(|i| format!("user:{:08}", i).into_bytes()).collect();
+        rand::thread_rng().shuffle(&mut keys);
+        for (i,k) in keys.iter().enumerate() {
+            assert_eq!(index.update(k, i as u64, any), (true,None));
+        }
+        let used = index.parts.iter()
+            .filter(|p| !p.read().is_empty()).count();
+        assert!(used > ORDERED_PARTS / 2);
+        keys.sort();
+
+        // page through in batches
+        let mut out = vec![];
+        let mut from: Vec<u8> = b"user:".to_vec();
+        loop {
+            let n = index.scan(&from, Some(&b"user;"[..]), 1000, &mut out)
+                .unwrap();
+            if n < 1000 {
+                break;
+            }
+            from = out[out.len() - 1].0.clone();
+            from.push(0u8);
+        }
+        let found: Vec<Vec<u8>> = out.into_iter().map(|t| t.0).collect();
+        assert_eq!(found, keys);
+    }
+
+    /// update_ifeq replaces only the expected entry, and
+    /// update_ifeq_if only when its predicate agrees
+    #[test]
+    fn update_ifeq() {
+        let any: Matcher = &|_| true;
+        let ntables = numa::NODE_MAP.sockets();
+        let hash = HashIndex::with_pages(ntables, 1usize << 10, false)
+            .unwrap();
+        let ordered = OrderedIndex::new();
+        let indexes: Vec<&Index> = vec![&hash, &ordered];
+        for index in indexes {
+            assert!(!index.update_ifeq(b"key", 2, 1));
+            assert_eq!(index.update(b"key", 1, any), (true,None));
+            assert!(!index.update_ifeq(b"key", 3, 2));
+            assert_eq!(index.get(b"key", any), Some(1));
+            assert!(index.update_ifeq(b"key", 2, 1));
+            assert_eq!(index.get(b"key", any), Some(2));
+            assert!(!index.update_ifeq_if(b"key", 3, 2, &mut || false));
+            assert!(!index.update_ifeq_if(b"key", 3, 1, &mut || true));
+            assert_eq!(index.get(b"key", any), Some(2));
+            assert!(index.update_ifeq_if(b"key", 3, 2, &mut || true));
+            assert_eq!(index.get(b"key", any), Some(3));
+        }
+    }
+
+    /// len follows insertions and removals by every path
+    #[test]
+    fn len() {
+        let any: Matcher = &|_| true;
+        let none: &Fn(usize, IndexEntry) -> bool = &|_,_| true;
+        let ntables = numa::NODE_MAP.sockets();
+        let hash = HashIndex::with_pages(ntables, 1usize << 10, false)
+            .unwrap();
+        let ordered = OrderedIndex::new();
+        let indexes: Vec<&Index> = vec![&hash, &ordered];
+        for index in indexes {
+            assert_eq!(index.len(), 0);
+            for k in 0..100u64 {
+                index.update(u64_key(&k), k, any);
+            }
+            index.update(u64_key(&0), 1, any);
+            assert_eq!(index.len(), 100);
+            assert!(index.update_map(b"a", 1, any, &mut |_| {}));
+            assert_eq!(index.update_map_if(b"b", 1, any,
+                                           &mut |_| false).ok(),
+                       Some(false));
+            assert_eq!(index.update_map_if(b"b", 1, any,
+                                           &mut |_| true).ok(),
+                       Some(true));
+            assert_eq!(index.len(), 102);
+            assert!(index.remove_map(b"a", any, &mut |_| {}));
+            assert!(!index.remove_map_if(b"b", any, &mut |_| false));
+            assert!(index.remove_map_if(b"b", any, &mut |_| true));
+            assert_eq!(index.remove(u64_key(&0), any), Some(1));
+            assert_eq!(index.remove(u64_key(&0), any), None);
+            assert_eq!(index.len(), 99);
+            let keys: Vec<&KeyType> = vec![u64_key(&1), b"c"];
+            assert_eq!(index.update_batch(&keys, &[None, Some(1)], none,
+                                          &mut |_| true).ok(),
+                       Some(true));
+            assert_eq!(index.len(), 99);
+        }
+    }
+
+    /// a scan proceeds while other partitions are written, and sees
+    /// every key that was present throughout
+    #[test]
+    fn ordered_scan_concurrent() {
+        let index = OrderedIndex::new();
+        let any: Matcher = &|_| true;
+        let keys: Vec<Vec<u8>> = (0..4096u64)
+            .map(|i| format!("a{:08}", i).into_bytes()).collect();
+        for k in &keys {
+            index.update(k, 1, any);
+        }
+        let stop = AtomicUsize::new(0);
+        crossbeam::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let any: Matcher = &|_| true;
+                let mut i = 0u64;
+                while stop.load(Ordering::Relaxed) == 0 {
+                    let k = format!("b{:08}", i % 4096).into_bytes();
+                    index.update(&k, i, any);
+                    if i % 2 == 1 {
+                        index.remove(&k, any);
+                    }
+                    i += 1;
+                }
+            });
+            for _ in 0..8 {
+                let mut out = vec![];
+                index.scan(b"", None, usize::max_value(), &mut out)
+                    .unwrap();
+                assert!(out.windows(2).all(|w| w[0].0 < w[1].0));
+                let found: Vec<Vec<u8>> = out.into_iter()
+                    .map(|t| t.0).filter(|k| k[0] == b'a').collect();
+                assert_eq!(found, keys);
+            }
+            stop.store(1, Ordering::Relaxed);
+            writer.join();
+        });
+    }
+
+    /// keys whose hashes collide are told apart only by the matcher;
+    /// here the same bytes stand for two keys, entries of one being
+    /// odd and of the other even
+    #[test]
+    fn hash_collisions() {
+        let ntables = numa::NODE_MAP.sockets();
+        let index = HashIndex::with_pages(ntables, 1usize << 10, false)
+            .unwrap();
+        let a: Matcher = &|e| e % 2 == 1;
+        let b: Matcher = &|e| e % 2 == 0;
+        assert_eq!(index.update(b"key", 1, a), (true,None));
+        assert_eq!(index.update(b"key", 2, b), (true,None));
+        assert_eq!(index.get(b"key", a), Some(1));
+        assert_eq!(index.get(b"key", b), Some(2));
+        assert_eq!(index.update(b"key", 3, a), (true,Some(1)));
+        assert_eq!(index.get(b"key", b), Some(2));
+        assert_eq!(index.remove(b"key", b), Some(2));
+        assert_eq!(index.get(b"key", b), None);
+        assert_eq!(index.get(b"key", a), Some(3));
+    }
 }
@@ -24,27 +24,36 @@ use segment::*;
 use index::*;
 use compaction::*;
 use numa::{self,NodeId};
-use meta;
+use meta::{self,NsId,NsInfo,DEFAULT_NS};
+use clock;
+use wal::{self,Wal,WalRef,Record,Update};
+use snapshot;
+use scrub::{Scrubber,ScrubReport};
+use sweep::Sweeper;
+use config::LSMConfig;
 
-use std::process;
 use std::sync::Arc;
 use std::thread::{self,JoinHandle};
+use std::panic;
 use parking_lot as pl;
 use std::mem;
+use std::cmp;
+use std::slice;
+use std::usize;
+use std::time::Duration;
+use std::path::Path;
+use std::collections::{HashMap,HashSet};
 
 //==----------------------------------------------------==//
 //      Constants
 //==----------------------------------------------------==//
 
-const MIN_SEG_PER_SOCKET: usize = 4;
+/// Number of index entries a scan collects at a time.
+const SCAN_BATCH: usize = 64;
 
-macro_rules! min_log_size {
-    ( $nsockets:expr ) => {
-        (num_log_heads() * MIN_SEG_PER_SOCKET)
-            * SEGMENT_SIZE * $nsockets
-            + RESERVE_SEGS
-    }
-}
+/// Times a walk over live objects looks for segments created while
+/// it ran. See LSM::for_each_live
+const LIVE_PASSES: usize = 4;
 
 //==----------------------------------------------------==//
 //      LSM interface
@@ -73,6 +82,8 @@ pub struct LSMPerNode {
     log: Log,
     seginfo: meta::SegmentInfoTableRef,
     compactor: CompactorRef, // TODO move to segmgr instead?
+    scrubber: pl::Mutex<Scrubber>,
+    sweeper: pl::Mutex<Sweeper>,
 }
 
 pub struct LSM {
@@ -81,12 +92,78 @@ pub struct LSM {
     nnodes: u32,
     index: IndexRef,
     capacity: usize,
+    /// For each socket, all sockets ordered by NUMA distance
+    nearest: Vec<Vec<usize>>,
+    /// Write-ahead log; only present if opened as durable
+    wal: Option<WalRef>,
+    config: LSMConfig,
+    /// Shared with the segment manager of each socket
+    namespaces: meta::NamespaceTableRef,
+}
+
+/// Summary of the resources behind an instance.
+#[derive(Clone,Debug)]
+pub struct LSMStats {
+    pub capacity: usize,
+    /// Free log memory on each socket
+    pub free: Vec<usize>,
+    /// How log memory on each socket was obtained; huge pages and
+    /// socket binding fall back when the system lacks them.
+    pub log_memory: Vec<MemMode>,
+    /// As log_memory, for each mapping held by the index (none for
+    /// indexes kept on the heap).
+    pub index_memory: Vec<MemMode>,
+    /// Objects evicted on each socket (see LSMConfig::eviction_ratio)
+    pub evicted: Vec<usize>,
 }
 
 #[derive(Copy,Clone,Debug)]
 pub enum PutPolicy {
     Specific(usize),
     Interleave,
+    /// Socket of the calling thread, else the next closest with
+    /// available memory.
+    Nearest,
+    /// Write the new value over the object being replaced, if their
+    /// lengths are equal. Else append it to the socket of that
+    /// object, or the next closest to it with available memory; new
+    /// objects are placed as by Nearest. Keeps an object where it was
+    /// placed.
+    Overwrite,
+}
+
+/// Condition under which a PUT or DEL takes effect, checked while
+/// the index holds the key's lock.
+#[derive(Copy,Clone,Debug)]
+enum Cond {
+    Always,
+    Absent,
+    Version(Version),
+}
+
+impl Cond {
+    /// found is the version of the current object, if any.
+    fn check(&self, found: Option<Version>) -> Result<(),ErrorCode> {
+        match (*self, found) {
+            (Cond::Always, _) | (Cond::Absent, None) => Ok(()),
+            (Cond::Absent, Some(_)) => Err(ErrorCode::KeyExists),
+            (Cond::Version(_), None) => Err(ErrorCode::KeyNotExist),
+            (Cond::Version(v), Some(f)) => if v == f { Ok(()) } else {
+                Err(ErrorCode::VersionMismatch { expected: v, found: f })
+            },
+        }
+    }
+}
+
+/// What LSM::edit did while the key was locked.
+enum Edited {
+    /// The edit was written over the value. value holds a copy of
+    /// the result, to log, if there is a write-ahead log.
+    InPlace { socket: usize, seq: u64, len: usize,
+              value: Option<Vec<u8>>, version: Version, expires: Expiry },
+    /// The value would change length, so the edit was made in a copy,
+    /// to put if the object is still at version.
+    Copy { value: Vec<u8>, version: Version, expires: Expiry },
 }
 
 impl LSM {
@@ -99,17 +176,29 @@ impl LSM {
 
     /// Create new instance of LSM. It partitions itself across the
     /// sockets. You must create an instance with at least enough
-    /// memory per-socket to hold some minimum of segments.
-    pub fn new(capacity: usize) -> Self {
-        Self::__new(capacity, Self::default_ht_nitems() )
+    /// memory per-socket to hold some minimum of segments. See
+    /// with_config for the errors.
+    pub fn new(capacity: usize) -> Result<Self,ErrorCode> {
+        Self::with_config(LSMConfig::default().capacity(capacity))
+    }
+
+    pub fn new2(capacity: usize, ht_nitems: usize)
+        -> Result<Self,ErrorCode> {
+        Self::with_config(LSMConfig::default()
+                          .capacity(capacity).index_items(ht_nitems))
     }
 
-    pub fn new2(capacity: usize, ht_nitems: usize) -> Self {
-        Self::__new(capacity, ht_nitems)
+    /// Create an instance using the given kind of index. Only an
+    /// ordered index supports scan and scan_prefix. ht_nitems is
+    /// ignored for indexes other than the hash index.
+    pub fn with_index(capacity: usize, ht_nitems: usize,
+                      kind: IndexKind) -> Result<Self,ErrorCode> {
+        Self::with_config(LSMConfig::default().capacity(capacity)
+                          .index_items(ht_nitems).index(kind))
     }
 
     /// Allocate LSM with a default (small) amount of memory.
-    pub fn default() -> Self {
+    pub fn default() -> Result<Self,ErrorCode> {
         Self::new2(
             Self::default_capacity(),
             Self::default_ht_nitems() )
@@ -119,40 +208,47 @@ impl LSM {
     /// was compiled with appropriate fallback implementation.
     fn __check_rdrand() -> bool {
         if !kvs_rdrand_compile_flags() {
-            println!(">> Oops: your CPU probably doesn't support 'rdrand'.");
-            println!(">> Please recompile LSM with --nordrand");
-            println!("");
+            error!("CPU does not support 'rdrand'; \
+                    build without the rdrand feature");
             false
         } else {
             true
         }
     }
 
-    fn __new(capacity: usize, ht_nitems: usize) -> Self {
+    /// Create an instance tuned by the given configuration. Fails
+    /// with InvalidConfig if it does not validate, or asks for a
+    /// block size other than the one already used in this process,
+    /// with NoRdrand if built for an instruction the CPU lacks, and
+    /// with OutOfMemory if the log or index cannot be mapped.
+    pub fn with_config(config: LSMConfig) -> Result<Self,ErrorCode> {
         if !LSM::__check_rdrand() {
-            println!("Cannot initialize LSM.");
-            println!("Please verify above messages.");
-            process::exit(1);
+            return Err(ErrorCode::NoRdrand);
         }
 
+        config.validate()?;
+        set_block_shift(config.block_shift())?;
+
         let nnodes = numa::NODE_MAP.sockets();
-        let mincap = min_log_size!(nnodes);
+        let capacity = config.get_capacity();
         let persock = capacity/nnodes;
 
-        assert!(capacity >= mincap,
-                "LSM requires more memory: {} GiB",
-                mincap / (1usize<<30));
-
         //let ntables = numa::NODE_MAP.ncpus();
         //let ntables: usize = 64;
 
         let nsock = numa::NODE_MAP.sockets();
         let ntables: usize = 8 * nsock;
-        let nitems = ht_nitems; //1usize << 30;
+        let nitems = config.get_index_items();
         let n_per  = nitems / ntables;
-
-        assert!(ntables.is_power_of_two(),
-            "ntables is not power of two: {:?}", ntables);
+        let kind = config.get_index();
+        let ssz = config.get_segment_size();
+        let nresv = config.get_reserve_segments();
+
+        if !ntables.is_power_of_two() {
+            error!("{} sockets cannot share {} index tables evenly",
+                   nsock, ntables);
+            return Err(ErrorCode::InvalidConfig);
+        }
 
         info!("    sockets:     {}", nnodes);
         info!("   capacity:     {:.2} GiB",
@@ -160,74 +256,119 @@ impl LSM {
         info!("   cap/sock:     {:.2} GiB",
               (persock as f64)/(2f64.powi(30)));
 
+        info!("      index:     {:?}", kind);
         info!("    index n:     {}", nitems);
         info!("    #tables:     {}", ntables);
 
-        info!("   seg size:     {}", SEGMENT_SIZE);
-        info!(" block size:     {}", BLOCK_SIZE);
-        info!("  block/seg:     {}", BLOCKS_PER_SEG);
-        info!("   #blk var:     {}", ALLOC_NBLKS_VAR);
-
-        info!(" resrv segs:     {} x{}", RESERVE_SEGS, nsock);
+        info!("   seg size:     {}", ssz);
+        info!(" block size:     {}", block_size());
+        info!("  block/seg:     {}", config.blocks_per_seg());
+        info!("   #blk var:     {}",
+              config.blocks_per_seg() >> ALLOC_NBLKS_VAR_SHIFT);
+        info!("  log heads:     {}", config.get_log_heads());
+        info!("   #workers:     {}", config.get_compaction_workers());
+        info!(" comp ratio:     {}", config.get_compaction_ratio());
+        info!("  hugepages:     {}", config.get_hugepages());
+
+        info!(" resrv segs:     {} x{}", nresv, nsock);
         info!(" resrv size:     {:.2} GiB x{}",
-              (RESERVE_SEGS * SEGMENT_SIZE) as f64 /
+              (nresv * ssz) as f64 /
                 (2f64.powi(30)), nsock);
         info!(" resrv as %:     {:.2}",
-              100f64 * (nsock * RESERVE_SEGS * SEGMENT_SIZE) as f64 /
+              100f64 * (nsock * nresv * ssz) as f64 /
               (capacity as f64));
 
-        let index = Arc::new(Index::new(ntables, n_per));
+        let index: IndexRef = match kind {
+            IndexKind::Hash =>
+                Arc::new(HashIndex::with_pages(ntables, n_per,
+                                               config.get_hugepages())?),
+            IndexKind::Ordered =>
+                Arc::new(OrderedIndex::new()),
+        };
+
+        let namespaces = Arc::new(meta::NamespaceTable::new());
 
         // Create all per-socket elements with threads.
-        let nodes: Arc<pl::Mutex<Vec<LSMPerNode>>>;
-        nodes = Arc::new(pl::Mutex::new(Vec::with_capacity(nnodes)));
-        {
-            let mut handles: Vec<JoinHandle<()>> = Vec::new();
-            for node in 0..nnodes {
-                let i = index.clone();
-                let nodes = nodes.clone();
-                let n = NodeId(node);
-                handles.push( thread::spawn( move || {
-                    let s = SEGMENT_SIZE;
-                    let manager = SegmentManager::numa(s,persock,n);
-                    let seginfo = manager.seginfo();
-                    let mref = Arc::new(manager);
-                    let per = LSMPerNode {
-                        socket: node,
-                        manager: mref.clone(),
-                        log: Log::new(mref.clone()),
-                        seginfo: seginfo,
-                        compactor: comp_ref!(&mref, &i),
-                    };
-                    nodes.lock().push(per);
-                }));
-            }
-            for handle in handles {
-                let _ = handle.join();
+        let mut handles: Vec<JoinHandle<Result<LSMPerNode,ErrorCode>>>;
+        handles = Vec::with_capacity(nnodes);
+        for node in 0..nnodes {
+            let i = index.clone();
+            let n = NodeId(node);
+            let config = config.clone();
+            let namespaces = namespaces.clone();
+            handles.push( thread::spawn( move || {
+                let mut manager =
+                    SegmentManager::with_config(persock, n, &config)?;
+                manager.set_namespaces(namespaces);
+                let seginfo = manager.seginfo();
+                let mref = Arc::new(manager);
+                let nheads = config.get_log_heads();
+                let comp = Compactor::with_config(&mref, &i, &config);
+                Ok(LSMPerNode {
+                    socket: node,
+                    manager: mref.clone(),
+                    log: Log::with_heads(mref.clone(), nheads),
+                    seginfo: seginfo,
+                    compactor: Arc::new(pl::Mutex::new(comp)),
+                    scrubber: pl::Mutex::new(Scrubber::new(&mref, &i)),
+                    sweeper: pl::Mutex::new(Sweeper::new(&mref, &i)),
+                })
+            }));
+        }
+        // join all before returning any error, so no thread outlives
+        // us; handles are in socket order
+        let mut nodes: Vec<LSMPerNode> = Vec::with_capacity(nnodes);
+        let mut failed: Option<ErrorCode> = None;
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(per)) => nodes.push(per),
+                Ok(Err(e)) => failed = Some(e),
+                Err(p) => panic::resume_unwind(p),
             }
         }
-        // consume the Arc and Mutex, then put back into order
-        let mut nodes = match Arc::try_unwrap(nodes) {
-            Err(_) => panic!("glug glug glug"),
-            Ok(lock) => lock.into_inner(),
-        };
-        nodes.sort_by( | a, b | {
-            a.socket.cmp(&b.socket)
-        });
-        LSM {
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        let nearest = (0..nnodes).map( |sock| {
+            numa::NODE_MAP.nearest(NodeId(sock))
+                .into_iter().map(|n| n.0).collect()
+        }).collect();
+        Ok(LSM {
             nodes: nodes,
             nnodes: nnodes as u32,
             index: index,
-            capacity: capacity
-        }
+            capacity: capacity,
+            nearest: nearest,
+            wal: None,
+            config: config,
+            namespaces: namespaces,
+        })
     }
 
     pub fn default_capacity() -> usize {
-        min_log_size!( numa::NODE_MAP.sockets() )
+        LSMConfig::default().min_capacity()
     }
 
     pub fn default_ht_nitems() -> usize {
-        1usize << 25
+        LSMConfig::default().get_index_items()
+    }
+
+    pub fn stats(&self) -> LSMStats {
+        LSMStats {
+            capacity: self.capacity,
+            free: self.nodes.iter()
+                .map(|n| n.manager.freesz()).collect(),
+            log_memory: self.nodes.iter()
+                .map(|n| n.manager.mem_mode()).collect(),
+            index_memory: self.index.memory(),
+            evicted: self.nodes.iter()
+                .map(|n| n.compactor.lock().evicted()).collect(),
+        }
+    }
+
+    /// The configuration this instance was created with.
+    pub fn config(&self) -> &LSMConfig {
+        &self.config
     }
 
     pub fn capacity(&self) -> usize {
@@ -238,188 +379,283 @@ impl LSM {
         self.nnodes as usize
     }
 
+    /// Start compaction threads on the node, or resume them if they
+    /// were paused.
     pub fn enable_compaction(&self, node: NodeId) {
         info!("Enabling compaction on node {}", node.0);
         let mut comp = self.nodes[node.0].compactor.lock();
         comp.spawn();
     }
 
-    #[allow(unused_variables)]
-    pub fn disable_compaction(&mut self, node: NodeId) {
-        unimplemented!();
+    /// Temporarily halt compaction on the node. Threads finish their
+    /// current round, then idle until resume_compaction is called.
+    pub fn pause_compaction(&self, node: NodeId) {
+        info!("Pausing compaction on node {}", node.0);
+        self.nodes[node.0].compactor.lock().pause();
+    }
+
+    pub fn resume_compaction(&self, node: NodeId) {
+        info!("Resuming compaction on node {}", node.0);
+        self.nodes[node.0].compactor.lock().resume();
+    }
+
+    /// Stop compaction on the node, waiting for its threads to
+    /// exit. enable_compaction may be used to start it again.
+    pub fn disable_compaction(&self, node: NodeId) {
+        info!("Disabling compaction on node {}", node.0);
+        self.nodes[node.0].compactor.lock().stop();
+    }
+
+    pub fn compaction_enabled(&self, node: NodeId) -> bool {
+        self.nodes[node.0].compactor.lock().is_running()
     }
 
     //
     // Get/Put/Del API
     //
 
-    #[cfg(not(feature="putow"))]
+    /// Append the object to the log on the socket chosen by the
+    /// policy. Returns the socket used and the entry's address.
     #[inline(always)]
-    fn __put(&self, obj: &ObjDesc, hint: PutPolicy) -> Status {
-        // NOTE DO NOT pin the epoch during a PUT. It will stall
-        // the compaction logic.
+    fn append_where(&self, obj: &ObjDesc, hint: PutPolicy)
+        -> Result<(usize,usize),ErrorCode> {
 
-        let va: usize;
         let socket: usize = match hint {
             PutPolicy::Specific(id) => id,
             PutPolicy::Interleave =>
                 (unsafe { rdrand() } % self.nnodes) as usize,
+            PutPolicy::Nearest | PutPolicy::Overwrite =>
+                return self.append_nearest(obj),
         };
-        trace!("PUT key {} socket {:?}", obj.getkey(),socket);
-
         if socket >= self.nodes.len() {
-            return Err(ErrorCode::InvalidSocket);
+            return Err(ErrorCode::InvalidSocket {
+                socket: socket, nsockets: self.nodes.len() });
         }
+        self.nodes[socket].log.append(obj).map(|va| (socket,va))
+    }
 
-        // 1. add object to log
-        // FIXME if full for this socket, should we attempt to append
-        // elsewhere?
-        match self.nodes[socket].log.append(obj) {
-            Err(code) => {
-                return Err(code);
-            },
-            Ok(v) => va = v,
-        }
-        let ientry = merge(socket as u16, va as u64);
-        trace!("key {} va 0x{:x} ientry 0x{:x}",
-               obj.getkey(), va, ientry);
+    /// Try the socket we are running on, then the remaining ones in
+    /// order of NUMA distance. Only when all are full do we report
+    /// OutOfMemory.
+    fn append_nearest(&self, obj: &ObjDesc)
+        -> Result<(usize,usize),ErrorCode> {
 
-        // 2. add to index; if we are updating it, remove live state from
-        // prior segment. running a lambda while we hold the item's
-        // lock avoids race conditions with the cleaner
+        let (sock,core) = clock::cpu_id();
+        let local = if numa::NODE_MAP.is_synthetic() {
+            numa::NODE_MAP.sock_of(core as usize).0
+        } else {
+            sock as usize % self.nodes.len()
+        };
+        self.append_near(obj, local)
+    }
 
-        let key = obj.getkey();
-        let ok: bool = self.index.update_map(key, ientry as u64, |old| {
-            // decrement live size of segment if we overwrite object
-            // old=None if this was an insertion
-            if let Some(ientry) = old {
-                let (socket,va) = extract(ientry);
-                let node = &self.nodes[socket as usize];
-                let idx: usize = node.manager.segment_of(va as usize);
-                let head = node.log.copy_header(va as usize);
-                // decrement live bytes
-                self.nodes[socket as usize].seginfo
-                    .decr_live(idx, head.len_with_header());
+    /// As append_nearest, starting from the given socket.
+    fn append_near(&self, obj: &ObjDesc, first: usize)
+        -> Result<(usize,usize),ErrorCode> {
+
+        for &socket in &self.nearest[first] {
+            match self.nodes[socket].log.append(obj) {
+                Ok(va) => return Ok((socket,va)),
+                Err(ErrorCode::OutOfMemory{..}) => {
+                    trace!("socket {} full, trying next", socket);
+                },
+                Err(code) => return Err(code),
             }
-        });
-        if !ok {
-            // no need to undo the log append;
-            // entries are stale until we update the index
-            warn!("index update returned false");
-            return Err(ErrorCode::TableFull);
         }
-
-        Ok(1)
+        Err(ErrorCode::OutOfMemory { socket: None })
     }
 
-    // va points to the entry in the log. modified from append_safe
-    // FIXME we assume that overwriting objects cannot grow in size
-    // and if it did, maybe we should allocate a new spot for it; if it
-    // shrinks, then also allocate, or overwrite?
-    #[cfg(feature="putow")]
     #[inline(always)]
-    fn write_object(&self, obj: &ObjDesc, ientry: u64) {
-        let (socket,va) = extract(ientry);
-        let node = &self.nodes[socket as usize];
-        let va = va as usize;
-        let block = node.manager.block_of(va);
-
-        // skipping the entry header, recalculate va
-        let mut blk_idx = block.blk_idx();
-        let mut seg_offset = blk_idx * BLOCK_SIZE + (va % BLOCK_SIZE);
-        seg_offset += mem::size_of::<EntryHeader>();
-        blk_idx = seg_offset / BLOCK_SIZE;
-        let blk_offset = seg_offset % BLOCK_SIZE;
-
-        unsafe {
-            let list = block.list();
-            let blocks = list.slice();
-            copy_in(blocks, blk_idx, blk_offset,
-                    obj.getvalue().0, obj.valuelen());
+    fn __put(&self, obj: &ObjDesc, hint: PutPolicy) -> Status {
+        if let PutPolicy::Overwrite = hint {
+            return self.put_overwrite(obj).map(|_| 1);
         }
+        self.put_cond(obj, hint, Cond::Always, NEVER).map(|_| 1)
     }
 
-    // this version of __put will overwrite existing objects,
-    // and only append when we are inserting a new object
-    #[cfg(feature="putow")]
-    #[inline(always)]
-    fn __put(&self, obj: &ObjDesc, hint: PutPolicy) -> Status {
-        // only hold epoch on PUT overwriting an object,
-        // not during an allocation
+    /// Put according to PutPolicy::Overwrite. The key is looked up
+    /// once: the value is written over the object found while its
+    /// key is locked, as by edit, or else the socket of that object
+    /// is where the append starts. Returns the new version.
+    fn put_overwrite(&self, obj: &ObjDesc) -> Result<Version,ErrorCode> {
+        let key = obj.getkey();
+        // socket of the object replaced, and the version and sequence
+        // number of the overwrite, if it was done in place
+        let mut near: Option<usize> = None;
+        let mut done: Option<(Version,u64)> = None;
+        {
+            let ep = PinnedEpoch::new();
+            self.index.lock_map_ifex(key,
+                &|e| self.is_key(e, obj.ns, key), &mut |ientry| {
+                let (socket,va) = extract(ientry);
+                let log = &self.nodes[socket as usize].log;
+                near = Some(socket as usize);
+                let head = log.copy_header(va as usize);
+                if !obj.copy || log.is_expired(va as usize, &head) ||
+                    head.getdatalen() as usize != obj.valuelen() {
+                    return;
+                }
+                let version = head.getversion() + 1;
+                log.edit_value(va as usize, 0, Self::value_of(obj),
+                               version);
+                log.set_expiry(va as usize, NEVER);
+                done = Some((version, self.wal_seq()));
+            });
+        }
+        if let Some((version,seq)) = done {
+            self.wal_put(near.unwrap(), seq, obj, version, NEVER)?;
+            return Ok(version);
+        }
 
-        let va: usize;
-        let socket: usize = match hint {
-            PutPolicy::Specific(id) => id,
-            PutPolicy::Interleave =>
-                (unsafe { rdrand() } % self.nnodes) as usize,
+        let (socket,va) = match near {
+            None => self.append_nearest(&obj.with_expiry(NEVER))?,
+            Some(first) => self.append_near(&obj.with_expiry(NEVER), first)?,
         };
-        trace!("PUT key {} socket {:?}", obj.getkey(),socket);
+        self.install_cond(obj, socket, va, Cond::Always, NEVER)
+    }
 
-        if socket >= self.nodes.len() {
-            return Err(ErrorCode::InvalidSocket);
+    /// Policy of put_object: Overwrite if the instance was configured
+    /// to overwrite, else Nearest.
+    #[inline(always)]
+    fn default_policy(&self) -> PutPolicy {
+        if self.config.get_overwrite() {
+            PutPolicy::Overwrite
+        } else {
+            PutPolicy::Nearest
         }
+    }
+
+    /// Append the object and install it in the index if cond holds
+    /// for the object it replaces; an expired object counts as
+    /// absent. Returns the version stamped into the new entry.
+    fn put_cond(&self, obj: &ObjDesc, hint: PutPolicy, cond: Cond,
+                expires: Expiry) -> Result<Version,ErrorCode> {
+        // NOTE DO NOT pin the epoch during a PUT. It will stall
+        // the compaction logic.
 
+        trace!("PUT key {:?} hint {:?} cond {:?}",
+               obj.getkey(), hint, cond);
         let key = obj.getkey();
 
-        // if object exists, grab epoch, overwrite object
-        let mut do_append = false;
-        let nodes = &self.nodes;
-        meta::pin();
-        if self.index.lock_map_ifex(key, |ientry: u64| {
-                let (s,va) = extract(ientry);
-                let head = nodes[s as usize].log.copy_header(va as usize);
-                if head.getdatalen() as usize >= obj.valuelen() {
-                    self.write_object(obj,ientry);
-                } else {
-                    //do_append = true;
-                    panic!("asdfasd");
-                }
-                meta::quiesce();
-            }) {
-        } else {
-            do_append = true;
-        }
-        if do_append {
-            meta::quiesce();
-        // add object to log
-        // FIXME if full for this socket, should we attempt to append
-        // elsewhere?
-        match self.nodes[socket].log.append(obj) {
-            Err(code) => {
-                return Err(code);
-            },
-            Ok(v) => va = v,
+        // fail early rather than fill the log with dead entries; the
+        // check is repeated below under the key's lock
+        if let Cond::Always = cond {} else {
+            let ep = PinnedEpoch::new();
+            let found = self.index.get(key, &|e| self.is_key(e, obj.ns, key))
+                .and_then(|e| self.live_version(e));
+            cond.check(found)?;
         }
+
+        // 1. add object to log, with room for its expiry
+        let (socket,va) = self.append_where(&obj.with_expiry(expires),
+                                            hint)?;
+        self.install_cond(obj, socket, va, cond, expires)
+    }
+
+    /// Second half of put_cond, for the object appended at va on the
+    /// socket, with room for an expiry unless expires is NEVER.
+    fn install_cond(&self, obj: &ObjDesc, socket: usize, va: usize,
+                    cond: Cond, expires: Expiry)
+        -> Result<Version,ErrorCode> {
+        let key = obj.getkey();
         let ientry = merge(socket as u16, va as u64);
-        trace!("key {} va 0x{:x} ientry 0x{:x}",
+        trace!("key {:?} va 0x{:x} ientry 0x{:x}",
                obj.getkey(), va, ientry);
 
         // 2. add to index; if we are updating it, remove live state from
         // prior segment. running a lambda while we hold the item's
         // lock avoids race conditions with the cleaner
 
-        let ok: bool = self.index.update_map(key, ientry as u64, |old| {
-            // decrement live size of segment if we overwrite object
+        let mut seq = 0u64;
+        let mut version: Version = 0;
+        let mut refused = ErrorCode::KeyNotExist;
+        let r = self.index.update_map_if(key, ientry,
+            &|e| self.is_key(e, obj.ns, key), &mut |old| {
+            let found = old.and_then(|e| self.live_version(e));
+            if let Err(code) = cond.check(found) {
+                refused = code;
+                return false;
+            }
+            // stamp before the entry becomes visible
+            version = found.unwrap_or(0) + 1;
+            self.nodes[socket].log.set_version(va, version);
+            seq = self.wal_seq();
             // old=None if this was an insertion
-            if let Some(ientry) = old {
-                let (socket,va) = extract(ientry);
-                let node = &self.nodes[socket as usize];
-                let idx: usize = node.manager.segment_of(va as usize);
-                let head = node.log.copy_header(va as usize);
-                // decrement live bytes
-                self.nodes[socket as usize].seginfo
-                    .decr_live(idx, head.len_with_header());
+            if let Some(old) = old {
+                self.drop_live(old);
+            }
+            true
+        });
+        match r {
+            Ok(true) => {},
+            // the appended entry is never referenced; let the
+            // cleaner reclaim it
+            Ok(false) => {
+                self.drop_live(ientry);
+                return Err(refused);
+            },
+            Err(code) => {
+                warn!("index update failed: {:?}", code);
+                self.drop_live(ientry);
+                return Err(code);
+            },
+        }
+
+        self.wal_put(socket, seq, obj, version, expires)?;
+        Ok(version)
+    }
+
+    /// Put an object with the given version and expiry, rather than
+    /// one more than the version of the object it replaces. Used to
+    /// bring objects back as they were when they were logged.
+    fn install(&self, obj: &ObjDesc, hint: PutPolicy, version: Version,
+               expires: Expiry) -> Status {
+        let key = obj.getkey();
+        let (socket,va) = self.append_where(&obj.with_expiry(expires),
+                                            hint)?;
+        self.nodes[socket].log.set_version(va, version);
+        let ientry = merge(socket as u16, va as u64);
+
+        let mut seq = 0u64;
+        let r = self.index.update_map_if(key, ientry,
+            &|e| self.is_key(e, obj.ns, key), &mut |old| {
+            seq = self.wal_seq();
+            if let Some(old) = old {
+                self.drop_live(old);
             }
+            true
         });
-        if !ok {
-            // no need to undo the log append;
-            // entries are stale until we update the index
-            warn!("index update returned false");
-            return Err(ErrorCode::TableFull);
+        if let Err(code) = r {
+            warn!("index update failed: {:?}", code);
+            self.drop_live(ientry);
+            return Err(code);
         }
+        self.wal_put(socket, seq, obj, version, expires)
+    }
+
+    /// Version of the object at ientry, or None if it has expired.
+    #[inline(always)]
+    fn live_version(&self, ientry: IndexEntry) -> Option<Version> {
+        let (socket,va) = extract(ientry);
+        let log = &self.nodes[socket as usize].log;
+        let head = log.copy_header(va as usize);
+        if log.is_expired(va as usize, &head) {
+            None
+        } else {
+            Some(head.getversion())
         }
+    }
 
-        Ok(1)
+    /// Decrement the live bytes of the segment holding the object at
+    /// ientry, and of its namespace, once the index no longer refers
+    /// to it.
+    fn drop_live(&self, ientry: IndexEntry) {
+        let (socket,va) = extract(ientry);
+        let node = &self.nodes[socket as usize];
+        let idx: usize = node.manager.segment_of(va as usize);
+        let head = node.log.copy_header(va as usize);
+        node.seginfo.decr_live(idx, head.len_with_header());
+        self.namespaces.credit(head.getns(), head.len_with_header());
     }
 
     /// Put an object according to a specific policy. If a node is
@@ -432,28 +668,74 @@ impl LSM {
         self.__put(obj, hint)
     }
 
-    /// FIXME shouldn't hard-code to Node 0
-    /// TODO Here is where logic should decide that, when an append to
-    /// a specific node fails, to try allocation to another node. Only
-    /// when all fail, return error to user.
+    /// Put an object on the socket of the calling thread. If that
+    /// socket is out of memory, the remaining sockets are tried in
+    /// order of NUMA distance; OutOfMemory is returned only when all
+    /// are full. If the instance was configured to overwrite, the
+    /// value is written over the one it replaces if their lengths are
+    /// equal, else the object goes to the socket of the one it
+    /// replaces (see PutPolicy::Overwrite).
     #[inline(always)]
     pub fn put_object(&self, obj: &ObjDesc) -> Status {
-        self.__put(obj, PutPolicy::Specific(0))
+        self.__put(obj, self.default_policy())
+    }
+
+    /// Put an object only if its key is not present, else return
+    /// KeyExists. Returns the version of the new object, always 1.
+    pub fn put_if_absent(&self, obj: &ObjDesc)
+        -> Result<Version,ErrorCode> {
+        self.put_cond(obj, PutPolicy::Nearest, Cond::Absent, NEVER)
+    }
+
+    /// Put an object only if the one it replaces has the expected
+    /// version (as returned by get_object_version or a prior PUT),
+    /// else return VersionMismatch, or KeyNotExist if the key is not
+    /// present. Returns the version of the new object.
+    pub fn put_if_version(&self, obj: &ObjDesc, expected: Version)
+        -> Result<Version,ErrorCode> {
+        self.put_cond(obj, PutPolicy::Nearest, Cond::Version(expected),
+                      NEVER)
+    }
+
+    /// Put an object which expires once ttl has passed. Until then
+    /// it behaves as any other; afterwards reads report KeyNotExist,
+    /// and its memory is reclaimed by compaction or a Sweeper. A
+    /// later put of the key without a ttl does not expire.
+    pub fn put_object_ttl(&self, obj: &ObjDesc, ttl: Duration) -> Status {
+        let nanos = ttl.as_secs() * clock::NANO_PER_SEC
+            + ttl.subsec_nanos() as u64;
+        let expires = clock::now() + cmp::max(1, clock::from_nano(nanos));
+        self.put_cond(obj, PutPolicy::Nearest, Cond::Always, expires)
+            .map(|_| 1)
     }
 
     #[inline(always)]
-    pub fn exists(&self, key: u64) -> bool {
-        self.index.get(key).is_some()
+    pub fn exists(&self, key: &KeyType) -> bool {
+        self.exists_in(DEFAULT_NS, key)
     }
 
-    /// FIXME why don't we return the length... ?
     #[inline(always)]
-    pub fn get_object(&self, key: u64, buf: &mut [u8]) -> Status {
-        meta::pin();
-        //let ep = PinnedEpoch::new();
+    fn exists_in(&self, ns: NsId, key: &KeyType) -> bool {
+        let ep = PinnedEpoch::new();
+        self.index.get(key, &|e| self.is_key(e, ns, key))
+            .and_then(|e| self.live_version(e)).is_some()
+    }
+
+    /// Copy the value of an object into buf. Returns the length of
+    /// the value, or BufferTooSmall if buf cannot hold all of it (in
+    /// which case buf is left untouched).
+    #[inline(always)]
+    pub fn get_object(&self, key: &KeyType, buf: &mut [u8]) -> Status {
+        self.get_in(DEFAULT_NS, key, buf)
+    }
+
+    #[inline(always)]
+    fn get_in(&self, ns: NsId, key: &KeyType, buf: &mut [u8]) -> Status {
+        let ep = PinnedEpoch::new();
 
         // 1. lookup the key and get the entry
-        let ientry: IndexEntry = match self.index.get(key) {
+        let ientry: IndexEntry =
+            match self.index.get(key, &|e| self.is_key(e, ns, key)) {
             None => return Err(ErrorCode::KeyNotExist),
             Some(entry) => entry,
         };
@@ -462,130 +744,974 @@ impl LSM {
 
         // 2. ask Log to give us the object
         self.nodes[socket as usize]
-            .log.get_entry(va as usize, buf);
-
-        meta::quiesce();
-        Ok(1)
+            .log.get_entry(va as usize, buf)
     }
 
-    #[inline(always)]
-    pub fn del_object(&self, key: u64) -> Status {
+    /// As get_object, but also return the version of the object, for
+    /// use with put_if_version and del_if_version.
+    pub fn get_object_version(&self, key: &KeyType, buf: &mut [u8])
+        -> Result<(usize,Version),ErrorCode> {
         let ep = PinnedEpoch::new();
 
-        // 1. remove key and acquire old
-        let r = self.index.remove_map(key, |entry| {
-            if let Some(ientry) = entry {
-                let (socket,va) = extract(ientry);
-
-                // 2. read the size of the object
-                let node = &self.nodes[socket as usize];
-                let head = node.log.copy_header(va as usize);
-
-                // 3. decrement live size of segment
-                let idx: usize = node.manager.segment_of(va as usize);
-                self.nodes[socket as usize].seginfo
-                    .decr_live(idx, head.len_with_header());
+        let ientry: IndexEntry =
+            match self.index.get(key, &|e| self.is_key(e, DEFAULT_NS, key)) {
+            None => return Err(ErrorCode::KeyNotExist),
+            Some(entry) => entry,
+        };
+        let (socket,va) = extract(ientry);
+        let log = &self.nodes[socket as usize].log;
+        loop {
+            let seq = seq_begin(va as usize);
+            let len = log.get_entry(va as usize, buf)?;
+            let version = log.copy_header(va as usize).getversion();
+            if !seq_retry(va as usize, seq) {
+                return Ok((len, version));
             }
-        });
-
-        if r { Ok(1) }
-        else { Err(ErrorCode::KeyNotExist) }
+        }
     }
 
-    //
-    // Lower-level allocation API
-    //
-
-    // Insert key into index; write object header into log, but
-    // do not write actual object itself.
-    // This version of alloc acts like an explicit insert.
-    // NOTE: this is only used for the shuffle benchmark for now.
-    // It will block if key already exists, and fail if key wasn't
-    // able to insert, or was an update
-    #[cfg(IGNORE)]
-    pub fn alloc(&self, key: u64, len: u64, sock: u32) -> Pointer<u8> {
-        // meta::pin();
-        let va: usize;
+    /// Read an object without copying it. The epoch stays pinned
+    /// while f runs, so the log memory referenced by the
+    /// EntryReference (e.g. via data_chunks) remains valid; nothing
+    /// borrowed from it may escape f. If the value is edited in place
+    /// (see incr, write_range) while f runs, f is called again.
+    /// Returns whatever the last call of f returns.
+    pub fn read_object<F,R>(&self, key: &KeyType, mut f: F)
+        -> Result<R,ErrorCode>
+        where F: FnMut(&EntryReference) -> R {
 
-        let obj = ObjDesc::null(key, len as usize);
+        let ep = PinnedEpoch::new();
 
-        // make sure we wait for key to be deleted before adding again
-        while let Some(_) = self.index.get(obj.key) {
-            ;
+        let ientry: IndexEntry =
+            match self.index.get(key, &|e| self.is_key(e, DEFAULT_NS, key)) {
+            None => return Err(ErrorCode::KeyNotExist),
+            Some(entry) => entry,
+        };
+        let (socket,va) = extract(ientry);
+        let log = &self.nodes[socket as usize].log;
+        loop {
+            let seq = seq_begin(va as usize);
+            let entry = log.entry_ref(va as usize);
+            let ret = if entry.is_expired() {
+                Err(ErrorCode::KeyNotExist)
+            } else if cfg!(feature="verify_get") && !entry.verify() {
+                Err(ErrorCode::Corrupted)
+            } else {
+                Ok(f(&entry))
+            };
+            if !seq_retry(va as usize, seq) {
+                return ret;
+            }
         }
-
-        let res = self.nodes[sock as usize].log.append(&obj);
-        debug_assert_eq!(res.is_ok(), true);
-        va = res.unwrap();
-
-        let ientry = merge(sock as u16, va as u64);
-
-        // 2. update reference to object
-        let (ok,opt) = self.index.update(obj.key, ientry);
-        // verify this inserted, and was not an update
-        debug_assert_eq!(ok, true,
-                        "Table insert failed; can it resize?");
-        // XXX
-        debug_assert_eq!(opt.is_none(), true);
-
-        // meta::quiesce();
-        Pointer(va as *const u8)
     }
 
-    // Delete key from index.
-    #[cfg(IGNORE)]
-    pub fn free(&self, key: u64) -> bool {
-        // we need to pin the epoch, because we use the VA to lookup
-        // the containing segment
-        meta::pin();
+    /// Get a batch of objects, copying the value of keys[i] into
+    /// bufs[i]. Returns a status for each key as get_object would.
+    /// All index buckets are brought into cache first, then all log
+    /// entries, so that the misses of one key overlap with those of
+    /// the others. The epoch is pinned once for the whole batch.
+    pub fn multi_get(&self, keys: &[&KeyType], bufs: &mut [&mut [u8]])
+        -> Vec<Status> {
+        assert_eq!(keys.len(), bufs.len(), "need one buffer per key");
+        let ep = PinnedEpoch::new();
 
-        // 1. remove key and acquire old
-        let res = self.index.remove(key);
-        if res.is_none() {
-            return false;
+        for key in keys {
+            self.index.prefetch(key, false);
         }
-        let ientry: IndexEntry = res.unwrap();
-        let (socket,va) = extract(ientry);
-
-        // 2. read the size of the object
-        let node = &self.nodes[socket as usize];
-        let head = node.log.copy_header(va as usize);
+        // Resolving a hash collision reads the stored key from the
+        // log, which would serialize the misses we want to overlap,
+        // so take the first entry with a matching hash and check it
+        // once its cache line has arrived.
+        let found: Vec<Option<IndexEntry>> = keys.iter().map(|key| {
+            let e = self.index.get(key, &|_| true);
+            if let Some(ientry) = e {
+                prefetch(extract(ientry).1 as *const u8);
+            }
+            e
+        }).collect();
+
+        keys.iter().zip(found).zip(bufs.iter_mut())
+            .map(|((key, e), buf)| {
+            let ientry = match e {
+                Some(ientry) if self.is_key(ientry, DEFAULT_NS, key) =>
+                    ientry,
+                // collision, or not present
+                _ => match self.index.get(key,
+                        &|e| self.is_key(e, DEFAULT_NS, key)) {
+                    None => return Err(ErrorCode::KeyNotExist),
+                    Some(ientry) => ientry,
+                },
+            };
+            let (socket,va) = extract(ientry);
+            self.nodes[socket as usize].log.get_entry(va as usize, buf)
+        }).collect()
+    }
 
-        // 3. decrement live size of segment
-        let idx: usize = node.manager.segment_of(va as usize);
-        node.seginfo.decr_live(idx, head.len_with_header());
+    /// Put a batch of objects, returning a status for each as
+    /// put_object would. The index buckets of all keys are brought
+    /// into cache before the first is appended. As with put_object,
+    /// the epoch is not held across the batch, and an error for one
+    /// object does not stop the rest.
+    pub fn multi_put(&self, objs: &[ObjDesc]) -> Vec<Status> {
+        for obj in objs {
+            self.index.prefetch(obj.getkey(), true);
+        }
+        let hint = self.default_policy();
+        objs.iter().map(|obj| self.__put(obj, hint)).collect()
+    }
 
-        meta::quiesce();
-        true
+    /// Add delta to the counter stored under key, wrapping on
+    /// overflow, and return the new count. The value must be 8 bytes
+    /// holding a u64 in native byte order, else NotCounter is
+    /// returned.
+    pub fn incr(&self, key: &KeyType, delta: i64)
+        -> Result<u64,ErrorCode> {
+        let mut count = 0u64;
+        self.edit(key, |entry, patch| {
+            let len = entry.datalen as usize;
+            if len != mem::size_of::<u64>() {
+                return Err(ErrorCode::NotCounter { len: len });
+            }
+            let mut old = 0u64;
+            unsafe { entry.get_data(&mut old as *mut u64 as *mut u8); }
+            count = old.wrapping_add(delta as u64);
+            patch.extend_from_slice(unsafe {
+                slice::from_raw_parts(&count as *const u64 as *const u8,
+                                      mem::size_of::<u64>())
+            });
+            Ok(0)
+        })?;
+        Ok(count)
     }
 
-    pub fn pin(key: u64) {
-        unimplemented!();
+    /// Add bytes to the end of the value stored under key. Returns
+    /// the new length of the value.
+    pub fn append(&self, key: &KeyType, bytes: &[u8]) -> Status {
+        self.edit(key, |entry, patch| {
+            patch.extend_from_slice(bytes);
+            Ok(entry.datalen as usize)
+        })
     }
 
-    pub fn unpin(key: u64) {
-        unimplemented!();
+    /// Overwrite the value stored under key with bytes, from offset
+    /// into it. A range reaching past the end of the value extends it
+    /// (with zeros between the old end and offset). Returns the new
+    /// length of the value.
+    pub fn write_range(&self, key: &KeyType, offset: usize,
+                       bytes: &[u8]) -> Status {
+        self.edit(key, |_, patch| {
+            patch.extend_from_slice(bytes);
+            Ok(offset)
+        })
     }
 
-    //
-    // Misc. methods
-    //
+    /// Modify the value of key. f is given the object while its key
+    /// is locked in the index, fills patch with bytes to write, and
+    /// returns the offset within the value to write them at. If they
+    /// lie within the value, they are written over it then and there
+    /// (readers copying it out meanwhile start over; see
+    /// Log::edit_value). Else the edit is made in a copy of the value,
+    /// put conditionally on the version f saw; this is retried
+    /// (calling f again) if the object changes meanwhile. Either way
+    /// the version advances, and the expiry of the object is kept.
+    fn edit<F>(&self, key: &KeyType, mut f: F) -> Status
+        where F: FnMut(&EntryReference, &mut Vec<u8>)
+                    -> Result<usize,ErrorCode> {
+
+        let mut patch: Vec<u8> = Vec::new();
+        loop {
+            let mut outcome: Result<Edited,ErrorCode>;
+            outcome = Err(ErrorCode::KeyNotExist);
+            {
+                let ep = PinnedEpoch::new();
+                self.index.lock_map_ifex(key,
+                    &|e| self.is_key(e, DEFAULT_NS, key), &mut |ientry| {
+                    let (socket,va) = extract(ientry);
+                    let log = &self.nodes[socket as usize].log;
+                    let entry = log.entry_ref(va as usize);
+                    if entry.is_expired() {
+                        return;
+                    }
+                    patch.clear();
+                    let offset = match f(&entry, &mut patch) {
+                        Err(code) => { outcome = Err(code); return; },
+                        Ok(offset) => offset,
+                    };
+                    let len = entry.datalen as usize;
+                    let end = cmp::max(len, offset + patch.len());
+                    if end == len {
+                        let version = entry.version + 1;
+                        log.edit_value(va as usize, offset, &patch,
+                                       version);
+                        // the write-ahead log holds whole values
+                        let value = self.wal.as_ref().map(|_| {
+                            let mut value = vec![0u8; len];
+                            unsafe { entry.get_buf(&mut value); }
+                            value
+                        });
+                        outcome = Ok(Edited::InPlace {
+                            socket: socket as usize, seq: self.wal_seq(),
+                            len: len, value: value, version: version,
+                            expires: entry.expires });
+                        return;
+                    }
+                    let mut copy: Vec<u8> = vec![0u8; end];
+                    unsafe { entry.get_buf(&mut copy[..len]); }
+                    copy[offset..offset + patch.len()]
+                        .copy_from_slice(&patch);
+                    outcome = Ok(Edited::Copy { value: copy,
+                        version: entry.version, expires: entry.expires });
+                });
+            }
 
-    #[cfg(test)]
-    pub fn nlive(&self) -> usize {
-        self.index.len()
+            match outcome? {
+                Edited::InPlace { socket, seq, len, value, version,
+                                  expires } => {
+                    if let Some(value) = value {
+                        let obj = ObjDesc::new(key, Pointer(value.as_ptr()),
+                                               value.len());
+                        self.wal_put(socket, seq, &obj, version, expires)?;
+                    }
+                    return Ok(len);
+                },
+                Edited::Copy { value, version, expires } => {
+                    let obj = ObjDesc::new(key, Pointer(value.as_ptr()),
+                                           value.len());
+                    match self.put_cond(&obj, PutPolicy::Nearest,
+                                        Cond::Version(version), expires) {
+                        Ok(_) => return Ok(value.len()),
+                        // changed since we looked; start over
+                        Err(ErrorCode::VersionMismatch{..}) => {},
+                        Err(code) => return Err(code),
+                    }
+                },
+            }
+        }
     }
 
-    #[cfg(IGNORE)]
-    pub fn dump_seg_info(&self) {
-        for node in &self.nodes {
-            node.manager.read().dump_seg_info();
+    /// Apply the puts and deletes of the batch such that readers see
+    /// either all of them or none. The objects are appended to the
+    /// log first, then every key is locked in the index and all are
+    /// switched over together. Objects are placed as by put_object.
+    /// Returns the number of operations in the batch. The write-ahead
+    /// log records the batch as a unit, so recovery after a crash
+    /// applies all of it or none.
+    pub fn write(&self, batch: &WriteBatch) -> Status {
+        let n = batch.ops.len();
+        if n == 0 {
+            return Ok(0);
         }
-    }
 
-    // hack
-    #[cfg(IGNORE)]
-    #[inline(always)]
+        // puts in a dropped namespace fail as they are appended
+        for &(ns, _, _) in &batch.ops {
+            if self.namespaces.is_dropped(ns) {
+                return Err(ErrorCode::NamespaceDropped);
+            }
+        }
+
+        // 1. add objects to the log
+        let mut news: Vec<Option<IndexEntry>> = Vec::with_capacity(n);
+        for &(_, _, ref op) in &batch.ops {
+            let obj = match *op {
+                None => { news.push(None); continue; },
+                Some(ref obj) => obj,
+            };
+            let obj = obj.with_expiry(NEVER);
+            match self.append_where(&obj, PutPolicy::Nearest) {
+                Ok((socket,va)) =>
+                    news.push(Some(merge(socket as u16, va as u64))),
+                Err(code) => {
+                    // nothing refers to them; let the cleaner reclaim
+                    for ientry in news.iter().filter_map(|e| *e) {
+                        self.drop_live(ientry);
+                    }
+                    return Err(code);
+                },
+            }
+        }
+
+        // 2. switch the index over to them in one step
+        let keys: Vec<&KeyType> = batch.ops.iter()
+            .map(|&(_,key,_)| key).collect();
+        let nss: Vec<NsId> = batch.ops.iter()
+            .map(|&(ns,_,_)| ns).collect();
+        // sequence number and version of each change to log
+        let mut logged: Vec<Option<(u64,Version)>> = vec![None; n];
+        let r = self.index.update_batch(&keys, &news,
+            &|i, e| self.is_key(e, nss[i], keys[i]), &mut |olds| {
+            for i in 0..n {
+                if let Some(ientry) = news[i] {
+                    let found = olds[i].and_then(|e| self.live_version(e));
+                    let version = found.unwrap_or(0) + 1;
+                    let (socket,va) = extract(ientry);
+                    self.nodes[socket as usize].log
+                        .set_version(va as usize, version);
+                    logged[i] = Some((self.wal_seq(), version));
+                } else if olds[i].is_some() {
+                    logged[i] = Some((self.wal_seq(), 0));
+                }
+                if let Some(old) = olds[i] {
+                    self.drop_live(old);
+                }
+            }
+            true
+        });
+        if let Err(code) = r {
+            warn!("index update failed: {:?}", code);
+            for ientry in news.iter().filter_map(|e| *e) {
+                self.drop_live(ientry);
+            }
+            return Err(code);
+        }
+
+        // 3. log the changes made, as one unit, in the file of the
+        // socket holding the first object put
+        if let Some(ref wal) = self.wal {
+            let ups: Vec<Update> = batch.ops.iter().zip(logged)
+                .filter_map(|(&(ns, key, ref op), log)| log.map(|(seq,v)| {
+                    match *op {
+                        Some(ref obj) => Update { seq: seq, ns: ns,
+                            key: key, value: Some(Self::value_of(obj)),
+                            version: v, expires: NEVER },
+                        None => Update { seq: seq, ns: ns,
+                            key: key, value: None, version: 0,
+                            expires: NEVER },
+                    }
+                })).collect();
+            let socket = news.iter().filter_map(|e| *e).next()
+                .map_or(0, |e| extract(e).0 as usize);
+            if !ups.is_empty() {
+                wal.log_batch(socket, &ups)?;
+            }
+        }
+        Ok(n)
+    }
+
+    #[inline(always)]
+    pub fn del_object(&self, key: &KeyType) -> Status {
+        self.del_cond(DEFAULT_NS, key, Cond::Always).map(|_| 1)
+    }
+
+    /// Delete an object only if it has the expected version, else
+    /// return VersionMismatch, or KeyNotExist if the key is not
+    /// present.
+    pub fn del_if_version(&self, key: &KeyType, expected: Version)
+        -> Status {
+        self.del_cond(DEFAULT_NS, key, Cond::Version(expected)).map(|_| 1)
+    }
+
+    /// Remove the key if cond holds for its object. Returns the
+    /// version of the object removed. An expired object is removed
+    /// regardless, but reported as KeyNotExist.
+    fn del_cond(&self, ns: NsId, key: &KeyType, cond: Cond)
+        -> Result<Version,ErrorCode> {
+        let ep = PinnedEpoch::new();
+
+        // 1. remove key and acquire old
+        let mut seq = 0u64;
+        let mut wsock = 0usize;
+        let mut result = Err(ErrorCode::KeyNotExist);
+        let removed = self.index.remove_map_if(key,
+            &|e| self.is_key(e, ns, key), &mut |entry| {
+            let ientry = match entry {
+                None => return false,
+                Some(ientry) => ientry,
+            };
+            let found = self.live_version(ientry);
+            if let Some(version) = found {
+                if let Err(code) = cond.check(found) {
+                    result = Err(code);
+                    return false;
+                }
+                result = Ok(version);
+            }
+            seq = self.wal_seq();
+            wsock = extract(ientry).0 as usize;
+
+            // 2. decrement live size of segment
+            self.drop_live(ientry);
+            true
+        });
+
+        if removed {
+            if let Some(ref wal) = self.wal {
+                wal.log_del(wsock, seq, ns, key)?;
+            }
+        }
+        result
+    }
+
+    /// Visit, in lexicographic byte order, each object whose key
+    /// lies within [start,end), or with no upper bound if end is
+    /// None. f is given the key and a reference to the object in the
+    /// log, and returns false to end the scan early. Each object is
+    /// read as one consistent version, but the scan as a whole is not
+    /// a snapshot. The epoch is pinned, and the object's key locked,
+    /// while f runs, so f must not call into the store. Expired
+    /// objects are skipped. Returns the number of objects visited, or
+    /// NotOrdered if this instance does not use an ordered index.
+    pub fn scan<F>(&self, start: &KeyType, end: Option<&KeyType>,
+                   mut f: F) -> Status
+        where F: FnMut(&KeyType, &EntryReference) -> bool {
+
+        let mut batch: Vec<(Vec<u8>,IndexEntry)>;
+        batch = Vec::with_capacity(SCAN_BATCH);
+        let mut from: Vec<u8> = start.to_vec();
+        let mut n = 0usize;
+        loop {
+            batch.clear();
+            // entries found stay readable while the epoch is pinned,
+            // even if they are relocated or removed meanwhile
+            let ep = PinnedEpoch::new();
+            let found = match self.index.scan(&from, end,
+                                              SCAN_BATCH, &mut batch) {
+                Err(code) => return Err(code),
+                Ok(found) => found,
+            };
+            for &(ref key, _) in &batch {
+                // the key's lock keeps the object from being edited
+                // in place while f looks at it. It may have been
+                // replaced since we read the index, so look it up again.
+                let mut more = true;
+                self.index.lock_map_ifex(key,
+                    &|e| self.is_key(e, DEFAULT_NS, key), &mut |ientry| {
+                    let (socket,va) = extract(ientry);
+                    let entry = self.nodes[socket as usize]
+                        .log.entry_ref(va as usize);
+                    if !entry.is_expired() {
+                        n += 1;
+                        more = f(key, &entry);
+                    }
+                });
+                if !more {
+                    return Ok(n);
+                }
+            }
+            if found < SCAN_BATCH {
+                return Ok(n);
+            }
+            // resume with the smallest key after the last one seen
+            from.clear();
+            from.extend_from_slice(&batch[found-1].0);
+            from.push(0u8);
+        }
+    }
+
+    /// Visit each object whose key begins with prefix. See scan.
+    pub fn scan_prefix<F>(&self, prefix: &KeyType, f: F) -> Status
+        where F: FnMut(&KeyType, &EntryReference) -> bool {
+        let end = prefix_end(prefix);
+        self.scan(prefix, end.as_ref().map(|v| &v[..]), f)
+    }
+
+    //
+    // Durability
+    //
+
+    /// Create a durable instance whose updates are also written to
+    /// per-socket files in dir. Files already in dir are first
+    /// replayed to recover the objects they hold, then rewritten into
+    /// a compact form. Objects are placed on the socket they were
+    /// written from, if it still exists and has room.
+    pub fn open(dir: &str, capacity: usize) -> Result<Self,ErrorCode> {
+        Self::open_with_config(dir, LSMConfig::default().capacity(capacity))
+    }
+
+    /// As open, but tuned by the given configuration (see
+    /// with_config).
+    pub fn open_with_config(dir: &str, config: LSMConfig)
+        -> Result<Self,ErrorCode> {
+        let mut lsm = Self::with_config(config)?;
+        let path = Path::new(dir);
+
+        let maxseq = {
+            let lsm_ = &lsm;
+            let nnodes = lsm_.nodes.len();
+            // sequence number of the update applied to each key
+            let mut seen: HashMap<(NsId,Vec<u8>),u64> = HashMap::new();
+            // namespaces by their ids in the files
+            let mut nsmap: HashMap<NsId,Arc<NsInfo>> = HashMap::new();
+            let mut err: Option<ErrorCode> = None;
+            let r = wal::replay(path, |socket, rec| {
+                // a put which has since expired removes the key, as it
+                // replaced any earlier value
+                let now = clock::now();
+                let (seq,ns,key,value) = match rec {
+                    Record::Put { seq, ns, key, value, version, expires } =>
+                        if expired_at(expires, now) {
+                            (seq, ns, key, None)
+                        } else {
+                            (seq, ns, key, Some((value, version, expires)))
+                        },
+                    Record::Del { seq, ns, key } =>
+                        (seq, ns, key, None),
+                    // every file names the namespace; the first one
+                    // replayed opens it
+                    Record::Namespace { ns, name } => {
+                        if !nsmap.contains_key(&ns) {
+                            let info = lsm_.namespaces.open(&name);
+                            nsmap.insert(ns, info);
+                        }
+                        return;
+                    },
+                    Record::DropNs { ns, name } => {
+                        if let Some(info) = nsmap.get(&ns) {
+                            if !info.is_dropped() {
+                                lsm_.namespaces.drop_ns(&name);
+                            }
+                        }
+                        return;
+                    },
+                };
+                let ns = match ns {
+                    DEFAULT_NS => DEFAULT_NS,
+                    ns => match nsmap.get(&ns) {
+                        Some(info) if !info.is_dropped() => info.id(),
+                        Some(_) => return, // dead with its namespace
+                        None => {
+                            warn!("replay: key {:?} of unknown \
+                                   namespace {} skipped", key, ns);
+                            return;
+                        },
+                    },
+                };
+                let key = (ns, key);
+                if let Some(&s) = seen.get(&key) {
+                    if s > seq {
+                        return;
+                    }
+                }
+                match value {
+                    Some((v, version, expires)) => {
+                        let obj = ObjDesc::new(&key.1,
+                                    Pointer(v.as_ptr()), v.len())
+                            .with_ns(ns);
+                        let hint = PutPolicy::Specific(socket % nnodes);
+                        let st = match lsm_.install(&obj, hint, version,
+                                                    expires) {
+                            Err(ErrorCode::OutOfMemory{..}) =>
+                                lsm_.install(&obj, PutPolicy::Nearest,
+                                             version, expires),
+                            st => st,
+                        };
+                        if let Err(code) = st {
+                            err = Some(code);
+                        }
+                    },
+                    None => {
+                        let _ = lsm_.del_cond(ns, &key.1, Cond::Always);
+                    },
+                }
+                seen.insert(key, seq);
+            });
+            if let Some(code) = err {
+                return Err(code);
+            }
+            match r {
+                Err(code) => return Err(code),
+                Ok(seq) => seq,
+            }
+        };
+
+        let managers = lsm.nodes.iter()
+            .map(|n| n.manager.clone()).collect();
+        let w = match Wal::open(path, managers,
+                                lsm.index.clone(), maxseq + 1) {
+            Err(code) => return Err(code),
+            Ok(w) => Arc::new(w),
+        };
+        if let Err(code) = w.checkpoint() {
+            return Err(code);
+        }
+        for node in &lsm.nodes {
+            node.compactor.lock().set_wal(w.clone());
+            node.sweeper.lock().set_wal(w.clone());
+        }
+        lsm.wal = Some(w);
+        Ok(lsm)
+    }
+
+    /// Flush the write-ahead log to stable storage. Does nothing if
+    /// this instance is not durable.
+    pub fn sync(&self) -> Status {
+        match self.wal {
+            None => Ok(1),
+            Some(ref wal) => wal.sync(),
+        }
+    }
+
+    /// Take a sequence number for the write-ahead log, if there is
+    /// one. Call while the key is locked in the index.
+    #[inline(always)]
+    fn wal_seq(&self) -> u64 {
+        match self.wal {
+            None => 0,
+            Some(ref wal) => wal.next_seq(),
+        }
+    }
+
+    #[inline(always)]
+    fn wal_put(&self, socket: usize, seq: u64, obj: &ObjDesc,
+               version: Version, expires: Expiry) -> Status {
+        match self.wal {
+            None => Ok(1),
+            Some(ref wal) => wal.log(socket, &Update { seq: seq,
+                ns: obj.ns, key: obj.getkey(),
+                value: Some(Self::value_of(obj)), version: version,
+                expires: expires }),
+        }
+    }
+
+    /// The caller's value bytes referred to by obj.
+    #[inline(always)]
+    fn value_of<'a>(obj: &ObjDesc<'a>) -> &'a [u8] {
+        unsafe {
+            slice::from_raw_parts(obj.getvalue().0, obj.valuelen())
+        }
+    }
+
+    //
+    // Snapshots
+    //
+
+    /// Write every live object, with its version, expiry and the name
+    /// of its namespace, to a file at path, which restore can load
+    /// later. Compaction waits until the snapshot is done, so that no
+    /// object moves during it: each object not written by clients
+    /// meanwhile is in the file once, as it was when the snapshot
+    /// began. Clients may continue, but objects they update during
+    /// the snapshot may appear with either value, and a key
+    /// overwritten concurrently may be missed. Returns the number of
+    /// objects written.
+    pub fn snapshot(&self, path: &str) -> Status {
+        let mut w = match snapshot::Writer::create(Path::new(path),
+                                                   self.nodes.len()) {
+            Err(code) => return Err(code),
+            Ok(w) => w,
+        };
+        let mut value: Vec<u8> = Vec::new();
+
+        // before pinning the epoch, as workers may wait for it to
+        // advance while they move objects
+        let moving: Vec<Arc<pl::RwLock<()>>> = self.nodes.iter()
+            .map(|n| n.compactor.lock().moving()).collect();
+        let held: Vec<_> = moving.iter().map(|m| m.write()).collect();
+
+        let ep = PinnedEpoch::new();
+        for socket in 0..self.nodes.len() {
+            let st = self.for_each_live(socket, |key, entry| {
+                let info = match entry.ns {
+                    DEFAULT_NS => None,
+                    ns => match self.namespaces.get(ns) {
+                        Some(ref info) if !info.is_dropped() =>
+                            Some(info.clone()),
+                        // dropped, so its objects are dead
+                        _ => return Ok(1),
+                    },
+                };
+                value.resize(entry.datalen as usize, 0u8);
+                unsafe { entry.get_buf(&mut value); }
+                w.add(&snapshot::Object {
+                    ns: info.as_ref().map(|i| i.name()),
+                    key: key,
+                    value: &value,
+                    version: entry.version,
+                    expires: expiry_to_wall(entry.expires),
+                })
+            });
+            if let Err(code) = st {
+                return Err(code);
+            }
+        }
+        drop(ep);
+        drop(held);
+
+        w.finish()
+    }
+
+    /// Load the objects in a file written by snapshot, placing each
+    /// according to the policy, with the version and expiry it had.
+    /// The file is checked in full before anything is inserted.
+    /// Objects already present under the same keys are replaced;
+    /// objects which have expired since the snapshot are skipped.
+    /// Objects of namespaces go to the namespaces of the same names,
+    /// which are opened if need be; an instance with an ordered index
+    /// fails on the first with InvalidConfig. Returns the number of
+    /// objects loaded.
+    pub fn restore(&self, path: &str, hint: PutPolicy) -> Status {
+        let mut n = 0usize;
+        snapshot::load(Path::new(path), |o| {
+            let expires = expiry_from_wall(o.expires);
+            if expired_at(expires, clock::now()) {
+                return Ok(0);
+            }
+            let obj = ObjDesc::new(o.key, Pointer(o.value.as_ptr()),
+                                   o.value.len());
+            let obj = match o.ns {
+                None => obj,
+                Some(name) => obj.with_ns(self.open_namespace(name)?.id()),
+            };
+            self.install(&obj, hint, o.version, expires)?;
+            n += 1;
+            Ok(1)
+        })?;
+        Ok(n)
+    }
+
+    //
+    // Namespaces
+    //
+
+    /// Open the namespace of the given name, creating it (with no
+    /// quota) if needed. Its keys never match those of the default
+    /// keyspace or of other namespaces. Not supported by instances
+    /// with an ordered index, which keys objects by their bytes
+    /// alone; fails with InvalidConfig. A durable instance logs the
+    /// creation, so that its objects are recovered into a namespace
+    /// of the same name; quotas must be set again after recovery.
+    pub fn namespace(&self, name: &str) -> Result<Namespace,ErrorCode> {
+        let info = self.open_namespace(name)?;
+        Ok(Namespace { lsm: self, info: info })
+    }
+
+    fn open_namespace(&self, name: &str)
+        -> Result<Arc<NsInfo>,ErrorCode> {
+        if self.config.get_index() != IndexKind::Hash {
+            return Err(ErrorCode::InvalidConfig);
+        }
+        self.namespaces.open_with(name, |ns| match self.wal {
+            None => Ok(1),
+            Some(ref wal) => wal.log_ns(ns, name),
+        })
+    }
+
+    /// Drop the namespace and all of its objects. They are no longer
+    /// reachable once this returns, and handles to the namespace fail
+    /// with NamespaceDropped; their memory is reclaimed by compaction
+    /// (or a Sweeper) over time. A namespace opened later with the
+    /// same name starts empty.
+    pub fn drop_namespace(&self, name: &str) -> Status {
+        match self.namespaces.drop_ns(name) {
+            None => Err(ErrorCode::KeyNotExist),
+            Some(info) => {
+                info!("dropped namespace {} ({} live bytes)",
+                      name, info.live());
+                if let Some(ref wal) = self.wal {
+                    wal.log_drop_ns(info.id(), name)?;
+                }
+                Ok(1)
+            },
+        }
+    }
+
+    //
+    // Scrubbing
+    //
+
+    /// Run one scrubbing pass over the socket on the calling thread.
+    /// See the scrub module for what is checked.
+    pub fn scrub(&self, node: NodeId) -> ScrubReport {
+        self.nodes[node.0].scrubber.lock().scrub()
+    }
+
+    /// Start a low-priority thread on the node which scrubs its
+    /// memory periodically.
+    pub fn enable_scrubbing(&self, node: NodeId) {
+        info!("Enabling scrubbing on node {}", node.0);
+        self.nodes[node.0].scrubber.lock().spawn();
+    }
+
+    /// Stop the node's scrubber thread, waiting for it to exit.
+    pub fn disable_scrubbing(&self, node: NodeId) {
+        info!("Disabling scrubbing on node {}", node.0);
+        self.nodes[node.0].scrubber.lock().stop();
+    }
+
+    pub fn scrubbing_enabled(&self, node: NodeId) -> bool {
+        self.nodes[node.0].scrubber.lock().is_running()
+    }
+
+    /// Result of the most recent scrubbing pass over the node, by
+    /// either its thread or scrub.
+    pub fn scrub_report(&self, node: NodeId) -> Option<ScrubReport> {
+        self.nodes[node.0].scrubber.lock().last_report()
+    }
+
+    /// Remove expired objects on the node, on the calling thread.
+    /// Returns the number removed. See the sweep module.
+    pub fn sweep(&self, node: NodeId) -> usize {
+        self.nodes[node.0].sweeper.lock().sweep()
+    }
+
+    /// Start a low-priority thread on the node which periodically
+    /// removes expired objects.
+    pub fn enable_sweeping(&self, node: NodeId) {
+        info!("Enabling sweeping on node {}", node.0);
+        self.nodes[node.0].sweeper.lock().spawn();
+    }
+
+    /// Stop the node's sweeper thread, waiting for it to exit.
+    pub fn disable_sweeping(&self, node: NodeId) {
+        info!("Disabling sweeping on node {}", node.0);
+        self.nodes[node.0].sweeper.lock().stop();
+    }
+
+    pub fn sweeping_enabled(&self, node: NodeId) -> bool {
+        self.nodes[node.0].sweeper.lock().is_running()
+    }
+
+    /// Invoke f on each live, unexpired object on the socket while
+    /// holding the object's key locked in the index, so it is neither
+    /// relocated nor overwritten meanwhile. Stops at the first error
+    /// f returns. Caller must have the epoch pinned.
+    fn for_each_live<F>(&self, socket: usize, mut f: F) -> Status
+        where F: FnMut(&KeyType, &EntryReference) -> Status {
+
+        let node = &self.nodes[socket];
+        let mut key: Vec<u8> = Vec::with_capacity(64);
+        let mut ret: Status = Ok(1);
+        // Objects may be moved or written into segments created after
+        // we listed them, so look again for new ones.
+        let mut seen: HashSet<usize> = HashSet::new();
+        let mut pass = 0;
+        loop {
+            let segs: Vec<SegmentRef> = node.manager.segments()
+                .into_iter()
+                .filter(|s| !seen.contains(&s.read().id()))
+                .collect();
+            if segs.is_empty() {
+                break;
+            }
+            if pass == LIVE_PASSES {
+                warn!("socket {} still has {} new segments",
+                      socket, segs.len());
+                break;
+            }
+            for segref in segs {
+                let seg = segref.read();
+                seen.insert(seg.id());
+                for entry in seg.into_iter() {
+                    unsafe { entry.get_key(&mut key); }
+                    let ientry = merge(socket as u16,
+                                       entry.get_loc() as u64);
+                    self.index.lock_map_ifex(&key, &|e| e == ientry,
+                        &mut |e| if e == ientry && !entry.is_expired() {
+                            ret = f(&key, &entry.reload());
+                        });
+                    if ret.is_err() {
+                        return ret;
+                    }
+                }
+            }
+            pass += 1;
+        }
+        ret
+    }
+
+    /// A hash index stores only hashes of keys. It uses this to check
+    /// whether an entry with a matching hash holds our key, in our
+    /// namespace.
+    #[inline(always)]
+    fn is_key(&self, ientry: IndexEntry, ns: NsId,
+              key: &KeyType) -> bool {
+        let (socket,va) = extract(ientry);
+        self.nodes[socket as usize].log.key_matches(va as usize, ns, key)
+    }
+
+    //
+    // Lower-level allocation API
+    //
+
+    // Insert key into index; write object header into log, but
+    // do not write actual object itself.
+    // This version of alloc acts like an explicit insert.
+    // NOTE: this is only used for the shuffle benchmark for now.
+    // It will block if key already exists, and fail if key wasn't
+    // able to insert, or was an update
+    #[cfg(IGNORE)]
+    pub fn alloc(&self, key: u64, len: u64, sock: u32) -> Pointer<u8> {
+        // meta::pin();
+        let va: usize;
+
+        let obj = ObjDesc::null(key, len as usize);
+
+        // make sure we wait for key to be deleted before adding again
+        while let Some(_) = self.index.get(obj.key) {
+            ;
+        }
+
+        let res = self.nodes[sock as usize].log.append(&obj);
+        debug_assert_eq!(res.is_ok(), true);
+        va = res.unwrap();
+
+        let ientry = merge(sock as u16, va as u64);
+
+        // 2. update reference to object
+        let (ok,opt) = self.index.update(obj.key, ientry);
+        // verify this inserted, and was not an update
+        debug_assert_eq!(ok, true,
+                        "Table insert failed; can it resize?");
+        // XXX
+        debug_assert_eq!(opt.is_none(), true);
+
+        // meta::quiesce();
+        Pointer(va as *const u8)
+    }
+
+    // Delete key from index.
+    #[cfg(IGNORE)]
+    pub fn free(&self, key: u64) -> bool {
+        // we need to pin the epoch, because we use the VA to lookup
+        // the containing segment
+        meta::pin();
+
+        // 1. remove key and acquire old
+        let res = self.index.remove(key);
+        if res.is_none() {
+            return false;
+        }
+        let ientry: IndexEntry = res.unwrap();
+        let (socket,va) = extract(ientry);
+
+        // 2. read the size of the object
+        let node = &self.nodes[socket as usize];
+        let head = node.log.copy_header(va as usize);
+
+        // 3. decrement live size of segment
+        let idx: usize = node.manager.segment_of(va as usize);
+        node.seginfo.decr_live(idx, head.len_with_header());
+
+        meta::quiesce();
+        true
+    }
+
+    pub fn pin(key: u64) {
+        unimplemented!();
+    }
+
+    pub fn unpin(key: u64) {
+        unimplemented!();
+    }
+
+    //
+    // Misc. methods
+    //
+
+    #[cfg(test)]
+    pub fn nlive(&self) -> usize {
+        self.index.len()
+    }
+
+    #[cfg(IGNORE)]
+    pub fn dump_seg_info(&self) {
+        for node in &self.nodes {
+            node.manager.read().dump_seg_info();
+        }
+    }
+
+    // hack
+    #[cfg(IGNORE)]
+    #[inline(always)]
     pub fn seg_of(&mut self, key: u64) -> Option<usize> {
         meta::pin();
         let va: usize;
@@ -601,10 +1727,1734 @@ impl LSM {
 
 }
 
+/// Smallest key greater than all keys beginning with prefix, or None
+/// if no such key exists (prefix is empty or all 0xff).
+fn prefix_end(prefix: &KeyType) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(b) = end.pop() {
+        if b < 0xff {
+            end.push(b + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Compaction threads hold references to the segment managers; stop
+/// them first so that dropping the nodes releases all memory.
+impl Drop for LSM {
+
+    fn drop(&mut self) {
+        for node in &self.nodes {
+            node.scrubber.lock().stop();
+            node.sweeper.lock().stop();
+            node.compactor.lock().stop();
+        }
+    }
+}
+
 //==----------------------------------------------------==//
-//      Unit tests
+//      Write batches
 //==----------------------------------------------------==//
 
+/// Puts and deletes to be applied together by LSM::write. The batch
+/// refers to the callers' keys and values rather than copying them.
+/// Keys are told apart by namespace as well as by their bytes.
+pub struct WriteBatch<'a> {
+    /// The namespace and key of each operation, and an object to put,
+    /// or None to delete the key
+    ops: Vec<(NsId, &'a KeyType, Option<ObjDesc<'a>>)>,
+    /// Position in ops of the operation on each key
+    pos: HashMap<(NsId, &'a KeyType), usize>,
+}
+
+impl<'a> WriteBatch<'a> {
+
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new(), pos: HashMap::new() }
+    }
+
+    /// Add a put, replacing any earlier operation on the same key.
+    /// The object goes to the namespace it was given, if any (see
+    /// put_in).
+    pub fn put(&mut self, obj: ObjDesc<'a>) {
+        let (ns, key) = (obj.ns, obj.key);
+        self.set(ns, key, Some(obj));
+    }
+
+    /// Add a delete, replacing any earlier operation on the same key.
+    /// Deleting a key which is not present is not an error.
+    pub fn delete(&mut self, key: &'a KeyType) {
+        self.set(DEFAULT_NS, key, None);
+    }
+
+    /// As put, within the namespace.
+    pub fn put_in(&mut self, ns: &Namespace, obj: ObjDesc<'a>) {
+        self.put(obj.with_ns(ns.id()));
+    }
+
+    /// As delete, within the namespace.
+    pub fn delete_in(&mut self, ns: &Namespace, key: &'a KeyType) {
+        self.set(ns.id(), key, None);
+    }
+
+    fn set(&mut self, ns: NsId, key: &'a KeyType, op: Option<ObjDesc<'a>>) {
+        if let Some(&i) = self.pos.get(&(ns, key)) {
+            self.ops[i].2 = op;
+            return;
+        }
+        self.pos.insert((ns, key), self.ops.len());
+        self.ops.push((ns, key, op));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.pos.clear();
+    }
+}
+
+//==----------------------------------------------------==//
+//      Namespaces
+//==----------------------------------------------------==//
+
+/// A handle to one namespace of an LSM instance. See LSM::namespace
+pub struct Namespace<'a> {
+    lsm: &'a LSM,
+    info: Arc<NsInfo>,
+}
+
+impl<'a> Namespace<'a> {
+
+    pub fn name(&self) -> &str {
+        self.info.name()
+    }
+
+    pub fn id(&self) -> NsId {
+        self.info.id()
+    }
+
+    /// Log memory held by objects of the namespace, headers included.
+    pub fn live_bytes(&self) -> usize {
+        self.info.live()
+    }
+
+    /// Limit in bytes of live_bytes, or None if unlimited.
+    pub fn quota(&self) -> Option<usize> {
+        match self.info.quota() {
+            usize::MAX => None,
+            q => Some(q),
+        }
+    }
+
+    /// Limit the log memory the namespace may hold. Puts which would
+    /// exceed it fail with QuotaExceeded; note that an object is
+    /// charged before the one it replaces is credited. Lowering the
+    /// quota below live_bytes removes nothing.
+    pub fn set_quota(&self, bytes: Option<usize>) {
+        self.info.set_quota(bytes.unwrap_or(usize::MAX));
+    }
+
+    #[inline(always)]
+    fn check(&self) -> Result<(),ErrorCode> {
+        if self.info.is_dropped() {
+            Err(ErrorCode::NamespaceDropped)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// As LSM::put_object, within the namespace.
+    pub fn put_object(&self, obj: &ObjDesc) -> Status {
+        self.check()?;
+        let obj = obj.with_ns(self.id());
+        self.lsm.__put(&obj, self.lsm.default_policy())
+    }
+
+    /// As LSM::get_object, within the namespace.
+    pub fn get_object(&self, key: &KeyType, buf: &mut [u8]) -> Status {
+        self.check()?;
+        self.lsm.get_in(self.id(), key, buf)
+    }
+
+    /// As LSM::del_object, within the namespace.
+    pub fn del_object(&self, key: &KeyType) -> Status {
+        self.check()?;
+        self.lsm.del_cond(self.id(), key, Cond::Always).map(|_| 1)
+    }
+
+    pub fn exists(&self, key: &KeyType) -> bool {
+        self.check().is_ok() && self.lsm.exists_in(self.id(), key)
+    }
+}
+
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use common::*;
+    use config::LSMConfig;
+    use logger;
+    use segment::block_size;
+    use rand::{self,Rng};
+
+    use std::env;
+    use std::fs::{self,File,OpenOptions};
+    use std::io::{Read,Write};
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool,Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn small() -> LSM {
+        LSM::with_config(LSMConfig::small()).unwrap()
+    }
+
+    /// Socket and segment index holding the object of the key.
+    fn segment_of(kvs: &LSM, key: &KeyType) -> (usize,usize) {
+        let ep = PinnedEpoch::new();
+        let opt = kvs.index.get(key, &|e| kvs.is_key(e, DEFAULT_NS, key));
+        assert!(opt.is_some(), "key {:?} not in index", key);
+        let (socket,va) = extract(opt.unwrap());
+        let socket = socket as usize;
+        (socket, kvs.nodes[socket].manager.segment_of(va as usize))
+    }
+
+    // test with one simple object
+    #[test]
+    fn simple() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"simple";
+        let value: Vec<u8> = vec![1u8,2,3,4,5];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), value.len());
+        assert!(kvs.put_object(&obj).is_ok());
+
+        let mut buf: Vec<u8> = vec![0u8; 16];
+        assert_eq!(kvs.get_object(key, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], &value[..]);
+
+        assert!(kvs.del_object(key).is_ok());
+        assert_eq!(kvs.del_object(key), Err(ErrorCode::KeyNotExist));
+        assert_eq!(kvs.get_object(key, &mut buf),
+                   Err(ErrorCode::KeyNotExist));
+    }
+
+    /// keys of any length name distinct objects, including keys which
+    /// are prefixes of others and keys which cross a block boundary
+    #[test]
+    fn variable_keys() {
+        logger::enable();
+        let kvs = small();
+
+        let long: Vec<u8> = (0..2 * block_size()).map(|i| i as u8)
+            .collect();
+        let keys: Vec<&KeyType> = vec![&b"k"[..], &b"ke"[..], &b"key"[..],
+            &b"key\0"[..], &long[..block_size() + 1], &long[..]];
+        for (i,key) in keys.iter().enumerate() {
+            let value: Vec<u8> = vec![i as u8; 8 + i];
+            let obj = ObjDesc::new(key, Pointer(value.as_ptr()), 8 + i);
+            assert!(kvs.put_object(&obj).is_ok());
+        }
+        let mut buf: Vec<u8> = vec![0u8; 64];
+        for (i,key) in keys.iter().enumerate() {
+            assert_eq!(kvs.get_object(key, &mut buf), Ok(8 + i));
+            assert!(buf[..8 + i].iter().all(|&b| b == i as u8));
+        }
+
+        // the log tells an entry's own key from all others
+        {
+            let ep = PinnedEpoch::new();
+            for key in &keys {
+                let e = kvs.index.get(key,
+                    &|e| kvs.is_key(e, DEFAULT_NS, key)).unwrap();
+                for other in &keys {
+                    assert_eq!(kvs.is_key(e, DEFAULT_NS, other),
+                               key == other);
+                }
+                assert!(!kvs.is_key(e, DEFAULT_NS + 1, key));
+            }
+        }
+
+        assert!(kvs.del_object(b"ke").is_ok());
+        assert!(!kvs.exists(b"ke"));
+        assert!(kvs.exists(b"k"));
+        assert!(kvs.exists(b"key"));
+
+        let obj = ObjDesc::new(b"", Pointer(buf.as_ptr()), 8);
+        assert_eq!(kvs.put_object(&obj), Err(ErrorCode::EmptyObject));
+        let big: Vec<u8> = vec![0u8; 2 * kvs.config.get_segment_size()];
+        let obj = ObjDesc::new(b"", Pointer(big.as_ptr()), big.len());
+        assert_eq!(kvs.put_object(&obj), Err(ErrorCode::EmptyObject));
+
+        // nothing in a batch with an empty key is applied
+        let mut batch = WriteBatch::new();
+        batch.put(ObjDesc::new(b"batched", Pointer(buf.as_ptr()), 8));
+        batch.put(ObjDesc::new(b"", Pointer(buf.as_ptr()), 8));
+        assert_eq!(kvs.write(&batch), Err(ErrorCode::EmptyObject));
+        assert!(!kvs.exists(b"batched"));
+    }
+
+    /// reads return the value's length, and copy nothing into a
+    /// buffer too small for it, whether the object lies within one
+    /// block or spans several
+    #[test]
+    fn read_lengths() {
+        logger::enable();
+        let kvs = small();
+
+        let lens = vec![100usize, 2 * block_size() + 100];
+        for (i,&len) in lens.iter().enumerate() {
+            let key = format!("read-{}", i);
+            let key = key.as_bytes();
+            let value: Vec<u8> = (0..len).map(|b| b as u8).collect();
+            let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+            assert!(kvs.put_object(&obj).is_ok());
+
+            let mut buf: Vec<u8> = vec![0xeeu8; len + 8];
+            assert_eq!(kvs.get_object(key, &mut buf), Ok(len));
+            assert_eq!(&buf[..len], &value[..]);
+            assert!(buf[len..].iter().all(|&b| b == 0xee));
+
+            let mut buf: Vec<u8> = vec![0xeeu8; len - 1];
+            assert_eq!(kvs.get_object(key, &mut buf),
+                Err(ErrorCode::BufferTooSmall { needed: len, len: len - 1 }));
+            assert!(buf.iter().all(|&b| b == 0xee));
+
+            // in place, in one piece per block
+            let read = kvs.read_object(key, |e| {
+                assert_eq!(e.datalen as usize, len);
+                e.data_chunks().fold(Vec::new(), |mut v, c| {
+                    v.extend_from_slice(c);
+                    v
+                })
+            });
+            assert_eq!(read, Ok(value));
+        }
+        assert_eq!(kvs.read_object(b"missing", |e| e.datalen),
+                   Err(ErrorCode::KeyNotExist));
+    }
+
+    // shove in the object multiple times to cross many blocks
+    #[test]
+    fn many_objects() {
+        logger::enable();
+        let kvs = small();
+        let mut rng = rand::thread_rng();
+
+        let value: Vec<u8> = (0..1600).map(|_| rng.gen::<u8>()).collect();
+        let vptr = Pointer(value.as_ptr());
+        let vlen = value.len();
+
+        let nobj = 2 * kvs.config().get_segment_size() / vlen;
+        info!("nobj {}", nobj);
+
+        for i in 0..nobj {
+            let k = (i+1) as u64;
+            let obj = ObjDesc::new(u64_key(&k), vptr, vlen);
+            assert!(kvs.put_object(&obj).is_ok());
+        }
+
+        let mut buf: Vec<u8> = vec![0u8; vlen];
+        for i in 0..nobj {
+            let k = (i+1) as u64;
+            assert_eq!(kvs.get_object(u64_key(&k), &mut buf), Ok(vlen));
+            assert_eq!(buf, value);
+        }
+
+        for i in 0..nobj {
+            let k = (i+1) as u64;
+            assert!(kvs.del_object(u64_key(&k)).is_ok());
+            assert_eq!(kvs.del_object(u64_key(&k)),
+                       Err(ErrorCode::KeyNotExist));
+        }
+
+        for i in 0..nobj {
+            let k = (i+1) as u64;
+            assert_eq!(kvs.get_object(u64_key(&k), &mut buf),
+                       Err(ErrorCode::KeyNotExist));
+        }
+    }
+
+    /// on init, epoch table should be zero
+    #[test]
+    fn epoch_0() {
+        logger::enable();
+        let kvs = small();
+
+        for node in &kvs.nodes {
+            for idx in 0..node.seginfo.len() {
+                assert_eq!(node.seginfo.get_live(idx), 0usize);
+                assert_eq!(node.seginfo.get_epoch(idx), 0usize);
+            }
+        }
+    }
+
+    /// add one item repeatedly and observe the live size of the
+    /// segment remains constant. upon rolling the head, check the
+    /// segment live size is zero and the new is updated
+    #[test]
+    fn epoch_1() {
+        logger::enable();
+        let kvs = small();
+        let seginfo = &kvs.nodes[0].seginfo;
+
+        let key = b"epoch_1";
+        let value: Vec<u8> = vec![1u8; 40];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), value.len());
+        let size = obj.len_with_header();
+
+        // do first insertion, grab head idx used
+        assert!(kvs.put_where(&obj, PutPolicy::Specific(0)).is_ok());
+        let (socket, head) = segment_of(&kvs, key);
+        assert_eq!(socket, 0);
+        assert_eq!(seginfo.get_live(head), size);
+
+        // insert until the head rolls
+        loop {
+            assert!(kvs.put_where(&obj, PutPolicy::Specific(0)).is_ok());
+            let (_, idx) = segment_of(&kvs, key);
+            assert_eq!(seginfo.get_live(idx), size);
+            if head != idx {
+                // head rolled. let's check prior segment live size
+                assert_eq!(seginfo.get_live(head), 0usize);
+                break;
+            }
+        }
+    }
+
+    /// add unique items, observe the live size of the segment grows
+    #[test]
+    fn epoch_2() {
+        logger::enable();
+        let kvs = small();
+        let seginfo = &kvs.nodes[0].seginfo;
+
+        let value: Vec<u8> = vec![2u8; 1600];
+        let vptr = Pointer(value.as_ptr());
+
+        let mut head: Option<usize> = None;
+        // accumulator excluding current obj
+        let mut total = 0usize;
+        for k in 1u64.. {
+            let obj = ObjDesc::new(u64_key(&k), vptr, value.len());
+            let len = obj.len_with_header();
+            assert!(kvs.put_where(&obj, PutPolicy::Specific(0)).is_ok());
+            let (_, idx) = segment_of(&kvs, u64_key(&k));
+            if head.is_none() {
+                head = Some(idx);
+            }
+            let first = head.unwrap();
+            if first != idx {
+                // head rolled. check old and new live sizes
+                assert_eq!(seginfo.get_live(first), total);
+                assert_eq!(seginfo.get_live(idx), len);
+                break;
+            }
+            total += len;
+            assert_eq!(seginfo.get_live(idx), total);
+        }
+    }
+
+    /// add/remove one item and observe the live size is set then zero
+    #[test]
+    fn epoch_3() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"epoch_3";
+        let value: Vec<u8> = vec![3u8; 40];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), value.len());
+        assert!(kvs.put_where(&obj, PutPolicy::Specific(0)).is_ok());
+
+        let (_, idx) = segment_of(&kvs, key);
+        assert_eq!(kvs.nodes[0].seginfo.get_live(idx),
+                   obj.len_with_header());
+
+        assert!(kvs.del_object(key).is_ok());
+        assert_eq!(kvs.nodes[0].seginfo.get_live(idx), 0usize);
+    }
+
+    /// empty values are stored and read back like any other, and
+    /// segments packed with them can be walked
+    #[test]
+    fn empty_value() {
+        logger::enable();
+        let kvs = small();
+
+        let value: Vec<u8> = Vec::new();
+        let vptr = Pointer(value.as_ptr());
+        let key = b"empty";
+        assert!(kvs.put_object(&ObjDesc::new(key, vptr, 0)).is_ok());
+
+        let mut buf: [u8; 0] = [];
+        assert_eq!(kvs.get_object(key, &mut buf), Ok(0));
+        assert_eq!(kvs.read_object(key, |e| e.datalen), Ok(0));
+        assert!(kvs.exists(key));
+        assert!(kvs.del_object(key).is_ok());
+        assert!(!kvs.exists(key));
+
+        // enough to close a few segments
+        let nobj = 4 * kvs.config().get_segment_size() /
+            ObjDesc::new(u64_key(&0), vptr, 0).len_with_header();
+        for k in 0..nobj as u64 {
+            let obj = ObjDesc::new(u64_key(&k), vptr, 0);
+            assert!(kvs.put_where(&obj, PutPolicy::Specific(0)).is_ok());
+        }
+        let report = kvs.scrub(NodeId(0));
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert!(report.segments > 0);
+        for k in 0..nobj as u64 {
+            assert_eq!(kvs.get_object(u64_key(&k), &mut buf), Ok(0));
+        }
+    }
+
+    /// an overwrite of the same length is made in place, others stay
+    /// on the socket of the object they replace, and live bytes
+    /// follow the object
+    #[test]
+    fn overwrite_policy() {
+        logger::enable();
+        let config = LSMConfig::small().overwrite(true);
+        let kvs = LSM::with_config(config).unwrap();
+        let ns = kvs.namespace("ow").unwrap();
+
+        let key = b"overwrite";
+        let a: Vec<u8> = vec![1u8; 64];
+        let b: Vec<u8> = vec![2u8; 64];
+        let c: Vec<u8> = vec![3u8; 200];
+        let d: Vec<u8> = vec![4u8; 32];
+        let put = |v: &Vec<u8>| {
+            ns.put_object(&ObjDesc::new(key, Pointer(v.as_ptr()), v.len()))
+        };
+        let at = || kvs.index.get(key, &|_| true);
+        assert_eq!(put(&a), Ok(1));
+        let live = ns.live_bytes();
+        let first = at();
+        assert_eq!(put(&b), Ok(1));
+        assert_eq!(at(), first);
+        assert_eq!(ns.live_bytes(), live);
+        let mut buf: Vec<u8> = vec![0u8; 200];
+        assert_eq!(ns.get_object(key, &mut buf), Ok(64));
+        assert_eq!(&buf[..64], &b[..]);
+        assert_eq!(put(&c), Ok(1));
+        assert!(at() != first);
+        assert_eq!(ns.live_bytes(), live + c.len() - a.len());
+        assert_eq!(ns.get_object(key, &mut buf), Ok(200));
+        assert_eq!(buf, c);
+        assert_eq!(put(&d), Ok(1));
+        assert_eq!(ns.live_bytes(), live + d.len() - a.len());
+        assert_eq!(ns.get_object(key, &mut buf), Ok(32));
+        assert_eq!(&buf[..32], &d[..]);
+
+        // per call, in the default keyspace
+        let socket = kvs.nnodes() - 1;
+        let obj = ObjDesc::new(key, Pointer(a.as_ptr()), 64);
+        assert!(kvs.put_where(&obj, PutPolicy::Specific(socket)).is_ok());
+        let obj = ObjDesc::new(key, Pointer(b.as_ptr()), 64);
+        assert!(kvs.put_where(&obj, PutPolicy::Overwrite).is_ok());
+        assert_eq!(segment_of(&kvs, key).0, socket);
+        assert_eq!(kvs.get_object_version(key, &mut buf), Ok((64, 2)));
+        assert_eq!(&buf[..64], &b[..]);
+    }
+
+    /// readers racing with overwrites see whole values only
+    #[test]
+    fn concurrent_overwrite() {
+        logger::enable();
+        let config = LSMConfig::small().overwrite(true);
+        let kvs = Arc::new(LSM::with_config(config).unwrap());
+
+        let key = b"overwritten";
+        let len = 1024usize;
+        let value: Vec<u8> = vec![0u8; len];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        assert!(kvs.put_object(&obj).is_ok());
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..2).map(|_| {
+            let (kvs, stop) = (kvs.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut buf: Vec<u8> = vec![0u8; len];
+                let mut n = 0usize;
+                while !stop.load(Ordering::Relaxed) {
+                    assert_eq!(kvs.get_object(key, &mut buf), Ok(len));
+                    assert!(buf.iter().all(|&b| b == buf[0]),
+                            "read a partly written value");
+                    n += 1;
+                }
+                n
+            })
+        }).collect();
+        let writers: Vec<_> = (0..2).map(|t| {
+            let kvs = kvs.clone();
+            thread::spawn(move || {
+                for i in 0..1000usize {
+                    let fill: Vec<u8> = vec![(t * 128 + i % 128) as u8; len];
+                    let obj = ObjDesc::new(key, Pointer(fill.as_ptr()), len);
+                    assert_eq!(kvs.put_object(&obj), Ok(1));
+                }
+            })
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            assert!(r.join().unwrap() > 0);
+        }
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(kvs.get_object_version(key, &mut buf), Ok((len, 2001)));
+    }
+
+    /// conditional operations take effect only on the expected
+    /// version, which each PUT of the key advances
+    #[test]
+    fn conditional_ops() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"cond";
+        let value: Vec<u8> = vec![0xaau8; 8];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), 8);
+
+        assert_eq!(kvs.put_if_version(&obj, 1),
+                   Err(ErrorCode::KeyNotExist));
+        assert_eq!(kvs.put_if_absent(&obj), Ok(1));
+        assert_eq!(kvs.put_if_absent(&obj), Err(ErrorCode::KeyExists));
+        assert!(kvs.put_object(&obj).is_ok());
+
+        let mut buf: [u8; 8] = [0; 8];
+        assert_eq!(kvs.get_object_version(key, &mut buf), Ok((8,2)));
+        assert_eq!(kvs.put_if_version(&obj, 1),
+            Err(ErrorCode::VersionMismatch { expected: 1, found: 2 }));
+        assert_eq!(kvs.put_if_version(&obj, 2), Ok(3));
+
+        assert_eq!(kvs.del_if_version(key, 2),
+            Err(ErrorCode::VersionMismatch { expected: 2, found: 3 }));
+        assert!(kvs.del_if_version(key, 3).is_ok());
+        assert_eq!(kvs.del_if_version(key, 3),
+                   Err(ErrorCode::KeyNotExist));
+    }
+
+    /// of threads racing to update a key from the version they read,
+    /// exactly one wins each version
+    #[test]
+    fn concurrent_put_if_version() {
+        logger::enable();
+        let kvs = Arc::new(small());
+
+        let key = b"contended";
+        let len = 64usize;
+        let value: Vec<u8> = vec![0xffu8; len];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        assert_eq!(kvs.put_if_absent(&obj), Ok(1));
+
+        let nthreads = 4usize;
+        let per = 500usize;
+        let threads: Vec<_> = (0..nthreads).map(|t| {
+            let kvs = kvs.clone();
+            thread::spawn(move || {
+                let fill: Vec<u8> = vec![t as u8; len];
+                let obj = ObjDesc::new(key, Pointer(fill.as_ptr()), len);
+                let mut buf: Vec<u8> = vec![0u8; len];
+                let mut won: Vec<Version> = Vec::with_capacity(per);
+                while won.len() < per {
+                    let (_,v) = kvs.get_object_version(key, &mut buf)
+                        .unwrap();
+                    match kvs.put_if_version(&obj, v) {
+                        Ok(next) => {
+                            assert_eq!(next, v + 1);
+                            won.push(next);
+                        },
+                        Err(ErrorCode::VersionMismatch{..}) => {},
+                        Err(code) => panic!("put failed: {}", code),
+                    }
+                }
+                won
+            })
+        }).collect();
+        let mut won: Vec<Version> = threads.into_iter()
+            .flat_map(|t| t.join().unwrap()).collect();
+        won.sort();
+        let last = (nthreads * per) as Version + 1;
+        assert_eq!(won, (2..last + 1).collect::<Vec<Version>>());
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(kvs.get_object_version(key, &mut buf), Ok((len, last)));
+    }
+
+    /// put_object moves on to other sockets as the nearest fills up,
+    /// and fails only once all of them are full
+    #[test]
+    fn nearest_fallback() {
+        logger::enable();
+        let kvs = small();
+
+        let len = 1usize << 14;
+        let value: Vec<u8> = vec![1u8; len];
+        let mut used: HashSet<usize> = HashSet::new();
+        let mut k = 0u64;
+        loop {
+            let obj = ObjDesc::new(u64_key(&k), Pointer(value.as_ptr()), len);
+            match kvs.put_object(&obj) {
+                Ok(_) => {},
+                Err(ErrorCode::OutOfMemory { socket: None }) => break,
+                Err(code) => panic!("put failed: {}", code),
+            }
+            used.insert(segment_of(&kvs, u64_key(&k)).0);
+            k += 1;
+        }
+        assert_eq!(used.len(), kvs.nnodes());
+        let obj = ObjDesc::new(u64_key(&k), Pointer(value.as_ptr()), len);
+        for socket in 0..kvs.nnodes() {
+            match kvs.put_where(&obj, PutPolicy::Specific(socket)) {
+                Err(ErrorCode::OutOfMemory{..}) => {},
+                r => panic!("socket {} not full: {:?}", socket, r),
+            }
+        }
+    }
+
+    /// an object larger than a segment gets a segment of its own,
+    /// which is accounted for and released as a unit
+    #[test]
+    fn obj_larger_than_segment() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"large";
+        let len = 2 * kvs.config().get_segment_size() + 1;
+        let value: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        assert!(kvs.put_where(&obj, PutPolicy::Specific(0)).is_ok());
+
+        let (_, idx) = segment_of(&kvs, key);
+        assert_eq!(kvs.nodes[0].seginfo.get_live(idx),
+                   obj.len_with_header());
+
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(kvs.get_object(key, &mut buf), Ok(len));
+        assert_eq!(buf, value);
+
+        assert!(kvs.del_object(key).is_ok());
+        assert_eq!(kvs.nodes[0].seginfo.get_live(idx), 0usize);
+    }
+
+    /// expired objects read as missing, may be replaced by a
+    /// conditional insert, and are removed by the sweeper
+    #[test]
+    fn ttl_expiry() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"ttl";
+        let value: Vec<u8> = vec![0xbbu8; 8];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), 8);
+        let ttl = Duration::from_millis(20);
+        assert!(kvs.put_object_ttl(&obj, ttl).is_ok());
+
+        let mut buf: [u8; 8] = [0; 8];
+        assert_eq!(kvs.get_object(key, &mut buf), Ok(8));
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(kvs.get_object(key, &mut buf),
+                   Err(ErrorCode::KeyNotExist));
+        assert!(!kvs.exists(key));
+        assert_eq!(kvs.put_if_absent(&obj), Ok(1));
+        assert_eq!(kvs.get_object(key, &mut buf), Ok(8));
+
+        // large objects sit in closed segments of their own
+        let key = b"ttl-large";
+        let len = 2 * kvs.config().get_segment_size() + 1;
+        let value: Vec<u8> = vec![0xccu8; len];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        assert!(kvs.put_object_ttl(&obj, ttl).is_ok());
+        thread::sleep(Duration::from_millis(40));
+        let swept: usize = (0..kvs.nnodes())
+            .map(|n| kvs.sweep(NodeId(n))).sum();
+        assert_eq!(swept, 1);
+        assert_eq!(kvs.del_object(key), Err(ErrorCode::KeyNotExist));
+    }
+
+    /// only objects put with a time-to-live hold an expiry in the log
+    #[test]
+    fn ttl_space() {
+        logger::enable();
+        let kvs = small();
+        let value: Vec<u8> = vec![0xddu8; 24];
+        let ttl = Duration::from_secs(60);
+        let len = |key: &KeyType| kvs.read_object(key, |e| e.len).unwrap();
+
+        let obj = ObjDesc::new(b"plain", Pointer(value.as_ptr()), 24);
+        assert!(kvs.put_object(&obj).is_ok());
+        assert_eq!(len(b"plain"), obj.len_with_header());
+        assert_eq!(kvs.read_object(b"plain", |e| e.expires), Ok(NEVER));
+
+        let obj = ObjDesc::new(b"timed", Pointer(value.as_ptr()), 24);
+        assert!(kvs.put_object_ttl(&obj, ttl).is_ok());
+        assert_eq!(len(b"timed"),
+                   obj.len_with_header() + size_of::<Expiry>());
+        let expires = kvs.read_object(b"timed", |e| e.expires).unwrap();
+        assert!(expires != NEVER && !expired_at(expires, clock::now()));
+        let mut buf: Vec<u8> = vec![0u8; 24];
+        assert_eq!(kvs.get_object(b"timed", &mut buf), Ok(24));
+        assert_eq!(buf, value);
+
+        // a plain put drops the expiry, and the space for it
+        assert!(kvs.put_object(&obj).is_ok());
+        assert_eq!(len(b"timed"), obj.len_with_header());
+        assert_eq!(kvs.read_object(b"timed", |e| e.expires), Ok(NEVER));
+    }
+
+    #[test]
+    fn obj_too_large() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"too large";
+        let obj = ObjDesc::null(key, kvs.capacity());
+        match kvs.put_where(&obj, PutPolicy::Specific(0)) {
+            Err(ErrorCode::ObjectTooBig { size, max }) => {
+                assert_eq!(size, obj.len_with_header());
+                assert!(max < size);
+            },
+            r => panic!("expected ObjectTooBig, got {:?}", r),
+        }
+    }
+
+    /// with eviction on, writing several times the capacity makes
+    /// room by dropping the oldest objects
+    #[test]
+    fn eviction() {
+        logger::enable();
+        let config = LSMConfig::small()
+            .compaction_ratio(0.5f64)
+            .eviction_ratio(0.25f64);
+        let kvs = LSM::with_config(config).unwrap();
+        for node in 0..kvs.nnodes() {
+            kvs.enable_compaction(NodeId(node));
+        }
+
+        let len = 1usize << 14;
+        let value: Vec<u8> = vec![7u8; len];
+        let v = Pointer(value.as_ptr());
+        let nobj = 3 * kvs.capacity() / len;
+        for k in 0..nobj as u64 {
+            let obj = ObjDesc::new(u64_key(&k), v, len);
+            loop {
+                match kvs.put_object(&obj) {
+                    Ok(_) => break,
+                    Err(ErrorCode::OutOfMemory{..}) => thread::yield_now(),
+                    Err(code) => panic!("put failed: {}", code),
+                }
+            }
+        }
+
+        let evicted: usize = kvs.stats().evicted.iter().sum();
+        assert!(evicted > 0);
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(kvs.get_object(u64_key(&0), &mut buf),
+                   Err(ErrorCode::KeyNotExist));
+        let last = (nobj - 1) as u64;
+        assert_eq!(kvs.get_object(u64_key(&last), &mut buf), Ok(len));
+        assert_eq!(buf, value);
+    }
+
+    #[test]
+    fn multi_ops() {
+        logger::enable();
+        let kvs = small();
+
+        let len = 100usize;
+        let values: Vec<Vec<u8>> = (0..16u8).map(|i| vec![i; len])
+            .collect();
+        let ids: Vec<u64> = (0..16u64).collect();
+        let keys: Vec<&KeyType> = ids.iter().map(|k| u64_key(k)).collect();
+        let objs: Vec<ObjDesc> = keys.iter().zip(&values)
+            .map(|(k,v)| ObjDesc::new(*k, Pointer(v.as_ptr()), len))
+            .collect();
+        for st in kvs.multi_put(&objs[..8]) {
+            assert_eq!(st, Ok(1));
+        }
+
+        // one buffer too short for its object
+        let mut storage: Vec<Vec<u8>> = vec![vec![0u8; len]; 16];
+        storage[3].truncate(len - 1);
+        let found = {
+            let mut bufs: Vec<&mut [u8]> = storage.iter_mut()
+                .map(|b| &mut b[..]).collect();
+            kvs.multi_get(&keys, &mut bufs)
+        };
+        for (i,st) in found.into_iter().enumerate() {
+            if i == 3 {
+                assert_eq!(st, Err(ErrorCode::BufferTooSmall {
+                    needed: len, len: len - 1 }));
+            } else if i < 8 {
+                assert_eq!(st, Ok(len));
+                assert_eq!(storage[i], values[i]);
+            } else {
+                assert_eq!(st, Err(ErrorCode::KeyNotExist));
+            }
+        }
+    }
+
+    #[test]
+    fn partial_updates() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"counter";
+        let zero = 40u64;
+        let obj = ObjDesc::new(key,
+                    Pointer(&zero as *const u64 as *const u8), 8);
+        assert!(kvs.put_object(&obj).is_ok());
+        assert_eq!(kvs.incr(key, 2), Ok(42));
+        assert_eq!(kvs.incr(key, -42), Ok(0));
+        assert_eq!(kvs.incr(b"missing", 1), Err(ErrorCode::KeyNotExist));
+
+        // within the value
+        assert_eq!(kvs.write_range(key, 0, &[1u8; 4]), Ok(8));
+        // past the end
+        assert_eq!(kvs.write_range(key, 10, &[2u8; 2]), Ok(12));
+        assert_eq!(kvs.append(key, &[3u8; 4]), Ok(16));
+        assert_eq!(kvs.incr(key, 1),
+                   Err(ErrorCode::NotCounter { len: 16 }));
+
+        let mut buf: Vec<u8> = vec![0u8; 16];
+        let (len, version) = kvs.get_object_version(key, &mut buf).unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(version, 6);
+        assert_eq!(buf, vec![1u8,1,1,1, 0,0,0,0, 0,0, 2,2, 3,3,3,3]);
+
+        // edits within the value are made where it lies
+        let at = kvs.index.get(key, &|_| true);
+        assert_eq!(kvs.write_range(key, 4, &[4u8; 4]), Ok(16));
+        assert_eq!(kvs.index.get(key, &|_| true), at);
+        assert_eq!(kvs.get_object_version(key, &mut buf), Ok((16, 7)));
+        assert_eq!(buf, vec![1u8,1,1,1, 4,4,4,4, 0,0, 2,2, 3,3,3,3]);
+        assert_eq!(kvs.append(key, &[5u8]), Ok(17));
+        assert!(kvs.index.get(key, &|_| true) != at);
+    }
+
+    /// edits racing with each other are all applied, and readers
+    /// never see one partly applied
+    #[test]
+    fn concurrent_edits() {
+        logger::enable();
+        let kvs = Arc::new(small());
+
+        let counter = b"counter";
+        let zero = 0u64;
+        let obj = ObjDesc::new(counter,
+                    Pointer(&zero as *const u64 as *const u8), 8);
+        assert!(kvs.put_object(&obj).is_ok());
+        let key = b"filled";
+        let len = 1024usize;
+        let value: Vec<u8> = vec![0u8; len];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        assert!(kvs.put_object(&obj).is_ok());
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..2).map(|_| {
+            let (kvs, stop) = (kvs.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut buf: Vec<u8> = vec![0u8; len];
+                let mut n = 0usize;
+                while !stop.load(Ordering::Relaxed) {
+                    assert_eq!(kvs.get_object(key, &mut buf), Ok(len));
+                    assert!(buf.iter().all(|&b| b == buf[0]),
+                            "read a partly written value");
+                    n += 1;
+                }
+                n
+            })
+        }).collect();
+        let nincr = 500u64;
+        let writers: Vec<_> = (0..4).map(|t| {
+            let kvs = kvs.clone();
+            thread::spawn(move || {
+                for i in 0..nincr {
+                    assert!(kvs.incr(counter, 1).is_ok());
+                    let fill: Vec<u8> = vec![(t * 64 + i % 64) as u8; len];
+                    assert_eq!(kvs.write_range(key, 0, &fill), Ok(len));
+                }
+            })
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            assert!(r.join().unwrap() > 0);
+        }
+
+        assert_eq!(kvs.incr(counter, 0), Ok(4 * nincr));
+    }
+
+    /// no edit made in place is lost when compaction moves the object
+    /// at the same time
+    #[test]
+    fn edits_under_compaction() {
+        logger::enable();
+        let kvs = Arc::new(small());
+        for node in 0..kvs.nnodes() {
+            kvs.enable_compaction(NodeId(node));
+        }
+
+        let nkeys = 1000u64;
+        let nchurn = 64u64;
+        let big = 1usize << 14;
+        let zero = 0u64;
+        let filler: Vec<u8> = vec![0xffu8; big];
+        // interleave the counters with objects overwritten below, so
+        // that compaction has to move the former
+        for k in 0..nkeys {
+            let obj = ObjDesc::new(u64_key(&k),
+                        Pointer(&zero as *const u64 as *const u8), 8);
+            assert!(kvs.put_object(&obj).is_ok());
+            let c = nkeys + k % nchurn;
+            let obj = ObjDesc::new(u64_key(&c), Pointer(filler.as_ptr()), big);
+            assert!(kvs.put_object(&obj).is_ok());
+        }
+        let before: Vec<(usize,usize)> = (0..nkeys)
+            .map(|k| segment_of(&kvs, u64_key(&k))).collect();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let churn = {
+            let (kvs, stop) = (kvs.clone(), stop.clone());
+            thread::spawn(move || {
+                let filler: Vec<u8> = vec![0xffu8; big];
+                let mut i = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    let c = nkeys + i % nchurn;
+                    let obj = ObjDesc::new(u64_key(&c),
+                                           Pointer(filler.as_ptr()), big);
+                    match kvs.put_object(&obj) {
+                        Ok(_) => i += 1,
+                        Err(ErrorCode::OutOfMemory{..}) =>
+                            thread::yield_now(),
+                        Err(code) => panic!("put failed: {}", code),
+                    }
+                }
+            })
+        };
+
+        let mut rounds = 0u64;
+        let mut moved = false;
+        while !moved || rounds < 3 {
+            assert!(rounds < 1000, "compaction moved nothing");
+            rounds += 1;
+            for k in 0..nkeys {
+                assert_eq!(kvs.incr(u64_key(&k), 1), Ok(rounds));
+            }
+            moved = moved || (0..nkeys).any(|k|
+                segment_of(&kvs, u64_key(&k)) != before[k as usize]);
+        }
+        stop.store(true, Ordering::Relaxed);
+        churn.join().unwrap();
+        for k in 0..nkeys {
+            assert_eq!(kvs.incr(u64_key(&k), 0), Ok(rounds));
+        }
+    }
+
+    #[test]
+    fn write_batch() {
+        logger::enable();
+        let kvs = small();
+
+        let len = 64usize;
+        let a: Vec<u8> = vec![1u8; len];
+        let b: Vec<u8> = vec![2u8; len];
+        let pa = Pointer(a.as_ptr());
+        let pb = Pointer(b.as_ptr());
+        let (k1, k2, k3): (&KeyType, &KeyType, &KeyType) =
+            (b"k1", b"k2", b"k3");
+        assert!(kvs.put_object(&ObjDesc::new(k3, pa, len)).is_ok());
+
+        let mut batch = WriteBatch::new();
+        batch.put(ObjDesc::new(k1, pa, len));
+        batch.put(ObjDesc::new(k2, pa, len));
+        // replaces the put above
+        batch.put(ObjDesc::new(k2, pb, len));
+        batch.delete(k3);
+        assert_eq!(batch.len(), 3);
+        assert_eq!(kvs.write(&batch), Ok(3));
+
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(kvs.get_object(k1, &mut buf), Ok(len));
+        assert_eq!(buf, a);
+        assert_eq!(kvs.get_object(k2, &mut buf), Ok(len));
+        assert_eq!(buf, b);
+        assert!(!kvs.exists(k3));
+
+        // versions advance as for single puts
+        batch.clear();
+        batch.put(ObjDesc::new(k1, pb, len));
+        assert_eq!(kvs.write(&batch), Ok(1));
+        assert_eq!(kvs.get_object_version(k1, &mut buf), Ok((len, 2)));
+    }
+
+    /// the same key in different namespaces names different objects
+    /// within a batch, too
+    #[test]
+    fn write_batch_namespaces() {
+        logger::enable();
+        let kvs = small();
+        let ns = kvs.namespace("batch").unwrap();
+
+        let len = 64usize;
+        let a: Vec<u8> = vec![1u8; len];
+        let b: Vec<u8> = vec![2u8; len];
+        let pa = Pointer(a.as_ptr());
+        let pb = Pointer(b.as_ptr());
+        let (k1, k2): (&KeyType, &KeyType) = (b"k1", b"k2");
+        assert!(kvs.put_object(&ObjDesc::new(k2, pa, len)).is_ok());
+        assert!(ns.put_object(&ObjDesc::new(k2, pa, len)).is_ok());
+
+        let mut batch = WriteBatch::new();
+        batch.put(ObjDesc::new(k1, pa, len));
+        batch.put_in(&ns, ObjDesc::new(k1, pb, len));
+        batch.delete_in(&ns, k2);
+        assert_eq!(batch.len(), 3);
+        // replaces only the put in the default namespace
+        batch.delete(k1);
+        assert_eq!(batch.len(), 3);
+        assert_eq!(kvs.write(&batch), Ok(3));
+
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert!(!kvs.exists(k1));
+        assert_eq!(ns.get_object(k1, &mut buf), Ok(len));
+        assert_eq!(buf, b);
+        assert_eq!(kvs.get_object(k2, &mut buf), Ok(len));
+        assert_eq!(buf, a);
+        assert!(!ns.exists(k2));
+
+        assert_eq!(kvs.drop_namespace("batch"), Ok(1));
+        assert_eq!(kvs.write(&batch), Err(ErrorCode::NamespaceDropped));
+    }
+
+    /// readers never see part of a batch: of two keys every batch
+    /// writes together, the one read second is never older than the
+    /// one read first, whichever order they are read in
+    #[test]
+    fn concurrent_write_batch() {
+        logger::enable();
+        let kvs = Arc::new(small());
+
+        let len = 64usize;
+        let (k1, k2): (&'static KeyType, &'static KeyType) =
+            (b"k1", b"k2");
+        let nbatches = 2000usize;
+        let write = |i: usize| {
+            let fill: Vec<u8> = vec![i as u8; len];
+            let p = Pointer(fill.as_ptr());
+            let mut batch = WriteBatch::new();
+            batch.put(ObjDesc::new(k1, p, len));
+            batch.put(ObjDesc::new(k2, p, len));
+            assert_eq!(kvs.write(&batch), Ok(2));
+        };
+        write(0);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let orders: Vec<(&KeyType,&KeyType)> = vec![(k1, k2), (k2, k1)];
+        let readers: Vec<_> = orders.into_iter().map(|(first, second)| {
+            let (kvs, stop) = (kvs.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut buf: Vec<u8> = vec![0u8; len];
+                let mut n = 0usize;
+                while !stop.load(Ordering::Relaxed) {
+                    let (_,v1) = kvs.get_object_version(first, &mut buf)
+                        .unwrap();
+                    let (_,v2) = kvs.get_object_version(second, &mut buf)
+                        .unwrap();
+                    assert!(v2 >= v1, "saw part of a batch: {:?} at {} \
+                            then {:?} at {}", first, v1, second, v2);
+                    n += 1;
+                }
+                n
+            })
+        }).collect();
+        for i in 1..nbatches {
+            write(i);
+        }
+        stop.store(true, Ordering::Relaxed);
+        for r in readers {
+            assert!(r.join().unwrap() > 0);
+        }
+        let mut buf: Vec<u8> = vec![0u8; len];
+        for key in &[k1, k2] {
+            assert_eq!(kvs.get_object_version(key, &mut buf),
+                       Ok((len, nbatches as Version)));
+        }
+    }
+
+    #[test]
+    fn namespaces() {
+        logger::enable();
+        let kvs = small();
+
+        let len = 1usize << 10;
+        let a: Vec<u8> = vec![1u8; len];
+        let b: Vec<u8> = vec![2u8; len];
+        let key = b"shared";
+        let obja = ObjDesc::new(key, Pointer(a.as_ptr()), len);
+        let objb = ObjDesc::new(key, Pointer(b.as_ptr()), len);
+
+        let nsa = kvs.namespace("a").unwrap();
+        let nsb = kvs.namespace("b").unwrap();
+        assert!(nsa.id() != nsb.id());
+        assert!(nsa.put_object(&obja).is_ok());
+        assert!(nsb.put_object(&objb).is_ok());
+        assert!(!kvs.exists(key));
+
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(nsa.get_object(key, &mut buf), Ok(len));
+        assert_eq!(buf, a);
+        assert_eq!(nsb.get_object(key, &mut buf), Ok(len));
+        assert_eq!(buf, b);
+
+        // the quota counts whole entries
+        let used = nsa.live_bytes();
+        assert_eq!(used, obja.len_with_header());
+        nsa.set_quota(Some(used + len));
+        assert_eq!(nsa.quota(), Some(used + len));
+        match nsa.put_object(&ObjDesc::new(b"other",
+                             Pointer(a.as_ptr()), len)) {
+            Err(ErrorCode::QuotaExceeded{..}) => {},
+            r => panic!("expected QuotaExceeded, got {:?}", r),
+        }
+        // the refused object was never charged
+        assert_eq!(nsa.live_bytes(), used);
+        assert_eq!(nsa.del_object(key), Ok(1));
+        assert_eq!(nsa.live_bytes(), 0);
+        assert!(nsb.exists(key));
+
+        assert_eq!(kvs.drop_namespace("b"), Ok(1));
+        assert_eq!(nsb.get_object(key, &mut buf),
+                   Err(ErrorCode::NamespaceDropped));
+        assert_eq!(kvs.drop_namespace("b"), Err(ErrorCode::KeyNotExist));
+        let nsb = kvs.namespace("b").unwrap();
+        assert!(!nsb.exists(key));
+    }
+
+    /// the ordered index keys objects by their bytes alone
+    #[test]
+    fn namespaces_need_hash_index() {
+        logger::enable();
+        let config = LSMConfig::small().index(IndexKind::Ordered);
+        let kvs = LSM::with_config(config).unwrap();
+        match kvs.namespace("a") {
+            Err(ErrorCode::InvalidConfig) => {},
+            r => panic!("expected InvalidConfig, got {:?}",
+                        r.map(|ns| ns.id())),
+        }
+    }
+
+    /// a scan pages through more keys than one index batch, in
+    /// order, skipping removed keys and stopping when asked
+    #[test]
+    fn scan_ordered() {
+        logger::enable();
+        let config = LSMConfig::small().index(IndexKind::Ordered);
+        let kvs = LSM::with_config(config).unwrap();
+        let n = SCAN_BATCH * 4 + 1;
+        let mut keys: Vec<Vec<u8>> = (0..n)
+            .map(|i| format!("user:{:04}", i).into_bytes()).collect();
+        rand::thread_rng().shuffle(&mut keys);
+        for k in &keys {
+            let obj = ObjDesc::new(k, Pointer(k.as_ptr()), k.len());
+            assert!(kvs.put_object(&obj).is_ok());
+        }
+        for k in &[&b"user"[..], b"user;", b"other"] {
+            let obj = ObjDesc::new(k, Pointer(k.as_ptr()), k.len());
+            assert!(kvs.put_object(&obj).is_ok());
+        }
+        keys.sort();
+        let gone = keys.remove(SCAN_BATCH);
+        assert!(kvs.del_object(&gone).is_ok());
+
+        let mut found: Vec<Vec<u8>> = vec![];
+        let visited = kvs.scan_prefix(b"user:", |key, entry| {
+            let mut value: Vec<u8> = vec![0u8; entry.datalen as usize];
+            unsafe { entry.get_data(value.as_mut_ptr()); }
+            assert_eq!(&value[..], key);
+            found.push(key.to_vec());
+            true
+        });
+        assert_eq!(visited, Ok(n - 1));
+        assert_eq!(found, keys);
+
+        found.clear();
+        let visited = kvs.scan(&keys[10], Some(&keys[20][..]), |key, _| {
+            found.push(key.to_vec());
+            found.len() < 5
+        });
+        assert_eq!(visited, Ok(5));
+        assert_eq!(&found[..], &keys[10..15]);
+    }
+
+    /// objects nearly a segment long still go through the log heads
+    #[test]
+    fn large_objs() {
+        logger::enable();
+        let kvs = small();
+
+        let key = b"nearly a segment";
+        let len = kvs.config().get_segment_size() - block_size();
+        let value: Vec<u8> = vec![9u8; len];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        for _ in 0..4 {
+            assert!(kvs.put_object(&obj).is_ok());
+        }
+        let mut buf: Vec<u8> = vec![0u8; len];
+        assert_eq!(kvs.get_object(key, &mut buf), Ok(len));
+        assert_eq!(buf, value);
+    }
+
+    fn tmpdir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nibble-lsm-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf) -> LSM {
+        LSM::open_with_config(dir.to_str().unwrap(), LSMConfig::small())
+            .unwrap()
+    }
+
+    /// Generation of the write-ahead log named by CURRENT.
+    fn wal_gen(dir: &PathBuf) -> usize {
+        let mut s = String::new();
+        File::open(dir.join("CURRENT")).unwrap()
+            .read_to_string(&mut s).unwrap();
+        s.trim().parse::<usize>().unwrap()
+    }
+
+    /// Value and version of the key, or the error reading it.
+    fn fetch(kvs: &LSM, key: &KeyType)
+        -> Result<(Vec<u8>,Version),ErrorCode> {
+        let mut buf: Vec<u8> = vec![0u8; 256];
+        let (len, version) = kvs.get_object_version(key, &mut buf)?;
+        buf.truncate(len);
+        Ok((buf, version))
+    }
+
+    /// updates made before an instance is dropped are all there,
+    /// with their versions, when the directory is opened again
+    #[test]
+    fn wal_reopen() {
+        logger::enable();
+        let dir = tmpdir("wal-reopen");
+        let len = 100usize;
+        let batched: Vec<u8> = vec![0xaau8; len];
+        let counter = 5u64;
+        let gone = 40u64;
+        {
+            let kvs = open(&dir);
+            for k in 0..64u64 {
+                let value: Vec<u8> = vec![k as u8; len];
+                let obj = ObjDesc::new(u64_key(&k),
+                                       Pointer(value.as_ptr()), len);
+                assert!(kvs.put_object(&obj).is_ok());
+            }
+            for k in 0..16u64 {
+                let value: Vec<u8> = vec![(k + 100) as u8; len];
+                let obj = ObjDesc::new(u64_key(&k),
+                                       Pointer(value.as_ptr()), len);
+                assert!(kvs.put_object(&obj).is_ok());
+            }
+            for k in 16..32u64 {
+                assert!(kvs.del_object(u64_key(&k)).is_ok());
+            }
+            let obj = ObjDesc::new(b"counter",
+                Pointer(&counter as *const u64 as *const u8), 8);
+            assert!(kvs.put_object(&obj).is_ok());
+            assert_eq!(kvs.incr(b"counter", 3), Ok(8));
+
+            let mut batch = WriteBatch::new();
+            batch.put(ObjDesc::new(b"batched",
+                                   Pointer(batched.as_ptr()), len));
+            batch.delete(u64_key(&gone));
+            assert_eq!(kvs.write(&batch), Ok(2));
+            assert!(kvs.sync().is_ok());
+        }
+
+        let kvs = open(&dir);
+        for k in 0..16u64 {
+            assert_eq!(fetch(&kvs, u64_key(&k)),
+                       Ok((vec![(k + 100) as u8; len], 2)));
+        }
+        for k in 16..32u64 {
+            assert!(!kvs.exists(u64_key(&k)));
+        }
+        for k in 32..64u64 {
+            if k == gone {
+                assert!(!kvs.exists(u64_key(&k)));
+            } else {
+                assert_eq!(fetch(&kvs, u64_key(&k)),
+                           Ok((vec![k as u8; len], 1)));
+            }
+        }
+        assert_eq!(fetch(&kvs, b"counter"),
+                   Ok((u64_key(&8).to_vec(), 2)));
+        assert_eq!(fetch(&kvs, b"batched"), Ok((batched.clone(), 1)));
+    }
+
+    /// a checkpoint moves the log to a new generation, which is what
+    /// a later open reads back
+    #[test]
+    fn wal_checkpoint() {
+        logger::enable();
+        let dir = tmpdir("wal-checkpoint");
+        let len = 100usize;
+        {
+            let kvs = open(&dir);
+            for round in 0..2u8 {
+                for k in 0..32u64 {
+                    let value: Vec<u8> = vec![round; len];
+                    let obj = ObjDesc::new(u64_key(&k),
+                                           Pointer(value.as_ptr()), len);
+                    assert!(kvs.put_object(&obj).is_ok());
+                }
+            }
+            let gen = wal_gen(&dir);
+            assert!(kvs.wal.as_ref().unwrap().checkpoint().is_ok());
+            assert_eq!(wal_gen(&dir), gen + 1);
+            for socket in 0..kvs.nnodes() {
+                assert!(!dir.join(format!("wal.{}.{}", gen, socket))
+                        .exists());
+                assert!(dir.join(format!("wal.{}.{}", gen + 1, socket))
+                        .exists());
+            }
+            // logged to the new generation
+            assert!(kvs.del_object(u64_key(&5)).is_ok());
+        }
+
+        let kvs = open(&dir);
+        for k in 0..32u64 {
+            if k == 5 {
+                assert!(!kvs.exists(u64_key(&k)));
+            } else {
+                assert_eq!(fetch(&kvs, u64_key(&k)),
+                           Ok((vec![1u8; len], 2)));
+            }
+        }
+    }
+
+    /// updates logged while a checkpoint runs are carried into the
+    /// new generation
+    #[test]
+    fn wal_checkpoint_concurrent() {
+        logger::enable();
+        let dir = tmpdir("wal-checkpoint-concurrent");
+        let nkeys = 64u64;
+        let rounds = 64u8;
+        {
+            let kvs = Arc::new(open(&dir));
+            let writer = {
+                let kvs = kvs.clone();
+                thread::spawn(move || {
+                    for round in 0..rounds {
+                        for k in 0..nkeys {
+                            let value: Vec<u8> = vec![round; 100];
+                            let obj = ObjDesc::new(u64_key(&k),
+                                Pointer(value.as_ptr()), value.len());
+                            assert!(kvs.put_object(&obj).is_ok());
+                        }
+                    }
+                })
+            };
+            let gen = wal_gen(&dir);
+            let mut n = 0;
+            while n < 4 || Arc::strong_count(&kvs) > 1 {
+                assert!(kvs.wal.as_ref().unwrap().checkpoint().is_ok());
+                n += 1;
+            }
+            writer.join().unwrap();
+            assert_eq!(wal_gen(&dir), gen + n);
+        }
+
+        let kvs = open(&dir);
+        for k in 0..nkeys {
+            assert_eq!(fetch(&kvs, u64_key(&k)),
+                       Ok((vec![rounds - 1; 100], rounds as Version)));
+        }
+    }
+
+    /// bytes left at the end of the files by a crash are ignored
+    #[test]
+    fn wal_torn_tail() {
+        logger::enable();
+        let dir = tmpdir("wal-torn");
+        let value: Vec<u8> = vec![3u8; 100];
+        let nnodes = {
+            let kvs = open(&dir);
+            for k in 0..8u64 {
+                let obj = ObjDesc::new(u64_key(&k),
+                                       Pointer(value.as_ptr()), 100);
+                assert!(kvs.put_object(&obj).is_ok());
+            }
+            kvs.nnodes()
+        };
+        let gen = wal_gen(&dir);
+        for socket in 0..nnodes {
+            let path = dir.join(format!("wal.{}.{}", gen, socket));
+            let mut f = OpenOptions::new().append(true).open(&path)
+                .unwrap();
+            f.write_all(&[0xffu8; 13]).unwrap();
+        }
+
+        let kvs = open(&dir);
+        for k in 0..8u64 {
+            assert_eq!(fetch(&kvs, u64_key(&k)), Ok((value.clone(), 1)));
+        }
+    }
+
+    /// expiry times are logged: objects expire after a reopen as they
+    /// would have before, and an expired put hides older values
+    #[test]
+    fn wal_expiry() {
+        logger::enable();
+        let dir = tmpdir("wal-expiry");
+        let value: Vec<u8> = vec![4u8; 100];
+        let short = Duration::from_millis(30);
+        let long = Duration::from_secs(3600);
+        {
+            let kvs = open(&dir);
+            let put = |key: &KeyType, ttl: Option<Duration>| {
+                let obj = ObjDesc::new(key, Pointer(value.as_ptr()), 100);
+                match ttl {
+                    None => kvs.put_object(&obj),
+                    Some(ttl) => kvs.put_object_ttl(&obj, ttl),
+                }
+            };
+            assert!(put(b"short", Some(short)).is_ok());
+            assert!(put(b"long", Some(long)).is_ok());
+            assert!(put(b"replaced", None).is_ok());
+            assert!(put(b"replaced", Some(short)).is_ok());
+            thread::sleep(short * 2);
+
+            // expired objects are left out of a checkpoint
+            assert!(kvs.wal.as_ref().unwrap().checkpoint().is_ok());
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            wal::replay(&dir, |_, rec| match rec {
+                Record::Put { key, .. } => keys.push(key),
+                _ => {},
+            }).unwrap();
+            assert_eq!(keys, vec![b"long".to_vec()]);
+        }
+        // not checkpointed
+        {
+            let kvs = open(&dir);
+            let obj = ObjDesc::new(b"later", Pointer(value.as_ptr()), 100);
+            assert!(kvs.put_object(&obj).is_ok());
+            assert!(kvs.put_object_ttl(&obj, short).is_ok());
+        }
+        thread::sleep(short * 2);
+
+        let kvs = open(&dir);
+        assert!(!kvs.exists(b"short"));
+        assert!(!kvs.exists(b"replaced"));
+        assert!(!kvs.exists(b"later"));
+        assert!(kvs.exists(b"long"));
+        let expires = kvs.read_object(b"long", |e| e.expires).unwrap();
+        assert!(expires != NEVER);
+        assert!(!expired_at(expires, clock::now()));
+    }
+
+    /// objects come back in namespaces of the same names, though their
+    /// ids differ, and those of a dropped namespace stay dead
+    #[test]
+    fn wal_namespaces() {
+        logger::enable();
+        let dir = tmpdir("wal-namespaces");
+        let len = 100usize;
+        let value: Vec<u8> = vec![5u8; len];
+        let other: Vec<u8> = vec![6u8; len];
+        let obj = ObjDesc::new(b"key", Pointer(value.as_ptr()), len);
+        let objo = ObjDesc::new(b"key", Pointer(other.as_ptr()), len);
+        {
+            let kvs = open(&dir);
+            // shifts the ids of those below
+            let _ = kvs.namespace("unused").unwrap();
+            let a = kvs.namespace("a").unwrap();
+            let b = kvs.namespace("b").unwrap();
+            assert!(a.put_object(&obj).is_ok());
+            assert!(b.put_object(&obj).is_ok());
+            assert!(kvs.put_object(&objo).is_ok());
+            assert_eq!(kvs.drop_namespace("b"), Ok(1));
+            let b = kvs.namespace("b").unwrap();
+            assert!(b.put_object(&objo).is_ok());
+            assert!(kvs.sync().is_ok());
+        }
+        // first from the files as written, then from the checkpoint
+        // taken by the first reopen
+        for _ in 0..2 {
+            let kvs = open(&dir);
+            let mut buf: Vec<u8> = vec![0u8; len];
+            let a = kvs.namespace("a").unwrap();
+            assert_eq!(a.get_object(b"key", &mut buf), Ok(len));
+            assert_eq!(buf, value);
+            assert_eq!(a.live_bytes(), obj.len_with_header());
+            let b = kvs.namespace("b").unwrap();
+            assert_eq!(b.get_object(b"key", &mut buf), Ok(len));
+            assert_eq!(buf, other);
+            assert_eq!(b.live_bytes(), objo.len_with_header());
+            assert_eq!(fetch(&kvs, b"key"), Ok((other.clone(), 1)));
+        }
+    }
+    /// a snapshot taken while compaction moves objects holds each of
+    /// them, in its namespace, and restores as it was
+    #[test]
+    fn snapshot_under_compaction() {
+        logger::enable();
+        let kvs = Arc::new(small());
+        for node in 0..kvs.nnodes() {
+            kvs.enable_compaction(NodeId(node));
+        }
+        let file = env::temp_dir().join("nibble-lsm-snapshot");
+        let path = file.to_str().unwrap();
+
+        let nkeys = 1000u64;
+        let nchurn = 64u64;
+        let len = 512usize;
+        let big = 1usize << 14;
+        let filler: Vec<u8> = vec![0xffu8; big];
+        let size = ObjDesc::new(u64_key(&0), Pointer(filler.as_ptr()), len)
+            .len_with_header();
+        // interleave the objects we keep with ones overwritten below,
+        // so that compaction has to move the former
+        {
+            let ns = kvs.namespace("ns").unwrap();
+            for k in 0..nkeys {
+                let value: Vec<u8> = vec![k as u8; len];
+                let obj = ObjDesc::new(u64_key(&k),
+                                       Pointer(value.as_ptr()), len);
+                assert!(kvs.put_object(&obj).is_ok());
+                let value: Vec<u8> = vec![!(k as u8); len];
+                let obj = ObjDesc::new(u64_key(&k),
+                                       Pointer(value.as_ptr()), len);
+                assert!(ns.put_object(&obj).is_ok());
+                let c = nkeys + k % nchurn;
+                let obj = ObjDesc::new(u64_key(&c),
+                                       Pointer(filler.as_ptr()), big);
+                assert!(kvs.put_object(&obj).is_ok());
+            }
+        }
+        let before: Vec<(usize,usize)> = (0..nkeys)
+            .map(|k| segment_of(&kvs, u64_key(&k))).collect();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let churn = {
+            let (kvs, stop) = (kvs.clone(), stop.clone());
+            thread::spawn(move || {
+                let filler: Vec<u8> = vec![0xffu8; big];
+                let mut i = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    let c = nkeys + i % nchurn;
+                    let obj = ObjDesc::new(u64_key(&c),
+                                           Pointer(filler.as_ptr()), big);
+                    match kvs.put_object(&obj) {
+                        Ok(_) => i += 1,
+                        Err(ErrorCode::OutOfMemory{..}) =>
+                            thread::yield_now(),
+                        Err(code) => panic!("put failed: {}", code),
+                    }
+                }
+            })
+        };
+
+        // until compaction has moved some of the objects we keep, and
+        // a few snapshots more
+        let mut rounds = 0;
+        let mut moved = false;
+        while !moved || rounds < 3 {
+            assert!(rounds < 1000, "compaction moved nothing");
+            moved = moved || (0..nkeys).any(|k|
+                segment_of(&kvs, u64_key(&k)) != before[k as usize]);
+            rounds += 1;
+
+            let n = kvs.snapshot(path).unwrap();
+            assert!(n >= 2 * nkeys as usize);
+            let other = small();
+            assert_eq!(other.restore(path, PutPolicy::Nearest), Ok(n));
+            let ns = other.namespace("ns").unwrap();
+            let mut buf: Vec<u8> = vec![0u8; len];
+            for k in 0..nkeys {
+                assert_eq!(other.get_object(u64_key(&k), &mut buf),
+                           Ok(len));
+                assert!(buf.iter().all(|&b| b == k as u8));
+                assert_eq!(ns.get_object(u64_key(&k), &mut buf), Ok(len));
+                assert!(buf.iter().all(|&b| b == !(k as u8)));
+            }
+            assert_eq!(ns.live_bytes(), nkeys as usize * size);
+        }
+        stop.store(true, Ordering::Relaxed);
+        churn.join().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+    /// objects come back from a snapshot with their versions and
+    /// expiry; those which expired meanwhile do not
+    #[test]
+    fn snapshot_versions() {
+        logger::enable();
+        let kvs = small();
+        let file = env::temp_dir().join("nibble-lsm-snapshot-versions");
+        let path = file.to_str().unwrap();
+        let len = 100usize;
+        let value: Vec<u8> = vec![3u8; len];
+        let v = Pointer(value.as_ptr());
+        let short = Duration::from_millis(50);
+        let long = Duration::from_secs(3600);
+
+        for _ in 0..3 {
+            assert!(kvs.put_object(&ObjDesc::new(b"thrice", v, len)).is_ok());
+        }
+        let ns = kvs.namespace("ns").unwrap();
+        for _ in 0..2 {
+            assert!(ns.put_object(&ObjDesc::new(b"twice", v, len)).is_ok());
+        }
+        assert!(kvs.put_object_ttl(&ObjDesc::new(b"long", v, len), long)
+                .is_ok());
+        assert!(kvs.put_object_ttl(&ObjDesc::new(b"short", v, len), short)
+                .is_ok());
+        assert_eq!(kvs.snapshot(path), Ok(4));
+        thread::sleep(short * 2);
+
+        let other = small();
+        assert_eq!(other.restore(path, PutPolicy::Nearest), Ok(3));
+        assert_eq!(fetch(&other, b"thrice"), Ok((value.clone(), 3)));
+        let mut buf: Vec<u8> = vec![0u8; len];
+        let ns = other.namespace("ns").unwrap();
+        assert_eq!(ns.get_object(b"twice", &mut buf), Ok(len));
+        let version = {
+            let key: &KeyType = b"twice";
+            let ep = PinnedEpoch::new();
+            other.index.get(key, &|e| other.is_key(e, ns.id(), key))
+                .and_then(|e| other.live_version(e))
+        };
+        assert_eq!(version, Some(2));
+        let expires = other.read_object(b"long", |e| e.expires).unwrap();
+        assert!(expires != NEVER);
+        assert!(!expired_at(expires, clock::now()));
+        assert!(expired_at(expires, clock::now() + clock::from_nano(
+            long.as_secs() * clock::NANO_PER_SEC)));
+        assert!(!other.exists(b"short"));
+        fs::remove_file(path).unwrap();
+    }
+}
+
 #[cfg(IGNORE)]
 mod tests {
     use super::*;
@@ -26,13 +26,13 @@
 /// Wrapper for intrinsics::likely
 #[macro_export]
 macro_rules! likely {
-    ( $b:expr ) => { unsafe { intrinsics::likely($b) } }
+    ( $b:expr ) => { ::std::intrinsics::likely($b) }
 }
 
 /// Wrapper for intrinsics::unlikely
 #[macro_export]
 macro_rules! unlikely {
-    ( $b:expr ) => { unsafe { intrinsics::unlikely($b) } }
+    ( $b:expr ) => { ::std::intrinsics::unlikely($b) }
 }
 
 //==----------------------------------------------------==//
@@ -77,7 +77,7 @@ macro_rules! segmgr_ref {
 #[macro_export]
 macro_rules! index_ref {
     ( ) => {
-        Arc::new( Index::new() )
+        Arc::new( HashIndex::new() )
     }
 }
 
@@ -23,12 +23,13 @@
 /// into each of the methods lock and unlock.
 use std::ptr;
 use std::intrinsics;
+use std::arch::x86_64::_mm_sfence;
 use std::thread;
 use std::sync::atomic::*;
 
 #[inline(always)]
 unsafe fn sfence() {
-    asm!("sfence" : : : "memory");
+    _mm_sfence();
 }
 
 pub struct McsQnode {
@@ -18,7 +18,7 @@
 
 
 use libc;
-use syscall;
+use std::arch::asm;
 use crossbeam;
 
 use std::mem;
@@ -30,7 +30,7 @@ use std::fs::{File};
 use std::os::unix::io::AsRawFd;
 
 use numa::{self,NodeId};
-use common::{Pointer,errno};
+use common::{Pointer,ErrorCode,errno};
 
 //==----------------------------------------------------==//
 //      Memory copying
@@ -38,11 +38,9 @@ use common::{Pointer,errno};
 
 #[inline(always)] pub
 unsafe fn copy(dst: *const u8, src: *const u8, len: usize) {
-    asm!("rep movsb"
-         :
-         : "{rcx}" (len), "{rdi}" (dst), "{rsi}" (src)
-         : "rcx", "rsi", "rdi"
-         : "volatile");
+    asm!("rep movsb",
+         inout("rcx") len => _, inout("rdi") dst => _,
+         inout("rsi") src => _, options(nostack, preserves_flags));
 }
 
 //==----------------------------------------------------==//
@@ -52,9 +50,9 @@ unsafe fn copy(dst: *const u8, src: *const u8, len: usize) {
 /// 64-byte type used for aligning data structures.
 /// Put a zero-sized array into your structure to align it and have
 /// auto-padding.
-#[repr(simd)]
+#[repr(C, align(64))]
 #[derive(Debug,Copy,Clone)]
-pub struct align64(u64, u64, u64, u64, u64, u64, u64, u64);
+pub struct align64([u64; 8]);
 
 //==----------------------------------------------------==//
 //      Heap allocation
@@ -128,14 +126,41 @@ impl Drop for Buffer {
 //      Memory map
 //==----------------------------------------------------==//
 
+/// Kind of pages backing a MemMap.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum PageKind {
+    /// Reserved 2 MiB pages (MAP_HUGETLB).
+    Huge,
+    /// Base pages the kernel was advised to back with transparent
+    /// huge pages (MADV_HUGEPAGE).
+    Transparent,
+    /// Base pages only.
+    Base,
+}
+
+/// How the memory of a MemMap was obtained, which may be less than
+/// was asked for if the system could not provide it.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct MemMode {
+    pub pages: PageKind,
+    /// Whether the memory was bound to its socket with mbind.
+    pub bound: bool,
+}
+
 /// Memory mapped region in our address space.
 pub struct MemMap {
     addr: usize,
     len: usize,
+    /// Address returned by mmap, before any alignment adjustment.
+    /// This is what must be given back to munmap.
+    base: usize,
+    /// Length given to mmap, including any padding for alignment.
+    maplen: usize,
+    mode: MemMode,
 }
 
 /// Create anonymous private memory mapped region.
-/// This needs to be aligned on BLOCK_SIZE boundary as we mask virtual
+/// This needs to be aligned on a block size boundary as we mask virtual
 /// addresses within this map to test for contiguity of objects.
 impl MemMap {
 
@@ -151,59 +176,92 @@ impl MemMap {
         debug!("mmap 0x{:x}-0x{:x} {} MiB",
               addr, (addr+len), len>>20);
         assert!(addr != libc::MAP_FAILED as usize);
-        MemMap { addr: addr, len: len }
+        let mode = MemMode { pages: PageKind::Base, bound: false };
+        MemMap { addr: addr, len: len, base: addr, maplen: len,
+                 mode: mode }
     }
 
     // map and allocate anon memory, bound to a socket
     // shm segments + hugepg + numa on Linux asinine to get working
-    // alignment must be power of two
-    pub fn numa(len_: usize, node: NodeId,
-                align: usize, alloc: bool) -> Self {
+    // alignment must be power of two, and a multiple of the huge page
+    // size if huge is set
+    //
+    // If huge pages are asked for but none are reserved, or mbind is
+    // not permitted (e.g. in containers), we carry on with what we
+    // can get and record it in the mode. Fails with OutOfMemory only
+    // if not even base pages can be mapped.
+    pub fn numa(len_: usize, node: NodeId, align: usize,
+                alloc: bool, huge: bool) -> Result<Self,ErrorCode> {
 
         // round len up to nearest multiple of alignment.  NOTE: this
         // must be done b/c mbind stupidly returns EINVAL otherwise
         let len = (len_ + align - 1) & !(align - 1);
         assert!(len >= len_);
 
-        assert!((align & (numa::PAGE_SIZE_HUGE-1)) == 0,
-            "alignment {} must be multiple of huge page", align);
-
-        debug!("len {} node {} align {}", len, node.0, align);
-        let prot: libc::c_int = libc::PROT_READ | libc::PROT_WRITE;
-        let flags: libc::c_int = libc::MAP_ANON |
-            libc::MAP_PRIVATE | libc::MAP_NORESERVE |
-            libc::MAP_HUGETLB;
-        let mut addr: usize = unsafe {
-            let p = 0 as *mut libc::c_void;
-            libc::mmap(p, len, prot, flags, -1, 0) as usize
-        };
-        if addr == libc::MAP_FAILED as usize {
-            panic!("mmap: {}", unsafe{errno()});
+        let page = if huge { numa::PAGE_SIZE_HUGE } else { numa::PAGE_SIZE };
+        assert!((align & (page-1)) == 0,
+            "alignment {} must be multiple of page {}", align, page);
+
+        debug!("len {} node {} align {} huge {}",
+               len, node.0, align, huge);
+
+        // hugetlb maps reserve their pages when created (see map), so
+        // mmap fails if the pool cannot back all of len; the pool is
+        // system-wide though, so also check this node has len free
+        let mut mapped: Option<(usize,usize)> = None;
+        if huge {
+            if Self::hugepages_free(node, len) {
+                mapped = Self::map(len, align, true);
+                if mapped.is_none() {
+                    warn!("node {}: hugetlb mmap failed ({}), \
+                           using base pages", node, unsafe{errno()});
+                }
+            } else {
+                warn!("node {}: fewer than {} free huge pages, \
+                       using base pages", node, len / numa::PAGE_SIZE_HUGE);
+            }
         }
+        let mut pages = PageKind::Huge;
+        let (base, maplen) = match mapped {
+            Some(m) => m,
+            None => {
+                pages = PageKind::Base;
+                match Self::map(len, align, false) {
+                    Some(m) => m,
+                    None => {
+                        warn!("node {}: mmap of {} bytes failed ({})",
+                              node, len, unsafe{errno()});
+                        return Err(ErrorCode::OutOfMemory {
+                            socket: Some(node.0) });
+                    },
+                }
+            },
+        };
         debug!("mmap    0x{:x}-0x{:x} {} MiB",
-              addr, (addr+len), len>>20);
+              base, (base+maplen), maplen>>20);
 
         // fix the alignment
-        addr = (addr + align - 1) & !(align - 1);
+        let addr = (base + align - 1) & !(align - 1);
         debug!("aligned to 0x{:x}-0x{:x}",
               addr, (addr+len));
 
-        // bind the memory to a socket
-        // mbind has no wrapper, currently
-        let nnodes = numa::NODE_MAP.sockets();
-        let mask = 1usize << node.0;
-        unsafe {
-            let maskaddr = &mask as *const usize as usize;
-            debug!("mbind 0x{:x} {} {} 0x{:x} (0x{:x}) {} {}",
-                addr, len, numa::MPOL_BIND,
-                maskaddr, mask, 64usize, numa::MPOL_MF_MOVE);
-            // syscall returns usize, but it's really an i32
-            let ret: i32 = syscall::syscall6(syscall::nr::MBIND,
-                addr, len, numa::MPOL_BIND,
-                maskaddr, 64usize, numa::MPOL_MF_MOVE) as i32;
-            assert_eq!(ret, 0, "mbind: {}", ret);
+        if huge && pages == PageKind::Base {
+            let p = addr as *mut libc::c_void;
+            let ret = unsafe {
+                libc::madvise(p, len, libc::MADV_HUGEPAGE)
+            };
+            if ret == 0 {
+                pages = PageKind::Transparent;
+            } else {
+                warn!("node {}: madvise(MADV_HUGEPAGE): {}",
+                      node, unsafe{errno()});
+            }
         }
 
+        let bound = Self::bind(addr, len, node);
+        let mode = MemMode { pages: pages, bound: bound };
+        info!("node {}: mapped {} MiB as {:?}", node, len>>20, mode);
+
         // fault pages in
         if alloc {
             let mut guards = vec![];
@@ -236,9 +294,76 @@ impl MemMap {
             }
             info!("alloc node {}: {} sec", node, now.elapsed().as_secs());
         }
-        MemMap { addr: addr, len: len }
+        Ok(MemMap { addr: addr, len: len, base: base, maplen: maplen,
+                    mode: mode })
+    }
+
+    /// Anonymous mapping large enough to align len bytes within.
+    /// Returns the mapping's address and length.
+    fn map(len: usize, align: usize, huge: bool)
+        -> Option<(usize,usize)> {
+        let prot: libc::c_int = libc::PROT_READ | libc::PROT_WRITE;
+        let mut flags: libc::c_int = libc::MAP_ANON | libc::MAP_PRIVATE;
+        // never MAP_NORESERVE with hugetlb: the mmap would succeed
+        // without pages to back it, and we would SIGBUS on first touch
+        if huge {
+            flags |= libc::MAP_HUGETLB;
+        } else {
+            flags |= libc::MAP_NORESERVE;
+        }
+        // hugetlb maps come back huge-page aligned; anything else may
+        // need to slide forward to reach the alignment
+        let maplen = if huge && align == numa::PAGE_SIZE_HUGE {
+            len
+        } else {
+            len + align
+        };
+        let addr: usize = unsafe {
+            let p = 0 as *mut libc::c_void;
+            libc::mmap(p, maplen, prot, flags, -1, 0) as usize
+        };
+        if addr == libc::MAP_FAILED as usize {
+            None
+        } else {
+            Some((addr, maplen))
+        }
     }
 
+    /// Whether the node has at least len bytes of free huge pages.
+    fn hugepages_free(node: NodeId, len: usize) -> bool {
+        match numa::free_hugepages(node.0) {
+            None => false,
+            Some(n) => n * numa::PAGE_SIZE_HUGE >= len,
+        }
+    }
+
+    /// Bind the memory to a socket. Returns false (after warning) if
+    /// the kernel refused, leaving placement to the default policy.
+    fn bind(addr: usize, len: usize, node: NodeId) -> bool {
+        if numa::NODE_MAP.is_synthetic() {
+            debug!("node {} is synthetic, not binding", node);
+            return false;
+        }
+        // mbind has no wrapper in libc
+        let mask = 1usize << node.0;
+        let maskaddr = &mask as *const usize as usize;
+        debug!("mbind 0x{:x} {} {} 0x{:x} (0x{:x}) {} {}",
+            addr, len, numa::MPOL_BIND,
+            maskaddr, mask, 64usize, numa::MPOL_MF_MOVE);
+        let ret = unsafe {
+            libc::syscall(libc::SYS_mbind,
+                addr, len, numa::MPOL_BIND,
+                maskaddr, 64usize, numa::MPOL_MF_MOVE) as i32
+        };
+        if ret != 0 {
+            warn!("node {}: mbind failed ({}), memory is not bound",
+                  node, ret);
+        }
+        ret == 0
+    }
+
+    pub fn mode(&self) -> MemMode { self.mode }
+
     /// Tell the operating system that, upon a crash, to exclude this
     /// memory mapping from a core dump file.
     pub fn exclude_corefile(&self) {
@@ -271,9 +396,9 @@ impl MemMap {
 impl Drop for MemMap {
 
     fn drop (&mut self) {
-        debug!("unmapping 0x{:x}", self.addr);
-        let p = self.addr as *mut libc::c_void;
-        unsafe { libc::munmap(p, self.len); }
+        debug!("unmapping 0x{:x}", self.base);
+        let p = self.base as *mut libc::c_void;
+        unsafe { libc::munmap(p, self.maplen); }
     }
 }
 
@@ -339,6 +464,61 @@ impl Drop for MemMapFile {
 //      Unit tests
 //==----------------------------------------------------==//
 
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    /// Write a word at the start and end of each page in the first
+    /// len bytes of the map and read them back.
+    fn touch(mm: &MemMap, len: usize) {
+        for pg in 0..(len / numa::PAGE_SIZE) {
+            let first = mm.addr() + pg * numa::PAGE_SIZE;
+            let last = first + numa::PAGE_SIZE - 8;
+            for &a in &[first, last] {
+                unsafe {
+                    ptr::write_volatile(a as *mut usize, a);
+                    assert_eq!(ptr::read_volatile(a as *const usize), a);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn base_pages() {
+        let align = 1usize << 20;
+        let mm = MemMap::numa(3 * align + 1, NodeId(0), align,
+                              false, false).unwrap();
+        assert_eq!(mm.len(), 4 * align);
+        assert_eq!(mm.addr() & (align - 1), 0);
+        let mode = mm.mode();
+        assert_eq!(mode.pages, PageKind::Base);
+        if numa::NODE_MAP.is_synthetic() {
+            assert!(!mode.bound);
+        }
+        let len = mm.len();
+        touch(&mm, len);
+    }
+
+    /// Ask for one huge page more than the node has free: the map
+    /// must fall back to base pages rather than fail.
+    #[test]
+    fn hugepage_fallback() {
+        let free = numa::free_hugepages(0).unwrap_or(0);
+        let len = (free + 1) * numa::PAGE_SIZE_HUGE;
+        let mm = MemMap::numa(len, NodeId(0), numa::PAGE_SIZE_HUGE,
+                              false, true).unwrap();
+        assert_eq!(mm.len(), len);
+        assert_eq!(mm.addr() & (numa::PAGE_SIZE_HUGE - 1), 0);
+        let mode = mm.mode();
+        assert!(mode.pages != PageKind::Huge, "{:?}", mode);
+        if numa::NODE_MAP.is_synthetic() {
+            assert!(!mode.bound);
+        }
+        // free may be large; one huge page's worth is enough
+        touch(&mm, numa::PAGE_SIZE_HUGE);
+    }
+}
+
 #[cfg(IGNORE)]
 mod tests {
     use super::*;
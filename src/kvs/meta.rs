@@ -24,7 +24,7 @@
 use segment::*;
 use common::*;
 use memory::*;
-use clock::rdtsc;
+use clock;
 
 use std::cell::UnsafeCell;
 use std::sync::atomic;
@@ -32,9 +32,12 @@ use std::sync::atomic::{AtomicUsize,AtomicU64,AtomicBool,Ordering};
 use std::sync::Arc;
 use std::mem;
 use std::u64;
+use std::usize;
 use std::thread;
+use std::collections::HashMap;
 
 use crossbeam::sync::SegQueue;
+use parking_lot as pl;
 
 //==----------------------------------------------------==//
 //      Constants
@@ -155,6 +158,177 @@ impl SegmentInfoTable {
     pub fn len(&self) -> usize { self.table.len() }
 }
 
+//==----------------------------------------------------==//
+//      Namespace usage table
+//==----------------------------------------------------==//
+
+/// Namespace an object belongs to, recorded in its entry header.
+/// Ids are never reused, so objects of a dropped namespace cannot
+/// reappear in a new one of the same name.
+pub type NsId = u32;
+
+/// The keyspace of the LSM instance itself.
+pub const DEFAULT_NS: NsId = 0;
+
+/// Quota and live bytes of one namespace, across all sockets.
+pub struct NsInfo {
+    id: NsId,
+    name: String,
+    /// usize::MAX if unlimited
+    quota: AtomicUsize,
+    /// bytes of log memory (headers included) of objects not yet dead
+    live: AtomicUsize,
+    dropped: AtomicBool,
+}
+
+impl NsInfo {
+
+    pub fn id(&self) -> NsId { self.id }
+    pub fn name(&self) -> &str { &self.name }
+    pub fn quota(&self) -> usize { self.quota.load(Ordering::Relaxed) }
+    pub fn live(&self) -> usize { self.live.load(Ordering::Relaxed) }
+
+    pub fn set_quota(&self, bytes: usize) {
+        self.quota.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub type NamespaceTableRef = Arc<NamespaceTable>;
+
+/// Namespaces of an LSM instance, shared by the SegmentManager of
+/// every socket. Like the live bytes of SegmentInfoTable, a
+/// namespace's live bytes grow as objects are appended and shrink
+/// as they die; unlike them, they do not move with compaction.
+/// Objects of DEFAULT_NS are not accounted.
+pub struct NamespaceTable {
+    byname: pl::RwLock<HashMap<String,Arc<NsInfo>>>,
+    /// also holds dropped namespaces, whose objects may linger
+    byid: pl::RwLock<HashMap<NsId,Arc<NsInfo>>>,
+    next: AtomicUsize,
+}
+
+impl NamespaceTable {
+
+    pub fn new() -> Self {
+        NamespaceTable {
+            byname: pl::RwLock::new(HashMap::new()),
+            byid: pl::RwLock::new(HashMap::new()),
+            next: AtomicUsize::new(DEFAULT_NS as usize + 1),
+        }
+    }
+
+    /// Look up the namespace by name, creating it (unlimited) if it
+    /// does not exist.
+    pub fn open(&self, name: &str) -> Arc<NsInfo> {
+        match self.open_with(name, |_| Ok(1)) {
+            Ok(info) => info,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// As open, but if the namespace is created, first call created
+    /// with its id; should that fail, the namespace is not created.
+    /// Lookups of the name wait until created returns, so that e.g.
+    /// the creation can be logged before any object of the namespace.
+    pub fn open_with<F>(&self, name: &str, created: F)
+        -> Result<Arc<NsInfo>,ErrorCode>
+        where F: FnOnce(NsId) -> Status {
+        if let Some(info) = self.byname.read().get(name) {
+            return Ok(info.clone());
+        }
+        let mut byname = self.byname.write();
+        // lost a race to create it
+        if let Some(info) = byname.get(name) {
+            return Ok(info.clone());
+        }
+        let id = self.next.fetch_add(1, Ordering::Relaxed) as NsId;
+        created(id)?;
+        let info = Arc::new(NsInfo {
+            id: id,
+            name: name.to_string(),
+            quota: AtomicUsize::new(usize::MAX),
+            live: AtomicUsize::new(0),
+            dropped: AtomicBool::new(false),
+        });
+        self.byid.write().insert(id, info.clone());
+        byname.insert(name.to_string(), info.clone());
+        Ok(info)
+    }
+
+    /// Id and name of each namespace not dropped. Waits for any being
+    /// created to be so.
+    pub fn list(&self) -> Vec<(NsId,String)> {
+        self.byname.read().iter()
+            .map(|(name,info)| (info.id(), name.clone()))
+            .collect()
+    }
+
+    /// Look up a namespace by id, dropped or not.
+    pub fn get(&self, ns: NsId) -> Option<Arc<NsInfo>> {
+        self.byid.read().get(&ns).cloned()
+    }
+
+    /// Mark the namespace dropped and forget its name. Returns it, or
+    /// None if no namespace has that name.
+    pub fn drop_ns(&self, name: &str) -> Option<Arc<NsInfo>> {
+        let info = self.byname.write().remove(name);
+        if let Some(ref info) = info {
+            info.dropped.store(true, Ordering::SeqCst);
+        }
+        info
+    }
+
+    /// Whether objects of ns are dead because it was dropped.
+    #[inline(always)]
+    pub fn is_dropped(&self, ns: NsId) -> bool {
+        if ns == DEFAULT_NS {
+            return false;
+        }
+        self.byid.read().get(&ns).map_or(false, |i| i.is_dropped())
+    }
+
+    /// Account amt bytes to the namespace if within its quota.
+    pub fn charge(&self, ns: NsId, amt: usize) -> Result<(),ErrorCode> {
+        if ns == DEFAULT_NS {
+            return Ok(());
+        }
+        let byid = self.byid.read();
+        let info = match byid.get(&ns) {
+            Some(info) if !info.is_dropped() => info,
+            _ => return Err(ErrorCode::NamespaceDropped),
+        };
+        let mut live = info.live.load(Ordering::Relaxed);
+        loop {
+            let quota = info.quota();
+            if live + amt > quota {
+                return Err(ErrorCode::QuotaExceeded {
+                    ns: ns, quota: quota });
+            }
+            let prior = info.live.compare_and_swap(live, live + amt,
+                                                   Ordering::Relaxed);
+            if prior == live {
+                return Ok(());
+            }
+            live = prior;
+        }
+    }
+
+    /// Return amt bytes to the namespace once an object has died.
+    pub fn credit(&self, ns: NsId, amt: usize) {
+        if ns == DEFAULT_NS {
+            return;
+        }
+        if let Some(info) = self.byid.read().get(&ns) {
+            debug_assert!(info.live() >= amt);
+            info.live.fetch_sub(amt, Ordering::Relaxed);
+        }
+    }
+}
+
 //==----------------------------------------------------==//
 //      Support to read the "epoch"
 //==----------------------------------------------------==//
@@ -173,10 +347,11 @@ pub type EpochRaw = u64;
 /// Special-case value of EpochRaw meaning the value is non-existent.
 pub const EPOCH_QUIESCE: u64 = 0;
 
-/// Read the current value of the epoch.
+/// Read the current value of the epoch: the timestamp counter or
+/// monotonic clock (see clock::now), which never reads zero.
 #[inline(always)]
 fn read() -> EpochRaw {
-    unsafe { rdtsc() }
+    clock::now()
 }
 
 /// This represents option 1. above
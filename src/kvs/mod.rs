@@ -36,5 +36,11 @@ pub mod hashtable;
 pub mod distributions;
 pub mod trace;
 pub mod mcs;
+pub mod wal;
+pub mod snapshot;
+pub mod background;
+pub mod scrub;
+pub mod sweep;
+pub mod config;
 
 pub mod logger;
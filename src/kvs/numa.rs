@@ -23,6 +23,7 @@ use std::io::Read;
 use std::path::Path;
 use std::fmt;
 use std::error::Error;
+use std::env;
 
 // Linux definitions for mbind system call.
 // From /usr/include/linux/mempolicy.h
@@ -37,6 +38,11 @@ pub const PAGE_SIZE_MASK: usize         = PAGE_SIZE - 1;
 pub const PAGE_SIZE_HUGE: usize         = 1usize << 21;
 pub const PAGE_SIZE_HUGE_MASK: usize    = PAGE_SIZE_HUGE - 1;
 
+/// If set to N, the topology is replaced by N synthetic nodes which
+/// partition the online CPUs, so that multi-socket code paths can be
+/// exercised on a single socket. Memory is then never bound.
+pub const SYNTHETIC_ENV: &'static str = "NIBNODES";
+
 /// Special type to represent a NUMA socket.
 #[derive(Copy,Clone)]
 pub struct NodeId(pub usize);
@@ -98,16 +104,29 @@ impl CpuSet {
 pub struct SocketInfo {
     ncpus: usize,
     cpus: CpuSet,
+    /// SLIT distance to each node, indexed by node ID
+    distances: Vec<usize>,
     //max_mem: usize,
 }
 
 impl SocketInfo {
 
-    pub fn new(node: usize) -> Self {
+    pub fn new(node: usize, nnodes: usize) -> Self {
         let set = read_node_cpus(node);
         SocketInfo {
             ncpus: set.len(),
             cpus: set,
+            distances: read_node_distances(node, nnodes),
+        }
+    }
+
+    /// Node of a synthetic topology, equidistant to all others.
+    fn synthetic(node: usize, nnodes: usize, cpus: CpuSet) -> Self {
+        SocketInfo {
+            ncpus: cpus.len(),
+            cpus: cpus,
+            distances: (0..nnodes)
+                .map(|n| if n == node { 10 } else { 20 }).collect(),
         }
     }
 }
@@ -117,28 +136,65 @@ impl SocketInfo {
 pub struct NodeMap {
     sockets: Vec<SocketInfo>,
     cpu2sock: HashMap<usize, usize>,
+    /// Set if the topology came from SYNTHETIC_ENV
+    synthetic: bool,
 }
 
 impl NodeMap {
 
     pub fn new() -> Self {
+        if let Some(n) = synthetic_nodes() {
+            return Self::synthetic(n);
+        }
         let nnodes = nodes();
         let mut sockets: Vec<SocketInfo> = Vec::with_capacity(nnodes);
+        for node in 0..nnodes {
+            sockets.push(SocketInfo::new(node, nnodes));
+        }
+        Self::with_sockets(sockets, false)
+    }
+
+    /// Split the online CPUs into n nodes of (nearly) equal size.
+    fn synthetic(n: usize) -> Self {
+        let cpus = read_cpu_ids("/sys/devices/system/cpu/online").get();
+        assert!(n > 0 && n <= cpus.len(),
+            "{}={}: must be between 1 and the {} online cpus",
+            SYNTHETIC_ENV, n, cpus.len());
+        info!("using synthetic topology of {} nodes", n);
+        let per = cpus.len() / n;
+        let extra = cpus.len() % n;
+        let mut start = 0;
+        let mut sockets: Vec<SocketInfo> = Vec::with_capacity(n);
+        for node in 0..n {
+            let len = per + if node < extra { 1 } else { 0 };
+            let set = CpuSet::new(&cpus[start..(start+len)].to_vec());
+            start += len;
+            sockets.push(SocketInfo::synthetic(node, n, set));
+        }
+        Self::with_sockets(sockets, true)
+    }
+
+    fn with_sockets(sockets: Vec<SocketInfo>, synthetic: bool) -> Self {
         let mut map: HashMap<usize,usize>;
         map = HashMap::new();
-        for node in 0..nnodes {
-            let sock = SocketInfo::new(node);
+        for (node, sock) in sockets.iter().enumerate() {
             for cpu in sock.cpus.get() {
                 map.insert(cpu, node);
             }
-            sockets.push(sock);
         }
         NodeMap {
             sockets: sockets,
             cpu2sock: map,
+            synthetic: synthetic,
         }
     }
 
+    /// Whether the topology is synthetic (see SYNTHETIC_ENV). Memory
+    /// cannot be bound to synthetic nodes.
+    pub fn is_synthetic(&self) -> bool {
+        self.synthetic
+    }
+
     pub fn cpus_of(&self, sock: NodeId) -> CpuSet {
         assert!(sock.0<self.sockets.len(),"sock is too big: {}",sock);
         self.sockets[sock.0].cpus.clone()
@@ -160,6 +216,20 @@ impl NodeMap {
         self.sockets.len()
     }
 
+    /// Relative cost of accessing memory on node 'to' from 'from'.
+    pub fn distance(&self, from: NodeId, to: NodeId) -> usize {
+        assert!(from.0<self.sockets.len(),"sock is too big: {}",from);
+        self.sockets[from.0].distances[to.0]
+    }
+
+    /// All nodes ordered by their distance from the given one,
+    /// starting with itself. Ties are broken by node ID.
+    pub fn nearest(&self, sock: NodeId) -> Vec<NodeId> {
+        let mut ids: Vec<usize> = (0..self.sockets.len()).collect();
+        ids.sort_by_key(|&to| (self.distance(sock, NodeId(to)), to));
+        ids.into_iter().map(|id| NodeId(id)).collect()
+    }
+
     pub fn ncpus(&self) -> usize {
         self.sockets.len() * self.sockets[0].ncpus
     }
@@ -255,6 +325,32 @@ fn read_node_cpus(node: usize) -> CpuSet {
     cpu
 }
 
+/// Read the SLIT distances from the given node to all others. If the
+/// file is missing or malformed, pretend all remote nodes are
+/// equidistant.
+fn read_node_distances(node: usize, nnodes: usize) -> Vec<usize> {
+    let fname = format!(
+        "/sys/devices/system/node/node{}/distance",
+        node);
+    let mut line = String::new();
+    let mut parsed: Option<Vec<usize>> = None;
+    if let Ok(mut file) = File::open(&fname) {
+        if file.read_to_string(&mut line).is_ok() {
+            parsed = line.split_whitespace()
+                .map(|s| s.parse::<usize>().ok())
+                .collect();
+        }
+    }
+    match parsed {
+        Some(ref v) if v.len() == nnodes => v.clone(),
+        _ => {
+            warn!("cannot read {}, assuming uniform distances", fname);
+            (0..nnodes).map(|n| if n == node { 10 } else { 20 })
+                .collect()
+        },
+    }
+}
+
 /// Read from /proc/self/numa_maps and report how many pages are
 /// allocated to each socket (index is socket ID)
 ///
@@ -295,6 +391,48 @@ pub fn numa_allocated() -> Vec<usize> {
     counts
 }
 
+/// Number of unused 2 MiB huge pages reserved on the node, or None if
+/// the kernel does not say (e.g. no hugetlbfs support). Synthetic
+/// nodes receive an equal share of the system's pages.
+pub fn free_hugepages(node: usize) -> Option<usize> {
+    if NODE_MAP.is_synthetic() {
+        let fname = "/sys/kernel/mm/hugepages/hugepages-2048kB/\
+                     free_hugepages";
+        return read_count(fname).map(|n| n / NODE_MAP.sockets());
+    }
+    let fname = format!(
+        "/sys/devices/system/node/node{}/hugepages/\
+         hugepages-2048kB/free_hugepages", node);
+    read_count(&fname)
+}
+
+/// Read a file holding a single count.
+fn read_count(fname: &str) -> Option<usize> {
+    let mut line = String::new();
+    match File::open(fname) {
+        Err(_) => return None,
+        Ok(mut file) => if file.read_to_string(&mut line).is_err() {
+            return None;
+        },
+    }
+    line.trim().parse::<usize>().ok()
+}
+
+/// Number of synthetic nodes requested via SYNTHETIC_ENV, if any.
+fn synthetic_nodes() -> Option<usize> {
+    env::var_os(SYNTHETIC_ENV).map( |osstr| {
+        parse_nodes(osstr.to_str().unwrap())
+    })
+}
+
+/// Parse the value of SYNTHETIC_ENV. Panics if it is not a number.
+fn parse_nodes(s: &str) -> usize {
+    match usize::from_str_radix(s, 10) {
+        Err(e) => panic!("{}: {:?}", SYNTHETIC_ENV, e),
+        Ok(n) => n,
+    }
+}
+
 /// Number of nodes in the system.
 /// FIXME we assume all nodes are online
 pub fn nodes() -> usize {
@@ -323,6 +461,105 @@ pub fn cpus_by_socket() -> Vec<usize> {
     cpus
 }
 
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    /// One CPU per node, with the given distance table.
+    fn map_of(distances: Vec<Vec<usize>>) -> NodeMap {
+        let sockets = distances.into_iter().enumerate()
+            .map(|(node, d)| SocketInfo {
+                ncpus: 1, cpus: CpuSet::new(&vec![node]), distances: d,
+            }).collect();
+        NodeMap::with_sockets(sockets, false)
+    }
+
+    fn ids(nodes: Vec<NodeId>) -> Vec<usize> {
+        nodes.into_iter().map(|n| n.0).collect()
+    }
+
+    /// the order in which sockets are tried for memory: by distance,
+    /// ties broken by ID, starting with the node itself
+    #[test]
+    fn nearest_order() {
+        let map = map_of(vec![
+            vec![10, 21, 31, 21],
+            vec![21, 10, 21, 31],
+            vec![31, 21, 10, 21],
+            vec![21, 31, 21, 10],
+        ]);
+        assert_eq!(ids(map.nearest(NodeId(0))), vec![0, 1, 3, 2]);
+        assert_eq!(ids(map.nearest(NodeId(1))), vec![1, 0, 2, 3]);
+        assert_eq!(ids(map.nearest(NodeId(2))), vec![2, 1, 3, 0]);
+        assert_eq!(ids(map.nearest(NodeId(3))), vec![3, 0, 2, 1]);
+        assert_eq!(map.sock_of(2).0, 2);
+
+        let one = map_of(vec![vec![10]]);
+        assert_eq!(ids(one.nearest(NodeId(0))), vec![0]);
+    }
+
+    #[test]
+    fn parse_synthetic() {
+        assert_eq!(parse_nodes("1"), 1);
+        assert_eq!(parse_nodes("16"), 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "NIBNODES")]
+    fn parse_synthetic_bad() {
+        parse_nodes("two");
+    }
+
+    #[test]
+    #[should_panic]
+    fn synthetic_zero() {
+        NodeMap::synthetic(0);
+    }
+
+    /// the online CPUs are split into disjoint nodes, each within 1
+    /// of the others in size, equidistant from each other
+    #[test]
+    fn synthetic_split() {
+        let online = read_cpu_ids("/sys/devices/system/cpu/online").get();
+        let mut counts = vec![1];
+        if online.len() > 1 {
+            counts.push(online.len());
+        }
+        if online.len() > 2 {
+            counts.push(2);
+        }
+        for n in counts {
+            let map = NodeMap::synthetic(n);
+            assert!(map.is_synthetic());
+            assert_eq!(map.sockets(), n);
+            let mut seen: Vec<usize> = vec![];
+            for node in 0..n {
+                let cpus = map.cpus_of(NodeId(node)).get();
+                assert_eq!(map.cpus_in(NodeId(node)), cpus.len());
+                let per = online.len() / n;
+                assert!(cpus.len() == per || cpus.len() == per + 1);
+                for &cpu in &cpus {
+                    assert_eq!(map.sock_of(cpu).0, node);
+                }
+                seen.extend(cpus);
+                for to in 0..n {
+                    let d = map.distance(NodeId(node), NodeId(to));
+                    assert_eq!(d, if to == node { 10 } else { 20 });
+                }
+                let near = ids(map.nearest(NodeId(node)));
+                assert_eq!(near[0], node);
+                assert_eq!(near.len(), n);
+            }
+            seen.sort();
+            assert_eq!(seen, online);
+        }
+    }
+}
+
 #[cfg(IGNORE)]
 mod tests {
     use super::*;
@@ -0,0 +1,407 @@
+/*
+ * Nibble - Concurrent Log-Structured Memory for Many-Core Key-Value Stores
+ *
+ * (c) 2017 Hewlett Packard Enterprise Development LP.
+ *
+ * This program is free software: you can redistribute it and/or modify it under the terms of the
+ * GNU Lesser General Public License as published by the Free Software Foundation, either version 3
+ * of the License, or (at your option) any later version. This program is distributed in the hope that
+ * it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE.  See the GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along with this program.
+ * If not, see <http://www.gnu.org/licenses/>. As an exception, the copyright holders of this Library
+ * grant you permission to (i) compile an Application with the Library, and (ii) distribute the Application
+ * containing code generated by the Library and added to the Application during this compilation process
+ * under terms of your choice, provided you also meet the terms and conditions of the Application license.
+ */
+
+//! Background scrubbing of log memory.
+//!
+//! Each socket may run one low-priority thread which repeatedly walks
+//! the socket's closed segments and the index, looking for damaged
+//! entries and for accounting drift before it causes harm (e.g. an
+//! OOM because live bytes were over-counted and segments were never
+//! chosen for compaction). Every pass checks:
+//!
+//! - each entry header has a non-zero key length and lies within the used
+//!   portion of its segment, and the entries account for exactly the
+//!   bytes and object count the segment (and its SegmentHeader)
+//!   report;
+//! - the live bytes in SegmentInfoTable agree with the sum recomputed
+//!   from the index;
+//! - each index entry for the socket refers to an entry holding a key
+//!   it could be stored under;
+//! - objects pass their checksum (only with the checksum feature).
+//!
+//! Findings are logged and kept in a ScrubReport, retrievable via
+//! LSM::scrub_report.
+
+use common::*;
+use segment::*;
+use index::*;
+use background::{Background,Pass};
+use meta;
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+
+use parking_lot as pl;
+
+//==----------------------------------------------------==//
+//      Constants
+//==----------------------------------------------------==//
+
+/// Pause between the end of one pass and the start of the next.
+const SCRUB_INTERVAL_MS: u64 = 10_000;
+
+/// Segments walked between renewals of our epoch, so that we never
+/// hold back reclamation for long.
+const SEGS_PER_PIN: usize = 8;
+
+//==----------------------------------------------------==//
+//      Reports
+//==----------------------------------------------------==//
+
+#[derive(Debug,Clone)]
+pub enum Finding {
+    /// An entry header with a zero length, or which runs past the
+    /// used portion of the segment. The rest of the segment is not
+    /// walked.
+    BadHeader { slot: usize, offset: usize },
+    /// Entries walked differ from the count in the SegmentHeader.
+    CountMismatch { slot: usize, walked: usize, header: usize },
+    /// Bytes walked differ from what the segment says it has used.
+    UsedMismatch { slot: usize, walked: usize, used: usize },
+    /// Live bytes recomputed from the index fall outside the range
+    /// SegmentInfoTable reported before and after the walk (objects
+    /// in closed segments only ever die, so it must lie within).
+    LiveDrift { slot: usize, measured: usize,
+                before: usize, after: usize },
+    /// An index entry which does not refer to an entry holding a key
+    /// it could be stored under.
+    BadIndexEntry { entry: IndexEntry },
+    /// An object whose key and value fail their checksum.
+    Corrupted { key: Vec<u8> },
+}
+
+/// Outcome of one pass over a socket.
+#[derive(Debug,Clone,Default)]
+pub struct ScrubReport {
+    pub socket: usize,
+    /// Closed segments walked
+    pub segments: usize,
+    /// Entries walked within those segments
+    pub entries: usize,
+    /// Index entries for this socket checked
+    pub indexed: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+//==----------------------------------------------------==//
+//      Scrubber
+//==----------------------------------------------------==//
+
+struct State {
+    socket: usize,
+    manager: SegmentManagerRef,
+    index: IndexRef,
+    seginfo: meta::SegmentInfoTableRef,
+    /// Most recently completed pass, by either thread or client
+    last: pl::Mutex<Option<ScrubReport>>,
+    passes: AtomicUsize,
+}
+
+pub struct Scrubber {
+    bg: Background<State>,
+}
+
+impl Scrubber {
+
+    pub fn new(manager: &SegmentManagerRef, index: &IndexRef) -> Self {
+        let socket = manager.socket().unwrap().0;
+        let state = State {
+            socket: socket,
+            manager: manager.clone(),
+            index: index.clone(),
+            seginfo: manager.seginfo(),
+            last: pl::Mutex::new(None),
+            passes: AtomicUsize::new(0),
+        };
+        let bg = Background::new("scrub", socket, SCRUB_INTERVAL_MS, state);
+        Scrubber { bg: bg }
+    }
+
+    /// Run one pass on the calling thread.
+    pub fn scrub(&self) -> ScrubReport {
+        self.bg.state().pass()
+    }
+
+    /// Start the background thread, if not already running.
+    pub fn spawn(&mut self) {
+        self.bg.spawn();
+    }
+
+    /// Stop the background thread and wait for it to exit. A pass in
+    /// progress is finished first.
+    pub fn stop(&mut self) {
+        self.bg.stop();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.bg.is_running()
+    }
+
+    /// The most recently completed pass, if any.
+    pub fn last_report(&self) -> Option<ScrubReport> {
+        self.bg.state().last.lock().clone()
+    }
+
+    /// Number of passes completed.
+    pub fn passes(&self) -> usize {
+        self.bg.state().passes.load(Ordering::Relaxed)
+    }
+}
+
+impl Pass for State {
+
+    fn run(&self) {
+        let _ = self.pass();
+    }
+}
+
+impl State {
+
+    fn pass(&self) -> ScrubReport {
+        let mut report = ScrubReport::default();
+        report.socket = self.socket;
+
+        self.check_segments(&mut report);
+        self.check_index(&mut report);
+
+        if report.is_clean() {
+            debug!("scrub socket {}: {} segments {} entries {} indexed, clean",
+                   self.socket, report.segments, report.entries,
+                   report.indexed);
+        } else {
+            for f in &report.findings {
+                warn!("scrub socket {}: {:?}", self.socket, f);
+            }
+        }
+        *self.last.lock() = Some(report.clone());
+        self.passes.fetch_add(1, Ordering::Relaxed);
+        report
+    }
+
+    /// Walk the segments which were closed when we started. Segments
+    /// closed since are left for the next pass.
+    fn check_segments(&self, report: &mut ScrubReport) {
+        let mut todo: HashSet<usize> = self.manager.segments().iter()
+            .map(|s| s.read())
+            .filter(|s| s.is_closed())
+            .map(|s| s.id())
+            .collect();
+        while !todo.is_empty() {
+            // Segments may be released whenever we are not pinned,
+            // so look them up again each time.
+            meta::pin();
+            let segs: Vec<SegmentRef> = self.manager.segments()
+                .into_iter()
+                .filter(|s| todo.contains(&s.read().id()))
+                .take(SEGS_PER_PIN)
+                .collect();
+            if segs.is_empty() {
+                // the rest were released meanwhile
+                meta::quiesce();
+                break;
+            }
+            for segref in segs {
+                let seg = segref.read();
+                todo.remove(&seg.id());
+                self.check_segment(&seg, report);
+            }
+            meta::quiesce();
+            thread::yield_now();
+        }
+    }
+
+    /// Caller must have the epoch pinned.
+    fn check_segment(&self, seg: &Segment, report: &mut ScrubReport) {
+        let slot = seg.slot();
+        let socket = self.socket;
+        let before = self.seginfo.get_live(slot);
+        let mut live = 0usize;
+        let mut key: Vec<u8> = Vec::with_capacity(64);
+        let mut corrupt: Vec<Vec<u8>> = Vec::new();
+
+        let walked = seg.walk_checked(|entry| {
+            unsafe { entry.get_key(&mut key); }
+            let ientry = merge(socket as u16, entry.get_loc() as u64);
+            // under the key's lock the object cannot die while we
+            // look at it
+            self.index.lock_map_ifex(&key, &|e| e == ientry,
+                &mut |e| if e == ientry {
+                    live += entry.len;
+                    // the checksum changes with edits in place
+                    if !entry.reload().verify() {
+                        corrupt.push(key.clone());
+                    }
+                });
+        });
+        let after = self.seginfo.get_live(slot);
+
+        report.segments += 1;
+        for key in corrupt {
+            report.findings.push(Finding::Corrupted { key: key });
+        }
+        let (n,bytes) = match walked {
+            Err(offset) => {
+                report.findings.push(
+                    Finding::BadHeader { slot: slot, offset: offset });
+                return;
+            },
+            Ok(x) => x,
+        };
+        report.entries += n;
+        if n != seg.header_nobj() {
+            report.findings.push(Finding::CountMismatch {
+                slot: slot, walked: n, header: seg.header_nobj(),
+            });
+        }
+        let used = seg.len() - seg.remaining();
+        if bytes != used {
+            report.findings.push(Finding::UsedMismatch {
+                slot: slot, walked: bytes, used: used,
+            });
+        }
+        if live > before || live < after {
+            report.findings.push(Finding::LiveDrift {
+                slot: slot, measured: live, before: before, after: after,
+            });
+        }
+    }
+
+    fn check_index(&self, report: &mut ScrubReport) {
+        let socket = self.socket;
+        let manager = &self.manager;
+        let mut key: Vec<u8> = Vec::with_capacity(64);
+        let mut n = 0usize;
+        let mut bad: Vec<IndexEntry> = Vec::new();
+
+        // for_each renews our epoch as it goes, so whatever an entry
+        // refers to cannot be released while we look at it
+        self.index.for_each(&mut |ientry, is_key| {
+            let (s,va) = extract(ientry);
+            if s as usize != socket {
+                return;
+            }
+            n += 1;
+            let ok = match manager.segment_containing(va as usize) {
+                None => false,
+                Some(segref) => {
+                    let seg = segref.read();
+                    match seg.entry_at(va as usize) {
+                        None => false,
+                        Some(entry) => {
+                            unsafe { entry.get_key(&mut key); }
+                            is_key(&key)
+                        },
+                    }
+                },
+            };
+            if !ok {
+                bad.push(ientry);
+            }
+        });
+        meta::quiesce();
+
+        report.indexed += n;
+        for ientry in bad {
+            report.findings.push(Finding::BadIndexEntry { entry: ientry });
+        }
+    }
+}
+
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::LSMConfig;
+    use numa::{self,NodeId};
+    use thelog::{EntryHeader,Log};
+
+    use std::mem::size_of;
+    use std::sync::Arc;
+
+    /// a clean log passes; an index entry under the wrong key, live
+    /// bytes which disagree with the index, and (with the checksum
+    /// feature) a damaged value are each reported
+    #[test]
+    fn reports() {
+        let config = LSMConfig::small();
+        let len = config.get_capacity() / numa::NODE_MAP.sockets();
+        let manager = Arc::new(
+            SegmentManager::with_config(len, NodeId(0), &config).unwrap());
+        let index: IndexRef = Arc::new(OrderedIndex::new());
+        let log = Log::with_heads(manager.clone(), 1);
+        let any: Matcher = &|_| true;
+
+        // enough to close a few segments
+        let nobj = 200u64;
+        let vlen = 1usize << 14;
+        let value: Vec<u8> = vec![5u8; vlen];
+        let mut vas: Vec<usize> = Vec::new();
+        for k in 0..nobj {
+            let obj = ObjDesc::new(u64_key(&k), Pointer(value.as_ptr()),
+                                   vlen);
+            let va = log.append(&obj).unwrap();
+            index.update(u64_key(&k), merge(0, va as u64), any);
+            vas.push(va);
+        }
+
+        let scrubber = Scrubber::new(&manager, &index);
+        assert!(scrubber.last_report().is_none());
+        let report = scrubber.scrub();
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert!(report.segments > 0);
+        assert!(report.entries > 0 && report.entries <= nobj as usize);
+        assert_eq!(report.indexed, nobj as usize);
+
+        // the first objects are in a closed segment
+        let bogus = merge(0, vas[0] as u64);
+        index.update(b"bogus", bogus, any);
+        index.remove(u64_key(&1), any);
+        let off = size_of::<EntryHeader>() + size_of::<u64>();
+        let p = (vas[2] + off) as *mut u8;
+        unsafe { *p ^= 1; }
+
+        let report = scrubber.scrub();
+        let mut found = (false, false, false);
+        for f in &report.findings {
+            match *f {
+                Finding::BadIndexEntry { entry } if entry == bogus =>
+                    found.0 = true,
+                // the removed object still counts as live
+                Finding::LiveDrift { measured, after, .. } =>
+                    found.1 = after - measured == off + vlen,
+                Finding::Corrupted { ref key } =>
+                    found.2 = &key[..] == u64_key(&2),
+                _ => panic!("unexpected {:?}", f),
+            }
+        }
+        assert_eq!(found, (true, true, cfg!(feature="checksum")));
+        assert_eq!(scrubber.passes(), 2);
+        let last = scrubber.last_report().unwrap();
+        assert_eq!(last.findings.len(), report.findings.len());
+    }
+}
@@ -25,6 +25,7 @@ use meta::*;
 use meta;
 use numa::{self,NodeId};
 use compaction;
+use config::LSMConfig;
 use mcs::{McsQnode};
 use sched;
 
@@ -45,18 +46,66 @@ use std::collections::VecDeque;
 use crossbeam::sync::SegQueue;
 use parking_lot as pl;
 
+// Defaults only; see config::LSMConfig to change them at runtime.
 // If block or segment size > huge page, it must be a multiple of huge page.
-pub const BLOCK_SHIFT:      usize = 16;
-pub const BLOCK_SIZE:       usize = 1 << BLOCK_SHIFT;
-pub const BLOCK_OFF_MASK:   usize = BLOCK_SIZE - 1;
+pub const DEFAULT_BLOCK_SHIFT: usize = 16;
 pub const SEGMENT_SHIFT:    usize = 25;
 pub const SEGMENT_SIZE:     usize = 1 << SEGMENT_SHIFT;
-pub const BLOCKS_PER_SEG:   usize = SEGMENT_SIZE / BLOCK_SIZE;
 
-/// Number of blocks reserved (per BlockAllocator) for use by
-/// compaction when memory is scarce.
+/// Number of segments' worth of blocks reserved (per
+/// BlockAllocator) for use by compaction when memory is scarce.
 pub const RESERVE_SEGS:     usize = 128;
-pub const RESERVE_BLOCKS:   usize = RESERVE_SEGS * BLOCKS_PER_SEG;
+
+/// How long a large-object allocation waits for enough free blocks
+/// before giving up with OutOfMemory.
+pub const LARGE_ALLOC_WAIT_MS: u64 = 1000;
+
+/// How long a log head waits for compaction to free blocks before
+/// its socket counts as full.
+pub const ALLOC_WAIT_MS: u64 = 1000;
+
+/// log2 of the block size used by this process. Virtual addresses
+/// are masked with it all over the read path, so unlike the other
+/// settings it cannot differ between instances: it is fixed by the
+/// first BlockAllocator created. Zero until then.
+static CUR_BLOCK_SHIFT: AtomicUsize = AtomicUsize::new(0);
+
+#[inline(always)]
+pub fn block_shift() -> usize {
+    match CUR_BLOCK_SHIFT.load(Ordering::Relaxed) {
+        0 => DEFAULT_BLOCK_SHIFT,
+        s => s,
+    }
+}
+
+#[inline(always)]
+pub fn block_size() -> usize {
+    1usize << block_shift()
+}
+
+#[inline(always)]
+pub fn block_off_mask() -> usize {
+    block_size() - 1
+}
+
+/// Fix the process-wide block size, if not already. Fails if a
+/// different size is already in use.
+pub fn set_block_shift(shift: usize) -> Result<(),ErrorCode> {
+    let prior = CUR_BLOCK_SHIFT.compare_and_swap(0, shift,
+                                                 Ordering::SeqCst);
+    if prior == 0 || prior == shift {
+        Ok(())
+    } else {
+        Err(ErrorCode::InvalidConfig)
+    }
+}
+
+/// True if the block size may still be chosen, or already equals
+/// 1<<shift.
+pub fn block_shift_ok(shift: usize) -> bool {
+    let cur = CUR_BLOCK_SHIFT.load(Ordering::SeqCst);
+    cur == 0 || cur == shift
+}
 
 //==----------------------------------------------------==//
 //      Utility functions
@@ -74,18 +123,22 @@ pub const RESERVE_BLOCKS:   usize = RESERVE_SEGS * BLOCKS_PER_SEG;
 #[cold]
 pub unsafe fn copy_out(blocks: &[BlockRef], offset: usize,
                        out: *mut u8, len: usize) {
+    // an empty value may end exactly at the last block
+    if len == 0 {
+        return;
+    }
     let mut remaining = len as isize;
 
     // which block does offset put us in?
-    let mut idx = offset / BLOCK_SIZE;
-    let offset = (offset % BLOCK_SIZE) as isize;
+    let mut idx = offset / block_size();
+    let offset = (offset % block_size()) as isize;
 
     // Logical offset into new buffer
     let mut poffset: isize = 0;
 
     // Copy first chunk in current block
     let mut base = blocks[idx].addr as *const u8;
-    let mut amt = cmp::min(BLOCK_SIZE as isize - offset, remaining);
+    let mut amt = cmp::min(block_size() as isize - offset, remaining);
     let from = base.offset(offset);
     let to   = out.offset(poffset);
     copy(to, from, amt as usize);
@@ -96,7 +149,7 @@ pub unsafe fn copy_out(blocks: &[BlockRef], offset: usize,
 
     while remaining > 0 {
         base = blocks[idx].addr as *const u8;
-        amt = cmp::min(BLOCK_SIZE as isize, remaining);
+        amt = cmp::min(block_size() as isize, remaining);
         copy(out.offset(poffset), base, amt as usize);
         remaining -= amt;
         poffset += amt;
@@ -104,9 +157,34 @@ pub unsafe fn copy_out(blocks: &[BlockRef], offset: usize,
     }
 }
 
+/// Compare bytes in the log against a buffer without copying them
+/// out. Arguments are interpreted the same as for copy_out.
+pub unsafe fn compare_out(blocks: &[BlockRef], offset: usize,
+                          other: &[u8]) -> bool {
+    let len = other.len();
+    let mut idx = offset / block_size();
+    let mut boff = offset % block_size();
+    let mut poffset: usize = 0;
+    while poffset < len {
+        let amt = cmp::min(block_size() - boff, len - poffset);
+        let base = (blocks[idx].addr + boff) as *const u8;
+        let chunk = slice::from_raw_parts(base, amt);
+        if chunk != &other[poffset..(poffset+amt)] {
+            return false;
+        }
+        poffset += amt;
+        boff = 0;
+        idx += 1;
+    }
+    true
+}
+
 pub unsafe fn copy_in(blocks: &[BlockRef], blk_idx: usize,
                       blk_offset: usize,
                       source: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
     let mut remaining = len as isize;
     let mut idx = blk_idx;
     let blk_offset = blk_offset as isize;
@@ -116,7 +194,7 @@ pub unsafe fn copy_in(blocks: &[BlockRef], blk_idx: usize,
 
     // Copy first chunk into current block
     let mut base = blocks[idx].addr as *const u8;
-    let mut amt = cmp::min(BLOCK_SIZE as isize - blk_offset, remaining);
+    let mut amt = cmp::min(block_size() as isize - blk_offset, remaining);
     let to   = base.offset(blk_offset);
     let from = source.offset(poffset);
     copy(to, from, amt as usize);
@@ -127,7 +205,7 @@ pub unsafe fn copy_in(blocks: &[BlockRef], blk_idx: usize,
 
     while remaining > 0 {
         base = blocks[idx].addr as *const u8;
-        amt = cmp::min(BLOCK_SIZE as isize, remaining);
+        amt = cmp::min(block_size() as isize, remaining);
         copy(base, source.offset(poffset), amt as usize);
         remaining -= amt;
         poffset += amt;
@@ -146,7 +224,7 @@ pub struct Block {
     /// Base address of this memory region. Immutable after creation.
     addr: usize,
     /// Length of this block in bytes. Immutable after creation.
-    /// TODO get rid of this... all blocks are / should be BLOCK_SIZE
+    /// TODO get rid of this... all blocks are / should be the same size
     len: usize,
     /// Index within BlockAllocator::pool. Immutable after creation.
     slot: usize,
@@ -262,18 +340,27 @@ impl fmt::Debug for BlockAllocator {
 
 impl BlockAllocator {
 
-    fn __new(bytes: usize, mmap: MemMap) -> Self {
+    fn __new(bytes: usize, mmap: MemMap, reserve_nblks: usize) -> Self {
+
+        // addresses within the map are masked by the block size, so
+        // it must not change from here on
+        if let Err(_) = set_block_shift(block_shift()) {
+            panic!("block size changed underneath allocator");
+        }
 
-        let count = bytes / BLOCK_SIZE;
+        let count = bytes / block_size();
         let mut pool:       Vec<Arc<Block>> =
             Vec::with_capacity(count);
         let mut freepool:   Vec<Arc<Block>> =
             Vec::with_capacity(count);
 
         let mut reserve:    Vec<Arc<Block>> =
-            Vec::with_capacity(RESERVE_BLOCKS);
+            Vec::with_capacity(reserve_nblks);
 
-        let general_blks = count - RESERVE_BLOCKS;
+        assert!(count > reserve_nblks,
+            "{} blocks cannot hold a reserve of {}",
+            count, reserve_nblks);
+        let general_blks = count - reserve_nblks;
 
         info!("new BlockAllocator len {} pool {} free {}",
               mmap.len(),
@@ -281,11 +368,11 @@ impl BlockAllocator {
               mem::size_of::<Vec<Arc<Block>>>() * count);
 
         info!("reserving {} blocks for compaction, {} for logs",
-               RESERVE_BLOCKS, general_blks);
+               reserve_nblks, general_blks);
 
         for b in 0..count {
-            let addr = mmap.addr() + b*BLOCK_SIZE;
-            let blk = Arc::new(Block::new(addr, b, BLOCK_SIZE));
+            let addr = mmap.addr() + b*block_size();
+            let blk = Arc::new(Block::new(addr, b, block_size()));
             pool.push(blk.clone());
             if b < general_blks {
                 freepool.push(blk.clone());
@@ -301,21 +388,24 @@ impl BlockAllocator {
             freepool_sz: AtomicUsize::new(fpsz),
             freepool_mcs: AtomicPtr::new(0usize as *mut McsQnode),
             reserve:  pl::RwLock::new(reserve),
-            reserve_nblks: RESERVE_BLOCKS,
+            reserve_nblks: reserve_nblks,
         }
     }
 
-    pub fn numa(bytes: usize, node: NodeId) -> Self {
-        let align = cmp::max(numa::PAGE_SIZE_HUGE, BLOCK_SIZE);
-        let mmap = MemMap::numa(bytes, node, align, true);
+    pub fn numa(bytes: usize, node: NodeId, reserve_nblks: usize,
+                huge: bool) -> Result<Self,ErrorCode> {
+        let page = if huge { numa::PAGE_SIZE_HUGE } else { numa::PAGE_SIZE };
+        let align = cmp::max(page, block_size());
+        let mmap = MemMap::numa(bytes, node, align, true, huge)?;
         mmap.exclude_corefile();
-        Self::__new(bytes, mmap)
+        Ok(Self::__new(bytes, mmap, reserve_nblks))
     }
 
     pub fn new(bytes: usize) -> Self {
         let mmap = MemMap::new(bytes);
         mmap.exclude_corefile();
-        Self::__new(bytes, mmap)
+        let reserve = RESERVE_SEGS * (SEGMENT_SIZE / block_size());
+        Self::__new(bytes, mmap, reserve)
     }
 
     /// Normal allocation path. Used by client / application threads
@@ -324,10 +414,12 @@ impl BlockAllocator {
     /// After queuing in line, the holder of the MCS lock will spin on
     /// the externalized size for the freepool, instead of competing
     /// with compaction threads for the freepool mutex.
-    /// This method is blocking.
-    pub fn alloc(&self, count: usize) -> BlockRefPool {
+    /// This method blocks for up to ALLOC_WAIT_MS, then returns None.
+    pub fn alloc(&self, count: usize) -> Option<BlockRefPool> {
         let mut slot = McsQnode::new();
         unsafe { McsQnode::lock(&self.freepool_mcs, &mut slot); }
+        let start = Instant::now();
+        let wait = Duration::from_millis(ALLOC_WAIT_MS);
         let mut blks = None;
         while blks.is_none() {
             // spin on the size variable with backoff
@@ -335,14 +427,20 @@ impl BlockAllocator {
             while self.freepool_sz.load(Ordering::Relaxed) < count {
                 tries += 1;
                 if tries > 10_000usize {
+                    if start.elapsed() > wait {
+                        break;
+                    }
                     sched::sleep_short();
                     tries = 0usize;
                 }
             }
             blks = self.allocp(count);
+            if blks.is_none() && start.elapsed() > wait {
+                break;
+            }
         }
         unsafe { McsQnode::unlock(&self.freepool_mcs, &mut slot); }
-        blks.unwrap()
+        blks
     }
 
     /// Priority allocation (does not wait in MCS); directly acquire
@@ -418,7 +516,12 @@ impl BlockAllocator {
     }
 
     pub fn freesz(&self) -> usize {
-        self.freelen() * BLOCK_SIZE
+        self.freelen() * block_size()
+    }
+
+    /// How the underlying memory was obtained.
+    pub fn mem_mode(&self) -> MemMode {
+        self.mmap.mode()
     }
 
     /// Convert virtual address to containing block.
@@ -428,21 +531,28 @@ impl BlockAllocator {
     /// result in a runtime error and halt.
     #[inline(always)]
     pub fn block_of(&self, addr: usize) -> Block {
-        let idx: usize = (addr - self.mmap.addr()) >> BLOCK_SHIFT;
+        let idx: usize = (addr - self.mmap.addr()) >> block_shift();
         let b: &BlockRef = &self.pool[idx];
         debug_assert!(addr >= b.addr);
-        debug_assert!(addr < (b.addr + BLOCK_SIZE));
+        debug_assert!(addr < (b.addr + block_size()));
         (**b).clone()
     }
 
     // XXX see if we can write block_of without cloning
 
+    /// Whether addr lies within memory managed by this allocator.
+    #[inline(always)]
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.mmap.addr() &&
+            ((addr - self.mmap.addr()) >> block_shift()) < self.pool.len()
+    }
+
     #[inline(always)]
     pub fn segment_of(&self, addr: usize) -> usize {
-        let idx: usize = (addr - self.mmap.addr()) >> BLOCK_SHIFT;
+        let idx: usize = (addr - self.mmap.addr()) >> block_shift();
         let b: &BlockRef = &self.pool[idx];
         debug_assert!(addr >= b.addr);
-        debug_assert!(addr < (b.addr + BLOCK_SIZE));
+        debug_assert!(addr < (b.addr + block_size()));
         b.seg_slot()
     }
 }
@@ -456,51 +566,80 @@ impl BlockAllocator {
 /// originating buffers exceeds that of an instance of ObjDesc used to
 /// refer to them.
 #[derive(Debug)]
-pub struct ObjDesc {
-    pub key: u64,
+pub struct ObjDesc<'a> {
+    pub key: &'a KeyType,
     pub value: Pointer<u8>,
     pub vlen: usize,
     // false - we do everything but copy the object itself
     pub copy: bool,
+    pub ns: NsId,
+    /// When the object expires, or NEVER. Only objects appended with
+    /// an expiry have room for one in the log. Puts set this from
+    /// their time-to-live, ignoring what the caller left here.
+    pub expires: Expiry,
 }
 
 
-impl ObjDesc {
+impl<'a> ObjDesc<'a> {
 
-    /// Create ObjDesc where key is str and value is arbitrary memory.
-    pub fn new(key: u64, value: Pointer<u8>, vlen: usize) -> Self {
-        ObjDesc { key: key, value: value, vlen: vlen, copy: true }
+    /// Create ObjDesc where key is bytes and value is arbitrary memory.
+    pub fn new(key: &'a KeyType, value: Pointer<u8>, vlen: usize) -> Self {
+        ObjDesc { key: key, value: value, vlen: vlen, copy: true,
+            ns: DEFAULT_NS, expires: NEVER }
     }
 
-    /// Create ObjDesc where key and value are String
-    pub fn new2(key: u64, value: &String) -> Self {
+    /// Create ObjDesc where value is a String
+    pub fn new2(key: &'a KeyType, value: &String) -> Self {
         ObjDesc {
             key: key,
             value: Pointer(value.as_ptr()),
             vlen: value.len(),
             copy: true,
+            ns: DEFAULT_NS,
+            expires: NEVER,
         }
     }
 
     /// Used to implement 'alloc' behavior (as opposed to PUT).
-    pub fn null(key: u64, vlen: usize) -> Self {
+    pub fn null(key: &'a KeyType, vlen: usize) -> Self {
         ObjDesc {
             key: key,
             value: Pointer(ptr::null::<u8>()),
             vlen: vlen,
             // nothing to copy
             copy: false,
+            ns: DEFAULT_NS,
+            expires: NEVER,
         }
     }
 
+    /// Same object, placed in namespace ns.
+    pub fn with_ns(&self, ns: NsId) -> Self {
+        ObjDesc { key: self.key, value: Pointer(self.value.0),
+            vlen: self.vlen, copy: self.copy, ns: ns,
+            expires: self.expires }
+    }
+
+    /// Same object, expiring at expires.
+    pub fn with_expiry(&self, expires: Expiry) -> Self {
+        ObjDesc { key: self.key, value: Pointer(self.value.0),
+            vlen: self.vlen, copy: self.copy, ns: self.ns,
+            expires: expires }
+    }
+
     #[inline(always)]
     pub fn len(&self) -> usize {
-        mem::size_of::<u64>() + self.vlen
+        self.key.len() + self.vlen
     }
 
     #[inline(always)]
     pub fn len_with_header(&self) -> usize {
-        size_of::<EntryHeader>() + self.len()
+        let trailer = if self.expires != NEVER {
+            size_of::<Expiry>()
+        } else {
+            0
+        };
+        size_of::<EntryHeader>() + self.len() + trailer
     }
 
     /// Releases memory associated with a .value that is allocated
@@ -514,9 +653,8 @@ impl ObjDesc {
         }
     }
 
-    //pub fn getkey(&self) -> &'a str { self.key }
-    pub fn getkey(&self) -> u64 { self.key }
-    pub fn keylen(&self) -> usize { mem::size_of::<u64>() }
+    pub fn getkey(&self) -> &'a KeyType { self.key }
+    pub fn keylen(&self) -> usize { self.key.len() }
     pub fn getvalue(&self) -> Pointer<u8> { self.value }
     pub fn valuelen(&self) -> usize { self.vlen }
 }
@@ -578,6 +716,10 @@ pub struct Segment {
     /// compiler does not allow sharing of raw pointers.
     front: usize,
     blocks: BlockRefPool,
+    /// Holds one object too large for a regular segment. Such a
+    /// segment is never compacted; it is released whole once the
+    /// object is dead.
+    large: bool,
 }
 
 impl fmt::Debug for Segment {
@@ -649,6 +791,7 @@ impl Segment {
             nobj: 0, curblk: blk,
             front: blocks[blk].addr,
             blocks: blocks,
+            large: false,
         }
     }
 
@@ -658,7 +801,7 @@ impl Segment {
     pub fn extend(&mut self, blocks: &mut BlockRefPool) {
         assert!(blocks.len() > 0);
 
-        let addlen = blocks.len() * BLOCK_SIZE;
+        let addlen = blocks.len() * block_size();
         self.len += addlen;
         self.rem += addlen;
         debug_assert!(addlen > 0, "addlen is zero");
@@ -686,21 +829,24 @@ impl Segment {
         } else if unlikely!(!self.can_hold(buf)) {
             Err(ErrorCode::SegmentFull)
         } else {
-            if unlikely!(0 == buf.vlen) {
+            if unlikely!(buf.key.is_empty()) {
                 return Err(ErrorCode::EmptyObject);
             }
             let va = self.headref() as usize;
             let header = EntryHeader::new(buf);
             let hlen = size_of::<EntryHeader>();
             self.append_safe(header.as_ptr(), hlen);
-            let v = &buf.key as *const _ as *const u8;
-            self.append_safe(v, mem::size_of::<u64>());
+            self.append_safe(buf.key.as_ptr(), buf.keylen());
             if likely!(buf.copy) {
                 self.append_safe(buf.value.0 as *const u8,
                                  buf.vlen as usize);
             } else {
                 self.bump_head(buf.vlen as usize);
             }
+            if header.has_expiry() {
+                self.append_safe(&buf.expires as *const Expiry
+                                 as *const u8, size_of::<Expiry>());
+            }
             self.nobj += 1;
             self.update_header(1);
             Ok(va)
@@ -717,11 +863,11 @@ impl Segment {
         if len < remblk {
             self.head += len;
             debug_assert!((self.head -
-                self.blocks[self.curblk].addr) < BLOCK_SIZE);
+                self.blocks[self.curblk].addr) < block_size());
         } else {
-            let nblks: usize = 1 + (len - remblk) / BLOCK_SIZE;
+            let nblks: usize = 1 + (len - remblk) / block_size();
             self.next_block(nblks);
-            let offset = (len - remblk) % BLOCK_SIZE;
+            let offset = (len - remblk) % block_size();
             self.head += offset;
         }
         self.rem -= len;
@@ -749,7 +895,7 @@ impl Segment {
 
         // append by-block since entry may not be virtually contiguous
         let mut va = entry.blocks[0].addr + entry.offset;
-        let mut amt = cmp::min(BLOCK_SIZE - entry.offset, entry.len);
+        let mut amt = cmp::min(block_size() - entry.offset, entry.len);
         trace!("append_entry len {} #blks {} amt {} va 0x{:x}",
                entry.len, entry.blocks.len(), amt, va);
         self.append_safe(va as *const u8, amt);
@@ -760,7 +906,7 @@ impl Segment {
         while remaining > 0 {
             assert!(bidx < entry.blocks.len());
             va = entry.blocks[bidx].addr;
-            amt = cmp::min(BLOCK_SIZE, remaining);
+            amt = cmp::min(block_size(), remaining);
             self.append_safe(va as *const u8, amt);
             remaining -= amt;
             bidx += 1;
@@ -784,11 +930,15 @@ impl Segment {
 
     }
 
+    #[inline(always)]
+    pub fn is_large(&self) -> bool { self.large }
     #[inline(always)]
     pub fn nobjects(&self) -> usize { self.nobj }
     #[inline(always)]
     pub fn nblocks(&self) -> usize { self.blocks.len() }
     #[inline(always)]
+    pub fn id(&self) -> usize { self.id }
+    #[inline(always)]
     pub fn slot(&self) -> usize { self.slot }
     #[inline(always)]
     pub fn len(&self) -> usize { self.len }
@@ -803,6 +953,75 @@ impl Segment {
         self.can_hold_amt(buf.len_with_header())
     }
 
+    /// Object count kept in the SegmentHeader at the front of the
+    /// segment's memory. Should equal nobjects.
+    pub fn header_nobj(&self) -> usize {
+        let header: &SegmentHeader = unsafe {
+            &*(self.front as *const SegmentHeader)
+        };
+        header.nobj() as usize
+    }
+
+    /// Walk the entries as SegmentIter does, but validate each header
+    /// before trusting it, so a corrupt one cannot lead us outside
+    /// the segment. Invokes f on each entry. Returns the number of
+    /// entries and bytes walked, or the offset of the first bad
+    /// header.
+    pub fn walk_checked<F>(&self, mut f: F) -> Result<(usize,usize),usize>
+        where F: FnMut(&EntryReference) {
+
+        let mut offset = SegmentHeader::len();
+        let mut n = 0usize;
+        while n < self.nobj {
+            if self.header_at(offset).is_none() {
+                return Err(offset);
+            }
+            let idx = offset / block_size();
+            let va = self.blocks[idx].addr + (offset % block_size());
+            let entry = get_ref(self.blocks.as_slice(), idx, va);
+            offset += entry.len;
+            n += 1;
+            f(&entry);
+        }
+        Ok((n,offset))
+    }
+
+    /// Reference to the entry at va, if va lies in this segment and
+    /// holds a plausible entry header.
+    pub fn entry_at(&self, va: usize) -> Option<EntryReference> {
+        let idx = match self.blocks.iter().position(|b|
+                        va >= b.addr && va < (b.addr + block_size())) {
+            None => return None,
+            Some(idx) => idx,
+        };
+        let offset = idx * block_size() + (va - self.blocks[idx].addr);
+        if offset < SegmentHeader::len() {
+            return None;
+        }
+        self.header_at(offset).map(|_|
+            get_ref(self.blocks.as_slice(), idx, va))
+    }
+
+    /// Copy out the entry header at offset, if it and the entry it
+    /// describes lie within the used portion of the segment.
+    fn header_at(&self, offset: usize) -> Option<EntryHeader> {
+        let used = self.len - self.rem;
+        let hlen = size_of::<EntryHeader>();
+        if offset + hlen > used {
+            return None;
+        }
+        let mut header = EntryHeader::empty();
+        unsafe {
+            copy_out(self.blocks.as_slice(), offset,
+                     header.as_mut_ptr(), hlen);
+        }
+        if header.getkeylen() == 0 ||
+                offset + header.len_with_header() > used {
+            return None;
+        }
+        Some(header)
+    }
+
     /// Increment values in header by specified amount
     #[inline(always)]
     fn update_header(&self, n: u32) {
@@ -855,7 +1074,7 @@ impl Segment {
         let mut copied = 0_usize;
         let mut amt: usize;
         loop {
-            let in_blk = BLOCK_SIZE - (loc as usize & (BLOCK_SIZE-1));
+            let in_blk = block_size() - (loc as usize & (block_size()-1));
             amt = cmp::min(in_blk, len - copied);
             copy(dst.offset(copied as isize), loc, amt);
             copied += amt;
@@ -870,7 +1089,7 @@ impl Segment {
 
         // if we wrote to the end of a block, next address is actually
         // the next block itself
-        if 0 == (next & (BLOCK_SIZE-1)) {
+        if 0 == (next & (block_size()-1)) {
             next = self.blocks[curblk+1].addr;
         }
 
@@ -922,7 +1141,7 @@ impl Segment {
                 }
                 self.head += amt;
                 rem -= amt;
-                loc = (from as usize + amt) as *const u8;
+                loc = (loc as usize + amt) as *const u8;
                 // If we exceeded the block, get the next one
                 if remblk == amt {
                     self.next_block(1);
@@ -1034,14 +1253,15 @@ impl<'a> Iterator for SegmentIter<'a> {
                          mem::size_of::<EntryHeader>());
             }
 
-            debug_assert!(entry.getdatalen() > 0);
+            debug_assert!(entry.getkeylen() > 0);
             // https://github.com/rust-lang/rust/issues/22644
-            debug_assert!( (entry.getdatalen() as usize) < SEGMENT_SIZE);
+            debug_assert!( (entry.getdatalen() as usize) <
+                           self.blocks.len() * block_size());
 
             // advance to next
             self.seg_offset += entry.len();
-            self.cur_blk = self.seg_offset / BLOCK_SIZE;
-            self.blk_offset = self.seg_offset % BLOCK_SIZE;
+            self.cur_blk = self.seg_offset / block_size();
+            self.blk_offset = self.seg_offset % block_size();
         }
 
         // read entry info
@@ -1058,9 +1278,10 @@ impl<'a> Iterator for SegmentIter<'a> {
                      mem::size_of::<EntryHeader>());
         }
 
-        debug_assert!(entry.getdatalen() > 0);
+        debug_assert!(entry.getkeylen() > 0);
         // https://github.com/rust-lang/rust/issues/22644
-        debug_assert!( (entry.getdatalen() as usize) < SEGMENT_SIZE);
+        debug_assert!( (entry.getdatalen() as usize) <
+                       self.blocks.len() * block_size());
 
         let entry_len = entry.len_with_header();
         trace!("read {:?}", entry);
@@ -1068,14 +1289,14 @@ impl<'a> Iterator for SegmentIter<'a> {
 
         // determine which blocks belong
         let mut nblks = 1;
-        let blk_tail = BLOCK_SIZE - (self.seg_offset % BLOCK_SIZE);
+        let blk_tail = block_size() - (self.seg_offset % block_size());
         if entry_len > blk_tail {
-            nblks += ((entry_len - blk_tail) / BLOCK_SIZE) + 1;
+            nblks += ((entry_len - blk_tail) / block_size()) + 1;
         }
 
         trace!("segiter: objlen {} blktail {} segoff {} blkoff {}",
                entry_len, blk_tail, self.seg_offset,
-               self.seg_offset % BLOCK_SIZE);
+               self.seg_offset % block_size());
 
         self.next_obj += 1;
 
@@ -1083,7 +1304,13 @@ impl<'a> Iterator for SegmentIter<'a> {
         let entry = EntryReference {
             offset: self.blk_offset,
             len: entry_len,
+            keylen: entry.getkeylen(),
             datalen: entry.getdatalen(),
+            #[cfg(feature="checksum")]
+            crc: entry.getcrc(),
+            ns: entry.getns(),
+            expires: read_expiry(self.blocks, self.seg_offset, &entry),
+            version: entry.getversion(),
             blocks: &self.blocks[self.cur_blk..last_blk],
         };
         trace!("entry {:?}", entry);
@@ -1102,9 +1329,13 @@ pub struct SegmentManager {
     socket: Option<NodeId>,
     /// Total memory
     size: usize,
+    /// Nominal size of new segments
+    segsz: usize,
     /// Each segment gets a new ID (but slots are reused).
     next_seg_id: atomic::AtomicUsize,
     seginfo: SegmentInfoTableRef,
+    /// Shared by the managers of all sockets; see set_namespaces.
+    namespaces: NamespaceTableRef,
     /// The lower-level memory allocator we use.
     allocator: BlockAllocator,
     /// Segment slots that are unused.
@@ -1146,9 +1377,9 @@ impl SegmentManager {
     }
 
     fn __new(sock: Option<NodeId>, segsz: usize, len: usize,
-             b: BlockAllocator) -> Self {
-        info!("SEGMENT_SIZE {} BLOCK_SIZE {} RESERVE_SEGS {}",
-              SEGMENT_SIZE, BLOCK_SIZE, RESERVE_SEGS);
+             nqueues: usize, b: BlockAllocator) -> Self {
+        info!("segment size {} block size {} reserve blocks {}",
+              segsz, block_size(), b.reserve_nblks);
         let num = 2 * len / segsz;
         let mut segments: Vec<Option<SegmentRef>>
             = Vec::with_capacity(num);
@@ -1165,8 +1396,8 @@ impl SegmentManager {
             free_slots.push(i as u32);
         }
         let mut closed: Vec<VecDeque<SegmentRef>> =
-            Vec::with_capacity(compaction::WTHREADS);
-        for _ in 0..compaction::WTHREADS {
+            Vec::with_capacity(nqueues);
+        for _ in 0..nqueues {
             closed.push(VecDeque::with_capacity(32));
         }
         info!("SegmentManager {} free_slots len {}",
@@ -1176,9 +1407,11 @@ impl SegmentManager {
         SegmentManager {
             socket: sock,
             size: len,
+            segsz: segsz,
             next_seg_id: atomic::AtomicUsize::new(0),
             allocator: b,
             seginfo: Arc::new(SegmentInfoTable::new(num)),
+            namespaces: Arc::new(NamespaceTable::new()),
             free_slots: Arc::new(free_slots),
             segments: pl::RwLock::new(segments),
             closed: pl::RwLock::new(closed),
@@ -1187,20 +1420,55 @@ impl SegmentManager {
         }
     }
 
-    pub fn numa(segsz: usize, len: usize, node: NodeId) -> Self {
-        let b = BlockAllocator::numa(len, node);
-        Self::__new(Some(node),segsz,len,b)
+    pub fn numa(segsz: usize, len: usize, node: NodeId)
+        -> Result<Self,ErrorCode> {
+        let config = LSMConfig::default().segment_size(segsz);
+        Self::with_config(len, node, &config)
+    }
+
+    /// Segment manager for a socket, sized and tuned by the config.
+    /// One closed-segment queue is kept per compaction worker. Fails
+    /// with OutOfMemory if the socket's memory cannot be mapped.
+    pub fn with_config(len: usize, node: NodeId,
+                       config: &LSMConfig) -> Result<Self,ErrorCode> {
+        let b = BlockAllocator::numa(len, node,
+                    config.reserve_blocks(), config.get_hugepages())?;
+        Ok(Self::__new(Some(node), config.get_segment_size(), len,
+                       config.get_compaction_workers(), b))
     }
 
     pub fn new(segsz: usize, len: usize) -> Self {
         let b = BlockAllocator::new(len);
-        Self::__new(None,segsz,len,b)
+        Self::__new(None,segsz,len,compaction::WTHREADS,b)
+    }
+
+    /// Nominal size of segments allocated for the log.
+    pub fn segment_size(&self) -> usize {
+        self.segsz
+    }
+
+    pub fn blocks_per_seg(&self) -> usize {
+        self.segsz / block_size()
+    }
+
+    /// Number of queues newly closed segments are spread across; one
+    /// per compaction worker.
+    pub fn nqueues(&self) -> usize {
+        self.closed.read().len()
     }
 
     pub fn get_nseg(&self) -> usize {
         self.segments.read().len()
     }
 
+    /// References to all segments currently allocated, including
+    /// open log heads.
+    pub fn segments(&self) -> Vec<SegmentRef> {
+        self.segments.read().iter()
+            .filter_map(|opt| opt.clone())
+            .collect()
+    }
+
     /// Make a Copy of the Block containing this VA (not cloning the
     /// Arc holding the Block).
     #[inline(always)]
@@ -1248,15 +1516,15 @@ impl SegmentManager {
 
         let mut ret: Option<SegmentRef> = None;
 
-        let mut blocks: BlockRefPool =
-            if mcs {
-                self.allocator.alloc(nblks)
-            } else {
-                match self.allocator.allocp(nblks) {
-                    None => return None,
-                    Some(b) => b,
-                }
-            };
+        let got = if mcs {
+            self.allocator.alloc(nblks)
+        } else {
+            self.allocator.allocp(nblks)
+        };
+        let mut blocks: BlockRefPool = match got {
+            None => return None,
+            Some(b) => b,
+        };
 
         // some extra in case of overflow
         blocks.reserve(8);
@@ -1268,7 +1536,8 @@ impl SegmentManager {
 
     /// Allocate a segment with a specific number of blocks.
     /// Uses the MCS path for allocating from the underlying
-    /// BlockAllocator (intended for use by client threads).
+    /// BlockAllocator (intended for use by client threads). Returns
+    /// None if the socket stays full for ALLOC_WAIT_MS.
     pub fn alloc_size(&self, nblks: usize) -> Option<SegmentRef> {
         self.do_alloc_size(nblks, true)
     }
@@ -1281,10 +1550,48 @@ impl SegmentManager {
         self.do_alloc_size(nblks, false)
     }
 
+    /// Allocate a segment to hold a single object of len bytes
+    /// (entry header included) that is too large for a log head.
+    /// Waits up to LARGE_ALLOC_WAIT_MS for compaction to free
+    /// enough blocks, else returns OutOfMemory. Returns ObjectTooBig
+    /// if the socket could never hold the object.
+    pub fn alloc_large(&self, len: usize)
+        -> Result<SegmentRef,ErrorCode> {
+
+        let nblks = (len + SegmentHeader::len() - 1) / block_size() + 1;
+        let usable = self.allocator.len() - self.allocator.reserve_nblks;
+        if nblks > usable {
+            return Err(ErrorCode::ObjectTooBig { size: len,
+                max: usable * block_size() - SegmentHeader::len() });
+        }
+        let start = Instant::now();
+        let wait = Duration::from_millis(LARGE_ALLOC_WAIT_MS);
+        let mut blocks = loop {
+            if let Some(b) = self.allocator.allocp(nblks) {
+                break b;
+            }
+            if start.elapsed() > wait {
+                debug!("no {} free blocks for large object", nblks);
+                return Err(ErrorCode::OutOfMemory {
+                    socket: self.socket.map(|s| s.0) });
+            }
+            sched::sleep_short();
+        };
+        // Segment::new reserves this too; doing it here keeps the
+        // Block::list pointers set by make_segment valid
+        blocks.reserve(8);
+        let segref = match self.make_segment(blocks) {
+            None => panic!("no segment slots available?"),
+            Some(s) => s,
+        };
+        segref.write().large = true;
+        Ok(segref)
+    }
+
     /// Allocate a segment with default size.
     #[cfg(IGNORE)]
     pub fn alloc(&self) -> Option<SegmentRef> {
-        let nblks = (SEGMENT_SIZE - 1) / BLOCK_SIZE + 1;
+        let nblks = (self.segsz - 1) / block_size() + 1;
         self.alloc_size(nblks)
     }
 
@@ -1391,6 +1698,20 @@ impl SegmentManager {
         self.allocator.segment_of(va)
     }
 
+    /// The segment currently holding the block containing va. Unlike
+    /// segment_of, va need not be valid. Caller must have the epoch
+    /// pinned, and should check the segment really contains va.
+    pub fn segment_containing(&self, va: usize) -> Option<SegmentRef> {
+        if !self.allocator.contains(va) {
+            return None;
+        }
+        let slot = self.segment_of(va);
+        match self.segments.read().get(slot) {
+            Some(&Some(ref seg)) => Some(seg.clone()),
+            _ => None,
+        }
+    }
+
     /// Construct an EntryReference to the object pointed to by 'va'.
     /// If va is bogus, return None. Remember to pin your epoch, else
     /// your location may be updated underneath you by compaction.
@@ -1417,7 +1738,7 @@ impl SegmentManager {
         let mut n;
         loop {
             n = self.next.load(Ordering::Relaxed);
-            let m = (n + 1) % compaction::WTHREADS;
+            let m = (n + 1) % closed.len();
             if n == self.next.compare_and_swap(n, m, order) {
                 break;
             }
@@ -1442,6 +1763,16 @@ impl SegmentManager {
         self.seginfo.clone()
     }
 
+    /// Account namespaces in the given table instead of our own, so
+    /// that quotas hold across sockets.
+    pub fn set_namespaces(&mut self, table: NamespaceTableRef) {
+        self.namespaces = table;
+    }
+
+    pub fn namespaces(&self) -> &NamespaceTable {
+        &self.namespaces
+    }
+
     pub fn socket(&self) -> Option<NodeId> {
         self.socket
     }
@@ -1454,6 +1785,10 @@ impl SegmentManager {
         self.allocator.freesz()
     }
 
+    pub fn mem_mode(&self) -> MemMode {
+        self.allocator.mem_mode()
+    }
+
     // hack
     #[cfg(IGNORE)]
     pub fn dump_seg_info(&self) {
@@ -1540,7 +1875,7 @@ mod tests {
     fn block_allocator_alloc_all() {
         logger::enable();
         let num = 64;
-        let bytes = num * BLOCK_SIZE;
+        let bytes = num * block_size();
         let mut ba = BlockAllocator::new(bytes);
         assert_eq!(ba.len(), num);
         assert_eq!(ba.freelen(), num);
@@ -1566,7 +1901,7 @@ mod tests {
     fn alloc_segment() {
         logger::enable();
         let num = 64;
-        let bytes = num * BLOCK_SIZE;
+        let bytes = num * block_size();
         let mut ba = BlockAllocator::new(bytes);
         let set;
         match ba.alloc(64) {
@@ -1625,7 +1960,7 @@ mod tests {
             match log.append(&obj) {
                 Ok(_) => count += 1,
                 Err(code) => match code {
-                    ErrorCode::OutOfMemory => break,
+                    ErrorCode::OutOfMemory{..} => break,
                     _ => panic!("filling log returned {:?}", code),
                 },
             }
@@ -1647,7 +1982,8 @@ mod tests {
 
         // TODO make a macro out of these lines
         let memlen = 1<<30;
-        let mut mgr = SegmentManager::numa(SEGMENT_SIZE, memlen, NodeId(0));
+        let mut mgr = SegmentManager::numa(SEGMENT_SIZE, memlen, NodeId(0))
+            .unwrap();
 
         let segref = mgr.alloc().unwrap();
         let mut seg = segref.write();
@@ -1663,7 +1999,8 @@ mod tests {
             "qwertyuiopasdfghjklzxcvbnmQWERTYUIOPASDFGHJKLZXCVBNM"
             .chars().collect();
 
-        let value_sizes: Vec<u32> = vec!(4337, 511, 997, 11);
+        // includes an empty value
+        let value_sizes: Vec<u32> = vec!(4337, 511, 0, 997, 11);
         let total: u32 = value_sizes.iter().fold(0, ops::Add::add)
             + (value_sizes.len() * size_of::<u64>()) as u32;
         let nbatches = (SEGMENT_SIZE/2) / (total as usize);
@@ -0,0 +1,430 @@
+/*
+ * Nibble - Concurrent Log-Structured Memory for Many-Core Key-Value Stores
+ *
+ * (c) 2017 Hewlett Packard Enterprise Development LP.
+ *
+ * This program is free software: you can redistribute it and/or modify it under the terms of the
+ * GNU Lesser General Public License as published by the Free Software Foundation, either version 3
+ * of the License, or (at your option) any later version. This program is distributed in the hope that
+ * it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE.  See the GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along with this program.
+ * If not, see <http://www.gnu.org/licenses/>. As an exception, the copyright holders of this Library
+ * grant you permission to (i) compile an Application with the Library, and (ii) distribute the Application
+ * containing code generated by the Library and added to the Application during this compilation process
+ * under terms of your choice, provided you also meet the terms and conditions of the Application license.
+ */
+
+//! File format for point-in-time copies of a store.
+//!
+//!     | FileHeader | object | object | ... | end marker | Trailer |
+//!
+//! Each object is an ObjHeader followed by the name of its namespace,
+//! if it has one, and the key and value bytes. ObjHeader holds their
+//! lengths, the object's version and expiry, and a CRC-32C over the
+//! header (crc zeroed), name, key and value. Expiry is in wall-clock
+//! nanoseconds since the UNIX epoch, or 0 for never. Objects of the
+//! default keyspace have a namespace length of NO_NS. The end marker
+//! is an ObjHeader with a zero key length.
+//! The Trailer holds the object count and a CRC-32C over every byte
+//! before it, so a truncated file, or one with any record dropped, is
+//! rejected.
+//!
+//! A snapshot may hold more than one copy of a key; the last one in
+//! the file is the most recent.
+
+use common::*;
+
+use std::fs::File;
+use std::io::{self,Read,Write,BufReader,BufWriter};
+use std::mem;
+use std::path::Path;
+use std::slice;
+use std::str;
+
+//==----------------------------------------------------==//
+//      Constants
+//==----------------------------------------------------==//
+
+const MAGIC: u64 = 0x4e49_4242_4c53_4e50; // "NIBBLSNP"
+
+/// Bump whenever the layout of anything in the file changes.
+pub const VERSION: u32 = 3;
+
+/// ObjHeader::nslen of an object in the default keyspace.
+const NO_NS: u32 = !0u32;
+
+//==----------------------------------------------------==//
+//      On-disk structures
+//==----------------------------------------------------==//
+
+#[derive(Debug)]
+#[repr(C,packed)]
+struct FileHeader {
+    magic: u64,
+    version: u32,
+    /// Sockets of the instance which wrote the snapshot; informative
+    nsockets: u32,
+}
+
+#[derive(Debug)]
+#[repr(C,packed)]
+struct ObjHeader {
+    version: u64,
+    expires: u64,
+    nslen: u32,
+    keylen: u32,
+    datalen: u32,
+    crc: u32,
+}
+
+/// One object as held in a snapshot.
+#[derive(Debug)]
+pub struct Object<'a> {
+    /// Name of the object's namespace; None for the default keyspace
+    pub ns: Option<&'a str>,
+    pub key: &'a KeyType,
+    pub value: &'a [u8],
+    pub version: u64,
+    /// Wall-clock nanoseconds since the UNIX epoch; 0 for never
+    pub expires: u64,
+}
+
+impl<'a> Object<'a> {
+
+    fn header(&self) -> ObjHeader {
+        ObjHeader {
+            version: self.version,
+            expires: self.expires,
+            nslen: self.ns.map_or(NO_NS, |n| n.len() as u32),
+            keylen: self.key.len() as u32,
+            datalen: self.value.len() as u32,
+            crc: 0,
+        }
+    }
+
+    fn name(&self) -> &'a [u8] {
+        self.ns.map_or(&[][..], |n| n.as_bytes())
+    }
+
+    fn crc(&self) -> u32 {
+        let mut h = self.header();
+        let crc = crc32c(0, as_bytes_mut(&mut h));
+        let crc = crc32c(crc, self.name());
+        crc32c(crc32c(crc, self.key), self.value)
+    }
+}
+
+#[derive(Debug)]
+#[repr(C,packed)]
+struct Trailer {
+    count: u64,
+    crc: u32,
+}
+
+/// View any of the above as raw bytes.
+fn as_bytes_mut<T>(t: &mut T) -> &mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(t as *mut T as *mut u8,
+                                  mem::size_of::<T>())
+    }
+}
+
+fn io_err(e: io::Error) -> ErrorCode {
+    warn!("snapshot: {}", e);
+    ErrorCode::IoError { kind: e.kind() }
+}
+
+fn bad(what: &str) -> ErrorCode {
+    warn!("snapshot: {}", what);
+    ErrorCode::BadSnapshot
+}
+
+//==----------------------------------------------------==//
+//      Writing
+//==----------------------------------------------------==//
+
+pub struct Writer {
+    w: BufWriter<File>,
+    /// Running CRC of all bytes written so far
+    crc: u32,
+    count: u64,
+}
+
+impl Writer {
+
+    pub fn create(path: &Path, nsockets: usize) -> Result<Self,ErrorCode> {
+        let file = try!(File::create(path).map_err(io_err));
+        let mut w = Writer {
+            w: BufWriter::new(file), crc: 0, count: 0,
+        };
+        let mut h = FileHeader {
+            magic: MAGIC, version: VERSION, nsockets: nsockets as u32,
+        };
+        try!(w.write(as_bytes_mut(&mut h)));
+        Ok(w)
+    }
+
+    pub fn add(&mut self, obj: &Object) -> Status {
+        debug_assert!(obj.key.len() > 0);
+        let mut h = obj.header();
+        h.crc = obj.crc();
+        try!(self.write(as_bytes_mut(&mut h)));
+        try!(self.write(obj.name()));
+        try!(self.write(obj.key));
+        try!(self.write(obj.value));
+        self.count += 1;
+        Ok(mem::size_of::<ObjHeader>() + obj.name().len()
+           + obj.key.len() + obj.value.len())
+    }
+
+    /// Write the end marker and trailer and flush everything to
+    /// stable storage. Returns the number of objects written.
+    pub fn finish(mut self) -> Status {
+        let mut end = ObjHeader {
+            version: 0, expires: 0,
+            nslen: NO_NS, keylen: 0, datalen: 0, crc: 0,
+        };
+        try!(self.write(as_bytes_mut(&mut end)));
+        let mut t = Trailer { count: self.count, crc: self.crc };
+        try!(self.w.write_all(as_bytes_mut(&mut t)).map_err(io_err));
+        let file = try!(self.w.into_inner().map_err(|e| io_err(e.into())));
+        try!(file.sync_all().map_err(io_err));
+        Ok(self.count as usize)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Status {
+        try!(self.w.write_all(bytes).map_err(io_err));
+        self.crc = crc32c(self.crc, bytes);
+        Ok(bytes.len())
+    }
+}
+
+//==----------------------------------------------------==//
+//      Reading
+//==----------------------------------------------------==//
+
+struct Reader {
+    r: BufReader<File>,
+    crc: u32,
+}
+
+/// Space for the variable-length parts of an object being read.
+#[derive(Default)]
+struct Buffers {
+    ns: Vec<u8>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Reader {
+
+    fn open(path: &Path) -> Result<Self,ErrorCode> {
+        let file = try!(File::open(path).map_err(io_err));
+        let mut r = Reader { r: BufReader::new(file), crc: 0 };
+        let mut h = FileHeader { magic: 0, version: 0, nsockets: 0 };
+        try!(r.read(as_bytes_mut(&mut h)));
+        let (magic,version) = (h.magic, h.version);
+        if magic != MAGIC {
+            return Err(bad("not a snapshot file"));
+        }
+        if version != VERSION {
+            warn!("snapshot: version {} unsupported (want {})",
+                  version, VERSION);
+            return Err(ErrorCode::BadSnapshot);
+        }
+        Ok(r)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Status {
+        match self.r.read_exact(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                return Err(bad("file is truncated")),
+            Err(e) => return Err(io_err(e)),
+            Ok(_) => {},
+        }
+        self.crc = crc32c(self.crc, buf);
+        Ok(buf.len())
+    }
+
+    /// Read the next object into buf. Returns None once the end
+    /// marker and trailer have been read and checked.
+    fn next<'a>(&mut self, buf: &'a mut Buffers, count: u64)
+        -> Result<Option<Object<'a>>,ErrorCode> {
+        let mut h = ObjHeader {
+            version: 0, expires: 0,
+            nslen: 0, keylen: 0, datalen: 0, crc: 0,
+        };
+        try!(self.read(as_bytes_mut(&mut h)));
+        let keylen = h.keylen as usize;
+        let datalen = h.datalen as usize;
+        if keylen == 0 {
+            try!(self.finish(count));
+            return Ok(None);
+        }
+        let nslen = match h.nslen {
+            NO_NS => None,
+            n => Some(n as usize),
+        };
+        buf.ns.resize(nslen.unwrap_or(0), 0u8);
+        buf.key.resize(keylen, 0u8);
+        buf.value.resize(datalen, 0u8);
+        try!(self.read(&mut buf.ns));
+        try!(self.read(&mut buf.key));
+        try!(self.read(&mut buf.value));
+        let ns = match nslen {
+            None => None,
+            Some(_) => match str::from_utf8(&buf.ns) {
+                Err(_) => return Err(bad("namespace name not UTF-8")),
+                Ok(name) => Some(name),
+            },
+        };
+        let obj = Object {
+            ns: ns, key: &buf.key, value: &buf.value,
+            version: h.version, expires: h.expires,
+        };
+        let crc = h.crc;
+        if crc != obj.crc() {
+            return Err(bad("object checksum mismatch"));
+        }
+        Ok(Some(obj))
+    }
+
+    fn finish(&mut self, count: u64) -> Status {
+        let mut t = Trailer { count: 0, crc: 0 };
+        match self.r.read_exact(as_bytes_mut(&mut t)) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                return Err(bad("file is truncated")),
+            Err(e) => return Err(io_err(e)),
+            Ok(_) => {},
+        }
+        let (tcount,tcrc) = (t.count, t.crc);
+        if tcrc != self.crc {
+            return Err(bad("file checksum mismatch"));
+        }
+        if tcount != count {
+            return Err(bad("object count mismatch"));
+        }
+        let mut extra = [0u8; 1];
+        match self.r.read(&mut extra) {
+            Ok(0) => Ok(count as usize),
+            Ok(_) => Err(bad("trailing bytes after trailer")),
+            Err(e) => Err(io_err(e)),
+        }
+    }
+}
+
+/// Invoke f on each object in the snapshot, in file order. Nothing
+/// is passed to f unless the whole file first verifies, so a bad file
+/// has no effect. Stops at the first error f returns. Returns the
+/// number of objects.
+pub fn load<F>(path: &Path, mut f: F) -> Status
+    where F: FnMut(&Object) -> Status {
+
+    try!(verify(path));
+
+    let mut r = try!(Reader::open(path));
+    let mut buf = Buffers::default();
+    let mut count = 0u64;
+    while let Some(obj) = try!(r.next(&mut buf, count)) {
+        try!(f(&obj));
+        count += 1;
+    }
+    Ok(count as usize)
+}
+
+/// Check the snapshot is well-formed and intact without loading it.
+/// Returns the number of objects.
+pub fn verify(path: &Path) -> Status {
+    let mut r = try!(Reader::open(path));
+    let mut buf = Buffers::default();
+    let mut count = 0u64;
+    while try!(r.next(&mut buf, count)).is_some() {
+        count += 1;
+    }
+    Ok(count as usize)
+}
+
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::*;
+
+    use std::env;
+    use std::fs::{self,OpenOptions};
+    use std::io::{Seek,SeekFrom,Write};
+    use std::path::PathBuf;
+
+    fn tmp(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("nibble-{}", name))
+    }
+
+    fn is_bad(s: Status) -> bool {
+        match s {
+            Err(ErrorCode::BadSnapshot) => true,
+            _ => false,
+        }
+    }
+
+    fn write_some(path: &Path) {
+        let mut w = Writer::create(path, 2).unwrap();
+        for i in 0..100u64 {
+            let value = vec![i as u8; i as usize];
+            let k = i + 1;
+            let ns = if i % 2 == 0 { None } else { Some("odd") };
+            let obj = Object {
+                ns: ns, key: u64_key(&k), value: &value,
+                version: k, expires: 1000 * k,
+            };
+            w.add(&obj).unwrap();
+        }
+        assert_eq!(w.finish().unwrap(), 100);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let path = tmp("snap-roundtrip");
+        write_some(&path);
+        let mut i = 0u64;
+        let n = load(&path, |obj| {
+            i += 1;
+            assert_eq!(obj.key, u64_key(&i));
+            assert_eq!(obj.ns, if i % 2 == 1 { None } else { Some("odd") });
+            assert_eq!((obj.version, obj.expires), (i, 1000 * i));
+            assert_eq!(obj.value.len(), (i-1) as usize);
+            assert!(obj.value.iter().all(|b| *b == (i-1) as u8));
+            Ok(1)
+        }).unwrap();
+        assert_eq!(n, 100);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt() {
+        let path = tmp("snap-corrupt");
+        write_some(&path);
+        {
+            let mut f = OpenOptions::new().write(true)
+                .open(&path).unwrap();
+            f.seek(SeekFrom::Start(1000)).unwrap();
+            f.write_all(&[0xffu8]).unwrap();
+        }
+        assert!(is_bad(verify(&path)));
+        let mut calls = 0;
+        let _ = load(&path, |_| { calls += 1; Ok(1) });
+        assert_eq!(calls, 0);
+
+        // truncated
+        write_some(&path);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap()
+            .set_len(len - 4).unwrap();
+        assert!(is_bad(verify(&path)));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * Nibble - Concurrent Log-Structured Memory for Many-Core Key-Value Stores
+ *
+ * (c) 2017 Hewlett Packard Enterprise Development LP.
+ *
+ * This program is free software: you can redistribute it and/or modify it under the terms of the
+ * GNU Lesser General Public License as published by the Free Software Foundation, either version 3
+ * of the License, or (at your option) any later version. This program is distributed in the hope that
+ * it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE.  See the GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along with this program.
+ * If not, see <http://www.gnu.org/licenses/>. As an exception, the copyright holders of this Library
+ * grant you permission to (i) compile an Application with the Library, and (ii) distribute the Application
+ * containing code generated by the Library and added to the Application during this compilation process
+ * under terms of your choice, provided you also meet the terms and conditions of the Application license.
+ */
+
+//! Background removal of expired objects.
+//!
+//! An object put with a time-to-live stays in the index after it
+//! expires until something notices: a write or delete of its key, or
+//! compaction of its segment. Reads merely report it missing. Objects
+//! in segments which are rarely compacted (e.g. those too large for a
+//! log head, which have a segment of their own) may thus hold memory
+//! long after they expire.
+//!
+//! Each socket may run one low-priority thread which periodically
+//! walks the socket's closed segments and removes from the index each
+//! object found expired, so its segment's live bytes drop and the
+//! memory is reclaimed by compaction (or released outright, for a
+//! segment of its own).
+//!
+//! Objects of a dropped namespace are dead too, and swept alike.
+
+use common::*;
+use segment::*;
+use index::*;
+use thelog::*;
+use wal::WalRef;
+use background::{self,Background,Pass};
+use clock;
+use meta::{self,NsId};
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+
+//==----------------------------------------------------==//
+//      Constants
+//==----------------------------------------------------==//
+
+/// Pause between the end of one pass and the start of the next.
+const SWEEP_INTERVAL_MS: u64 = 1_000;
+
+/// Segments walked between renewals of our epoch, so that we never
+/// hold back reclamation for long.
+const SEGS_PER_PIN: usize = 8;
+
+//==----------------------------------------------------==//
+//      Sweeper
+//==----------------------------------------------------==//
+
+struct State {
+    socket: usize,
+    manager: SegmentManagerRef,
+    index: IndexRef,
+    seginfo: meta::SegmentInfoTableRef,
+    wal: Option<WalRef>,
+    /// Objects removed over all passes
+    swept: AtomicUsize,
+}
+
+pub struct Sweeper {
+    bg: Background<State>,
+}
+
+impl Sweeper {
+
+    pub fn new(manager: &SegmentManagerRef, index: &IndexRef) -> Self {
+        let socket = manager.socket().unwrap().0;
+        let state = State {
+            socket: socket,
+            manager: manager.clone(),
+            index: index.clone(),
+            seginfo: manager.seginfo(),
+            wal: None,
+            swept: AtomicUsize::new(0),
+        };
+        let bg = Background::new("sweep", socket, SWEEP_INTERVAL_MS, state);
+        Sweeper { bg: bg }
+    }
+
+    /// Record removals in the write-ahead log. Must be called before
+    /// spawn.
+    pub fn set_wal(&mut self, wal: WalRef) {
+        match self.bg.state_mut() {
+            Some(state) => state.wal = Some(wal),
+            None => panic!("set_wal after sweeper was spawned"),
+        }
+    }
+
+    /// Run one pass on the calling thread. Returns the number of
+    /// objects removed.
+    pub fn sweep(&self) -> usize {
+        self.bg.state().pass()
+    }
+
+    /// Start the background thread, if not already running.
+    pub fn spawn(&mut self) {
+        self.bg.spawn();
+    }
+
+    /// Stop the background thread and wait for it to exit. A pass in
+    /// progress is finished first.
+    pub fn stop(&mut self) {
+        self.bg.stop();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.bg.is_running()
+    }
+
+    /// Number of expired objects removed, by either thread or sweep.
+    pub fn swept(&self) -> usize {
+        self.bg.state().swept.load(Ordering::Relaxed)
+    }
+}
+
+impl Pass for State {
+
+    fn run(&self) {
+        let _ = self.pass();
+    }
+}
+
+impl State {
+
+    /// Walk the segments which were closed when we started, removing
+    /// expired objects. Segments closed since are left for the next
+    /// pass.
+    fn pass(&self) -> usize {
+        let mut todo: HashSet<usize> = self.manager.segments().iter()
+            .map(|s| s.read())
+            .filter(|s| s.is_closed())
+            .map(|s| s.id())
+            .collect();
+        let mut key: Vec<u8> = Vec::with_capacity(64);
+        let mut expired: Vec<(Vec<u8>,IndexEntry,meta::NsId,usize,usize)>;
+        expired = Vec::new();
+        let mut n = 0usize;
+        while !todo.is_empty() {
+            // Segments may be released whenever we are not pinned,
+            // so look them up again each time.
+            meta::pin();
+            let segs: Vec<SegmentRef> = self.manager.segments()
+                .into_iter()
+                .filter(|s| todo.contains(&s.read().id()))
+                .take(SEGS_PER_PIN)
+                .collect();
+            if segs.is_empty() {
+                // the rest were released meanwhile
+                meta::quiesce();
+                break;
+            }
+            let now = clock::now();
+            let namespaces = self.manager.namespaces();
+            for segref in segs {
+                // collect first, so the segment is not locked while
+                // we take locks in the index
+                expired.clear();
+                {
+                    let seg = segref.read();
+                    todo.remove(&seg.id());
+                    for entry in seg.into_iter() {
+                        if !expired_at(entry.expires, now) &&
+                            !namespaces.is_dropped(entry.ns) {
+                            continue;
+                        }
+                        unsafe { entry.get_key(&mut key); }
+                        let ientry = merge(self.socket as u16,
+                                           entry.get_loc() as u64);
+                        expired.push((key.clone(), ientry, entry.ns,
+                                      seg.slot(), entry.len));
+                    }
+                }
+                for &(ref key, ientry, ns, slot, len) in &expired {
+                    if self.remove(key, ns, ientry, slot, len) {
+                        namespaces.credit(ns, len);
+                        n += 1;
+                    }
+                }
+            }
+            meta::quiesce();
+            thread::yield_now();
+        }
+        if n > 0 {
+            debug!("sweep socket {}: removed {} expired objects",
+                   self.socket, n);
+        }
+        self.swept.fetch_add(n, Ordering::Relaxed);
+        n
+    }
+
+    /// Remove the key if it still refers to the expired entry at
+    /// ientry, a len-byte entry in slot.
+    fn remove(&self, key: &KeyType, ns: NsId, ientry: IndexEntry,
+              slot: usize, len: usize) -> bool {
+        if !background::remove_ifeq(&self.index, self.wal.as_ref(),
+                                    key, ns, ientry) {
+            return false;
+        }
+        self.seginfo.decr_live(slot, len);
+        true
+    }
+}
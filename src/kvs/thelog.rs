@@ -27,33 +27,117 @@ use numa::{self,NodeId};
 
 use std::mem::{self,size_of};
 use std::sync::Arc;
+use std::sync::atomic::{self,AtomicUsize,Ordering};
 use std::ptr;
+use std::slice;
+use std::cmp;
 use std::intrinsics;
+use std::hint;
+use std::time::{SystemTime,UNIX_EPOCH};
 
 use parking_lot as pl;
 
-/// 2^this number of blocks to vary new segment allocations
-pub const ALLOC_NBLKS_VAR:  usize = BLOCKS_PER_SEG >> 2;
-
-/// If this breaks compilation, you need to make segments larger or
-/// blocks smaller.
-#[allow(dead_code)]
-fn __assert_Check_nblks_Not_Zero() {
-    1/ALLOC_NBLKS_VAR;
-}
+/// New segment allocations vary by up to blocks-per-segment >> this
+/// many blocks. LSMConfig::validate ensures the result is not zero.
+pub const ALLOC_NBLKS_VAR_SHIFT: usize = 2;
 
 //==----------------------------------------------------==//
 //      Entry header
 //==----------------------------------------------------==//
 
+/// Stored in place of a checksum for entries whose value was not
+/// known when appended (see LSM::alloc); these are never verified.
+#[cfg(feature="checksum")]
+pub const CRC_NONE: u32 = 0;
+
+/// Checksum of an entry's key and value bytes, continuing from crc.
+/// A result equal to CRC_NONE is stored as its complement.
+#[cfg(feature="checksum")]
+#[inline(always)]
+fn seal_crc(crc: u32) -> u32 {
+    if unlikely!(crc == CRC_NONE) { !crc } else { crc }
+}
+
+/// Per-object version. Each PUT of a key stamps the prior version
+/// plus one, starting from 1 when the key is inserted.
+pub type Version = u64;
+
+/// Time (in units of clock::now) at which an object expires, or
+/// NEVER.
+pub type Expiry = u64;
+pub const NEVER: Expiry = 0;
+
+/// Whether an object with the given expiry is dead at time now.
+#[inline(always)]
+pub fn expired_at(expires: Expiry, now: u64) -> bool {
+    expires != NEVER && now >= expires
+}
+
+/// Nanoseconds since the UNIX epoch.
+fn wall_nanos() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Err(_) => 0,
+        Ok(d) => d.as_secs() * clock::NANO_PER_SEC
+            + d.subsec_nanos() as u64,
+    }
+}
+
+/// An expiry as wall-clock nanoseconds since the UNIX epoch, for
+/// files read by other processes: clock::now counts from an origin
+/// of its own. NEVER stays 0.
+pub fn expiry_to_wall(expires: Expiry) -> u64 {
+    if expires == NEVER {
+        return 0;
+    }
+    let now = clock::now();
+    let left = if expires > now { clock::to_nano(expires - now) } else { 0 };
+    wall_nanos() + left
+}
+
+/// Inverse of expiry_to_wall. A time already past gives an expiry
+/// which is, too.
+pub fn expiry_from_wall(wall: u64) -> Expiry {
+    if wall == 0 {
+        return NEVER;
+    }
+    let (now, wnow) = (clock::now(), wall_nanos());
+    if wall <= wnow {
+        now
+    } else {
+        now + cmp::max(1, clock::from_nano(wall - wnow))
+    }
+}
+
+/// Set in EntryHeader::keylen if the entry ends with an Expiry.
+const HAS_EXPIRY: u32 = 1 << 31;
+
+/// Longest key an entry can hold; the top bit of its length is
+/// HAS_EXPIRY.
+pub const MAX_KEY_LEN: usize = (HAS_EXPIRY - 1) as usize;
+
 /// Describe entry in the log. Format is:
-///     | EntryHeader | Key bytes | Data bytes |
+///     | EntryHeader | Key bytes | Data bytes | [Expiry] |
+/// where the Expiry is present only for objects put with a
+/// time-to-live, so others do not pay for it.
 /// This struct MUST NOT contain any pointers.
 #[derive(Debug)]
 #[repr(C,packed)]
 pub struct EntryHeader {
-    //keylen: u32, // TODO don't need this; keys are fixed-size at 8B
+    /// HAS_EXPIRY is or'd in
+    keylen: u32,
     datalen: u32,
+    /// CRC-32C over key then value bytes, or CRC_NONE
+    #[cfg(feature="checksum")]
+    crc: u32,
+    ns: NsId,
+    /// Set before the entry is installed in the index, as is the
+    /// expiry; neither is covered by the checksum.
+    /// Must remain the last field. A full u64: at 10M puts/s a hot
+    /// key would wrap a u32 in about 7 minutes, and a stale
+    /// put_if_version could then succeed. Nor can the entry's place
+    /// in the log stand in for it, as compaction moves entries and
+    /// edits in place bump it without moving them.
+    version: Version,
 }
 
 // TODO can I get rid of most of this?
@@ -61,39 +145,101 @@ pub struct EntryHeader {
 impl EntryHeader {
 
     pub fn new(desc: &ObjDesc) -> Self {
-        debug_assert!(desc.valuelen() > 0usize);
+        // values may be empty; keys may not
+        debug_assert!(desc.keylen() > 0usize);
         // NOTE an ObjDesc may have a null value pointer,
         // as it may originate from an alloc instead of a PUT.
         // assert!(!desc.getvalue().0 .is_null());
+        let flag = if desc.expires != NEVER { HAS_EXPIRY } else { 0 };
         EntryHeader {
-            //keylen: desc.keylen() as u32,
+            keylen: desc.keylen() as u32 | flag,
             datalen: desc.valuelen() as u32,
+            #[cfg(feature="checksum")]
+            crc: Self::desc_crc(desc),
+            ns: desc.ns,
+            version: 0,
         }
     }
 
     pub fn empty() -> Self {
         EntryHeader {
-            //keylen: 0 as u32,
+            keylen: 0 as u32,
             datalen: 0 as u32,
+            #[cfg(feature="checksum")]
+            crc: CRC_NONE,
+            ns: DEFAULT_NS,
+            version: 0,
+        }
+    }
+
+    #[cfg(feature="checksum")]
+    fn desc_crc(desc: &ObjDesc) -> u32 {
+        if !desc.copy {
+            return CRC_NONE;
         }
+        let value: &[u8] = unsafe {
+            slice::from_raw_parts(desc.getvalue().0, desc.valuelen())
+        };
+        seal_crc(crc32c(crc32c(0, desc.getkey()), value))
     }
 
+    #[cfg(feature="checksum")]
+    #[inline(always)]
+    pub fn getcrc(&self) -> u32 { self.crc }
+
+    #[inline(always)]
+    pub fn getkeylen(&self) -> u32 { self.keylen & !HAS_EXPIRY }
     #[inline(always)]
     pub fn getdatalen(&self) -> u32 { self.datalen }
+    #[inline(always)]
+    pub fn getns(&self) -> NsId { self.ns }
+    #[inline(always)]
+    pub fn getversion(&self) -> Version { self.version }
+
+    /// Whether the entry ends with an Expiry.
+    #[inline(always)]
+    pub fn has_expiry(&self) -> bool {
+        self.keylen & HAS_EXPIRY != 0
+    }
+
+    /// Offset of the version within the header.
+    #[inline(always)]
+    pub fn version_offset() -> usize {
+        size_of::<EntryHeader>() - size_of::<Version>()
+    }
+
+    /// Offset of the Expiry within the entry, if it has one.
+    #[inline(always)]
+    pub fn expires_offset(&self) -> usize {
+        size_of::<EntryHeader>() + self.object_length() as usize
+    }
+
+    /// Bytes following the value.
+    #[inline(always)]
+    fn trailer_len(&self) -> usize {
+        if self.has_expiry() { size_of::<Expiry>() } else { 0 }
+    }
+
+    /// Offset of the checksum within the header, after the lengths.
+    #[cfg(feature="checksum")]
+    #[inline(always)]
+    pub fn crc_offset() -> usize {
+        2 * size_of::<u32>()
+    }
+
     #[inline(always)]
     pub fn object_length(&self) -> u32 {
-        self.datalen + size_of::<KeyType>() as u32
+        self.datalen + self.getkeylen()
     }
     #[inline(always)]
     pub fn len_with_header(&self) -> usize {
         (self.object_length() as usize) + size_of::<EntryHeader>()
+            + self.trailer_len()
     }
 
     /// Size of this (entire) entry in the log.
     pub fn len(&self) -> usize {
-        size_of::<EntryHeader>() +
-            self.datalen as usize +
-            size_of::<KeyType>()
+        self.len_with_header()
     }
 
     pub fn as_ptr(&self) -> *const u8 {
@@ -104,14 +250,59 @@ impl EntryHeader {
         self as *mut Self as *mut u8
     }
 
-    //#[cfg(test)]
-    //pub fn set_key_len(&mut self, l: u32) { self.keylen = l; }
+    #[cfg(test)]
+    pub fn set_key_len(&mut self, l: u32) { self.keylen = l; }
 
     #[cfg(test)]
     pub fn set_data_len(&mut self, l: u32) { self.datalen = l; }
 }
 
 
+//==----------------------------------------------------==//
+//      Edits in place
+//==----------------------------------------------------==//
+
+// Log::edit_value rewrites the value of a live entry, which readers
+// copy out without taking the key's lock. Entries map by address onto
+// a fixed set of sequence counters, each odd while an edit of one of
+// its entries is under way. A reader takes the counter before reading
+// an entry and reads it again if the counter has changed since.
+// Editors also hold the key's lock in the index, which is what keeps
+// them from editing the same entry at once.
+
+const EDIT_SEQS: usize = 4096;
+
+const SEQ_INIT: AtomicUsize = AtomicUsize::new(0);
+static EDIT_SEQ: [AtomicUsize; EDIT_SEQS] = [SEQ_INIT; EDIT_SEQS];
+
+#[inline(always)]
+fn edit_seq(va: usize) -> &'static AtomicUsize {
+    &EDIT_SEQ[(va >> 5) & (EDIT_SEQS - 1)]
+}
+
+/// Counter to give seq_retry once the entry at va has been read.
+/// Waits for an edit under way to finish.
+#[inline(always)]
+pub fn seq_begin(va: usize) -> usize {
+    let seq = edit_seq(va);
+    loop {
+        let s = seq.load(Ordering::Acquire);
+        if likely!(s & 1 == 0) {
+            return s;
+        }
+        hint::spin_loop();
+    }
+}
+
+/// Whether the entry at va may have been edited since seq_begin
+/// returned seq, in which case what was read of it is to be thrown
+/// away and read again.
+#[inline(always)]
+pub fn seq_retry(va: usize, seq: usize) -> bool {
+    atomic::fence(Ordering::Acquire);
+    edit_seq(va).load(Ordering::Relaxed) != seq
+}
+
 //==----------------------------------------------------==//
 //      Log head
 //==----------------------------------------------------==//
@@ -144,10 +335,12 @@ impl LogHead {
     }
 
     pub fn append(&mut self, buf: &ObjDesc) -> Status {
-        debug_assert!(buf.len_with_header() <
-                (SEGMENT_SIZE-size_of::<SegmentHeader>()),
-                "object {} larger than segment {}",
-                buf.len_with_header(), SEGMENT_SIZE);
+        // an object must fit within a single segment
+        let max = max_head_object(self.manager.segment_size());
+        if unlikely!(buf.len_with_header() > max) {
+            return Err(ErrorCode::ObjectTooBig {
+                size: buf.len_with_header(), max: max });
+        }
 
         let roll: bool;
 
@@ -186,13 +379,15 @@ impl LogHead {
     /// allocations (in case many threads roll all at once).
     fn replace(&mut self) -> Status {
         //self.segment = self.manager.alloc();
+        let perseg = self.manager.blocks_per_seg();
         let nblks: usize = unsafe {
-            (rdrand() as usize % ALLOC_NBLKS_VAR) +
-                BLOCKS_PER_SEG
+            (rdrand() as usize % (perseg >> ALLOC_NBLKS_VAR_SHIFT)) +
+                perseg
         };
         self.segment = self.manager.alloc_size(nblks);
         match self.segment {
-            None => Err(ErrorCode::OutOfMemory),
+            None => Err(ErrorCode::OutOfMemory {
+                socket: self.manager.socket().map(|s| s.0) }),
             _ => Ok(1),
         }
     }
@@ -222,6 +417,13 @@ impl LogHead {
 //      The log
 //==----------------------------------------------------==//
 
+/// Largest object (with its header) a log head can hold, given the
+/// nominal segment size.
+pub fn max_head_object(segsz: usize) -> usize {
+    segsz - size_of::<SegmentHeader>() - 1
+}
+
+/// Default number of log heads per socket.
 pub fn num_log_heads() -> usize {
     numa::NODE_MAP.cpus_in(NodeId(0))
 }
@@ -232,7 +434,7 @@ pub struct Log {
     seginfo: SegmentInfoTableRef,
     // TODO track current capacity?
     /// Number of log heads per instance of the log (per socket).
-    /// Allocate as many as there are cores on the socket.
+    /// By default as many as there are cores on the socket.
     nheads: usize,
 
 }
@@ -240,7 +442,12 @@ pub struct Log {
 impl Log {
 
     pub fn new(manager: SegmentManagerRef) -> Self {
-        let nheads: usize = num_log_heads();
+        Self::with_heads(manager, num_log_heads())
+    }
+
+    pub fn with_heads(manager: SegmentManagerRef,
+                      nheads: usize) -> Self {
+        assert!(nheads > 0, "log needs at least one head");
         let seginfo = manager.seginfo();
         let mut heads: Vec<LogHeadRef>;
         heads = Vec::with_capacity(nheads);
@@ -260,17 +467,46 @@ impl Log {
     }
 
     /// Append an object to the log. If successful, returns the
-    /// virtual address within the log inside Ok().
+    /// virtual address within the log inside Ok(). Objects must have
+    /// a key; EmptyObject is returned otherwise.
     pub fn append(&self, buf: &ObjDesc) -> Status {
+        if unlikely!(buf.key.is_empty()) {
+            return Err(ErrorCode::EmptyObject);
+        }
+        if unlikely!(buf.ns != DEFAULT_NS) {
+            return self.append_ns(buf);
+        }
+        self.__append(buf)
+    }
+
+    /// Append an object of a namespace, charging it against the
+    /// namespace's quota first. Credited back when the object dies.
+    #[cold]
+    fn append_ns(&self, buf: &ObjDesc) -> Status {
+        let len = buf.len_with_header();
+        let namespaces = self.manager.namespaces();
+        namespaces.charge(buf.ns, len)?;
+        let ret = self.__append(buf);
+        if ret.is_err() {
+            namespaces.credit(buf.ns, len);
+        }
+        ret
+    }
+
+    fn __append(&self, buf: &ObjDesc) -> Status {
         let va: usize;
 
+        if unlikely!(buf.len_with_header() > self.max_head_object()) {
+            return self.append_large(buf);
+        }
+
         // fast quasi-randomness (TODO might always be zero?)
         //let mut i = (buf as *const _ as usize) & LOG_HEADS_MASK;
         //let mut i = clock::now() as usize & LOG_HEADS_MASK;
 
         // using processor ID should hopefully avoid conflicts
         // compared to random assignment and hoping for luck
-        let (sockID,coreID) = clock::rdtscp_id();
+        let (sockID,coreID) = clock::cpu_id();
         let i = coreID as usize % self.nheads;
         //let i = unsafe { rdrand() as usize % self.nheads };
 
@@ -293,15 +529,53 @@ impl Log {
         // 2. update segment info table
         let idx = self.manager.segment_of(va);
         let len = buf.len_with_header();
-        debug_assert!(len < SEGMENT_SIZE);
         self.seginfo.incr_live(idx, len);
 
         // 3. return virtual address of new object
         Ok(va)
     }
 
+    /// Largest object (with its header) a log head will take. Anything
+    /// larger gets a segment of its own; see append_large.
+    pub fn max_head_object(&self) -> usize {
+        max_head_object(self.manager.segment_size())
+    }
+
+    /// Store an object too large for a regular segment in a segment
+    /// of its own, sized to fit. The object is laid out across that
+    /// segment's blocks like any other entry, so the index points to
+    /// it directly and reads need no special handling. The segment is
+    /// closed immediately and handed to compaction, which never moves
+    /// it but frees it whole once the object is overwritten or
+    /// deleted.
     #[cold]
-    fn get_entry_slow(&self, va: usize, buf: &mut [u8]) {
+    fn append_large(&self, buf: &ObjDesc) -> Status {
+        let len = buf.len_with_header();
+        // entry headers record lengths as u32
+        let max = u32::max_value() as usize;
+        if buf.keylen() > MAX_KEY_LEN || buf.valuelen() > max {
+            return Err(ErrorCode::ObjectTooBig { size: len, max: max });
+        }
+        let segref = self.manager.alloc_large(len)?;
+        let va = {
+            let mut seg = segref.write();
+            let va = seg.append(buf);
+            seg.close();
+            va
+        };
+        // hand the segment over even if the append failed, so that
+        // compaction releases it
+        self.manager.add_closed(&segref);
+        let va = va?;
+        let idx = self.manager.segment_of(va);
+        self.seginfo.incr_live(idx, len);
+        debug!("large object {} bytes in slot {}", len, idx);
+        Ok(va)
+    }
+
+    /// Construct a reference to the entry at va. Caller must have
+    /// the epoch pinned for as long as the reference is used.
+    pub fn entry_ref(&self, va: usize) -> EntryReference {
         let block: Block = self.manager.block_of(va);
         debug_assert_eq!(block.list().ptr().is_null(), false);
         let usl = block.list();
@@ -309,31 +583,96 @@ impl Log {
             "block idx {} out of bounds for uslice {}",
             block.blk_idx(), usl.len());
         let list: &[BlockRef] = unsafe { usl.slice() };
-        let entry = get_ref(list, block.blk_idx(), va);
+        get_ref(list, block.blk_idx(), va)
+    }
+
+    #[cold]
+    fn get_entry_slow(&self, va: usize, buf: &mut [u8]) -> Status {
+        let entry = self.entry_ref(va);
+        if unlikely!(entry.is_expired()) {
+            return Err(ErrorCode::KeyNotExist);
+        }
+        let value_len = entry.datalen as usize;
+        if unlikely!(buf.len() < value_len) {
+            return Err(ErrorCode::BufferTooSmall {
+                needed: value_len, len: buf.len() });
+        }
+        unsafe { entry.get_buf(buf); }
+        Ok(value_len)
+    }
+
+    /// Like get_entry_slow, but first check the entry against its
+    /// checksum, returning Corrupted on a mismatch.
+    fn get_entry_verified(&self, va: usize, buf: &mut [u8]) -> Status {
+        let entry = self.entry_ref(va);
+        if unlikely!(entry.is_expired()) {
+            return Err(ErrorCode::KeyNotExist);
+        }
+        let value_len = entry.datalen as usize;
+        if unlikely!(buf.len() < value_len) {
+            return Err(ErrorCode::BufferTooSmall {
+                needed: value_len, len: buf.len() });
+        }
+        if unlikely!(!entry.verify()) {
+            warn!("checksum mismatch for entry at 0x{:x}", va);
+            return Err(ErrorCode::Corrupted);
+        }
         unsafe { entry.get_buf(buf); }
+        Ok(value_len)
     }
 
     /// Pull out the value for an entry within the log (not the entire
-    /// object). DO NOT do any buffer allocations on this fast path.
+    /// object). Returns the length of the value, or BufferTooSmall
+    /// (copying nothing) if it does not fit in buf, or KeyNotExist if
+    /// the object has expired. If built with
+    /// verify_get, the entry is checked against its checksum first.
+    /// The value is copied again if it was edited in place meanwhile.
+    /// DO NOT do any buffer allocations on this fast path.
+    #[inline(always)]
+    pub fn get_entry(&self, va: usize, buf: &mut [u8]) -> Status {
+        loop {
+            let seq = seq_begin(va);
+            let ret = self.__get_entry(va, buf);
+            if likely!(!seq_retry(va, seq)) {
+                return ret;
+            }
+        }
+    }
+
     #[inline(always)]
-    pub fn get_entry(&self, va: usize, buf: &mut [u8]) {
+    fn __get_entry(&self, va: usize, buf: &mut [u8]) -> Status {
+        if cfg!(feature="verify_get") {
+            return self.get_entry_verified(va, buf);
+        }
         let head_len = mem::size_of::<EntryHeader>();
-        let key_len = mem::size_of::<KeyType>();
-        let block_addr: usize = va & !BLOCK_OFF_MASK;
-        let remain: usize = BLOCK_SIZE - (va - block_addr);
+        let block_addr: usize = va & !block_off_mask();
+        let remain: usize = block_size() - (va - block_addr);
 
         // If object lands squarely within a single block, just memcpy
         // that out. else, figure out the segment and thus the
         // block list, and do a slowpath extraction
         if unlikely!(remain < head_len) {
-            self.get_entry_slow(va,buf);
+            self.get_entry_slow(va,buf)
         } else {
             let remain = remain - head_len;
-            let p = va as *const u32;
-            let value_len = unsafe { ptr::read_volatile(p) } as usize;
-            if unlikely!(remain < (key_len+value_len)) {
-                self.get_entry_slow(va,buf);
+            let p = va as *const EntryHeader;
+            let header = unsafe { ptr::read_volatile(p) };
+            let key_len = header.getkeylen() as usize;
+            let value_len = header.getdatalen() as usize;
+            if unlikely!(remain < (key_len+value_len+header.trailer_len())) {
+                self.get_entry_slow(va,buf)
             } else {
+                if unlikely!(header.has_expiry()) {
+                    let p = (va + header.expires_offset()) as *const Expiry;
+                    let expires = unsafe { ptr::read_unaligned(p) };
+                    if expired_at(expires, clock::now()) {
+                        return Err(ErrorCode::KeyNotExist);
+                    }
+                }
+                if unlikely!(buf.len() < value_len) {
+                    return Err(ErrorCode::BufferTooSmall {
+                        needed: value_len, len: buf.len() });
+                }
                 let valuep = (va + head_len + key_len)
                     as *const usize as *const u8;
                 unsafe {
@@ -341,8 +680,38 @@ impl Log {
                     //ptr::copy_nonoverlapping(valuep,
                             //buf.as_mut_ptr(), value_len);
                 }
+                Ok(value_len)
+            }
+        }
+    }
+
+    /// Compare the namespace and key stored with the entry at va
+    /// against those given. Used by the index to resolve keys whose
+    /// hashes collide. Caller must either have the epoch pinned, or
+    /// hold the lock on the index bucket referring to va.
+    #[inline(always)]
+    pub fn key_matches(&self, va: usize, ns: NsId,
+                       key: &KeyType) -> bool {
+        let head_len = mem::size_of::<EntryHeader>();
+        let block_addr: usize = va & !block_off_mask();
+        let remain: usize = block_size() - (va - block_addr);
+
+        // same fast path as get_entry
+        if likely!(remain >= head_len) {
+            let p = va as *const EntryHeader;
+            let header = unsafe { ptr::read_volatile(p) };
+            let key_len = header.getkeylen() as usize;
+            if key_len != key.len() || header.getns() != ns {
+                return false;
+            }
+            if likely!((remain - head_len) >= key_len) {
+                let keyp = (va + head_len) as *const u8;
+                let stored = unsafe { slice::from_raw_parts(keyp, key_len) };
+                return stored == key;
             }
         }
+        let entry = self.entry_ref(va);
+        entry.ns == ns && unsafe { entry.key_eq(key) }
     }
 
     /// Only pull out the entry header. Useful to know the object size
@@ -352,8 +721,8 @@ impl Log {
     #[inline(always)]
     pub fn copy_header(&self, va: usize) -> EntryHeader {
         let mut header: EntryHeader = EntryHeader::empty();
-        let offset = va & BLOCK_OFF_MASK;
-        let blk_tail = BLOCK_SIZE - offset;
+        let offset = va & block_off_mask();
+        let blk_tail = block_size() - offset;
         let len = size_of::<EntryHeader>();
 
         if blk_tail >= len {
@@ -379,6 +748,109 @@ impl Log {
         header
     }
 
+    /// Stamp a version into the header of the entry at va. Caller
+    /// must hold the lock on the key in the index.
+    pub fn set_version(&self, va: usize, version: Version) {
+        self.set_header_word(va, EntryHeader::version_offset(), version);
+    }
+
+    /// Set when the entry at va expires. Only entries appended with
+    /// an expiry have room for one; others may only be set to NEVER,
+    /// which they are already. Caller must hold the lock on the key
+    /// in the index, or not yet have installed the entry.
+    pub fn set_expiry(&self, va: usize, expires: Expiry) {
+        let head = self.copy_header(va);
+        if !head.has_expiry() {
+            debug_assert_eq!(expires, NEVER);
+            return;
+        }
+        self.set_header_word(va, head.expires_offset(), expires);
+    }
+
+    /// Whether the entry at va, whose header is head, has expired.
+    /// Only reads the clock if the entry has an expiry.
+    pub fn is_expired(&self, va: usize, head: &EntryHeader) -> bool {
+        if likely!(!head.has_expiry()) {
+            return false;
+        }
+        let mut expires: Expiry = NEVER;
+        self.read_at(va, head.expires_offset(),
+                     &mut expires as *mut Expiry as *mut u8,
+                     size_of::<Expiry>());
+        expired_at(expires, clock::now())
+    }
+
+    /// Overwrite the value of the live entry at va with bytes, from
+    /// offset into it, and stamp the entry with version. The bytes
+    /// must lie within the value. Readers never see the edit partly
+    /// made: see seq_begin. With the checksum feature, the checksum
+    /// is updated too, unless the entry has none. Caller must hold
+    /// the lock on the key in the index.
+    pub fn edit_value(&self, va: usize, offset: usize, bytes: &[u8],
+                      version: Version) {
+        let seq = edit_seq(va);
+        let mut s = seq.load(Ordering::Relaxed);
+        loop {
+            if s & 1 == 0 {
+                match seq.compare_exchange_weak(s, s + 1,
+                        Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(cur) => s = cur,
+                }
+            } else {
+                hint::spin_loop();
+                s = seq.load(Ordering::Relaxed);
+            }
+        }
+
+        let entry = self.entry_ref(va);
+        debug_assert!(offset + bytes.len() <= entry.datalen as usize);
+        let at = size_of::<EntryHeader>() + entry.keylen as usize + offset;
+        self.write_at(va, at, bytes.as_ptr(), bytes.len());
+        self.set_version(va, version);
+        #[cfg(feature="checksum")]
+        {
+            if entry.crc != CRC_NONE {
+                let crc = entry.compute_crc();
+                self.write_at(va, EntryHeader::crc_offset(),
+                              &crc as *const u32 as *const u8,
+                              size_of::<u32>());
+            }
+        }
+
+        seq.store(s + 2, Ordering::Release);
+    }
+
+    fn set_header_word(&self, va: usize, field: usize, value: u64) {
+        self.write_at(va, field, &value as *const u64 as *const u8,
+                      size_of::<u64>());
+    }
+
+    /// Copy len bytes from src into the entry at va, at byte offset
+    /// at from its start.
+    fn write_at(&self, va: usize, at: usize, src: *const u8, len: usize) {
+        let block: Block = self.manager.block_of(va);
+        debug_assert_eq!(block.list().ptr().is_null(), false);
+        let list: &[BlockRef] = unsafe { block.list().slice() };
+        let offset = (va & block_off_mask()) + at;
+        unsafe {
+            copy_in(&list[block.blk_idx()..], offset / block_size(),
+                    offset % block_size(), src, len);
+        }
+    }
+
+    /// Copy len bytes into dst from the entry at va, at byte offset
+    /// at from its start.
+    fn read_at(&self, va: usize, at: usize, dst: *mut u8, len: usize) {
+        let block: Block = self.manager.block_of(va);
+        debug_assert_eq!(block.list().ptr().is_null(), false);
+        let list: &[BlockRef] = unsafe { block.list().slice() };
+        let offset = (va & block_off_mask()) + at;
+        unsafe {
+            copy_out(&list[block.blk_idx()..], offset, dst, len);
+        }
+    }
+
     //
     // --- Internal methods used for testing only ---
     //
@@ -400,7 +872,13 @@ impl Log {
 pub struct EntryReference<'a> {
     pub offset: usize, // into first block
     pub len: usize, /// header + key + data
+    pub keylen: u32,
     pub datalen: u32,
+    #[cfg(feature="checksum")]
+    pub crc: u32,
+    pub ns: NsId,
+    pub expires: Expiry,
+    pub version: Version,
     /// TODO can we avoid cloning the Arcs?
     pub blocks: &'a [BlockRef]
 }
@@ -413,23 +891,38 @@ impl<'a> EntryReference<'a> {
         self.offset + self.blocks[0].addr()
     }
 
-    /// Copy out the key
-    pub unsafe fn get_key(&self) -> u64 {
+    /// Whether the object's time-to-live has passed.
+    #[inline(always)]
+    pub fn is_expired(&self) -> bool {
+        self.expires != NEVER && expired_at(self.expires, clock::now())
+    }
+
+    /// Copy out the key, replacing the contents of key.
+    pub unsafe fn get_key(&self, key: &mut Vec<u8>) {
         let offset = self.offset + size_of::<EntryHeader>();
-        let mut key: u64 = 0;
+        let klen = self.keylen as usize;
+        key.clear();
+        key.reserve(klen);
+        key.set_len(klen);
         // TODO optimize if contiguous
-        // hm, lots of overhead for copying 8 bytes
         segment::copy_out(&self.blocks, offset,
-                          &mut key as *mut u64 as *mut u8,
-                          size_of::<u64>());
-        key
+                          key.as_mut_ptr(), klen);
+    }
+
+    /// Compare the stored key against the one given, without copying
+    /// it out of the log.
+    pub unsafe fn key_eq(&self, key: &KeyType) -> bool {
+        if key.len() != self.keylen as usize {
+            return false;
+        }
+        let offset = self.offset + size_of::<EntryHeader>();
+        segment::compare_out(&self.blocks, offset, key)
     }
 
     /// Copy out the value
     #[inline(always)]
     pub unsafe fn get_data(&self, out: *mut u8) {
-        let offset = self.offset + self.len
-                            - self.datalen as usize;
+        let offset = self.data_offset();
         // TODO optimize if contiguous
         segment::copy_out(&self.blocks, offset,
                           out, self.datalen as usize);
@@ -440,7 +933,7 @@ impl<'a> EntryReference<'a> {
     #[inline(always)]
     pub unsafe fn get_buf(&self, out: &mut [u8]) {
         let dlen = self.datalen as usize;
-        let offset = self.offset + self.len - dlen;
+        let offset = self.data_offset();
         //if unlikely!(out.len() < dlen) {
             //panic!("ur buf is 2 smal");
         //}
@@ -449,6 +942,86 @@ impl<'a> EntryReference<'a> {
                           out.as_mut_ptr(), dlen);
     }
 
+    /// The entry as it is now. Its version, and checksum, change
+    /// when it is edited in place; see Log::edit_value.
+    pub fn reload(&self) -> EntryReference<'a> {
+        get_ref(self.blocks, 0, self.get_loc())
+    }
+
+    /// Iterate over the value in place, one slice per block it
+    /// occupies. Nothing is copied; the slices are only valid while
+    /// the epoch that produced this reference remains pinned.
+    pub fn data_chunks(&self) -> DataChunks<'a> {
+        let dlen = self.datalen as usize;
+        self.chunks(self.data_offset(), dlen)
+    }
+
+    /// Check the key and value against the checksum in the header.
+    /// Entries without one (and all entries, when built without the
+    /// checksum feature) always pass.
+    #[cfg(feature="checksum")]
+    pub fn verify(&self) -> bool {
+        if self.crc == CRC_NONE {
+            return true;
+        }
+        self.compute_crc() == self.crc
+    }
+
+    #[cfg(not(feature="checksum"))]
+    #[inline(always)]
+    pub fn verify(&self) -> bool { true }
+
+    #[cfg(feature="checksum")]
+    fn compute_crc(&self) -> u32 {
+        let hlen = size_of::<EntryHeader>();
+        let len = self.keylen as usize + self.datalen as usize;
+        let crc = self.chunks(self.offset + hlen, len)
+            .fold(0u32, |crc, chunk| crc32c(crc, chunk));
+        seal_crc(crc)
+    }
+
+    /// Logical offset of the value; it follows the key.
+    #[inline(always)]
+    fn data_offset(&self) -> usize {
+        self.offset + size_of::<EntryHeader>() + self.keylen as usize
+    }
+
+    /// In-place slices covering len bytes at the logical offset.
+    fn chunks(&self, offset: usize, len: usize) -> DataChunks<'a> {
+        DataChunks {
+            blocks: self.blocks,
+            idx: offset / block_size(),
+            offset: offset % block_size(),
+            remaining: len,
+        }
+    }
+
+}
+
+/// Iterator over the pieces of an entry's value as they lie in the
+/// log's blocks. See EntryReference::data_chunks
+pub struct DataChunks<'a> {
+    blocks: &'a [BlockRef],
+    idx: usize,
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for DataChunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let amt = cmp::min(block_size() - self.offset, self.remaining);
+        let base = (self.blocks[self.idx].addr() + self.offset) as *const u8;
+        let chunk = unsafe { slice::from_raw_parts(base, amt) };
+        self.remaining -= amt;
+        self.offset = 0;
+        self.idx += 1;
+        Some(chunk)
+    }
 }
 
 /// Construct an EntryReference given a VA and a set of Blocks.
@@ -456,8 +1029,8 @@ impl<'a> EntryReference<'a> {
 pub fn get_ref(list: &[BlockRef], idx: usize, va: usize) -> EntryReference {
     let mut header: EntryHeader;
     let href: &EntryHeader;
-    let offset = va & BLOCK_OFF_MASK;
-    let blk_tail = BLOCK_SIZE - offset;
+    let offset = va & block_off_mask();
+    let blk_tail = block_size() - offset;
     let len = size_of::<EntryHeader>();
 
     // only copy out the header if it is split across blocks,
@@ -471,30 +1044,100 @@ pub fn get_ref(list: &[BlockRef], idx: usize, va: usize) -> EntryReference {
         href = &header;
     }}
 
-    debug_assert!(href.getdatalen() > 0);
+    debug_assert!(href.getkeylen() > 0);
     // https://github.com/rust-lang/rust/issues/22644
-    debug_assert!( (href.getdatalen() as usize) < SEGMENT_SIZE);
+    debug_assert!( (href.getdatalen() as usize) <
+                   list.len() * block_size());
 
     // determine which blocks belong
     let mut nblks = 1;
     let entry_len = href.len_with_header();
     if entry_len > blk_tail {
-        nblks += ((entry_len - blk_tail - 1) / BLOCK_SIZE) + 1;
+        nblks += ((entry_len - blk_tail - 1) / block_size()) + 1;
     }
     debug_assert!( (idx + nblks - 1) < list.len() );
 
     EntryReference {
         offset: offset,
         len: entry_len,
+        keylen: href.getkeylen(),
         datalen: href.getdatalen(),
+        #[cfg(feature="checksum")]
+        crc: href.getcrc(),
+        ns: href.getns(),
+        expires: read_expiry(&list[idx..], offset, href),
+        version: href.getversion(),
         blocks: &list[idx..(idx + nblks)],
     }
 }
 
+/// Expiry of the entry at the logical offset into blocks, whose
+/// header is head.
+#[inline(always)]
+pub fn read_expiry(blocks: &[BlockRef], offset: usize,
+                   head: &EntryHeader) -> Expiry {
+    let mut expires: Expiry = NEVER;
+    if unlikely!(head.has_expiry()) {
+        unsafe {
+            copy_out(blocks, offset + head.expires_offset(),
+                     &mut expires as *mut Expiry as *mut u8,
+                     size_of::<Expiry>());
+        }
+    }
+    expires
+}
+
 //==----------------------------------------------------==//
 //      Unit tests
 //==----------------------------------------------------==//
 
+#[cfg(all(test, feature="checksum"))]
+mod checksum_tests {
+    use super::*;
+    use config::LSMConfig;
+
+    fn log() -> Log {
+        let config = LSMConfig::small();
+        let len = config.get_capacity() / numa::NODE_MAP.sockets();
+        let manager = SegmentManager::with_config(len, NodeId(0), &config)
+            .unwrap();
+        Log::with_heads(Arc::new(manager), 1)
+    }
+
+    /// a flipped bit in the key or the value fails the checksum, and
+    /// reads report it if built to verify them
+    #[test]
+    fn detects_corruption() {
+        let log = log();
+        let key = b"checksummed";
+        let len = 100usize;
+        let value: Vec<u8> = vec![7u8; len];
+        let obj = ObjDesc::new(key, Pointer(value.as_ptr()), len);
+        let va = log.append(&obj).unwrap();
+        assert!(log.entry_ref(va).crc != CRC_NONE);
+        assert!(log.entry_ref(va).verify());
+
+        let hlen = size_of::<EntryHeader>();
+        let mut buf: Vec<u8> = vec![0u8; len];
+        for &off in &[hlen, hlen + key.len() + len - 1] {
+            // the entry lies within the first block of the log
+            let p = (va + off) as *mut u8;
+            unsafe { *p ^= 1; }
+            assert!(!log.entry_ref(va).verify());
+            if cfg!(feature="verify_get") {
+                assert_eq!(log.get_entry(va, &mut buf),
+                           Err(ErrorCode::Corrupted));
+            } else {
+                assert_eq!(log.get_entry(va, &mut buf), Ok(len));
+            }
+            unsafe { *p ^= 1; }
+            assert!(log.entry_ref(va).verify());
+        }
+        assert_eq!(log.get_entry(va, &mut buf), Ok(len));
+        assert_eq!(buf, value);
+    }
+}
+
 #[cfg(IGNORE)]
 mod tests {
     use super::*;
@@ -522,7 +1165,7 @@ mod tests {
         loop {
             if let Err(code) = log.append(&obj) {
                 match code {
-                    ErrorCode::OutOfMemory => break,
+                    ErrorCode::OutOfMemory{..} => break,
                     _ => panic!("filling log returned {:?}", code),
                 }
             }
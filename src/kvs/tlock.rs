@@ -27,7 +27,7 @@
  *
  */
 
-use std::sync::atomic::{self, Ordering};
+use std::sync::atomic::{self, AtomicU64, Ordering};
 use std::intrinsics;
 use std::ptr;
 
@@ -53,7 +53,7 @@ impl TicketLock {
     fn incr_next(&self) -> u64 {
         let nextp = &self.next as *const u64 as *mut u64;
         unsafe {
-            intrinsics::atomic_xadd_acq(nextp, 1)
+            AtomicU64::from_ptr(nextp).fetch_add(1, Ordering::Acquire)
         }
     }
 
@@ -179,7 +179,8 @@ mod tests {
         for t in tids {
             let _ = t.join();
         }
-        assert_eq!(shared, nthreads*niters);
+        let total = unsafe { ptr::read_volatile(&shared) };
+        assert_eq!(total, nthreads*niters);
 
         let tlock = unsafe {
             Box::from_raw(u as *const TicketLock
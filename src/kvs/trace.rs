@@ -93,10 +93,6 @@ impl Trace {
         let mut mapped = memory::MemMapFile::new(path);
         let entries = unsafe { mapped.as_slice::<TraceFileEntry>() };
         for entry in entries {
-            // LSM does not allow key 0
-            if entry.key == 0 { continue; }
-            // FIXME LSM still cannot handle zero-sized objects
-            let size = if entry.size == 0 { 1 } else { entry.size };
             // convert TraceFileEntry to Entry
             let op = match entry.op {
                 0 => Op::Get,
@@ -104,7 +100,7 @@ impl Trace {
                 2 => Op::Del,
                 e @ _ => panic!("Unexpected op code: {}", e),
             };
-            self.rec.push( Entry::new(entry.key,op,size) );
+            self.rec.push( Entry::new(entry.key,op,entry.size) );
             if 0 == (self.rec.len() % 500_000_000) {
                 info!("Loaded {} mil. entries",
                       self.rec.len() / 1_000_000_usize);
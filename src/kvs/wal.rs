@@ -0,0 +1,1075 @@
+/*
+ * Nibble - Concurrent Log-Structured Memory for Many-Core Key-Value Stores
+ *
+ * (c) 2017 Hewlett Packard Enterprise Development LP.
+ *
+ * This program is free software: you can redistribute it and/or modify it under the terms of the
+ * GNU Lesser General Public License as published by the Free Software Foundation, either version 3
+ * of the License, or (at your option) any later version. This program is distributed in the hope that
+ * it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+ * FITNESS FOR A PARTICULAR PURPOSE.  See the GNU Lesser General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public License along with this program.
+ * If not, see <http://www.gnu.org/licenses/>. As an exception, the copyright holders of this Library
+ * grant you permission to (i) compile an Application with the Library, and (ii) distribute the Application
+ * containing code generated by the Library and added to the Application during this compilation process
+ * under terms of your choice, provided you also meet the terms and conditions of the Application license.
+ */
+
+//! Write-ahead log for durability.
+//!
+//! Each socket has its own append-only file, which begins with a
+//! FileHeader naming the format. Every PUT and DEL is then recorded
+//! as
+//!
+//!     | RecordHeader | key bytes | value bytes |
+//!
+//! where RecordHeader holds the key and value lengths, the kind of
+//! operation, a sequence number, the version, expiry and namespace of
+//! the object, and a CRC-32C over the whole record. Expiry times are
+//! kept as wall-clock time, as the clock objects are stamped with
+//! starts over in each process. Its layout is fixed,
+//! independent of the in-memory EntryHeader (which varies with build
+//! features). Sequence numbers are taken while the key is locked in
+//! the index, so they order updates to one key even when they land in
+//! different files. The updates of a write batch are written together
+//! to one file, between a BEGIN and a COMMIT record, and replay
+//! applies them only if the COMMIT was written.
+//!
+//! Objects record their namespace by id, which holds only for the
+//! instance that wrote them. The creation and drop of a namespace are
+//! thus logged too, as NS and NSDROP records (with the name as key)
+//! written to every file, ahead of any object of the namespace in
+//! that file. Replay maps the ids in the files to those of namespaces
+//! it opens by name. Quotas are not logged.
+//!
+//! Files belong to a generation, named wal.<gen>.<socket>. The file
+//! CURRENT names the live generation. A checkpoint writes the live
+//! objects into the next generation while records are still appended
+//! to the old one, copies those records over, then atomically
+//! switches CURRENT and removes the old files; this is how the
+//! on-disk logs are cleaned and truncated. Checkpoints are triggered by the
+//! compaction threads once the files have grown enough.
+
+use common::*;
+use segment::*;
+use index::*;
+use thelog::{self,Version,Expiry,NEVER};
+use meta::{self,NsId};
+
+use std::collections::HashSet;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Read,Write,Seek,SeekFrom,BufReader,BufWriter};
+use std::mem;
+use std::path::{Path,PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use parking_lot as pl;
+
+//==----------------------------------------------------==//
+//      Constants
+//==----------------------------------------------------==//
+
+/// "NIBBLWAL"
+const MAGIC: u64 = 0x4e49_4242_4c57_414c;
+
+/// Layout of the files. Bumped whenever the records change; files
+/// of another format are refused rather than misread.
+const FORMAT: u32 = 4;
+
+const REC_PUT: u32 = 1;
+const REC_DEL: u32 = 2;
+/// Open and close a batch; these have no key, and hold the number of
+/// updates in the batch in place of a sequence number.
+const REC_BEGIN: u32 = 3;
+const REC_COMMIT: u32 = 4;
+/// Create and drop a namespace; the key is its name.
+const REC_NS: u32 = 5;
+const REC_NSDROP: u32 = 6;
+
+/// Files are not checkpointed until they hold at least this much.
+const CKPT_MIN_BYTES: usize = 1usize << 26;
+
+/// Checkpoint once the files have grown this many times larger than
+/// they were after the prior checkpoint.
+const CKPT_RATIO: usize = 2;
+
+const CURRENT: &'static str = "CURRENT";
+
+//==----------------------------------------------------==//
+//      Records
+//==----------------------------------------------------==//
+
+#[derive(Debug)]
+#[repr(C,packed)]
+struct FileHeader {
+    magic: u64,
+    format: u32,
+    socket: u32,
+}
+
+impl FileHeader {
+
+    fn new(socket: usize) -> Self {
+        FileHeader { magic: MAGIC, format: FORMAT, socket: socket as u32 }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut Self as *mut u8,
+                                      mem::size_of::<Self>())
+        }
+    }
+}
+
+#[derive(Debug)]
+#[repr(C,packed)]
+struct RecordHeader {
+    keylen: u32,
+    datalen: u32,
+    kind: u32,
+    crc: u32,
+    seq: u64,
+    version: u64,
+    /// Nanoseconds since the UNIX epoch, or 0 for never
+    expires: u64,
+    ns: u32,
+    pad: u32,
+}
+
+impl RecordHeader {
+
+    fn empty() -> Self {
+        RecordHeader {
+            keylen: 0, datalen: 0, kind: 0, crc: 0,
+            seq: 0, version: 0, expires: 0, ns: 0, pad: 0,
+        }
+    }
+
+    fn new(kind: u32, up: &Update) -> Self {
+        let value = up.value.unwrap_or(&[]);
+        let mut h = RecordHeader {
+            keylen: up.key.len() as u32,
+            datalen: value.len() as u32,
+            kind: kind,
+            crc: 0,
+            seq: up.seq,
+            version: up.version,
+            expires: thelog::expiry_to_wall(up.expires),
+            ns: up.ns,
+            pad: 0,
+        };
+        let crc = h.checksum(up.key, value);
+        h.crc = crc;
+        h
+    }
+
+    /// CRC over the header (with crc zeroed), key and value.
+    fn checksum(&self, key: &KeyType, value: &[u8]) -> u32 {
+        let mut h = RecordHeader {
+            keylen: self.keylen,
+            datalen: self.datalen,
+            kind: self.kind,
+            crc: 0,
+            seq: self.seq,
+            version: self.version,
+            expires: self.expires,
+            ns: self.ns,
+            pad: self.pad,
+        };
+        let mut crc = crc32c(0, h.as_bytes_mut());
+        crc = crc32c(crc, key);
+        crc32c(crc, value)
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut Self as *mut u8,
+                                      mem::size_of::<Self>())
+        }
+    }
+}
+
+/// An update to be logged. Borrows the caller's key and value.
+pub struct Update<'a> {
+    pub seq: u64,
+    pub ns: NsId,
+    pub key: &'a KeyType,
+    /// None to delete the key
+    pub value: Option<&'a [u8]>,
+    /// Of the object put; these are ignored for deletes
+    pub version: Version,
+    pub expires: Expiry,
+}
+
+/// An operation read back from a file.
+#[derive(Debug,PartialEq)]
+pub enum Record {
+    Put { seq: u64, ns: NsId, key: Vec<u8>, value: Vec<u8>,
+          version: Version, expires: Expiry },
+    Del { seq: u64, ns: NsId, key: Vec<u8> },
+    /// A namespace was created with the given id, or dropped. These
+    /// carry no sequence number.
+    Namespace { ns: NsId, name: String },
+    DropNs { ns: NsId, name: String },
+}
+
+impl Record {
+    pub fn seq(&self) -> u64 {
+        match *self {
+            Record::Put { seq, .. } | Record::Del { seq, .. } => seq,
+            Record::Namespace { .. } | Record::DropNs { .. } => 0,
+        }
+    }
+}
+
+/// What read_record finds next in a file.
+enum Item {
+    Rec(Record),
+    Begin(u64),
+    Commit(u64),
+}
+
+fn write_header<W: Write>(w: &mut W, socket: usize)
+    -> io::Result<usize> {
+    let mut h = FileHeader::new(socket);
+    try!(w.write_all(h.as_bytes_mut()));
+    Ok(mem::size_of::<FileHeader>())
+}
+
+/// Append a PUT or DEL record to buf. Returns its length.
+fn encode_record(buf: &mut Vec<u8>, up: &Update) -> usize {
+    let kind = if up.value.is_some() { REC_PUT } else { REC_DEL };
+    let value = up.value.unwrap_or(&[]);
+    let mut h = RecordHeader::new(kind, up);
+    let len = mem::size_of::<RecordHeader>() + up.key.len() + value.len();
+    buf.reserve(len);
+    buf.extend_from_slice(h.as_bytes_mut());
+    buf.extend_from_slice(up.key);
+    buf.extend_from_slice(value);
+    len
+}
+
+/// Write a PUT or DEL record with a single write, so that records
+/// appended to one file by different threads cannot interleave.
+fn write_record<W: Write>(w: &mut W, up: &Update) -> io::Result<usize> {
+    let mut buf: Vec<u8> = Vec::new();
+    let len = encode_record(&mut buf, up);
+    try!(w.write_all(&buf));
+    Ok(len)
+}
+
+/// Write a BEGIN or COMMIT record for a batch of n updates.
+fn write_mark<W: Write>(w: &mut W, kind: u32, n: usize)
+    -> io::Result<usize> {
+    let mut h = RecordHeader::empty();
+    h.kind = kind;
+    h.seq = n as u64;
+    let crc = h.checksum(&[], &[]);
+    h.crc = crc;
+    try!(w.write_all(h.as_bytes_mut()));
+    Ok(mem::size_of::<RecordHeader>())
+}
+
+/// Write an NS or NSDROP record.
+fn write_ns<W: Write>(w: &mut W, kind: u32, ns: NsId, name: &str)
+    -> io::Result<usize> {
+    let mut h = RecordHeader::empty();
+    h.kind = kind;
+    h.keylen = name.len() as u32;
+    h.ns = ns;
+    let crc = h.checksum(name.as_bytes(), &[]);
+    h.crc = crc;
+    let mut buf: Vec<u8>;
+    buf = Vec::with_capacity(mem::size_of::<RecordHeader>() + name.len());
+    buf.extend_from_slice(h.as_bytes_mut());
+    buf.extend_from_slice(name.as_bytes());
+    try!(w.write_all(&buf));
+    Ok(buf.len())
+}
+
+/// Check the header at the start of a file. Returns false if the
+/// file is empty or its header was torn (it was being created when
+/// we crashed), and an error if it was written in another format.
+fn read_header<R: Read>(r: &mut R, name: &Path, socket: usize)
+    -> io::Result<bool> {
+    let mut h = FileHeader { magic: 0, format: 0, socket: 0 };
+    let n = try!(read_full(r, h.as_bytes_mut()));
+    if n < mem::size_of::<FileHeader>() {
+        if n > 0 {
+            warn!("{:?}: truncated file header", name);
+        }
+        return Ok(false);
+    }
+    let (magic, format) = (h.magic, h.format);
+    if magic != MAGIC || format != FORMAT ||
+        h.socket as usize != socket {
+        warn!("{:?}: not a log file of format {} (magic 0x{:x} \
+               format {})", name, FORMAT, magic, format);
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "unknown write-ahead log format"));
+    }
+    Ok(true)
+}
+
+/// Read the next record. Returns None at the end of the file, or at
+/// the first record which is truncated or fails its checksum (e.g.
+/// a write torn by a crash); everything after it is ignored.
+fn read_record<R: Read>(r: &mut R, name: &Path) -> Option<Item> {
+    let mut h = RecordHeader::empty();
+    match read_full(r, h.as_bytes_mut()) {
+        Ok(0) => return None,
+        Ok(n) if n == mem::size_of::<RecordHeader>() => {},
+        _ => {
+            warn!("{:?}: truncated record header", name);
+            return None;
+        },
+    }
+    let keylen = h.keylen as usize;
+    let datalen = h.datalen as usize;
+    let kind = h.kind;
+    let ok = match kind {
+        REC_PUT => keylen > 0,
+        REC_DEL => keylen > 0 && datalen == 0,
+        REC_NS | REC_NSDROP => datalen == 0,
+        REC_BEGIN | REC_COMMIT => keylen == 0 && datalen == 0,
+        _ => false,
+    };
+    if !ok {
+        warn!("{:?}: malformed record header", name);
+        return None;
+    }
+    let mut key: Vec<u8> = vec![0u8; keylen];
+    let mut value: Vec<u8> = vec![0u8; datalen];
+    let ok = match (read_full(r, &mut key), read_full(r, &mut value)) {
+        (Ok(k), Ok(v)) => k == keylen && v == datalen,
+        _ => false,
+    };
+    if !ok {
+        warn!("{:?}: truncated record", name);
+        return None;
+    }
+    if h.crc != h.checksum(&key, &value) {
+        warn!("{:?}: record checksum mismatch", name);
+        return None;
+    }
+    let (seq, ns, version) = (h.seq, h.ns, h.version);
+    let expires = thelog::expiry_from_wall(h.expires);
+    if kind == REC_NS || kind == REC_NSDROP {
+        let name = match String::from_utf8(key) {
+            Ok(name) => name,
+            Err(_) => {
+                warn!("{:?}: namespace name is not UTF-8", name);
+                return None;
+            },
+        };
+        return Some(Item::Rec(match kind {
+            REC_NS => Record::Namespace { ns: ns, name: name },
+            _ => Record::DropNs { ns: ns, name: name },
+        }));
+    }
+    Some(match kind {
+        REC_PUT => Item::Rec(Record::Put { seq: seq, ns: ns, key: key,
+            value: value, version: version, expires: expires }),
+        REC_DEL => Item::Rec(Record::Del { seq: seq, ns: ns, key: key }),
+        REC_BEGIN => Item::Begin(seq),
+        _ => Item::Commit(seq),
+    })
+}
+
+/// Like read_exact, but reports how much was read before EOF.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0usize;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+//==----------------------------------------------------==//
+//      File naming
+//==----------------------------------------------------==//
+
+fn wal_path(dir: &Path, gen: usize, socket: usize) -> PathBuf {
+    dir.join(format!("wal.{}.{}", gen, socket))
+}
+
+/// Generation named by CURRENT, or zero if there is none yet.
+fn current_gen(dir: &Path) -> io::Result<usize> {
+    let mut s = String::new();
+    match File::open(dir.join(CURRENT)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+        Ok(mut f) => { try!(f.read_to_string(&mut s)); },
+    }
+    s.trim().parse::<usize>().map_err(|_|
+        io::Error::new(io::ErrorKind::InvalidData,
+                       "CURRENT does not hold a generation"))
+}
+
+/// Atomically replace CURRENT with one naming gen.
+fn set_current_gen(dir: &Path, gen: usize) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", CURRENT));
+    {
+        let mut f = try!(File::create(&tmp));
+        try!(write!(f, "{}\n", gen));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&tmp, dir.join(CURRENT)));
+    // make the rename itself durable
+    try!(try!(File::open(dir)).sync_all());
+    Ok(())
+}
+
+/// Append to w what follows offset from in the file at path.
+/// Returns the offset copied up to.
+fn copy_tail<W: Write>(path: &Path, from: u64, w: &mut W)
+    -> io::Result<u64> {
+    let mut f = try!(File::open(path));
+    try!(f.seek(SeekFrom::Start(from)));
+    let n = try!(io::copy(&mut f, w));
+    Ok(from + n)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn io_err(e: io::Error) -> ErrorCode {
+    warn!("wal: {}", e);
+    ErrorCode::IoError { kind: e.kind() }
+}
+
+/// Read back every record in the current generation, in file order,
+/// one socket's file at a time. Sockets are numbered as they were
+/// when the files were written. The records of a batch are passed on
+/// once its COMMIT is read; those of a batch cut short by the end of
+/// a file are dropped. Returns the largest sequence number seen.
+/// Records from different files may be interleaved in time; callers
+/// must use the sequence numbers to order updates to a key.
+pub fn replay<F>(dir: &Path, mut f: F) -> Result<u64,ErrorCode>
+    where F: FnMut(usize, Record) {
+
+    let gen = try!(current_gen(dir).map_err(io_err));
+    let mut maxseq = 0u64;
+    let mut socket = 0usize;
+    loop {
+        let path = wal_path(dir, gen, socket);
+        let file = match File::open(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(io_err(e)),
+            Ok(file) => file,
+        };
+        info!("replaying {:?}", path);
+        let mut r = BufReader::new(file);
+        if !try!(read_header(&mut r, &path, socket).map_err(io_err)) {
+            socket += 1;
+            continue;
+        }
+        let mut n = 0usize;
+        // batch being read: its length, and the records so far
+        let mut batch: Option<(u64,Vec<Record>)> = None;
+        {
+            let mut apply = |rec: Record| {
+                if rec.seq() > maxseq {
+                    maxseq = rec.seq();
+                }
+                f(socket, rec);
+                n += 1;
+            };
+            while let Some(item) = read_record(&mut r, &path) {
+                match item {
+                    Item::Rec(rec) => match batch {
+                        Some((_, ref mut recs)) => recs.push(rec),
+                        None => apply(rec),
+                    },
+                    Item::Begin(len) => {
+                        if batch.is_some() {
+                            warn!("{:?}: batch begins inside another",
+                                  path);
+                            break;
+                        }
+                        batch = Some((len, Vec::new()));
+                    },
+                    Item::Commit(len) => match batch.take() {
+                        Some((l, recs)) if l == len &&
+                            recs.len() as u64 == len => {
+                            for rec in recs {
+                                apply(rec);
+                            }
+                        },
+                        _ => {
+                            warn!("{:?}: commit matches no batch", path);
+                            break;
+                        },
+                    },
+                }
+            }
+        }
+        if let Some((_, recs)) = batch {
+            warn!("{:?}: dropped {} records of an incomplete batch",
+                  path, recs.len());
+        }
+        info!("replayed {} records from {:?}", n, path);
+        socket += 1;
+    }
+    Ok(maxseq)
+}
+
+//==----------------------------------------------------==//
+//      The write-ahead log
+//==----------------------------------------------------==//
+
+pub type WalRef = Arc<Wal>;
+
+pub struct Wal {
+    dir: PathBuf,
+    /// Generation of the files currently appended to
+    gen: AtomicUsize,
+    /// Indexed per socket
+    files: Vec<pl::Mutex<File>>,
+    /// Indexed per socket; used by checkpoints to find live objects
+    managers: Vec<SegmentManagerRef>,
+    index: IndexRef,
+    seq: AtomicUsize,
+    /// Bytes appended since the last checkpoint, and the size of the
+    /// files right after it.
+    written: AtomicUsize,
+    base: AtomicUsize,
+    /// Compaction holds this for reading while it relocates objects,
+    /// so that a checkpoint does not miss any. Lock order: relocate,
+    /// then files.
+    relocate: pl::RwLock<()>,
+    ckpt: pl::Mutex<()>,
+}
+
+impl Wal {
+
+    /// Open the files of the current generation in dir for
+    /// appending, creating them if needed. Sequence numbers continue
+    /// after nextseq.
+    pub fn open(dir: &Path, managers: Vec<SegmentManagerRef>,
+                index: IndexRef, nextseq: u64) -> Result<Self,ErrorCode> {
+
+        try!(fs::create_dir_all(dir).map_err(io_err));
+        let gen = try!(current_gen(dir).map_err(io_err));
+        let mut files = Vec::with_capacity(managers.len());
+        let mut size = 0usize;
+        for socket in 0..managers.len() {
+            let mut f = try!(open_append(&wal_path(dir, gen, socket))
+                             .map_err(io_err));
+            let mut len = try!(f.metadata().map_err(io_err)).len();
+            if len == 0 {
+                len = try!(write_header(&mut f, socket)
+                           .map_err(io_err)) as u64;
+            }
+            size += len as usize;
+            files.push(pl::Mutex::new(f));
+        }
+        info!("wal: dir {:?} gen {} size {}", dir, gen, size);
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            gen: AtomicUsize::new(gen),
+            files: files,
+            managers: managers,
+            index: index,
+            seq: AtomicUsize::new(nextseq as usize),
+            written: AtomicUsize::new(0),
+            base: AtomicUsize::new(size),
+            relocate: pl::RwLock::new(()),
+            ckpt: pl::Mutex::new(()),
+        })
+    }
+
+    /// Take a sequence number for an update. Call this while the key
+    /// is locked in the index.
+    #[inline(always)]
+    pub fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::Relaxed) as u64
+    }
+
+    /// Record a PUT, or a DEL if the update has no value.
+    pub fn log(&self, socket: usize, up: &Update) -> Status {
+        let mut file = self.files[socket].lock();
+        let n = try!(write_record(&mut *file, up).map_err(io_err));
+        self.written.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    pub fn log_del(&self, socket: usize, seq: u64, ns: NsId,
+                   key: &KeyType) -> Status {
+        self.log(socket, &Update { seq: seq, ns: ns, key: key,
+                                   value: None, version: 0,
+                                   expires: NEVER })
+    }
+
+    /// Record the updates of a batch such that replay applies all of
+    /// them or none. They go to one file, in a single write.
+    pub fn log_batch(&self, socket: usize, ups: &[Update]) -> Status {
+        let mut buf: Vec<u8> = Vec::new();
+        try!(write_mark(&mut buf, REC_BEGIN, ups.len()).map_err(io_err));
+        for up in ups {
+            encode_record(&mut buf, up);
+        }
+        try!(write_mark(&mut buf, REC_COMMIT, ups.len()).map_err(io_err));
+        let mut file = self.files[socket].lock();
+        try!(file.write_all(&buf).map_err(io_err));
+        self.written.fetch_add(buf.len(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    /// Record the creation of a namespace, in every file. Call before
+    /// any object of it is logged.
+    pub fn log_ns(&self, ns: NsId, name: &str) -> Status {
+        self.log_all(REC_NS, ns, name)
+    }
+
+    pub fn log_drop_ns(&self, ns: NsId, name: &str) -> Status {
+        self.log_all(REC_NSDROP, ns, name)
+    }
+
+    fn log_all(&self, kind: u32, ns: NsId, name: &str) -> Status {
+        let mut n = 0usize;
+        for f in &self.files {
+            let mut file = f.lock();
+            n += try!(write_ns(&mut *file, kind, ns, name)
+                      .map_err(io_err));
+        }
+        self.written.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    /// Flush all files to stable storage.
+    pub fn sync(&self) -> Status {
+        for f in &self.files {
+            try!(f.lock().sync_data().map_err(io_err));
+        }
+        Ok(1)
+    }
+
+    /// Held by compaction while it moves objects between segments.
+    /// Records may be appended while it is held, but not the other
+    /// way round: take this before any lock on the files.
+    pub fn relocating(&self) -> pl::RwLockReadGuard<()> {
+        self.relocate.read()
+    }
+
+    /// Checkpoint if the files have grown enough since the last one
+    /// and no other checkpoint is running.
+    pub fn maybe_checkpoint(&self) {
+        let base = self.base.load(Ordering::Relaxed);
+        let size = base + self.written.load(Ordering::Relaxed);
+        if size < CKPT_MIN_BYTES || size < CKPT_RATIO * base {
+            return;
+        }
+        if let Err(e) = self.checkpoint() {
+            warn!("wal checkpoint failed: {:?}", e);
+        }
+    }
+
+    /// Write all live objects into a new generation of files, make it
+    /// current, and delete the prior one. Appends continue to the
+    /// prior generation meanwhile; what they wrote is then copied to
+    /// the end of the new files, and only the last of it, and the
+    /// switch itself, block appends. Objects are not relocated by
+    /// compaction until this completes.
+    pub fn checkpoint(&self) -> Status {
+        let _ckpt = match self.ckpt.try_lock() {
+            None => return Ok(0), // someone else is doing it
+            Some(g) => g,
+        };
+
+        // Stop relocation first, so each live object is in a segment
+        // we are about to list; compaction appends to the files while
+        // holding relocate for reading, so it must be taken before
+        // them. Records are appended once the index is updated, so
+        // any written before the ends of the files as they are now
+        // will be found in the index by write_live, or superseded;
+        // those written after are copied over from the old files.
+        let _reloc = self.relocate.write();
+        let mut ends: Vec<u64> = Vec::with_capacity(self.files.len());
+        for f in &self.files {
+            ends.push(try!(f.lock().metadata().map_err(io_err)).len());
+        }
+        // the NS records of these may lie before the ends, but objects
+        // of theirs copied over after them
+        let known = match self.managers.first() {
+            None => vec![],
+            Some(m) => m.namespaces().list(),
+        };
+
+        let old = self.gen.load(Ordering::Relaxed);
+        let gen = old + 1;
+        info!("wal: checkpoint gen {} -> {}", old, gen);
+
+        meta::pin();
+        let ret = self.write_live(gen, &known);
+        meta::quiesce();
+        let mut size = try!(ret);
+
+        // copy most of what was appended meanwhile without blocking
+        // appends, then the remainder with the files locked
+        let mut news: Vec<File> = Vec::with_capacity(self.files.len());
+        for socket in 0..self.files.len() {
+            let mut f = try!(open_append(&wal_path(&self.dir, gen, socket))
+                             .map_err(io_err));
+            let end = try!(copy_tail(&wal_path(&self.dir, old, socket),
+                                     ends[socket], &mut f)
+                           .map_err(io_err));
+            try!(f.sync_data().map_err(io_err));
+            size += (end - ends[socket]) as usize;
+            ends[socket] = end;
+            news.push(f);
+        }
+        let mut files: Vec<pl::MutexGuard<File>> =
+            self.files.iter().map(|f| f.lock()).collect();
+        for (socket,f) in news.iter_mut().enumerate() {
+            let end = try!(copy_tail(&wal_path(&self.dir, old, socket),
+                                     ends[socket], f)
+                           .map_err(io_err));
+            try!(f.sync_data().map_err(io_err));
+            size += (end - ends[socket]) as usize;
+        }
+
+        try!(set_current_gen(&self.dir, gen).map_err(io_err));
+        for (socket,f) in news.into_iter().enumerate() {
+            *files[socket] = f;
+            if let Err(e) = fs::remove_file(
+                    wal_path(&self.dir, old, socket)) {
+                warn!("wal: removing gen {}: {}", old, e);
+            }
+        }
+        self.gen.store(gen, Ordering::Relaxed);
+        self.base.store(size, Ordering::Relaxed);
+        self.written.store(0, Ordering::Relaxed);
+        info!("wal: checkpoint wrote {} bytes", size);
+        Ok(size)
+    }
+
+    /// Write a PUT record for every live object into the files of
+    /// generation gen, each file naming the known namespaces first,
+    /// and any other namespace of its objects before the first of
+    /// them. Objects which have expired, or whose namespace was
+    /// dropped, are left out, as no record would remove them. Caller
+    /// must hold the epoch pinned. Returns the number of bytes
+    /// written.
+    fn write_live(&self, gen: usize, known: &[(NsId,String)]) -> Status {
+        let mut total = 0usize;
+        let mut key: Vec<u8> = Vec::with_capacity(64);
+        let mut value: Vec<u8> = Vec::new();
+        for (socket,manager) in self.managers.iter().enumerate() {
+            let path = wal_path(&self.dir, gen, socket);
+            let file = try!(File::create(&path).map_err(io_err));
+            let mut w = BufWriter::new(file);
+            total += try!(write_header(&mut w, socket).map_err(io_err));
+            let namespaces = manager.namespaces();
+            // namespaces already named in this file
+            let mut named: HashSet<NsId> = HashSet::new();
+            for &(ns, ref name) in known {
+                total += try!(write_ns(&mut w, REC_NS, ns, name)
+                              .map_err(io_err));
+                named.insert(ns);
+            }
+            for segref in manager.segments() {
+                let seg = segref.read();
+                for entry in seg.into_iter() {
+                    if entry.is_expired() ||
+                        namespaces.is_dropped(entry.ns) {
+                        continue;
+                    }
+                    unsafe { entry.get_key(&mut key); }
+                    let ientry = merge(socket as u16,
+                                       entry.get_loc() as u64);
+                    // new sequence number taken, and the value read,
+                    // under the key's lock, so any later update to it
+                    // (including an edit in place) supersedes this one
+                    let mut seq: Option<(u64,Version)> = None;
+                    self.index.lock_map_ifex(&key, &|e| e == ientry,
+                        &mut |e| if e == ientry {
+                            let entry = entry.reload();
+                            value.resize(entry.datalen as usize, 0u8);
+                            unsafe { entry.get_buf(&mut value); }
+                            seq = Some((self.next_seq(), entry.version));
+                        });
+                    let (seq,version) = match seq {
+                        None => continue, // stale entry
+                        Some(s) => s,
+                    };
+                    if entry.ns != meta::DEFAULT_NS &&
+                        !named.contains(&entry.ns) {
+                        // ids enter the table before any object
+                        // can use them
+                        let info = match namespaces.get(entry.ns) {
+                            None => continue,
+                            Some(info) => info,
+                        };
+                        total += try!(write_ns(&mut w, REC_NS, entry.ns,
+                                               info.name())
+                                      .map_err(io_err));
+                        named.insert(entry.ns);
+                    }
+                    let up = Update { seq: seq, ns: entry.ns, key: &key,
+                        value: Some(&value[..]), version: version,
+                        expires: entry.expires };
+                    total += try!(write_record(&mut w, &up)
+                                  .map_err(io_err));
+                }
+            }
+            let file = try!(w.into_inner().map_err(|e| io_err(e.into())));
+            try!(file.sync_all().map_err(io_err));
+        }
+        Ok(total)
+    }
+}
+
+//==----------------------------------------------------==//
+//      Unit tests
+//==----------------------------------------------------==//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock;
+    use meta::DEFAULT_NS;
+    use thelog::expired_at;
+
+    use std::env;
+    use std::io::{Seek,SeekFrom};
+
+    fn tmpdir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nibble-wal-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn put<'a>(seq: u64, key: &'a KeyType, value: &'a [u8],
+               version: Version) -> Update<'a> {
+        Update { seq: seq, ns: DEFAULT_NS, key: key,
+                 value: Some(value), version: version, expires: NEVER }
+    }
+
+    fn del<'a>(seq: u64, key: &'a KeyType) -> Update<'a> {
+        Update { seq: seq, ns: DEFAULT_NS, key: key,
+                 value: None, version: 0, expires: NEVER }
+    }
+
+    fn rec(up: &Update) -> Record {
+        match up.value {
+            Some(v) => Record::Put { seq: up.seq, ns: up.ns,
+                key: up.key.to_vec(), value: v.to_vec(),
+                version: up.version, expires: up.expires },
+            None => Record::Del { seq: up.seq, ns: up.ns,
+                key: up.key.to_vec() },
+        }
+    }
+
+    /// Create the file of socket in generation gen holding ups.
+    fn write_file(dir: &Path, gen: usize, socket: usize, ups: &[Update])
+        -> PathBuf {
+        let path = wal_path(dir, gen, socket);
+        let mut f = File::create(&path).unwrap();
+        write_header(&mut f, socket).unwrap();
+        for up in ups {
+            write_record(&mut f, up).unwrap();
+        }
+        path
+    }
+
+    fn replay_all(dir: &Path) -> (u64, Vec<(usize,Record)>) {
+        let mut recs: Vec<(usize,Record)> = Vec::new();
+        let maxseq = replay(dir, |socket, rec| recs.push((socket, rec)))
+            .unwrap();
+        (maxseq, recs)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = tmpdir("roundtrip");
+        let mut ns = put(2, b"b", b"berry", 7);
+        ns.ns = 3;
+        let ups = vec![put(1, b"a", b"apple", 1), ns, del(4, b"a")];
+        write_file(&dir, 0, 0, &ups);
+        write_file(&dir, 0, 1, &[put(3, b"c", b"", 2)]);
+
+        let (maxseq, recs) = replay_all(&dir);
+        assert_eq!(maxseq, 4);
+        let expected: Vec<(usize,Record)> = ups.iter()
+            .map(|up| (0, rec(up)))
+            .chain(Some((1, rec(&put(3, b"c", b"", 2)))))
+            .collect();
+        assert_eq!(recs, expected);
+    }
+
+    /// a record cut short by a crash ends the file
+    #[test]
+    fn torn_record() {
+        let dir = tmpdir("torn");
+        let ups = vec![put(1, b"a", b"apple", 1), put(2, b"b", b"berry", 1)];
+        let path = write_file(&dir, 0, 0, &ups);
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 3).unwrap();
+
+        let (maxseq, recs) = replay_all(&dir);
+        assert_eq!(maxseq, 1);
+        assert_eq!(recs, vec![(0, rec(&ups[0]))]);
+    }
+
+    /// as is one whose bytes do not match its checksum
+    #[test]
+    fn crc_mismatch() {
+        let dir = tmpdir("crc");
+        let ups = vec![put(1, b"a", b"apple", 1), put(2, b"b", b"berry", 1),
+                       put(3, b"c", b"cherry", 1)];
+        let path = write_file(&dir, 0, 0, &ups);
+        // last byte of the second value
+        let at = 2 * mem::size_of::<RecordHeader>() + 1 + 5 + 1 + 5 - 1;
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start((mem::size_of::<FileHeader>() + at) as u64))
+            .unwrap();
+        f.write_all(b"X").unwrap();
+
+        let (_, recs) = replay_all(&dir);
+        assert_eq!(recs, vec![(0, rec(&ups[0]))]);
+    }
+
+    /// a batch is applied only once its commit is read
+    #[test]
+    fn batches() {
+        let dir = tmpdir("batches");
+        let path = wal_path(&dir, 0, 0);
+        let done = vec![put(1, b"a", b"apple", 1), del(2, b"b")];
+        let torn = vec![put(3, b"c", b"cherry", 1)];
+        {
+            let mut f = File::create(&path).unwrap();
+            write_header(&mut f, 0).unwrap();
+            write_mark(&mut f, REC_BEGIN, done.len()).unwrap();
+            for up in &done {
+                write_record(&mut f, up).unwrap();
+            }
+            write_mark(&mut f, REC_COMMIT, done.len()).unwrap();
+            write_mark(&mut f, REC_BEGIN, torn.len()).unwrap();
+            write_record(&mut f, &torn[0]).unwrap();
+        }
+
+        let (maxseq, recs) = replay_all(&dir);
+        assert_eq!(maxseq, 2);
+        assert_eq!(recs, vec![(0, rec(&done[0])), (0, rec(&done[1]))]);
+
+        // a commit for another number of records is malformed
+        {
+            let mut f = open_append(&path).unwrap();
+            write_mark(&mut f, REC_COMMIT, 2).unwrap();
+            write_record(&mut f, &put(4, b"d", b"date", 1)).unwrap();
+        }
+        let (maxseq, recs) = replay_all(&dir);
+        assert_eq!(maxseq, 2);
+        assert_eq!(recs.len(), 2);
+    }
+
+    /// replay reads only the generation CURRENT names
+    #[test]
+    fn current_generation() {
+        let dir = tmpdir("current");
+        assert_eq!(current_gen(&dir).unwrap(), 0);
+        let old = put(1, b"a", b"old", 1);
+        let new = put(2, b"a", b"new", 2);
+        write_file(&dir, 0, 0, &[old]);
+        write_file(&dir, 1, 0, &[put(2, b"a", b"new", 2)]);
+        let (_, recs) = replay_all(&dir);
+        assert_eq!(recs.len(), 1);
+        assert_eq!(recs[0].1.seq(), 1);
+
+        set_current_gen(&dir, 1).unwrap();
+        assert_eq!(current_gen(&dir).unwrap(), 1);
+        let (maxseq, recs) = replay_all(&dir);
+        assert_eq!(maxseq, 2);
+        assert_eq!(recs, vec![(0, rec(&new))]);
+    }
+
+    /// an empty file has no records; one of another format is refused
+    #[test]
+    fn file_header() {
+        let dir = tmpdir("header");
+        File::create(wal_path(&dir, 0, 0)).unwrap();
+        write_file(&dir, 0, 1, &[put(1, b"a", b"apple", 1)]);
+        let (_, recs) = replay_all(&dir);
+        assert_eq!(recs.len(), 1);
+        assert_eq!(recs[0].0, 1);
+
+        {
+            let mut f = File::create(wal_path(&dir, 0, 0)).unwrap();
+            let mut h = FileHeader::new(0);
+            h.format = FORMAT - 1;
+            f.write_all(h.as_bytes_mut()).unwrap();
+        }
+        match replay(&dir, |_, _| {}) {
+            Err(ErrorCode::IoError { kind: io::ErrorKind::InvalidData }) => {},
+            r => panic!("expected InvalidData, got {:?}", r),
+        }
+    }
+
+    /// expiry times survive being written by one process and read by
+    /// another, whose clock has another origin
+    #[test]
+    fn expiry() {
+        let dir = tmpdir("expiry");
+        let hour = clock::from_nano(3600 * clock::NANO_PER_SEC);
+        let mut live = put(1, b"a", b"apple", 1);
+        live.expires = clock::now() + hour;
+        let mut dead = put(2, b"b", b"berry", 1);
+        dead.expires = clock::now();
+        write_file(&dir, 0, 0, &[live, dead]);
+
+        let (_, recs) = replay_all(&dir);
+        let now = clock::now();
+        match recs[0].1 {
+            Record::Put { expires, .. } => {
+                assert!(!expired_at(expires, now));
+                let second = clock::from_nano(clock::NANO_PER_SEC);
+                assert!(expires <= now + hour + second);
+            },
+            ref r => panic!("expected a put, got {:?}", r),
+        }
+        match recs[1].1 {
+            Record::Put { expires, .. } => assert!(expired_at(expires, now)),
+            ref r => panic!("expected a put, got {:?}", r),
+        }
+    }
+
+    /// namespace records read back with their names, and order with
+    /// the objects of the file
+    #[test]
+    fn namespaces() {
+        let dir = tmpdir("namespaces");
+        {
+            let mut f = File::create(wal_path(&dir, 0, 0)).unwrap();
+            write_header(&mut f, 0).unwrap();
+            write_ns(&mut f, REC_NS, 3, "users").unwrap();
+            let mut up = put(1, b"a", b"apple", 1);
+            up.ns = 3;
+            write_record(&mut f, &up).unwrap();
+            write_ns(&mut f, REC_NSDROP, 3, "users").unwrap();
+            write_ns(&mut f, REC_NS, 4, "").unwrap();
+        }
+        let (maxseq, recs) = replay_all(&dir);
+        assert_eq!(maxseq, 1);
+        let recs: Vec<Record> = recs.into_iter().map(|(_, r)| r).collect();
+        let mut up = put(1, b"a", b"apple", 1);
+        up.ns = 3;
+        assert_eq!(recs, vec![
+            Record::Namespace { ns: 3, name: "users".to_string() },
+            rec(&up),
+            Record::DropNs { ns: 3, name: "users".to_string() },
+            Record::Namespace { ns: 4, name: String::new() },
+        ]);
+    }
+}
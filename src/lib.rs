@@ -18,13 +18,6 @@
 
 
 #![feature(test)]
-#![feature(const_fn)]
-#![feature(asm)]
-#![feature(repr_simd)]
-#![feature(integer_atomics)]
-
-// used for kvs/tlock.rs
-#![feature(core_intrinsics)]
 
 // Clippy tool
 //#![feature(plugin)]
@@ -39,12 +32,14 @@
 
 // Used for likely/unlikely
 #![feature(core_intrinsics)]
+#![allow(internal_features)]
 
 // Remove these XXX
 #![allow(dead_code)]
 #![allow(unreachable_code)]
 
-#![forbid(const_err)]
+// shared structures are updated in place through raw pointers
+#![allow(invalid_reference_casting)]
 
 #[macro_use]
 extern crate log;
@@ -59,7 +54,6 @@ extern crate time;
 extern crate crossbeam;
 extern crate itertools;
 extern crate quicksort;
-extern crate syscall;
 extern crate parking_lot;
 extern crate num;
 
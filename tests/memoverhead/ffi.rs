@@ -50,7 +50,7 @@ fn kvs_init(cap: usize, nitems: usize) {
 	logger::enable();
 	println!("# LSM allocating...");
 	//let kvs: Box<LSM> = Box::new(LSM::default());
-	let kvs: Box<LSM> = Box::new(LSM::new2(cap,nitems));
+	let kvs: Box<LSM> = Box::new(LSM::new2(cap,nitems).unwrap());
 	println!("# LSM enabling compaction on Node 0");
     kvs.enable_compaction(NodeId(0));
 	let p = Box::into_raw(kvs);
@@ -69,7 +69,7 @@ fn kvs_put(key: u64, len: u64) -> i32 {
     match kvs.put_where(&obj, lsm::PutPolicy::Specific(0)) {
         Ok(_) => 0i32,
         Err(e) => match e {
-            ErrorCode::OutOfMemory => 1i32,
+            ErrorCode::OutOfMemory{..} => 1i32,
             _ => panic!("error put: {:?}", e),
         },
     }
@@ -50,7 +50,7 @@ fn kvs_init(cap: usize, nitems: usize) {
 	logger::enable();
 	println!("# LSM allocating...");
 	//let kvs: Box<LSM> = Box::new(LSM::default());
-	let kvs: Box<LSM> = Box::new(LSM::new2(cap,nitems));
+	let kvs: Box<LSM> = Box::new(LSM::new2(cap,nitems).unwrap());
 	println!("# LSM enabling compaction");
 	for sock in 0..numa::NODE_MAP.sockets() {
 		kvs.enable_compaction(NodeId(sock));
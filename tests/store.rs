@@ -42,7 +42,6 @@ use kvs::lsm::LSM;
 use kvs::segment::{ObjDesc,SEGMENT_SIZE};
 use kvs::logger;
 use kvs::common::ErrorCode;
-use kvs::epoch;
 use kvs::numa::NodeId;
 
 // TODO test objects larger than block, and segment
@@ -60,7 +59,7 @@ fn alloc_free(pct_to_free: f32) {
 
     logger::enable();
 
-    let mut kvs = LSM::default();
+    let mut kvs = LSM::default().unwrap();
 
     kvs.enable_compaction(NodeId(0));
     thread::yield_now();
@@ -80,7 +79,7 @@ fn alloc_free(pct_to_free: f32) {
             size += obj.len_with_header();
             if let Err(code) = kvs.put_object(&obj) {
                 match code {
-                    ErrorCode::OutOfMemory => break,
+                    ErrorCode::OutOfMemory{..} => break,
                     _ => panic!("put failed"),
                 }
             }